@@ -12,6 +12,7 @@ use std::io::prelude::*;
 
 use std::borrow::Borrow;
 use std::boxed::Box;
+use std::collections::HashMap;
 use std::collections::hash_map::RandomState;
 use std::error::Error as StdError;
 use std::ffi::{OsStr, OsString};
@@ -59,10 +60,35 @@ fn get_all_files<P: AsRef<Path>>(path: P) -> Box<Iterator<Item=(PathBuf, u64)>>
     Box::new(files.into_iter().map(|(_mtime, path, size)| (path, size)))
 }
 
+/// The name of the file under the cache root in which the LRU order is saved.
+const INDEX_FILE: &'static str = ".lru-index";
+/// The name of the temporary file the index is written to before being renamed into place.
+const INDEX_TMP_FILE: &'static str = ".lru-index.tmp";
+/// The first line of a valid index file.
+const INDEX_HEADER: &'static str = "lru-disk-cache index v1";
+
+/// Parse the contents of an index file into a map of relative path to position in the LRU
+/// order, least-recently-used first. Returns `None` if the contents are not a valid index.
+fn parse_index(contents: &str) -> Option<HashMap<OsString, usize>> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(INDEX_HEADER) {
+        return None;
+    }
+    let mut index = HashMap::new();
+    for (i, line) in lines.enumerate() {
+        if line.is_empty() {
+            return None;
+        }
+        index.insert(OsString::from(line), i);
+    }
+    Some(index)
+}
+
 /// An LRU cache of files on disk.
 pub struct LruDiskCache<S: BuildHasher = RandomState> {
     lru: LruCache<OsString, (PathBuf, u64), S, FileSize>,
     root: PathBuf,
+    max_files: Option<usize>,
 }
 
 /// Errors returned by this crate.
@@ -121,18 +147,31 @@ fn filetime_now() -> FileTime {
 impl LruDiskCache {
     /// Create an `LruDiskCache` that stores files in `path`, limited to `size` bytes.
     ///
-    /// Existing files in `path` will be stored with their last-modified time from the filesystem
-    /// used as the order for the recency of their use. Any files that are individually larger
-    /// than `size` bytes will be removed.
+    /// Existing files in `path` will be stored in the order recorded by the index saved when the
+    /// cache was last dropped. Files missing from the index are treated as less recently used
+    /// than every indexed file, and are ordered among themselves by their last-modified time from
+    /// the filesystem. Any files that are individually larger than `size` bytes will be removed.
     ///
     /// The cache is not observant of changes to files under `path` from external sources, it
     /// expects to have sole maintence of the contents.
     pub fn new<T>(path: T, size: usize) -> Result<Self>
         where PathBuf: From<T>
+    {
+        LruDiskCache::with_max_files(path, size, None)
+    }
+
+    /// Create an `LruDiskCache` like `new`, but which also holds at most `max_files` files, if
+    /// given. A limit of zero is treated as no limit.
+    pub fn with_max_files<T>(path: T, size: usize, max_files: Option<usize>) -> Result<Self>
+        where PathBuf: From<T>
     {
         LruDiskCache {
             lru: LruCache::with_meter(size, FileSize),
             root: PathBuf::from(path),
+            max_files: match max_files {
+                Some(0) => None,
+                m => m,
+            },
         }.init()
     }
 
@@ -142,13 +181,35 @@ impl LruDiskCache {
     /// Return the maximum size of the cache.
     pub fn capacity(&self) -> usize { self.lru.capacity() }
 
+    /// Return the number of files in the cache.
+    pub fn len(&self) -> usize { self.lru.len() }
+
+    /// Return the maximum number of files in the cache, if limited.
+    pub fn max_files(&self) -> Option<usize> { self.max_files }
+
     /// Return the path in which the cache is stored.
     pub fn path(&self) -> &Path { self.root.as_path() }
 
     /// Scan `self.root` for existing files and store them.
     fn init(mut self) -> Result<Self> {
         try!(fs::create_dir_all(&self.root));
-        for (file, size) in get_all_files(&self.root) {
+        let index_path = self.root.join(INDEX_FILE);
+        let tmp_path = self.root.join(INDEX_TMP_FILE);
+        let index = self.read_index();
+        let mut files: Vec<_> = get_all_files(&self.root)
+            .filter(|&(ref file, _)| *file != index_path && *file != tmp_path)
+            .collect();
+        if let Some(index) = index {
+            // This is a stable sort, so unindexed files stay in mtime order.
+            let root = self.root.clone();
+            files.sort_by_key(|&(ref file, _)| {
+                file.strip_prefix(&root).ok()
+                    .and_then(|rel| index.get(rel.as_os_str()))
+                    .map(|&i| i + 1)
+                    .unwrap_or(0)
+            });
+        }
+        for (file, size) in files {
             if !self.can_store(size) {
                 fs::remove_file(file).unwrap_or_else(|e| error!("Error removing file `{}` which is too large for the cache ({} bytes)", e, size));
             } else {
@@ -159,6 +220,29 @@ impl LruDiskCache {
         Ok(self)
     }
 
+    /// Read and remove the index saved by a previous instance, if there is one. The index is
+    /// removed so that a stale order isn't used if this instance exits without saving its own.
+    fn read_index(&self) -> Option<HashMap<OsString, usize>> {
+        let index_path = self.root.join(INDEX_FILE);
+        let mut contents = String::new();
+        let read = File::open(&index_path).and_then(|mut f| f.read_to_string(&mut contents));
+        match read {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read LRU index `{:?}`, ignoring it: {}", index_path, e);
+                fs::remove_file(&index_path).unwrap_or_else(|e| error!("Error removing LRU index: {}", e));
+                return None;
+            }
+        }
+        let index = parse_index(&contents);
+        if index.is_none() {
+            warn!("LRU index `{:?}` is corrupt, ignoring it", index_path);
+        }
+        fs::remove_file(&index_path).unwrap_or_else(|e| error!("Error removing LRU index: {}", e));
+        index
+    }
+
     /// Returns `true` if the disk cache can store a file of `size` bytes.
     pub fn can_store(&self, size: u64) -> bool {
         size <= self.lru.capacity() as u64
@@ -174,8 +258,11 @@ impl LruDiskCache {
         } else {
             path.strip_prefix(&self.root).expect("Bad path?").as_os_str()
         });
+        // Replacing an existing entry doesn't change the number of files.
+        let new_files = if self.lru.contains_key(&rel_path) { 0 } else { 1 };
         //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
-        while self.lru.size() as u64 + size > self.lru.capacity() as u64 {
+        while self.lru.size() as u64 + size > self.lru.capacity() as u64
+            || self.max_files.map_or(false, |max| self.lru.len() + new_files > max) {
             let (_, (remove_path, _)) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
             //TODO: check that files are removable during `init`, so that this is only
             // due to outside interference.
@@ -241,11 +328,69 @@ impl LruDiskCache {
                 Ok(Box::new(try!(File::open(path))) as Box<ReadSeek>)
             })
     }
+
+    /// Remove the file at `key` from the cache, deleting it from disk.
+    pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
+        match self.lru.remove(key.as_ref()) {
+            Some((path, _)) => {
+                try!(fs::remove_file(&path));
+                Ok(())
+            }
+            None => Err(Error::FileNotInCache),
+        }
+    }
+
+    /// Remove all files from the cache, deleting them from disk.
+    ///
+    /// Returns the number of files removed and their total size.
+    pub fn clear(&mut self) -> Result<(usize, u64)> {
+        let mut count = 0;
+        let mut bytes = 0;
+        while let Some((_, (path, size))) = self.lru.remove_lru() {
+            try!(fs::remove_file(&path));
+            count += 1;
+            bytes += size;
+        }
+        Ok((count, bytes))
+    }
+}
+
+impl<S: BuildHasher> LruDiskCache<S> {
+    /// Save the current LRU order to an index file under the cache root, so that it can be
+    /// restored by the next `LruDiskCache` created there. This is called when the cache is
+    /// dropped. Nothing is written for an empty cache.
+    pub fn save_index(&self) -> Result<()> {
+        if self.lru.len() == 0 {
+            return Ok(());
+        }
+        let tmp_path = self.root.join(INDEX_TMP_FILE);
+        {
+            let mut f = io::BufWriter::new(try!(File::create(&tmp_path)));
+            try!(writeln!(f, "{}", INDEX_HEADER));
+            for (rel_path, _) in self.lru.iter() {
+                match rel_path.to_str() {
+                    Some(p) if !p.is_empty() && !p.contains('\n') => try!(writeln!(f, "{}", p)),
+                    _ => debug!("Not saving unrepresentable path `{:?}` in LRU index", rel_path),
+                }
+            }
+            try!(f.flush());
+        }
+        try!(fs::rename(&tmp_path, self.root.join(INDEX_FILE)));
+        Ok(())
+    }
+}
+
+impl<S: BuildHasher> Drop for LruDiskCache<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_index() {
+            warn!("Failed to save LRU index: {}", e);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{LruDiskCache, Error};
+    use super::{LruDiskCache, Error, INDEX_FILE};
 
     use filetime::{FileTime, set_file_times};
     use std::fs::{self,File};
@@ -410,6 +555,142 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lru_index_persists() {
+        let f = TestFixture::new();
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 30).unwrap();
+            c.insert_bytes("file1", &vec![1; 10]).unwrap();
+            c.insert_bytes("file2", &vec![2; 10]).unwrap();
+            c.insert_bytes("file3", &vec![3; 10]).unwrap();
+            // Bump file1, so the order is now file2, file3, file1.
+            c.get("file1").unwrap();
+        }
+        assert!(f.tmp().join(INDEX_FILE).exists());
+        // Make the mtimes disagree with the saved order.
+        set_mtime_back(f.tmp().join("file1"), 30);
+        set_mtime_back(f.tmp().join("file3"), 20);
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 30).unwrap();
+            assert_eq!(c.size(), 30);
+            c.insert_bytes("file4", &vec![4; 10]).unwrap();
+            assert!(!c.contains_key("file2"));
+            c.insert_bytes("file5", &vec![5; 10]).unwrap();
+            assert!(!c.contains_key("file3"));
+            assert!(c.contains_key("file1"));
+        }
+    }
+
+    #[test]
+    fn test_lru_index_reconcile() {
+        let f = TestFixture::new();
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 30).unwrap();
+            c.insert_bytes("file1", &vec![1; 10]).unwrap();
+            c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        }
+        // Remove an indexed file and add an unindexed one behind the cache's back.
+        fs::remove_file(f.tmp().join("file1")).unwrap();
+        f.create_file("file3", 10);
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 30).unwrap();
+            assert_eq!(c.size(), 20);
+            assert!(!c.contains_key("file1"));
+            c.insert_bytes("file4", &vec![4; 10]).unwrap();
+            // The unindexed file is older than anything in the index.
+            c.insert_bytes("file5", &vec![5; 10]).unwrap();
+            assert!(!c.contains_key("file3"));
+            assert!(c.contains_key("file2"));
+        }
+    }
+
+    #[test]
+    fn test_lru_index_corrupt() {
+        let f = TestFixture::new();
+        f.create_file("file1", 10);
+        f.create_file("file2", 10);
+        set_mtime_back(f.tmp().join("file1"), 10);
+        create_file(f.tmp(), INDEX_FILE, |mut file| file.write_all(b"garbage\nfile2\nfile1\n")).unwrap();
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 20).unwrap();
+            // The corrupt index is discarded, so the order comes from mtimes.
+            assert!(!f.tmp().join(INDEX_FILE).exists());
+            assert_eq!(c.size(), 20);
+            c.insert_bytes("file3", &vec![3; 10]).unwrap();
+            assert!(!c.contains_key("file1"));
+            assert!(c.contains_key("file2"));
+        }
+    }
+
+    #[test]
+    fn test_max_files() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::with_max_files(f.tmp(), 1024, Some(3)).unwrap();
+        assert_eq!(c.max_files(), Some(3));
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        c.insert_bytes("file3", &vec![3; 10]).unwrap();
+        assert_eq!(c.len(), 3);
+        // A fourth file should only evict the least-recently-used file.
+        c.insert_bytes("file4", &vec![4; 10]).unwrap();
+        assert_eq!(c.len(), 3);
+        assert_eq!(c.size(), 30);
+        assert!(!c.contains_key("file1"));
+        assert!(c.contains_key("file2"));
+        assert!(c.contains_key("file3"));
+        assert!(c.contains_key("file4"));
+        // Replacing an existing file shouldn't evict anything.
+        c.insert_bytes("file4", &vec![4; 5]).unwrap();
+        assert_eq!(c.len(), 3);
+        assert!(c.contains_key("file2"));
+    }
+
+    #[test]
+    fn test_max_files_existing() {
+        let f = TestFixture::new();
+        f.create_file("file1", 10);
+        f.create_file("file2", 10);
+        f.create_file("file3", 10);
+        set_mtime_back(f.tmp().join("file1"), 10);
+        set_mtime_back(f.tmp().join("file2"), 5);
+        let c = LruDiskCache::with_max_files(f.tmp(), 1024, Some(2)).unwrap();
+        assert_eq!(c.len(), 2);
+        assert!(!c.contains_key("file1"));
+        assert!(!f.tmp().join("file1").exists());
+    }
+
+    #[test]
+    fn test_remove() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("a/b/c", &vec![0; 10]).unwrap();
+        c.insert_bytes("a/b/d", &vec![0; 10]).unwrap();
+        assert_eq!(c.size(), 20);
+        c.remove("a/b/c").unwrap();
+        assert_eq!(c.size(), 10);
+        assert!(!c.contains_key("a/b/c"));
+        assert!(!f.tmp().join("a/b/c").exists());
+        assert!(c.contains_key("a/b/d"));
+        match c.remove("a/b/c") {
+            Err(Error::FileNotInCache) => assert!(true),
+            x @ _ => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_clear() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("a/b/c", &vec![0; 10]).unwrap();
+        c.insert_bytes("a/b/d", &vec![0; 5]).unwrap();
+        assert_eq!(c.clear().unwrap(), (2, 15));
+        assert_eq!(c.size(), 0);
+        assert!(!c.contains_key("a/b/c"));
+        assert!(!f.tmp().join("a/b/c").exists());
+        assert!(!f.tmp().join("a/b/d").exists());
+        assert_eq!(c.clear().unwrap(), (0, 0));
+    }
+
     #[test]
     fn test_insert_bytes_too_large() {
         let f = TestFixture::new();
@@ -12,18 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use cache::disk::DiskCache;
+use cache::chain::ChainCache;
+use cache::disk::{DiskCache, ShardedDiskCache, SharedDiskCache};
+use cache::memory::MemoryCache;
 #[cfg(feature = "redis")]
 use cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use cache::s3::S3Cache;
-use config::{self, CONFIG};
+use config::{self, Config, CONFIG};
 use futures_cpupool::CpuPool;
 use std::fmt;
 use std::io::{
     self,
     Read,
     Seek,
+    SeekFrom,
     Write,
 };
 use std::sync::Arc;
@@ -88,11 +91,22 @@ impl CacheRead {
         io::copy(&mut file, to)?;
         Ok(file.unix_mode())
     }
+
+    /// Read the whole entry back, as it was stored.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut reader = self.zip.into_inner();
+        reader.seek(SeekFrom::Start(0))?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
 }
 
 /// Data to be stored in the compiler cache.
 pub struct CacheWrite {
     zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    /// The data of an entry that was already finished, if this is a copy.
+    finished: Option<Vec<u8>>,
 }
 
 impl CacheWrite {
@@ -101,6 +115,17 @@ impl CacheWrite {
     {
         CacheWrite {
             zip: ZipWriter::new(io::Cursor::new(vec!())),
+            finished: None,
+        }
+    }
+
+    /// Create a cache entry holding `data`, which another entry's `finish`
+    /// returned, so that it can be stored more than once.
+    pub fn from_finished(data: Vec<u8>) -> CacheWrite
+    {
+        CacheWrite {
+            zip: ZipWriter::new(io::Cursor::new(vec!())),
+            finished: Some(data),
         }
     }
 
@@ -109,6 +134,9 @@ impl CacheWrite {
     pub fn put_object<T>(&mut self, name: &str, from: &mut T, mode: Option<u32>) -> Result<()>
         where T: Read,
     {
+        if self.finished.is_some() {
+            bail!("Can't add objects to a finished cache entry");
+        }
         let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
         let opts = if let Some(mode) = mode { opts.unix_permissions(mode) } else { opts };
         self.zip.start_file(name, opts).chain_err(|| {
@@ -121,12 +149,24 @@ impl CacheWrite {
     /// Finish writing data to the cache entry writer, and return the data.
     pub fn finish(self) -> Result<Vec<u8>>
     {
-        let CacheWrite { mut zip } = self;
+        let CacheWrite { mut zip, finished } = self;
+        if let Some(data) = finished {
+            return Ok(data);
+        }
         let cur = zip.finish().chain_err(|| "Failed to finish cache entry zip")?;
         Ok(cur.into_inner())
     }
 }
 
+/// The result of removing every entry from a cache with `Storage::clear`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClearStats {
+    /// The number of entries removed, if known.
+    pub entries: Option<usize>,
+    /// The total size of the entries removed, if known.
+    pub bytes: Option<u64>,
+}
+
 /// An interface to cache storage.
 pub trait Storage {
     /// Get a cache entry by `key`.
@@ -144,6 +184,14 @@ pub trait Storage {
     /// finished.
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration>;
 
+    /// Remove the cache entry for `key`.
+    ///
+    /// Removing an entry that is not in the cache is not an error.
+    fn delete(&self, key: &str) -> SFuture<()>;
+
+    /// Remove every entry from the cache.
+    fn clear(&self) -> SFuture<ClearStats>;
+
     /// Get the storage location.
     fn location(&self) -> String;
 
@@ -153,20 +201,55 @@ pub trait Storage {
     /// Get the maximum storage size, if applicable.
     fn max_size(&self) -> Option<usize>;
 
+    /// Get the current number of entries, if applicable.
+    fn current_entries(&self) -> Option<usize> { None }
+
+    /// Get the maximum number of entries, if applicable.
+    fn max_entries(&self) -> Option<usize> { None }
+
+    /// Get how long new entries are kept before they expire, if applicable.
+    fn ttl(&self) -> Option<Duration> { None }
+
     /// Is this storage configured for test purposes?
     fn is_test(&self) -> bool { false }
 }
 
 /// Get a suitable `Storage` implementation from the environment.
-pub fn storage_from_environment(pool: &CpuPool, _handle: &Handle) -> Arc<Storage> {
-    use config::CacheType;
+pub fn storage_from_environment(pool: &CpuPool, handle: &Handle) -> Arc<Storage> {
     println!("storage_from_environment: {:?}", *config::CONFIG);
-    match CONFIG.cache_type {
+    storage_from_config(&CONFIG, pool, handle)
+}
+
+/// Get a suitable `Storage` implementation for `config`, with an in-memory
+/// cache in front of it if `config` asks for one.
+pub fn storage_from_config(config: &Config, pool: &CpuPool, handle: &Handle) -> Arc<Storage> {
+    let storage = backend_from_config(config, pool, handle);
+    match config.memcache_size {
+        Some(size) => {
+            trace!("Using MemoryCache of size {}", size);
+            Arc::new(MemoryCache::new(storage, size, pool))
+        }
+        None => storage,
+    }
+}
+
+/// Get the `Storage` implementation that `config` stores entries in.
+fn backend_from_config(config: &Config, pool: &CpuPool, _handle: &Handle) -> Arc<Storage> {
+    use config::CacheType;
+    match config.cache_type {
         CacheType::S3(ref c) => {
             if cfg!(feature = "s3") {
                 debug!("Trying S3Cache({})", c.endpoint);
                 #[cfg(feature = "s3")]
-                match S3Cache::new(&c.bucket, &c.endpoint, _handle) {
+                match S3Cache::new(&c.bucket,
+                                   &c.endpoint,
+                                   &c.key_prefix,
+                                   c.server_side_encryption.as_ref().map(|s| s.as_str()),
+                                   c.kms_key_id.as_ref().map(|s| s.as_str()),
+                                   config.http_timeout,
+                                   config.cache_retry,
+                                   c.compute_size,
+                                   _handle) {
                     Ok(s) => {
                         trace!("Using S3Cache");
                         return Arc::new(s);
@@ -182,7 +265,7 @@ pub fn storage_from_environment(pool: &CpuPool, _handle: &Handle) -> Arc<Storage
             if cfg!(feature = "redis") {
                 debug!("Trying Redis({})", c.url);
                 #[cfg(feature = "redis")]
-                match RedisCache::new(&c.url, pool) {
+                match RedisCache::new(&c.url, &c.key_prefix, config.cache_ttl, config.cache_retry, pool) {
                     Ok(s) => {
                         trace!("Using Redis: {}", c.url);
                         return Arc::new(s);
@@ -194,19 +277,52 @@ pub fn storage_from_environment(pool: &CpuPool, _handle: &Handle) -> Arc<Storage
             }
         },
 
+        CacheType::Chain(ref tiers) => {
+            debug!("Using ChainCache({:?})", tiers);
+            let tiers = tiers.iter().map(|tier| {
+                let mut config = config.clone();
+                config.cache_type = tier.clone();
+                backend_from_config(&config, pool, _handle)
+            }).collect();
+            return Arc::new(ChainCache::new(tiers, pool))
+        },
+
+        // Without a server, other processes use the cache at the same time.
+        CacheType::Disk(ref c) if !c.shard_dirs.is_empty() && config.no_server => {
+            trace!("Using shared ShardedDiskCache({:?})", c.shard_dirs);
+            trace!("ShardedDiskCache size: {}, max files: {:?}", c.cache_size, c.max_files);
+            return Arc::new(ShardedDiskCache::new_shared(&c.shard_dirs, c.cache_size, c.max_files, pool))
+        },
+
+        CacheType::Disk(ref c) if !c.shard_dirs.is_empty() => {
+            trace!("Using ShardedDiskCache({:?})", c.shard_dirs);
+            trace!("ShardedDiskCache size: {}, max files: {:?}", c.cache_size, c.max_files);
+            return Arc::new(ShardedDiskCache::new(&c.shard_dirs, c.cache_size, c.max_files, pool))
+        },
+
+        CacheType::Disk(ref c) if config.no_server => {
+            trace!("Using SharedDiskCache({:?})", c.cache_dir);
+            trace!("SharedDiskCache size: {}, max files: {:?}", c.cache_size, c.max_files);
+            return Arc::new(SharedDiskCache::new(&c.cache_dir, c.cache_size, c.max_files, pool))
+        },
+
         CacheType::Disk(ref c) => {
             trace!("Using DiskCache({:?})", c.cache_dir);
-            trace!("DiskCache size: {}", c.cache_size);
-            return Arc::new(DiskCache::new(&c.cache_dir, c.cache_size, pool))
+            trace!("DiskCache size: {}, max files: {:?}", c.cache_size, c.max_files);
+            return Arc::new(DiskCache::with_max_files(&c.cache_dir, c.cache_size, c.max_files, pool))
         },
 
         CacheType::Invalid => {
-            panic!("Somehow got here with uninitialized CONFIG!");
+            panic!("Somehow got here with uninitialized config!");
         },
     }
 
     // Fall through to default disk cache
     let dir = config::default_disk_cache_dir();
+    if config.no_server {
+        trace!("Using fallback SharedDiskCache! ({:?})", dir);
+        return Arc::new(SharedDiskCache::new(&dir, 10 * 1024 * 1024 * 1024, None, pool));
+    }
     trace!("Using fallback DiskCache! ({:?})", dir);
     return Arc::new(DiskCache::new(&dir, 10 * 1024 * 1024 * 1024, pool));
 }
@@ -0,0 +1,176 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheWrite,
+    ClearStats,
+    Storage,
+};
+use futures::future::{self, Future};
+use futures_cpupool::CpuPool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use errors::*;
+
+/// A cache that tries each of several caches in order, such as a shared
+/// remote cache backed up by a local disk cache. A cache that fails is
+/// skipped, so that an unreachable tier doesn't fail the compile.
+pub struct ChainCache {
+    tiers: Arc<Vec<Arc<Storage>>>,
+    /// Thread pool to compress entries on, once for every tier.
+    pool: CpuPool,
+}
+
+impl ChainCache {
+    /// Create a new `ChainCache` over `tiers`, which are tried first to last,
+    /// compressing entries on `pool`.
+    pub fn new(tiers: Vec<Arc<Storage>>, pool: &CpuPool) -> ChainCache {
+        assert!(!tiers.is_empty(), "ChainCache needs at least one cache");
+        ChainCache {
+            tiers: Arc::new(tiers),
+            pool: pool.clone(),
+        }
+    }
+}
+
+/// Look up `key` in `tiers`, starting at `index`, until one of them has it.
+fn get_from(tiers: Arc<Vec<Arc<Storage>>>, index: usize, key: String) -> SFuture<Cache> {
+    if index == tiers.len() {
+        return f_ok(Cache::Miss)
+    }
+    Box::new(tiers[index].get(&key).then(move |result| {
+        match result {
+            Ok(Cache::Miss) => {}
+            Ok(cache) => {
+                trace!("ChainCache::get({}): found in {}", key, tiers[index].location());
+                return f_ok(cache)
+            }
+            Err(e) => warn!("ChainCache::get({}): {} failed: {}",
+                            key, tiers[index].location(), e),
+        }
+        get_from(tiers, index + 1, key)
+    }))
+}
+
+impl Storage for ChainCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("ChainCache::get({})", key);
+        get_from(self.tiers.clone(), 0, key.to_owned())
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        trace!("ChainCache::put({})", key);
+        let tiers = self.tiers.clone();
+        let key = key.to_owned();
+        let data = self.pool.spawn_fn(move || entry.finish());
+        Box::new(data.and_then(move |data| {
+            let puts = tiers.iter().map(|tier| {
+                let location = tier.location();
+                let key = key.clone();
+                tier.put(&key, CacheWrite::from_finished(data.clone())).then(move |result| {
+                    if let Err(ref e) = result {
+                        warn!("ChainCache::put({}): {} failed: {}", key, location, e);
+                    }
+                    Ok::<_, Error>(result.ok())
+                })
+            }).collect::<Vec<_>>();
+            future::join_all(puts).and_then(move |results| {
+                // Only fail if no cache stored the entry.
+                match results.into_iter().filter_map(|r| r).max() {
+                    Some(duration) => Ok(duration),
+                    None => bail!("failed to store {} in any cache", key),
+                }
+            })
+        }))
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        trace!("ChainCache::delete({})", key);
+        let deletes = self.tiers.iter().map(|tier| tier.delete(key)).collect::<Vec<_>>();
+        Box::new(future::join_all(deletes).map(|_| ()))
+    }
+
+    fn clear(&self) -> SFuture<ClearStats> {
+        trace!("ChainCache::clear");
+        let clears = self.tiers.iter().map(|tier| tier.clear()).collect::<Vec<_>>();
+        Box::new(future::join_all(clears).map(|stats| {
+            stats.iter().fold(ClearStats { entries: Some(0), bytes: Some(0) }, |total, s| {
+                ClearStats {
+                    entries: total.entries.and_then(|t| s.entries.map(|n| t + n)),
+                    bytes: total.bytes.and_then(|t| s.bytes.map(|n| t + n)),
+                }
+            })
+        }))
+    }
+
+    fn location(&self) -> String {
+        let locations = self.tiers.iter().map(|tier| tier.location()).collect::<Vec<_>>();
+        format!("Chain: {}", locations.join(" -> "))
+    }
+
+    // Report the first cache's sizes, which is the one usually hit.
+    fn current_size(&self) -> Option<usize> { self.tiers[0].current_size() }
+    fn max_size(&self) -> Option<usize> { self.tiers[0].max_size() }
+    fn current_entries(&self) -> Option<usize> { self.tiers[0].current_entries() }
+    fn max_entries(&self) -> Option<usize> { self.tiers[0].max_entries() }
+    fn ttl(&self) -> Option<Duration> { self.tiers[0].ttl() }
+
+    fn is_test(&self) -> bool {
+        self.tiers.iter().any(|tier| tier.is_test())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cache::disk::DiskCache;
+    use tempdir::TempDir;
+    use test::mock_storage::MockStorage;
+
+    #[test]
+    fn test_chain_cache_fallback() {
+        let tempdir = TempDir::new("sccache_test_chain").unwrap();
+        let pool = CpuPool::new(1);
+        let primary = Arc::new(MockStorage::new());
+        let fallback = DiskCache::new(&tempdir.path(), 1024 * 1024, &pool);
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"hello"[..], None).unwrap();
+        fallback.put("abcdef", entry).wait().unwrap();
+        let cache = ChainCache::new(vec![primary.clone() as Arc<Storage>, Arc::new(fallback)], &pool);
+        // The primary failing falls back to the next cache, which has the entry.
+        primary.next_get(f_err("S3 unreachable"));
+        match cache.get("abcdef").wait().unwrap() {
+            Cache::Hit(mut hit) => {
+                let mut obj = vec![];
+                hit.get_object("obj", &mut obj).unwrap();
+                assert_eq!(b"hello", obj.as_slice());
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // A miss in every cache is a miss.
+        primary.next_get(f_ok(Cache::Miss));
+        match cache.get("012345").wait().unwrap() {
+            Cache::Miss => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Entries are stored in every cache.
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"world"[..], None).unwrap();
+        cache.put("012345", entry).wait().unwrap();
+        assert!(tempdir.path().join("0").join("1").join("012345").exists());
+        assert!(cache.location().starts_with("Chain: Mock Storage -> Local disk: "));
+    }
+}
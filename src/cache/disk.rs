@@ -16,13 +16,17 @@ use cache::{
     Cache,
     CacheRead,
     CacheWrite,
+    ClearStats,
     Storage,
 };
-use futures::Future;
+use config;
+use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
 use lru_disk_cache::LruDiskCache;
 use lru_disk_cache::Error as LruError;
 use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read};
 use std::path::{Path,PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Instant, Duration};
@@ -45,9 +49,19 @@ impl DiskCache {
     pub fn new<T: AsRef<OsStr>>(root: &T,
                                 max_size: usize,
                                 pool: &CpuPool) -> DiskCache {
+        DiskCache::with_max_files(root, max_size, None, pool)
+    }
+
+    /// Create a new `DiskCache` like `new`, which also holds at most `max_files` entries, if given.
+    pub fn with_max_files<T: AsRef<OsStr>>(root: &T,
+                                           max_size: usize,
+                                           max_files: Option<usize>,
+                                           pool: &CpuPool) -> DiskCache {
+        //TODO: change this function to return a Result
+        let lru = LruDiskCache::with_max_files(root, max_size, max_files).expect("Couldn't instantiate disk cache!");
+        config::chown_to_sudo_user(lru.path());
         DiskCache {
-            //TODO: change this function to return a Result
-            lru: Arc::new(Mutex::new(LruDiskCache::new(root, max_size).expect("Couldn't instantiate disk cache!"))),
+            lru: Arc::new(Mutex::new(lru)),
             pool: pool.clone(),
             testing: false,
         }
@@ -102,19 +116,410 @@ impl Storage for DiskCache {
         self.pool.spawn_fn(move || {
             let start = Instant::now();
             let v = entry.finish()?;
-            lru.lock().unwrap().insert_bytes(key, &v)?;
+            let mut lru = lru.lock().unwrap();
+            lru.insert_bytes(&key, &v)?;
+            config::chown_to_sudo_user(&lru.path().join(&key));
             Ok(start.elapsed())
         }).boxed()
     }
 
+    fn delete(&self, key: &str) -> SFuture<()> {
+        trace!("DiskCache::delete({})", key);
+        let lru = self.lru.clone();
+        let path = make_key_path(key);
+        self.pool.spawn_fn(move || {
+            match lru.lock().unwrap().remove(&path) {
+                Ok(()) | Err(LruError::FileNotInCache) => Ok(()),
+                Err(LruError::Io(e)) => Err(e.into()),
+                Err(_) => panic!("Unexpected error!"),
+            }
+        }).boxed()
+    }
+
+    fn clear(&self) -> SFuture<ClearStats> {
+        trace!("DiskCache::clear");
+        let lru = self.lru.clone();
+        self.pool.spawn_fn(move || {
+            match lru.lock().unwrap().clear() {
+                Ok((entries, bytes)) => Ok(ClearStats {
+                    entries: Some(entries),
+                    bytes: Some(bytes),
+                }),
+                Err(LruError::Io(e)) => Err(e.into()),
+                Err(_) => panic!("Unexpected error!"),
+            }
+        }).boxed()
+    }
+
     fn location(&self) -> String {
         format!("Local disk: {:?}", self.lru.lock().unwrap().path())
     }
 
     fn current_size(&self) -> Option<usize> { Some(self.lru.lock().unwrap().size()) }
     fn max_size(&self) -> Option<usize> { Some(self.lru.lock().unwrap().capacity()) }
+    fn current_entries(&self) -> Option<usize> { Some(self.lru.lock().unwrap().len()) }
+    fn max_entries(&self) -> Option<usize> { self.lru.lock().unwrap().max_files() }
 
     fn is_test(&self) -> bool {
         self.testing
     }
 }
+
+/// A cache that stores entries at local disk paths, like `DiskCache`, in a
+/// directory that other processes use at the same time. That's the case
+/// when each compile runs in its own process, without a server.
+///
+/// Each lookup and store takes a lock shared by those processes, and scans
+/// the directory again while holding it, so that every process evicts
+/// entries by the same LRU order, and none reads an entry while it's being
+/// written. That makes it slower than `DiskCache` for large caches.
+#[derive(Clone)]
+pub struct SharedDiskCache {
+    root: PathBuf,
+    /// The file locked while using the cache. It's next to the directory
+    /// rather than in it, where it would be taken for an entry.
+    lock: PathBuf,
+    max_size: usize,
+    max_files: Option<usize>,
+    /// Thread pool to execute disk I/O
+    pool: CpuPool,
+}
+
+impl SharedDiskCache {
+    /// Create a new `SharedDiskCache` rooted at `root`, with `max_size` as the maximum cache size
+    /// on-disk, in bytes, and holding at most `max_files` entries, if given.
+    pub fn new<T: AsRef<OsStr>>(root: &T,
+                                max_size: usize,
+                                max_files: Option<usize>,
+                                pool: &CpuPool) -> SharedDiskCache {
+        let root = PathBuf::from(root.as_ref());
+        let lock = match root.file_name() {
+            Some(name) => {
+                let mut name = name.to_owned();
+                name.push(".lock");
+                root.with_file_name(name)
+            }
+            None => root.join(".lock"),
+        };
+        SharedDiskCache {
+            root: root,
+            lock: lock,
+            max_size: max_size,
+            max_files: max_files,
+            pool: pool.clone(),
+        }
+    }
+
+    /// Run `f` on the current contents of the cache, holding the lock.
+    fn with_lru<F, T>(&self, f: F) -> SFuture<T>
+        where F: FnOnce(&mut LruDiskCache) -> Result<T> + Send + 'static,
+              T: Send + 'static,
+    {
+        let root = self.root.clone();
+        let lock = self.lock.clone();
+        let max_size = self.max_size;
+        let max_files = self.max_files;
+        self.pool.spawn_fn(move || {
+            fs::create_dir_all(&root)?;
+            let _lock = lock_file(&lock).chain_err(|| format!("failed to lock {:?}", lock))?;
+            config::chown_to_sudo_user(&root);
+            config::chown_to_sudo_user(&lock);
+            let mut lru = match LruDiskCache::with_max_files(&root, max_size, max_files) {
+                Ok(lru) => lru,
+                Err(LruError::Io(e)) => return Err(e.into()),
+                Err(_) => panic!("Unexpected error!"),
+            };
+            // `lru` is dropped before `_lock`, so the index it saves is
+            // written while the lock is held.
+            f(&mut lru)
+        }).boxed()
+    }
+}
+
+/// Open `path` and take an exclusive lock on it, which other processes wait
+/// for until the returned `File` is closed.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> io::Result<File> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    let f = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(f)
+}
+
+#[cfg(windows)]
+fn lock_file(path: &Path) -> io::Result<File> {
+    use kernel32;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::{LOCKFILE_EXCLUSIVE_LOCK, OVERLAPPED};
+
+    let f = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+    let mut overlapped: OVERLAPPED = unsafe { mem::zeroed() };
+    if unsafe { kernel32::LockFileEx(f.as_raw_handle(), LOCKFILE_EXCLUSIVE_LOCK, 0, !0, !0, &mut overlapped) } == 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(f)
+}
+
+impl Storage for SharedDiskCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("SharedDiskCache::get({})", key);
+        let path = make_key_path(key);
+        let key = key.to_owned();
+        self.with_lru(move |lru| {
+            let mut f = match lru.get(&path) {
+                Ok(f) => f,
+                Err(LruError::FileNotInCache) => {
+                    trace!("SharedDiskCache::get({}): FileNotInCache", key);
+                    return Ok(Cache::Miss);
+                }
+                Err(LruError::Io(e)) => {
+                    trace!("SharedDiskCache::get({}): IoError: {:?}", key, e);
+                    return Err(e.into());
+                }
+                Err(_) => panic!("Unexpected error!"),
+            };
+            // Read the entry while holding the lock, since another process
+            // may evict it as soon as it's released.
+            let mut data = vec![];
+            f.read_to_end(&mut data)?;
+            let hit = CacheRead::from(Cursor::new(data))?;
+            Ok(Cache::Hit(hit))
+        })
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        trace!("SharedDiskCache::put({})", key);
+        let key = make_key_path(key);
+        let start = Instant::now();
+        let v = self.pool.spawn_fn(move || entry.finish());
+        let me = self.clone();
+        Box::new(v.and_then(move |v| {
+            me.with_lru(move |lru| {
+                lru.insert_bytes(&key, &v)?;
+                config::chown_to_sudo_user(&lru.path().join(&key));
+                Ok(start.elapsed())
+            })
+        }))
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        trace!("SharedDiskCache::delete({})", key);
+        let path = make_key_path(key);
+        self.with_lru(move |lru| {
+            match lru.remove(&path) {
+                Ok(()) | Err(LruError::FileNotInCache) => Ok(()),
+                Err(LruError::Io(e)) => Err(e.into()),
+                Err(_) => panic!("Unexpected error!"),
+            }
+        })
+    }
+
+    fn clear(&self) -> SFuture<ClearStats> {
+        trace!("SharedDiskCache::clear");
+        self.with_lru(|lru| {
+            match lru.clear() {
+                Ok((entries, bytes)) => Ok(ClearStats {
+                    entries: Some(entries),
+                    bytes: Some(bytes),
+                }),
+                Err(LruError::Io(e)) => Err(e.into()),
+                Err(_) => panic!("Unexpected error!"),
+            }
+        })
+    }
+
+    fn location(&self) -> String {
+        format!("Local disk, shared between processes: {:?}", self.root)
+    }
+
+    // Other processes change the size, so it's only known while locked.
+    fn current_size(&self) -> Option<usize> { None }
+    fn max_size(&self) -> Option<usize> { Some(self.max_size) }
+    fn max_entries(&self) -> Option<usize> { self.max_files }
+}
+
+/// A cache that spreads its entries across several `DiskCache`s, each in its
+/// own directory, picking the directory for an entry by hashing its key.
+pub struct ShardedDiskCache {
+    roots: Vec<PathBuf>,
+    shards: Vec<Box<Storage>>,
+}
+
+impl ShardedDiskCache {
+    /// Create a new `ShardedDiskCache` over `roots`, which share `max_size`
+    /// and `max_files` evenly between them.
+    pub fn new<T: AsRef<OsStr>>(roots: &[T],
+                                max_size: usize,
+                                max_files: Option<usize>,
+                                pool: &CpuPool) -> ShardedDiskCache {
+        ShardedDiskCache::with_shards(roots, max_size, max_files, |root, max_size, max_files| {
+            Box::new(DiskCache::with_max_files(root, max_size, max_files, pool))
+        })
+    }
+
+    /// Create a new `ShardedDiskCache` like `new`, whose shards are
+    /// `SharedDiskCache`s.
+    pub fn new_shared<T: AsRef<OsStr>>(roots: &[T],
+                                       max_size: usize,
+                                       max_files: Option<usize>,
+                                       pool: &CpuPool) -> ShardedDiskCache {
+        ShardedDiskCache::with_shards(roots, max_size, max_files, |root, max_size, max_files| {
+            Box::new(SharedDiskCache::new(root, max_size, max_files, pool))
+        })
+    }
+
+    fn with_shards<T, F>(roots: &[T],
+                         max_size: usize,
+                         max_files: Option<usize>,
+                         new_shard: F) -> ShardedDiskCache
+        where T: AsRef<OsStr>,
+              F: Fn(&T, usize, Option<usize>) -> Box<Storage>,
+    {
+        assert!(!roots.is_empty(), "ShardedDiskCache needs at least one directory");
+        let n = roots.len();
+        ShardedDiskCache {
+            roots: roots.iter().map(|root| PathBuf::from(root.as_ref())).collect(),
+            shards: roots.iter().map(|root| {
+                new_shard(root, max_size / n, max_files.map(|m| m / n))
+            }).collect(),
+        }
+    }
+
+    /// Get the shard that stores the entry with key `key`.
+    fn shard(&self, key: &str) -> &Storage {
+        &*self.shards[shard_index(key, self.shards.len())]
+    }
+
+    /// Sum `f` over all shards, or return `None` if any shard has no value.
+    fn sum<F>(&self, f: F) -> Option<usize>
+        where F: Fn(&Storage) -> Option<usize>,
+    {
+        self.shards.iter().fold(Some(0), |total, shard| {
+            total.and_then(|total| f(&**shard).map(|n| total + n))
+        })
+    }
+}
+
+/// Pick which of `shards` shards stores the entry with key `key`. This uses
+/// FNV-1a rather than the standard library's hasher, whose output isn't
+/// guaranteed to stay the same between Rust releases.
+fn shard_index(key: &str, shards: usize) -> usize {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % shards as u64) as usize
+}
+
+impl Storage for ShardedDiskCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.shard(key).get(key)
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        self.shard(key).put(key, entry)
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        self.shard(key).delete(key)
+    }
+
+    fn clear(&self) -> SFuture<ClearStats> {
+        trace!("ShardedDiskCache::clear");
+        let clears = self.shards.iter().map(|shard| shard.clear()).collect::<Vec<_>>();
+        Box::new(future::join_all(clears).map(|stats| {
+            stats.iter().fold(ClearStats { entries: Some(0), bytes: Some(0) }, |total, s| {
+                ClearStats {
+                    entries: total.entries.and_then(|t| s.entries.map(|n| t + n)),
+                    bytes: total.bytes.and_then(|t| s.bytes.map(|n| t + n)),
+                }
+            })
+        }))
+    }
+
+    fn location(&self) -> String {
+        format!("Local disk: {:?}", self.roots)
+    }
+
+    fn current_size(&self) -> Option<usize> { self.sum(|s| s.current_size()) }
+    fn max_size(&self) -> Option<usize> { self.sum(|s| s.max_size()) }
+    fn current_entries(&self) -> Option<usize> { self.sum(|s| s.current_entries()) }
+    fn max_entries(&self) -> Option<usize> { self.sum(|s| s.max_entries()) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_shared_disk_cache() {
+        let tempdir = TempDir::new("sccache_test_shared").unwrap();
+        let dir = tempdir.path().join("cache");
+        let pool = CpuPool::new(1);
+        // As if in two processes.
+        let a = SharedDiskCache::new(&dir, 1024 * 1024, Some(2), &pool);
+        let b = SharedDiskCache::new(&dir, 1024 * 1024, Some(2), &pool);
+        let put = |cache: &SharedDiskCache, key: &str| {
+            let mut entry = CacheWrite::new();
+            entry.put_object("obj", &mut &b"hello"[..], None).unwrap();
+            cache.put(key, entry).wait().unwrap();
+        };
+        let hit = |cache: &SharedDiskCache, key: &str| {
+            match cache.get(key).wait().unwrap() {
+                Cache::Hit(_) => true,
+                Cache::Miss => false,
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        };
+        put(&a, "aaa");
+        put(&b, "bbb");
+        assert!(hit(&b, "aaa"));
+        // Each sees the other's reads and writes, so the entry evicted to
+        // make room is the one neither used last.
+        put(&a, "ccc");
+        assert!(!hit(&a, "bbb"));
+        assert!(hit(&a, "aaa"));
+        assert!(hit(&b, "ccc"));
+    }
+
+    #[test]
+    fn test_sharded_disk_cache() {
+        let tempdir = TempDir::new("sccache_test_sharded").unwrap();
+        let dirs = vec![tempdir.path().join("a"), tempdir.path().join("b")];
+        let pool = CpuPool::new(1);
+        let cache = ShardedDiskCache::new(&dirs, 2 * 1024 * 1024, Some(100), &pool);
+        assert_eq!(Some(2 * 1024 * 1024), cache.max_size());
+        assert_eq!(Some(100), cache.max_entries());
+        let keys = (0..16).map(|i| format!("{:02x}deadbeef", i)).collect::<Vec<_>>();
+        for key in keys.iter() {
+            let mut entry = CacheWrite::new();
+            entry.put_object("obj", &mut &b"hello"[..], None).unwrap();
+            cache.put(key, entry).wait().unwrap();
+        }
+        // Every entry is in the directory its key picks, and only there.
+        let mut counts = vec![0; dirs.len()];
+        for key in keys.iter() {
+            let shard = shard_index(key, dirs.len());
+            counts[shard] += 1;
+            for (i, dir) in dirs.iter().enumerate() {
+                assert_eq!(i == shard, dir.join(make_key_path(key)).exists());
+            }
+            match cache.get(key).wait().unwrap() {
+                Cache::Hit(_) => {}
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+        assert!(counts.iter().all(|&n| n > 0), "entries not spread out: {:?}", counts);
+        assert_eq!(Some(keys.len()), cache.current_entries());
+        assert_eq!(cache.shards.iter().map(|s| s.current_size().unwrap()).sum::<usize>(),
+                   cache.current_size().unwrap());
+        let stats = cache.clear().wait().unwrap();
+        assert_eq!(Some(keys.len()), stats.entries);
+        assert_eq!(Some(0), cache.current_entries());
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    ClearStats,
+    Storage,
+};
+use futures::Future;
+use futures_cpupool::CpuPool;
+use lru_cache::{LruCache, Meter};
+use std::borrow::Borrow;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use errors::*;
+
+struct EntrySize;
+
+/// Measure entries by the size of their data.
+impl<K> Meter<K, Vec<u8>> for EntrySize {
+    type Measure = usize;
+    fn measure<Q: ?Sized>(&self, _: &Q, v: &Vec<u8>) -> usize
+        where K: Borrow<Q>
+    {
+        v.len()
+    }
+}
+
+/// A bounded cache of recently used entries, kept in memory in front of
+/// another cache, so that hot entries don't need to be read from disk or
+/// the network again.
+pub struct MemoryCache {
+    inner: Arc<Storage>,
+    lru: Arc<Mutex<LruCache<String, Vec<u8>, EntrySize>>>,
+    /// Thread pool to read entries from `inner` into memory, and to compress
+    /// entries written to it.
+    pool: CpuPool,
+}
+
+impl MemoryCache {
+    /// Create a new `MemoryCache` holding at most `max_size` bytes of the
+    /// entries read from or written to `inner`.
+    pub fn new(inner: Arc<Storage>, max_size: usize, pool: &CpuPool) -> MemoryCache {
+        MemoryCache {
+            inner: inner,
+            lru: Arc::new(Mutex::new(LruCache::with_meter(max_size, EntrySize))),
+            pool: pool.clone(),
+        }
+    }
+}
+
+/// Keep `data` for `key` in `lru`, evicting the least recently used entries
+/// to make room. Entries too big for the whole cache aren't kept.
+fn insert(lru: &Mutex<LruCache<String, Vec<u8>, EntrySize>>, key: String, data: Vec<u8>) {
+    let mut lru = lru.lock().unwrap();
+    if data.len() > lru.capacity() {
+        return
+    }
+    lru.insert(key, data);
+    while lru.size() > lru.capacity() {
+        lru.remove_lru();
+    }
+}
+
+impl Storage for MemoryCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("MemoryCache::get({})", key);
+        let data = self.lru.lock().unwrap().get(key).cloned();
+        if let Some(data) = data {
+            trace!("MemoryCache::get({}): hit", key);
+            return match CacheRead::from(Cursor::new(data)) {
+                Ok(hit) => f_ok(Cache::Hit(hit)),
+                Err(e) => f_err(e),
+            }
+        }
+        let lru = self.lru.clone();
+        let pool = self.pool.clone();
+        let key = key.to_owned();
+        Box::new(self.inner.get(&key).and_then(move |cache| -> SFuture<Cache> {
+            match cache {
+                Cache::Hit(hit) => Box::new(pool.spawn_fn(move || {
+                    let data = hit.into_bytes()?;
+                    insert(&lru, key, data.clone());
+                    Ok(Cache::Hit(CacheRead::from(Cursor::new(data))?))
+                })),
+                cache => f_ok(cache),
+            }
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        trace!("MemoryCache::put({})", key);
+        let inner = self.inner.clone();
+        let lru = self.lru.clone();
+        let key = key.to_owned();
+        let data = self.pool.spawn_fn(move || entry.finish());
+        Box::new(data.and_then(move |data| {
+            let put = inner.put(&key, CacheWrite::from_finished(data.clone()));
+            put.map(move |duration| {
+                insert(&lru, key, data);
+                duration
+            })
+        }))
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        trace!("MemoryCache::delete({})", key);
+        self.lru.lock().unwrap().remove(key);
+        self.inner.delete(key)
+    }
+
+    fn clear(&self) -> SFuture<ClearStats> {
+        trace!("MemoryCache::clear");
+        self.lru.lock().unwrap().clear();
+        self.inner.clear()
+    }
+
+    fn location(&self) -> String {
+        format!("{} (with {} bytes in memory)", self.inner.location(),
+                self.lru.lock().unwrap().capacity())
+    }
+
+    fn current_size(&self) -> Option<usize> { self.inner.current_size() }
+    fn max_size(&self) -> Option<usize> { self.inner.max_size() }
+    fn current_entries(&self) -> Option<usize> { self.inner.current_entries() }
+    fn max_entries(&self) -> Option<usize> { self.inner.max_entries() }
+    fn ttl(&self) -> Option<Duration> { self.inner.ttl() }
+
+    fn is_test(&self) -> bool {
+        self.inner.is_test()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test::mock_storage::MockStorage;
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn get_object(cache: &Storage, key: &str) -> Option<Vec<u8>> {
+        match cache.get(key).wait().unwrap() {
+            Cache::Hit(mut hit) => {
+                let mut obj = vec![];
+                hit.get_object("obj", &mut obj).unwrap();
+                Some(obj)
+            }
+            Cache::Miss => None,
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let pool = CpuPool::new(1);
+        let inner = Arc::new(MockStorage::new());
+        let data = entry(b"hello").finish().unwrap();
+        let cache = MemoryCache::new(inner.clone(), data.len() * 2, &pool);
+        // The first read goes to the inner cache.
+        inner.next_get(f_ok(Cache::Hit(CacheRead::from(Cursor::new(data)).unwrap())));
+        assert_eq!(Some(b"hello".to_vec()), get_object(&cache, "abcdef"));
+        // The second doesn't; `MockStorage` would panic if it did.
+        assert_eq!(Some(b"hello".to_vec()), get_object(&cache, "abcdef"));
+        // Written entries are kept too.
+        cache.put("012345", entry(b"world")).wait().unwrap();
+        assert_eq!(Some(b"world".to_vec()), get_object(&cache, "012345"));
+        // Reading "012345" made "abcdef" the least recently used, so it's
+        // evicted to make room.
+        cache.put("6789ab", entry(b"again")).wait().unwrap();
+        inner.next_get(f_ok(Cache::Miss));
+        assert_eq!(None, get_object(&cache, "abcdef"));
+        assert_eq!(Some(b"world".to_vec()), get_object(&cache, "012345"));
+        assert_eq!(Some(b"again".to_vec()), get_object(&cache, "6789ab"));
+    }
+}
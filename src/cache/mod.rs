@@ -13,9 +13,13 @@
 // limitations under the License.
 
 pub mod cache;
+pub mod chain;
 pub mod disk;
+pub mod memory;
 #[cfg(feature = "redis")]
 pub mod redis;
+#[cfg(any(feature = "redis", feature = "s3"))]
+pub mod retry;
 #[cfg(feature = "s3")]
 pub mod s3;
 
@@ -0,0 +1,246 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retrying network cache operations that fail for reasons that are likely
+//! to pass, like a dropped connection or a server error. Each retry waits
+//! twice as long as the one before it.
+
+use futures::future::{self, Future, Loop};
+#[cfg(feature = "hyper")]
+use hyper;
+#[cfg(feature = "hyper")]
+use hyper::status::StatusClass;
+#[cfg(feature = "redis")]
+use redis::RedisError;
+use std::cmp;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::*;
+
+/// How long to wait before the first retry, in milliseconds.
+const INITIAL_DELAY_MS: u64 = 100;
+
+/// The longest to wait before any retry, in milliseconds.
+const MAX_DELAY_MS: u64 = 5_000;
+
+/// How long to wait after the failure of attempt number `attempt`,
+/// counting from 1.
+fn backoff(attempt: u32) -> Duration {
+    let doublings = cmp::min(attempt - 1, 16);
+    Duration::from_millis(cmp::min(INITIAL_DELAY_MS << doublings, MAX_DELAY_MS))
+}
+
+/// Return true if `e` is an I/O error that retrying may get past.
+fn io_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "hyper")]
+fn hyper_transient(e: &hyper::Error) -> bool {
+    match *e {
+        hyper::Error::Io(ref e) => io_transient(e),
+        // The connection closed partway through the response.
+        hyper::Error::Incomplete => true,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_transient(e: &RedisError) -> bool {
+    e.is_timeout() || e.is_connection_dropped()
+}
+
+/// Return true if `e` is a timeout, a dropped connection or a server error,
+/// which are worth retrying. Missing entries and denied requests aren't.
+///
+/// Only the kind of `e` itself is looked at: the causes that `chain_err`
+/// adds are plain `error::Error`s that can't be told apart, so operations
+/// that may be retried return transport errors as they are, and context is
+/// added after retrying.
+pub fn is_transient(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::Io(ref e) => io_transient(e),
+        #[cfg(feature = "hyper")]
+        ErrorKind::Hyper(ref e) => hyper_transient(e),
+        #[cfg(feature = "hyper")]
+        ErrorKind::BadHTTPStatus(ref status) => status.class() == StatusClass::ServerError,
+        #[cfg(feature = "redis")]
+        ErrorKind::Redis(ref e) => redis_transient(e),
+        ErrorKind::RequestTimedOut(_) => true,
+        _ => false,
+    }
+}
+
+/// Run the operation that `f` starts up to `attempts` times, for as long as
+/// it fails with transient errors, waiting on `handle` between attempts.
+pub fn retry_future<T, F>(attempts: u32, handle: &Handle, mut f: F) -> SFuture<T>
+    where F: FnMut() -> SFuture<T> + 'static,
+          T: 'static,
+{
+    let handle = handle.clone();
+    Box::new(future::loop_fn(1, move |attempt| {
+        let handle = handle.clone();
+        f().then(move |result| -> SFuture<Loop<T, u32>> {
+            match result {
+                Ok(v) => f_ok(Loop::Break(v)),
+                Err(ref e) if attempt < attempts && is_transient(e) => {
+                    let delay = backoff(attempt);
+                    debug!("Cache operation failed on attempt {} of {}, retrying in {:?}: {}",
+                           attempt, attempts, delay, e);
+                    match Timeout::new(delay, &handle) {
+                        Ok(timeout) => {
+                            Box::new(timeout.map(move |()| Loop::Continue(attempt + 1)).map_err(Error::from))
+                        }
+                        Err(e) => f_err(e),
+                    }
+                }
+                Err(e) => f_err(e),
+            }
+        })
+    }))
+}
+
+/// Run `f` up to `attempts` times, for as long as it fails with transient
+/// errors, sleeping between attempts. For operations that block a thread
+/// anyway.
+pub fn retry_blocking<T, F>(attempts: u32, mut f: F) -> Result<T>
+    where F: FnMut() -> Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref e) if attempt < attempts && is_transient(e) => {
+                let delay = backoff(attempt);
+                debug!("Cache operation failed on attempt {} of {}, retrying in {:?}: {}",
+                       attempt, attempts, delay, e);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+
+    fn reset() -> Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer").into()
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::from_millis(100), backoff(1));
+        assert_eq!(Duration::from_millis(200), backoff(2));
+        assert_eq!(Duration::from_millis(400), backoff(3));
+        assert_eq!(Duration::from_millis(MAX_DELAY_MS), backoff(40));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&reset()));
+        assert!(is_transient(&ErrorKind::RequestTimedOut(Duration::from_secs(1)).into()));
+        // Adding context hides the error, so it's added after retrying.
+        let chained = Err::<(), _>(reset()).chain_err(|| "failed to put cache entry").unwrap_err();
+        assert!(!is_transient(&chained));
+        assert!(!is_transient(&"failed to get AWS credentials".into()));
+        assert!(!is_transient(&io::Error::new(io::ErrorKind::PermissionDenied, "denied").into()));
+    }
+
+    #[test]
+    #[cfg(feature = "hyper")]
+    fn test_is_transient_http_status() {
+        use hyper::status::StatusCode;
+        assert!(is_transient(&ErrorKind::BadHTTPStatus(StatusCode::InternalServerError).into()));
+        assert!(is_transient(&ErrorKind::BadHTTPStatus(StatusCode::ServiceUnavailable).into()));
+        assert!(!is_transient(&ErrorKind::BadHTTPStatus(StatusCode::NotFound).into()));
+        assert!(!is_transient(&ErrorKind::BadHTTPStatus(StatusCode::Forbidden).into()));
+    }
+
+    #[test]
+    fn test_retry_future() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        // Fails twice, then succeeds.
+        let calls = Rc::new(Cell::new(0));
+        let c = calls.clone();
+        let result = core.run(retry_future(3, &handle, move || {
+            c.set(c.get() + 1);
+            if c.get() < 3 { f_err(reset()) } else { f_ok(c.get()) }
+        }));
+        assert_eq!(3, result.unwrap());
+        assert_eq!(3, calls.get());
+        // Gives up after running out of attempts.
+        let calls = Rc::new(Cell::new(0));
+        let c = calls.clone();
+        let result = core.run(retry_future(2, &handle, move || -> SFuture<()> {
+            c.set(c.get() + 1);
+            f_err(reset())
+        }));
+        assert!(result.is_err());
+        assert_eq!(2, calls.get());
+        // Doesn't retry errors that aren't transient.
+        let calls = Rc::new(Cell::new(0));
+        let c = calls.clone();
+        let result = core.run(retry_future(3, &handle, move || -> SFuture<()> {
+            c.set(c.get() + 1);
+            f_err("not found")
+        }));
+        assert!(result.is_err());
+        assert_eq!(1, calls.get());
+    }
+
+    #[test]
+    fn test_retry_blocking() {
+        // Fails twice, then succeeds.
+        let mut calls = 0;
+        let result = retry_blocking(3, || {
+            calls += 1;
+            if calls < 3 { Err(reset()) } else { Ok(calls) }
+        });
+        assert_eq!(3, result.unwrap());
+        // Gives up after running out of attempts, with the last error.
+        let mut calls = 0;
+        let result = retry_blocking(2, || -> Result<()> {
+            calls += 1;
+            Err(reset())
+        });
+        assert!(is_transient(&result.unwrap_err()));
+        assert_eq!(2, calls);
+        // Doesn't retry errors that aren't transient.
+        let mut calls = 0;
+        let result = retry_blocking(3, || -> Result<()> {
+            calls += 1;
+            bail!("not found")
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+    }
+}
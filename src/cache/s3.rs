@@ -16,8 +16,10 @@ use cache::{
     Cache,
     CacheRead,
     CacheWrite,
+    ClearStats,
     Storage,
 };
+use cache::retry::retry_future;
 use futures::future::{self, Future};
 use simples3::{
     AutoRefreshingProvider,
@@ -25,8 +27,12 @@ use simples3::{
     ChainProvider,
     ProfileProvider,
     ProvideAwsCredentials,
+    AwsCredentials,
+    Sse,
     Ssl,
+    default_profile,
 };
+use std::cell::RefCell;
 use std::env;
 use std::io;
 use std::rc::Rc;
@@ -41,14 +47,55 @@ pub struct S3Cache {
     bucket: Rc<Bucket>,
     /// Credentials provider.
     provider: AutoRefreshingProvider<ChainProvider>,
+    /// A prefix for every object in the bucket.
+    key_prefix: String,
+    /// The size of the cache from listing the bucket, if enabled.
+    size: Option<Rc<RefCell<BucketSize>>>,
+    /// How many times to try a get or put that fails with a transient error.
+    attempts: u32,
+    handle: Handle,
+}
+
+/// How long the size of the cache from listing the bucket is reported
+/// before listing it again.
+const SIZE_REFRESH_INTERVAL_SECS: u64 = 10 * 60;
+
+/// What's known of the size of the cache from listing the bucket.
+#[derive(Default)]
+struct BucketSize {
+    /// The number of objects and their total size, once the bucket has
+    /// been listed.
+    totals: Option<(usize, usize)>,
+    /// When the last listing started.
+    listed: Option<Instant>,
 }
 
 impl S3Cache {
-    /// Create a new `S3Cache` storing data in `bucket`.
-    pub fn new(bucket: &str, endpoint: &str, handle: &Handle) -> Result<S3Cache> {
+    /// Create a new `S3Cache` storing data in `bucket`, with every object
+    /// stored under `key_prefix` if it's not empty.
+    ///
+    /// Objects are encrypted by S3 if `sse` is `AES256` or `aws:kms`, in the
+    /// latter case with `kms_key_id` if given.
+    ///
+    /// Individual HTTP requests are cut off after `http_timeout`, if given.
+    /// Gets and puts that fail with transient errors, like timeouts and
+    /// server errors, are tried up to `attempts` times.
+    ///
+    /// If `compute_size` is set, the size of the cache is found by listing
+    /// the bucket in the background when it's first asked for, and then
+    /// every so often.
+    pub fn new(bucket: &str,
+               endpoint: &str,
+               key_prefix: &str,
+               sse: Option<&str>,
+               kms_key_id: Option<&str>,
+               http_timeout: Option<Duration>,
+               attempts: u32,
+               compute_size: bool,
+               handle: &Handle) -> Result<S3Cache> {
         let home = env::home_dir().ok_or("Couldn't find home directory")?;
         let profile_providers = vec![
-            ProfileProvider::with_configuration(home.join(".aws").join("credentials"), "default"),
+            ProfileProvider::with_configuration(home.join(".aws").join("credentials"), default_profile()),
             //TODO: this is hacky, this is where our mac builders store their
             // credentials. We should either match what boto does more directly
             // or make those builders put their credentials in ~/.aws/credentials
@@ -56,22 +103,117 @@ impl S3Cache {
         ];
         let provider = AutoRefreshingProvider::new(ChainProvider::with_profile_providers(profile_providers, handle));
         //TODO: configurable SSL
-        let bucket = Rc::new(Bucket::new(bucket, endpoint, Ssl::No, handle));
+        let mut bucket = Bucket::new(bucket, endpoint, Ssl::No, http_timeout, handle);
+        match sse {
+            None => {}
+            Some("AES256") => bucket.set_server_side_encryption(Sse::Aes256),
+            Some("aws:kms") => bucket.set_server_side_encryption(Sse::Kms(kms_key_id.map(|s| s.to_owned()))),
+            Some(s) => bail!("unknown S3 server-side encryption: {}", s),
+        }
+        let bucket = Rc::new(bucket);
         Ok(S3Cache {
             bucket: bucket,
             provider: provider,
+            key_prefix: key_prefix.trim_matches('/').to_owned(),
+            size: if compute_size { Some(Rc::new(RefCell::new(BucketSize::default()))) } else { None },
+            attempts: attempts,
+            handle: handle.clone(),
         })
     }
+
+    /// Return the prefix of the keys of every object in the cache.
+    fn list_prefix(&self) -> String {
+        if self.key_prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", self.key_prefix)
+        }
+    }
+
+    /// Return the number of objects in the cache and their total size, from
+    /// the last listing of the bucket. Starts listing it again in the
+    /// background if that was long enough ago.
+    fn bucket_size(&self) -> Option<(usize, usize)> {
+        let size = match self.size {
+            Some(ref size) => size,
+            None => return None,
+        };
+        let stale = size.borrow().listed.map_or(true, |listed| {
+            listed.elapsed() >= Duration::from_secs(SIZE_REFRESH_INTERVAL_SECS)
+        });
+        if stale {
+            size.borrow_mut().listed = Some(Instant::now());
+            let bucket = self.bucket.clone();
+            let prefix = self.list_prefix();
+            let size = size.clone();
+            let listing = self.provider.credentials().chain_err(|| {
+                "failed to get AWS credentials"
+            }).and_then(move |credentials| {
+                list_all(bucket, prefix, None, credentials, (0, 0))
+            }).then(move |result| {
+                match result {
+                    Ok(totals) => size.borrow_mut().totals = Some(totals),
+                    Err(e) => warn!("Failed to list S3 bucket: {}", e),
+                }
+                Ok::<(), ()>(())
+            });
+            self.handle.spawn(listing);
+        }
+        let totals = size.borrow().totals;
+        totals
+    }
+}
+
+/// List every object in `bucket` under `prefix` after `marker`, adding the
+/// number of objects and their size to `totals`.
+fn list_all(bucket: Rc<Bucket>, prefix: String, marker: Option<String>,
+            credentials: AwsCredentials, totals: (usize, usize)) -> SFuture<(usize, usize)> {
+    let list = bucket.list(&prefix, marker.as_ref().map(|m| m.as_str()), &credentials);
+    Box::new(list.and_then(move |list| {
+        let totals = (totals.0 + list.keys.len(), totals.1 + list.bytes as usize);
+        match list.next_marker {
+            Some(marker) => list_all(bucket, prefix, Some(marker), credentials, totals),
+            None => f_ok(totals),
+        }
+    }))
+}
+
+/// Delete every object in `bucket` under `prefix` after `marker`, a page of
+/// the listing at a time, adding the number of objects and their size to
+/// `totals`.
+fn delete_all(bucket: Rc<Bucket>, prefix: String, marker: Option<String>,
+              credentials: AwsCredentials, totals: (usize, usize)) -> SFuture<(usize, usize)> {
+    let list = bucket.list(&prefix, marker.as_ref().map(|m| m.as_str()), &credentials);
+    Box::new(list.and_then(move |list| {
+        let deletes = list.keys.iter()
+            .map(|key| bucket.delete(key, &credentials))
+            .collect::<Vec<_>>();
+        let totals = (totals.0 + list.keys.len(), totals.1 + list.bytes as usize);
+        let next_marker = list.next_marker;
+        future::join_all(deletes).and_then(move |_| {
+            match next_marker {
+                Some(marker) => delete_all(bucket, prefix, Some(marker), credentials, totals),
+                None => f_ok(totals),
+            }
+        })
+    }))
 }
 
-fn normalize_key(key: &str) -> String {
-    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+fn normalize_key(prefix: &str, key: &str) -> String {
+    let key = format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key);
+    if prefix.is_empty() {
+        key
+    } else {
+        format!("{}/{}", prefix, key)
+    }
 }
 
 impl Storage for S3Cache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = normalize_key(key);
-        Box::new(self.bucket.get(&key).then(|result| {
+        let key = normalize_key(&self.key_prefix, key);
+        let bucket = self.bucket.clone();
+        let get = retry_future(self.attempts, &self.handle, move || bucket.get(&key));
+        Box::new(get.then(|result| {
             match result {
                 Ok(data) => {
                     let hit = CacheRead::from(io::Cursor::new(data))?;
@@ -86,7 +228,7 @@ impl Storage for S3Cache {
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = normalize_key(&key);
+        let key = normalize_key(&self.key_prefix, &key);
         let start = Instant::now();
         let data = match entry.finish() {
             Ok(data) => data,
@@ -97,8 +239,12 @@ impl Storage for S3Cache {
         });
 
         let bucket = self.bucket.clone();
+        let attempts = self.attempts;
+        let handle = self.handle.clone();
         let response = credentials.and_then(move |credentials| {
-            bucket.put(&key, data, &credentials).chain_err(|| {
+            retry_future(attempts, &handle, move || {
+                bucket.put(&key, data.clone(), &credentials)
+            }).chain_err(|| {
                 "failed to put cache entry in s3"
             })
         });
@@ -106,10 +252,148 @@ impl Storage for S3Cache {
         Box::new(response.map(move |_| start.elapsed()))
     }
 
+    fn delete(&self, key: &str) -> SFuture<()> {
+        let key = normalize_key(&self.key_prefix, key);
+        let credentials = self.provider.credentials().chain_err(|| {
+            "failed to get AWS credentials"
+        });
+
+        let bucket = self.bucket.clone();
+        Box::new(credentials.and_then(move |credentials| {
+            bucket.delete(&key, &credentials).chain_err(|| {
+                "failed to delete cache entry in s3"
+            })
+        }))
+    }
+
+    fn clear(&self) -> SFuture<ClearStats> {
+        let credentials = self.provider.credentials().chain_err(|| {
+            "failed to get AWS credentials"
+        });
+        let bucket = self.bucket.clone();
+        let prefix = self.list_prefix();
+        let size = self.size.clone();
+        Box::new(credentials.and_then(move |credentials| {
+            delete_all(bucket, prefix, None, credentials, (0, 0)).chain_err(|| {
+                "failed to clear s3 cache"
+            })
+        }).map(move |(entries, bytes)| {
+            // List the bucket again the next time its size is asked for.
+            if let Some(size) = size {
+                *size.borrow_mut() = BucketSize::default();
+            }
+            ClearStats {
+                entries: Some(entries),
+                bytes: Some(bytes as u64),
+            }
+        }))
+    }
+
     fn location(&self) -> String {
-        format!("S3, bucket: {}", self.bucket)
+        if self.key_prefix.is_empty() {
+            format!("S3, bucket: {}", self.bucket)
+        } else {
+            format!("S3, bucket: {}, prefix: {}", self.bucket, self.key_prefix)
+        }
     }
 
-    fn current_size(&self) -> Option<usize> { None }
+    fn current_size(&self) -> Option<usize> { self.bucket_size().map(|(_, bytes)| bytes) }
     fn max_size(&self) -> Option<usize> { None }
+    fn current_entries(&self) -> Option<usize> { self.bucket_size().map(|(objects, _)| objects) }
+}
+
+#[test]
+fn test_normalize_key() {
+    assert_eq!("a/b/c/abcdef", normalize_key("", "abcdef"));
+    assert_eq!("project/a/b/c/abcdef", normalize_key("project", "abcdef"));
+    assert_eq!("some/project/a/b/c/abcdef", normalize_key("some/project", "abcdef"));
+}
+
+/// Serve `count` HTTP requests on a new port, answering each with the bytes
+/// `respond` returns for its request line. Returns the address to connect
+/// to, and a channel that gets each request line once it's answered.
+#[cfg(test)]
+fn mock_server<F>(count: usize, respond: F) -> (String, ::std::sync::mpsc::Receiver<String>)
+    where F: Fn(&str) -> Vec<u8> + Send + 'static,
+{
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().take(count) {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request).lines().next().unwrap_or("").to_owned();
+            drop(stream.write_all(&respond(&request)));
+            tx.send(request).unwrap();
+        }
+    });
+    (endpoint, rx)
+}
+
+#[test]
+fn test_delete_all() {
+    use tokio_core::reactor::Core;
+
+    // A server that lists two objects, and then accepts deleting them.
+    let (endpoint, rx) = mock_server(3, |request| {
+        if request.starts_with("GET ") {
+            let body = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                        <Contents><Key>p/a/b/c/abc</Key><Size>10</Size></Contents>\
+                        <Contents><Key>p/d/e/f/def</Key><Size>5</Size></Contents>\
+                        </ListBucketResult>";
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).into_bytes()
+        } else {
+            b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_vec()
+        }
+    });
+    let mut core = Core::new().unwrap();
+    let bucket = Rc::new(Bucket::new("bucket", &endpoint, Ssl::No, None, &core.handle()));
+    let creds = AwsCredentials::new("key", "secret", None, ::chrono::UTC::now());
+    let deleted = core.run(delete_all(bucket, "p/".to_owned(), None, creds, (0, 0))).unwrap();
+    assert_eq!((2, 15), deleted);
+    let mut requests = rx.iter().take(3).collect::<Vec<_>>();
+    requests.sort();
+    assert_eq!(vec!["DELETE /p/a/b/c/abc HTTP/1.1",
+                    "DELETE /p/d/e/f/def HTTP/1.1",
+                    "GET /?prefix=p%2F HTTP/1.1"], requests);
+}
+
+#[test]
+fn test_get_retries_server_errors() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_core::reactor::Core;
+
+    // A server that fails twice, and then returns an entry.
+    let entry = CacheWrite::new().finish().unwrap();
+    let served = AtomicUsize::new(0);
+    let (endpoint, rx) = mock_server(3, move |_| {
+        if served.fetch_add(1, Ordering::SeqCst) < 2 {
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+        } else {
+            let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                       entry.len()).into_bytes();
+            response.extend_from_slice(&entry);
+            response
+        }
+    });
+    let mut core = Core::new().unwrap();
+    let cache = S3Cache::new("bucket", &endpoint, "", None, None, None, 3, false, &core.handle()).unwrap();
+    match core.run(cache.get("abcdef")).unwrap() {
+        Cache::Hit(_) => {}
+        r => panic!("Unexpected result: {:?}", r),
+    }
+    assert_eq!(vec!["GET /a/b/c/abcdef HTTP/1.1"; 3], rx.iter().take(3).collect::<Vec<_>>());
 }
@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use byteorder::{ByteOrder, BigEndian};
-use protocol::{Request, Response};
+use config;
+#[cfg(windows)]
+use named_pipe;
+use protocol::{PROTOCOL_VERSION, Request, Response, decode_frame, encode_frame};
 use retry::retry;
 use bincode;
 use errors::*;
@@ -24,30 +27,199 @@ use std::io::{
     Read,
     Write,
 };
+#[cfg(windows)]
+use std::fs::File;
+#[cfg(windows)]
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(windows)]
+use std::thread;
+#[cfg(windows)]
+use std::time::Instant;
+use std::time::Duration;
+#[cfg(unix)]
 use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use unix_socket;
+
+/// The connection to the server on Unix: a UNIX domain socket, or a TCP
+/// socket if `SCCACHE_SERVER_TCP` is set.
+#[cfg(unix)]
+pub enum ServerStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// The connection to the server on Windows: a named pipe.
+#[cfg(windows)]
+pub type ServerStream = File;
+
+/// Reads from named pipes can't time out, so on Windows this cancels the
+/// blocking reads of the thread that started it once its timeout passes,
+/// until it's dropped.
+#[cfg(windows)]
+struct ReadWatchdog {
+    /// Whether the reads are done, and a way to wake the watchdog thread.
+    done: Arc<(Mutex<bool>, Condvar)>,
+}
+
+#[cfg(windows)]
+impl ReadWatchdog {
+    fn start(timeout: Option<Duration>) -> io::Result<Option<ReadWatchdog>> {
+        use kernel32;
+        use winapi::{FALSE, HANDLE, THREAD_TERMINATE};
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Ok(None),
+        };
+        let handle = unsafe {
+            kernel32::OpenThread(THREAD_TERMINATE, FALSE, kernel32::GetCurrentThreadId())
+        };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+        // Handles can't be sent between threads, but their values can.
+        let handle = handle as usize;
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let watched = done.clone();
+        thread::spawn(move || {
+            let &(ref lock, ref cvar) = &*watched;
+            let start = Instant::now();
+            let mut finished = lock.lock().unwrap();
+            while !*finished {
+                let elapsed = start.elapsed();
+                let wait = if elapsed >= timeout {
+                    // Keep cancelling, in case the thread was between reads.
+                    unsafe { kernel32::CancelSynchronousIo(handle as HANDLE); }
+                    Duration::from_millis(100)
+                } else {
+                    timeout - elapsed
+                };
+                finished = cvar.wait_timeout(finished, wait).unwrap().0;
+            }
+            unsafe { kernel32::CloseHandle(handle as HANDLE); }
+        });
+        Ok(Some(ReadWatchdog { done: done }))
+    }
+}
+
+/// Reads from sockets time out by themselves, so there's nothing to watch.
+#[cfg(unix)]
+enum ReadWatchdog {}
+
+#[cfg(unix)]
+impl ReadWatchdog {
+    fn start(_timeout: Option<Duration>) -> io::Result<Option<ReadWatchdog>> {
+        Ok(None)
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ReadWatchdog {
+    fn drop(&mut self) {
+        let &(ref lock, ref cvar) = &*self.done;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+}
+
+#[cfg(unix)]
+impl ServerStream {
+    fn try_clone(&self) -> io::Result<ServerStream> {
+        match *self {
+            ServerStream::Tcp(ref s) => s.try_clone().map(ServerStream::Tcp),
+            ServerStream::Unix(ref s) => s.try_clone().map(ServerStream::Unix),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            ServerStream::Tcp(ref s) => s.set_read_timeout(timeout),
+            ServerStream::Unix(ref s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Read for ServerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ServerStream::Tcp(ref mut s) => s.read(buf),
+            ServerStream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Write for ServerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ServerStream::Tcp(ref mut s) => s.write(buf),
+            ServerStream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ServerStream::Tcp(ref mut s) => s.flush(),
+            ServerStream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
 
 /// A connection to an sccache server.
 pub struct ServerConnection {
     /// A reader for the socket connected to the server.
-    reader : BufReader<TcpStream>,
+    reader : BufReader<ServerStream>,
     /// A writer for the socket connected to the server.
-    writer : BufWriter<TcpStream>,
+    writer : BufWriter<ServerStream>,
+    /// Whether to compress large requests.
+    compress : bool,
+    /// Whether messages are sent in frames, which is only the case after
+    /// the server answers `check_server_version`. See
+    /// `protocol::is_current_hello`.
+    framed : bool,
+    /// How long reads from the server may take.
+    read_timeout : Option<Duration>,
 }
 
 impl ServerConnection {
     /// Create a new connection using `stream`.
-    pub fn new(stream : TcpStream) -> io::Result<ServerConnection> {
+    pub fn new(stream : ServerStream) -> io::Result<ServerConnection> {
         let writer = try!(stream.try_clone());
         Ok(ServerConnection {
             reader : BufReader::new(stream),
             writer : BufWriter::new(writer),
+            compress : config::CONFIG.compress_protocol,
+            framed : false,
+            read_timeout : None,
         })
     }
 
+    /// Make reads from the server fail if they take longer than `timeout`.
+    #[cfg(unix)]
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        self.reader.get_ref().set_read_timeout(timeout)
+    }
+
+    /// Make reads from the server fail if they take longer than `timeout`.
+    /// Each `read_one_response` starts a `ReadWatchdog` to enforce it.
+    #[cfg(windows)]
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
     /// Send `request` to the server, read and return a `Response`.
     pub fn request(&mut self, request: Request) -> Result<Response> {
         trace!("ServerConnection::request");
-        let bytes = bincode::serialize(&request, bincode::Infinite)?;
+        let mut bytes = bincode::serialize(&request, bincode::Infinite)?;
+        if self.framed {
+            bytes = encode_frame(&bytes, self.compress)?;
+        }
         let mut len = [0; 4];
         BigEndian::write_u32(&mut len, bytes.len() as u32);
         self.writer.write_all(&len)?;
@@ -60,6 +232,7 @@ impl ServerConnection {
     /// Read a single `Response` from the server.
     pub fn read_one_response(&mut self) -> Result<Response> {
         trace!("ServerConnection::read_one_response");
+        let watchdog = ReadWatchdog::start(self.read_timeout)?;
         let mut bytes = [0; 4];
         self.reader.read_exact(&mut bytes).chain_err(|| "Failed to read response header")?;
         let len = BigEndian::read_u32(&bytes);
@@ -67,30 +240,83 @@ impl ServerConnection {
         let mut data = vec![0; len as usize];
         self.reader.read_exact(&mut data)?;
         trace!("Done reading");
+        drop(watchdog);
+        if self.framed {
+            data = decode_frame(&data)?;
+        }
         Ok(bincode::deserialize(&data)?)
     }
 }
 
-/// Establish a TCP connection to an sccache server listening on `port`.
-pub fn connect_to_server(port: u16) -> io::Result<ServerConnection> {
-    trace!("connect_to_server({})", port);
-    let stream = try!(TcpStream::connect(("127.0.0.1", port)));
+/// Open a stream to the sccache server for `port`.
+#[cfg(unix)]
+fn connect_stream(port: u16) -> io::Result<ServerStream> {
+    if config::CONFIG.server_tcp {
+        TcpStream::connect(("127.0.0.1", port)).map(ServerStream::Tcp)
+    } else {
+        unix_socket::connect(port).map(ServerStream::Unix)
+    }
+}
+
+/// Open a stream to the sccache server for `port`.
+#[cfg(windows)]
+fn connect_stream(port: u16) -> io::Result<ServerStream> {
+    named_pipe::connect(&named_pipe::pipe_name(port)?)
+}
+
+/// Establish a connection to the sccache server for `port`, without
+/// checking that the server speaks the same protocol version.
+///
+/// Only use this for requests that every version of the server understands,
+/// like `Request::Shutdown`.
+pub fn connect_to_server_unchecked(port: u16) -> io::Result<ServerConnection> {
+    trace!("connect_to_server_unchecked({})", port);
+    let stream = try!(connect_stream(port));
     ServerConnection::new(stream)
 }
 
-/// Attempt to establish a TCP connection to an sccache server listening on `port`.
+/// Establish a connection to the sccache server for `port`, and check that
+/// it speaks the same protocol version as this client.
+pub fn connect_to_server(port: u16) -> Result<ServerConnection> {
+    trace!("connect_to_server({})", port);
+    let conn = connect_to_server_unchecked(port)?;
+    check_server_version(conn)
+}
+
+/// Exchange protocol versions with the server on `conn`, and return the
+/// connection if they match.
+pub fn check_server_version(mut conn: ServerConnection) -> Result<ServerConnection> {
+    trace!("check_server_version");
+    match conn.request(Request::Hello { version: PROTOCOL_VERSION }) {
+        Ok(Response::Hello { version }) if version == PROTOCOL_VERSION => {
+            conn.framed = true;
+            Ok(conn)
+        }
+        Ok(Response::Hello { version }) => {
+            Err(ErrorKind::ServerVersionMismatch(version, PROTOCOL_VERSION).into())
+        }
+        Ok(_) => bail!("unexpected response from server"),
+        // Servers from before the handshake drop the connection when they
+        // can't parse the request.
+        Err(e) => Err(e).chain_err(|| {
+            "sccache server didn't answer the version check; it may be from an older sccache, run --stop-server"
+        }),
+    }
+}
+
+/// Attempt to establish a connection to the sccache server for `port`.
 ///
 /// If the connection fails, retry a few times.
-pub fn connect_with_retry(port: u16) -> io::Result<ServerConnection> {
+pub fn connect_with_retry(port: u16) -> Result<ServerConnection> {
     trace!("connect_with_retry({})", port);
     // TODOs:
     // * Pass the server Child in here, so we can stop retrying
     //   if the process exited.
     // * Send a pipe handle to the server process so it can notify
     //   us once it starts the server instead of us polling.
-    match retry(10, 500, || connect_to_server(port), |res| res.is_ok()) {
-        Ok(Ok(conn)) => Ok(conn),
+    match retry(10, 500, || connect_to_server_unchecked(port), |res| res.is_ok()) {
+        Ok(Ok(conn)) => check_server_version(conn),
         _ => Err(io::Error::new(io::ErrorKind::TimedOut,
-                                "Connection to server timed out")),
+                                "Connection to server timed out").into()),
     }
 }
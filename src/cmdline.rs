@@ -17,11 +17,12 @@ use clap::{
     AppSettings,
     Arg,
 };
+use commands::DEFAULT_WORKER_ADDR;
 use config;
 use errors::*;
 use std::env;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use which::which_in;
 
 arg_enum!{
@@ -29,7 +30,9 @@ arg_enum!{
     #[allow(non_camel_case_types)]
     pub enum StatsFormat {
         text,
-        json
+        json,
+        prometheus,
+        csv
     }
 }
 
@@ -37,14 +40,31 @@ arg_enum!{
 pub enum Command {
     /// Show cache statistics and exit.
     ShowStats(StatsFormat),
+    /// Show the settings of the loaded configuration and exit.
+    ShowConfig(StatsFormat),
     /// Zero cache statistics and exit.
     ZeroStats,
+    /// Remove a single entry from the cache and exit.
+    Evict(String),
+    /// Make the server re-read its configuration and exit.
+    ReloadConfig,
+    /// Remove every entry from the cache and exit.
+    ClearCache,
+    /// Detect the kind and version of a compiler, print them and exit.
+    CompilerCheck {
+        /// The compiler to check.
+        exe: OsString,
+        /// The directory in which to look for `exe`.
+        cwd: PathBuf,
+    },
     /// Run background server.
     InternalStartServer,
     /// Start background server as a subprocess.
     StartServer,
-    /// Stop background server.
-    StopServer,
+    /// Stop background server, showing its final stats in the given format.
+    StopServer(StatsFormat),
+    /// Run a worker for distributed compiles, listening on the address.
+    StartWorker(String),
     /// Run a compiler command.
     Compile {
         /// The binary to execute.
@@ -56,6 +76,18 @@ pub enum Command {
         /// The environment variables to use for execution.
         env_vars: Vec<(OsString, OsString)>,
     },
+    /// Run a compiler command directly and through the cache, and compare
+    /// the outputs.
+    VerifyCache {
+        /// The binary to execute.
+        exe: OsString,
+        /// The commandline arguments to pass to `exe`.
+        cmdline: Vec<OsString>,
+        /// The directory in which to execute the command.
+        cwd: PathBuf,
+        /// The environment variables to use for execution.
+        env_vars: Vec<(OsString, OsString)>,
+    },
 }
 
 /// Get the `App` used for argument parsing.
@@ -66,13 +98,21 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
         .after_help(concat!(
                 "Enabled features:\n",
                 "    S3:    ", cfg!(feature = "s3"), "\n",
-                "    Redis: ", cfg!(feature = "redis"), "\n")
+                "    Redis: ", cfg!(feature = "redis"), "\n",
+                "    Dist:  ", cfg!(feature = "dist"), "\n")
                 )
         .args_from_usage(
             "-s --show-stats 'show cache statistics'
+             --show-config 'show the resolved configuration and where each setting came from'
              -z, --zero-stats 'zero statistics counters'
              --start-server  'start background server'
-             --stop-server   'stop background server'"
+             --stop-server   'stop background server'
+             --start-worker [addr] 'run a worker for distributed compiles, listening on addr'
+             --evict [key]   'remove the entry for key from the cache'
+             --reload-config 'make the server re-read its configuration'
+             --clear-cache   'remove every entry from the cache'
+             --compiler-check [exe] 'print the detected kind and version of the compiler exe'
+             --verify-cache  'run the given compile command directly and through the cache, and compare the outputs'"
                 )
         .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
              .possible_values(&StatsFormat::variants())
@@ -84,6 +124,15 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 )
 }
 
+/// Return the compiler in `compiler_dir` with the same name as `exe`, a copy
+/// of sccache named after a compiler, if there is one.
+fn compiler_in_dir(compiler_dir: &Path, exe: &Path) -> Option<PathBuf> {
+    match exe.file_name().map(|name| compiler_dir.join(name)) {
+        Some(ref compiler) if compiler.exists() => Some(compiler.clone()),
+        _ => None,
+    }
+}
+
 /// Parse the commandline into a `Command` to execute.
 pub fn parse() -> Result<Command> {
     trace!("parse");
@@ -104,13 +153,12 @@ pub fn parse() -> Result<Command> {
                 // to ourselves again if it's in the PATH.
                 _ => {
                     let mut found_it = false;
-                    if let Some(ref dir) = config::CONFIG.compiler_dir {
-                        let maybe_exe = dir.join(exe.file_name().unwrap());
-                        if maybe_exe.exists() {
-                            args[0] = maybe_exe.into();
-                            args.insert(0, env!("CARGO_PKG_NAME").into());
-                            found_it = true;
-                        }
+                    if let Some(compiler) = config::CONFIG.compiler_dir.as_ref()
+                        .and_then(|dir| compiler_in_dir(dir, &exe))
+                    {
+                        args[0] = compiler.into();
+                        args.insert(0, env!("CARGO_PKG_NAME").into());
+                        found_it = true;
                     }
 
                     if !found_it {
@@ -152,9 +200,16 @@ pub fn parse() -> Result<Command> {
     let matches = get_app().get_matches_from(args);
 
     let show_stats = matches.is_present("show-stats");
+    let show_config = matches.is_present("show-config");
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
+    let start_worker = matches.is_present("start-worker");
     let zero_stats = matches.is_present("zero-stats");
+    let evict = matches.value_of("evict");
+    let reload_config = matches.is_present("reload-config");
+    let clear_cache = matches.is_present("clear-cache");
+    let compiler_check = matches.value_of_os("compiler-check");
+    let verify_cache = matches.is_present("verify-cache");
     let cmd = matches.values_of_os("cmd");
     // Ensure that we've only received one command to run.
     fn is_some<T>(x : &Option<T>) -> bool {
@@ -163,9 +218,15 @@ pub fn parse() -> Result<Command> {
     if [
         internal_start_server,
         show_stats,
+        show_config,
         start_server,
         stop_server,
-        is_some(&cmd),
+        start_worker,
+        is_some(&evict),
+        reload_config,
+        clear_cache,
+        is_some(&compiler_check),
+        verify_cache || is_some(&cmd),
             ].iter()
         .fold(0, |acc, &x| acc + (x as usize)) > 1 {
             bail!("Too many commands specified");
@@ -176,25 +237,77 @@ pub fn parse() -> Result<Command> {
         let fmt = value_t!(matches.value_of("stats-format"), StatsFormat)
             .unwrap_or_else(|e| e.exit());
         Ok(Command::ShowStats(fmt))
+    } else if show_config {
+        let fmt = value_t!(matches.value_of("stats-format"), StatsFormat)
+            .unwrap_or_else(|e| e.exit());
+        Ok(Command::ShowConfig(fmt))
     } else if start_server {
         Ok(Command::StartServer)
     } else if stop_server {
-        Ok(Command::StopServer)
+        let fmt = value_t!(matches.value_of("stats-format"), StatsFormat)
+            .unwrap_or_else(|e| e.exit());
+        Ok(Command::StopServer(fmt))
+    } else if start_worker {
+        Ok(Command::StartWorker(matches.value_of("start-worker").unwrap_or(DEFAULT_WORKER_ADDR).to_owned()))
     } else if zero_stats {
         Ok(Command::ZeroStats)
+    } else if let Some(key) = evict {
+        Ok(Command::Evict(key.to_owned()))
+    } else if reload_config {
+        Ok(Command::ReloadConfig)
+    } else if clear_cache {
+        Ok(Command::ClearCache)
+    } else if let Some(exe) = compiler_check {
+        Ok(Command::CompilerCheck {
+            exe: exe.to_owned(),
+            cwd: cwd,
+        })
     } else if let Some(mut args) = cmd {
         if let Some(exe) = args.next() {
             let cmdline = args.map(|s| s.to_owned()).collect::<Vec<_>>();
-            Ok(Command::Compile {
-                exe: exe.to_owned(),
-                cmdline: cmdline,
-                cwd: cwd,
-                env_vars: env::vars_os().collect(),
-            })
+            if verify_cache {
+                Ok(Command::VerifyCache {
+                    exe: exe.to_owned(),
+                    cmdline: cmdline,
+                    cwd: cwd,
+                    env_vars: env::vars_os().collect(),
+                })
+            } else {
+                Ok(Command::Compile {
+                    exe: exe.to_owned(),
+                    cmdline: cmdline,
+                    cwd: cwd,
+                    env_vars: env::vars_os().collect(),
+                })
+            }
         } else {
             bail!("No compile command");
         }
+    } else if verify_cache {
+        bail!("No compile command to verify");
     } else {
         bail!("No command specified");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{self, File};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_compiler_in_dir() {
+        let tempdir = TempDir::new("sccache_test_cmdline").unwrap();
+        let dir = tempdir.path().join("tc");
+        fs::create_dir(&dir).unwrap();
+        File::create(dir.join("gcc")).unwrap();
+        let exe = tempdir.path().join("bin").join("gcc");
+        // A copy of sccache named `gcc` runs the `gcc` in `compiler_dir`,
+        // with or without a trailing slash.
+        assert_eq!(Some(dir.join("gcc")), compiler_in_dir(&dir, &exe));
+        assert_eq!(Some(dir.join("gcc")), compiler_in_dir(&dir.join(""), &exe));
+        // Names that aren't in `compiler_dir` are looked up in PATH.
+        assert_eq!(None, compiler_in_dir(&dir, &tempdir.path().join("bin").join("clang")));
+    }
+}
@@ -13,23 +13,40 @@
 // limitations under the License.
 
 use client::{
+    check_server_version,
     connect_to_server,
+    connect_to_server_unchecked,
     connect_with_retry,
     ServerConnection,
 };
 use cmdline::{Command, StatsFormat};
+use cache::{storage_from_config, ClearStats, Storage};
+use compiler::{
+    self,
+    CacheControl,
+    CCompilerKind,
+    CompileOptions,
+    CompilerArguments,
+    CompilerKind,
+    CompileResult,
+    get_compiler_info,
+    get_forced_compiler_info,
+};
+use config::{self, ConfigSetting};
+use futures::future::{self, Future};
+use futures_cpupool::CpuPool;
 use log::LogLevel::Trace;
 use mock_command::{
     CommandCreatorSync,
     ProcessCommandCreator,
     RunCommand,
 };
-use protocol::{Request, Response, CompileResponse, CompileFinished, Compile};
+use protocol::{CacheStatus, Request, Response, CompileResponse, CompileFinished, Compile};
 use serde_json;
 use server::{self, ServerInfo};
 use std::env;
 use std::ffi::{OsStr,OsString};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{
     self,
     Read,
@@ -39,9 +56,15 @@ use std::io::{
 use std::os::unix::process::ExitStatusExt;
 use std::path::{
     Path,
+    PathBuf,
 };
 use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio_core::reactor::Core;
+use tokio_io::AsyncRead;
+use tokio_io::io::read_exact;
 use util::run_input_output;
 use which::which_in;
 
@@ -52,9 +75,16 @@ use osstringext::OsStrExt2;
 /// The default sccache server port.
 pub const DEFAULT_PORT: u16 = 4226;
 
+/// The address a dist worker listens on if not told otherwise.
+pub const DEFAULT_WORKER_ADDR: &'static str = "127.0.0.1:4227";
+
 /// The number of milliseconds to wait for server startup.
 const SERVER_STARTUP_TIMEOUT_MS: u32 = 5000;
 
+/// The number of milliseconds `verify_cache` waits for the server to write
+/// the cache entry.
+const VERIFY_CACHE_WRITE_TIMEOUT_MS: u64 = 10000;
+
 // Should this just be a Result?
 /// Result of background server startup.
 enum ServerStartup {
@@ -66,11 +96,37 @@ enum ServerStartup {
     Err(Error),
 }
 
+/// Read the server's startup notification from `r`, as written by
+/// `server::notify_server_startup`.
+fn read_server_startup<R>(r: R) -> Box<Future<Item=ServerStartup, Error=io::Error>>
+    where R: AsyncRead + 'static,
+{
+    Box::new(read_exact(r, [0u8]).and_then(|(r, status)| -> Box<Future<Item=ServerStartup, Error=io::Error>> {
+        if status[0] == 0 {
+            return Box::new(future::ok(ServerStartup::Ok))
+        }
+        Box::new(read_exact(r, [0u8; 2]).and_then(|(r, len)| {
+            let len = (len[0] as usize) << 8 | len[1] as usize;
+            read_exact(r, vec![0; len])
+        }).map(move |(_r, error)| {
+            let err = if error.is_empty() {
+                format!("Server startup failed: {}", status[0])
+            } else {
+                format!("Server startup failed: {}", String::from_utf8_lossy(&error))
+            };
+            ServerStartup::Err(err.into())
+        }))
+    }))
+}
+
 /// Get the port on which the server should listen.
 fn get_port() -> u16 {
     env::var("SCCACHE_SERVER_PORT")
         .ok()
         .and_then(|s| s.parse().ok())
+        .or_else(|| {
+            config::CONFIG.port_file.as_ref().and_then(|p| server::read_port_file(p))
+        })
         .unwrap_or(DEFAULT_PORT)
 }
 
@@ -78,13 +134,11 @@ fn get_port() -> u16 {
 /// for it to start up.
 #[cfg(not(windows))]
 fn run_server_process() -> Result<ServerStartup> {
-    extern crate tokio_uds;
-
-    use futures::{Future, Stream};
+    use futures::Stream;
     use std::time::Duration;
     use tempdir::TempDir;
-    use tokio_io::io::read_exact;
     use tokio_core::reactor::Timeout;
+    use tokio_uds;
 
     trace!("run_server_process");
     let tempdir = TempDir::new("sccache")?;
@@ -102,14 +156,7 @@ fn run_server_process() -> Result<ServerStartup> {
     let startup = listener.incoming().into_future().map_err(|e| e.0);
     let startup = startup.and_then(|(socket, _rest)| {
         let (socket, _addr) = socket.unwrap(); // incoming() never returns None
-        read_exact(socket, [0u8]).map(|(_socket, byte)| {
-            if byte[0] == 0 {
-                ServerStartup::Ok
-            } else {
-                let err = format!("Server startup failed: {}", byte[0]).into();
-                ServerStartup::Err(err)
-            }
-        })
+        read_server_startup(socket)
     });
 
     let timeout = Duration::from_millis(SERVER_STARTUP_TIMEOUT_MS.into());
@@ -240,14 +287,12 @@ fn redirect_error_log() -> Result<()> {
 /// Re-execute the current executable as a background server.
 #[cfg(windows)]
 fn run_server_process() -> Result<ServerStartup> {
-    use futures::Future;
     use kernel32;
     use mio_named_pipes::NamedPipe;
     use std::mem;
     use std::os::windows::ffi::OsStrExt;
     use std::ptr;
     use std::time::Duration;
-    use tokio_io::io::read_exact;
     use tokio_core::reactor::{Core, Timeout, PollEvented};
     use uuid::Uuid;
     use winapi::{CREATE_UNICODE_ENVIRONMENT,DETACHED_PROCESS,CREATE_NEW_PROCESS_GROUP};
@@ -324,14 +369,7 @@ fn run_server_process() -> Result<ServerStartup> {
         return Err(io::Error::last_os_error().into())
     }
 
-    let result = read_exact(server, [0u8]).map(|(_socket, byte)| {
-        if byte[0] == 0 {
-            ServerStartup::Ok
-        } else {
-            let err = format!("Server startup failed: {}", byte[0]).into();
-            ServerStartup::Err(err)
-        }
-    });
+    let result = read_server_startup(server);
 
     let timeout = Duration::from_millis(SERVER_STARTUP_TIMEOUT_MS.into());
     let timeout = Timeout::new(timeout, &handle)?.map(|()| ServerStartup::TimedOut);
@@ -341,19 +379,35 @@ fn run_server_process() -> Result<ServerStartup> {
     }
 }
 
+/// Describe the server for `port` not starting in time.
+fn startup_timeout_error(port: u16) -> String {
+    format!("Timed out after {}s waiting for the server to listen on port {}; \
+             set SCCACHE_ERROR_LOG to see why",
+            SERVER_STARTUP_TIMEOUT_MS / 1000, port)
+}
+
 /// Attempt to connect to an sccache server listening on `port`, or start one if no server is running.
 fn connect_or_start_server(port: u16) -> Result<ServerConnection> {
     trace!("connect_or_start_server({})", port);
-    match connect_to_server(port) {
-        Ok(server) => Ok(server),
+    match connect_to_server_unchecked(port) {
+        Ok(server) => check_server_version(server),
         Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused ||
-                      e.kind() == io::ErrorKind::TimedOut => {
-            // If the connection was refused we probably need to start
-            // the server.
-            //TODO: check startup value!
-            let _startup = run_server_process()?;
-            let server = connect_with_retry(port)?;
-            Ok(server)
+                      e.kind() == io::ErrorKind::TimedOut ||
+                      e.kind() == io::ErrorKind::NotFound => {
+            // If the connection was refused, or there's no socket or pipe,
+            // we probably need to start the server.
+            match run_server_process()? {
+                ServerStartup::Ok => connect_with_retry(port),
+                // Another client may have started a server at the same
+                // time, taking the port, so try connecting to that one.
+                ServerStartup::Err(e) => {
+                    debug!("failed to start server, trying to connect anyway: {}", e);
+                    connect_with_retry(port).chain_err(|| e.to_string())
+                }
+                ServerStartup::TimedOut => {
+                    connect_with_retry(port).chain_err(|| startup_timeout_error(port))
+                }
+            }
         }
         Err(e) => Err(e.into())
     }
@@ -399,18 +453,62 @@ pub fn request_shutdown(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send an `Evict` request to the server to remove the cache entry for `key`.
+pub fn request_evict(mut conn: ServerConnection, key: String) -> Result<()> {
+    debug!("request_evict");
+    let response = conn.request(Request::Evict(key)).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::Evicted = response {
+        Ok(())
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `ClearCache` request to the server, and return what was removed if successful.
+pub fn request_clear_cache(mut conn: ServerConnection) -> Result<ClearStats> {
+    debug!("request_clear_cache");
+    let response = conn.request(Request::ClearCache).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    if let Response::CacheCleared(cleared) = response {
+        Ok(cleared)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Send a `ReloadConfig` request to the server, and return the names of
+/// changed settings that require a server restart if successful.
+pub fn request_reload_config(mut conn: ServerConnection) -> Result<Vec<String>> {
+    debug!("request_reload_config");
+    let response = conn.request(Request::ReloadConfig).chain_err(|| {
+        "Failed to send data to or receive data from server"
+    })?;
+    match response {
+        Response::ConfigReloaded(needs_restart) => Ok(needs_restart),
+        Response::ConfigReloadFailed(e) => bail!("invalid sccache config: {}", e),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
 /// Send a `Compile` request to the server, and return the server response if successful.
-fn request_compile<W, X, Y>(conn: &mut ServerConnection, exe: W, args: &Vec<X>, cwd: Y,
-                            env_vars: Vec<(OsString, OsString)>) -> Result<CompileResponse>
+pub fn request_compile<W, X, Y>(conn: &mut ServerConnection, exe: W, args: &Vec<X>, cwd: Y,
+                                env_vars: Vec<(OsString, OsString)>) -> Result<CompileResponse>
     where W: AsRef<Path>,
           X: AsRef<OsStr>,
           Y: AsRef<Path>,
 {
+    let build_tag = env_vars.iter()
+        .find(|&&(ref k, _)| k.as_os_str() == OsStr::new("SCCACHE_BUILD_TAG"))
+        .map(|&(_, ref v)| v.to_string_lossy().into_owned());
     let req = Request::Compile(Compile {
         exe: exe.as_ref().to_owned().into(),
         cwd: cwd.as_ref().to_owned().into(),
         args: args.iter().map(|a| a.as_ref().to_owned()).collect(),
         env_vars: env_vars,
+        build_tag: build_tag,
     });
     trace!("request_compile: {:?}", req);
     //TODO: better error mapping?
@@ -438,23 +536,37 @@ fn status_signal(_status : process::ExitStatus) -> Option<i32> {
     None
 }
 
+/// Whether to tell the user whether each compile was cached, and why not,
+/// from `SCCACHE_DEBUG`.
+fn debug_cache_decisions() -> bool {
+    env::var("SCCACHE_DEBUG").map(|v| v != "0").unwrap_or(false)
+}
+
 /// Handle `response`, the output from running a compile on the server. Return the compiler exit status.
 fn handle_compile_finished(response: CompileFinished,
                            stdout: &mut Write,
                            stderr: &mut Write) -> Result<i32> {
     trace!("handle_compile_finished");
+    debug!("compile cache status: {:?}", response.cache_status);
     // It might be nice if the server sent stdout/stderr as the process
     // ran, but then it would have to also save them in the cache as
     // interleaved streams to really make it work.
     stdout.write_all(&response.stdout)?;
     stderr.write_all(&response.stderr)?;
+    if debug_cache_decisions() {
+        let status = match response.cache_status {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Unknown => "not used",
+        };
+        writeln!(stderr, "sccache: cache {}", status)?;
+    }
 
     if let Some(ret) = response.retcode {
         trace!("compiler exited with status {}", ret);
         Ok(ret)
     } else if let Some(signal) = response.signal {
-        println!("Compiler killed by signal {}", signal);
-        Ok(-2)
+        bail!(ErrorKind::CompilerKilled(signal))
     } else {
         println!("Missing compiler exit status!");
         Ok(-3)
@@ -466,12 +578,14 @@ fn handle_compile_finished(response: CompileFinished,
 /// If the server returned `CompileStarted`, wait for a `CompileFinished` and
 /// print the results.
 ///
-/// If the server returned `UnhandledCompile`, run the compilation command
-/// locally using `creator` and return the result.
-fn handle_compile_response<T>(mut creator: T,
+/// If the server returned `UnhandledCompile`, or doesn't finish the compile
+/// by `deadline`, run the compilation command locally using `creator` and
+/// return the result.
+fn handle_compile_response<T>(creator: T,
                               core: &mut Core,
                               conn: &mut ServerConnection,
                               response: CompileResponse,
+                              deadline: Option<Instant>,
                               exe: &Path,
                               cmdline: Vec<OsString>,
                               cwd: &Path,
@@ -482,12 +596,23 @@ fn handle_compile_response<T>(mut creator: T,
     match response {
         CompileResponse::CompileStarted => {
             debug!("Server sent CompileStarted");
+            if let Some(deadline) = deadline {
+                // A zero timeout means none at all, so wait at least a little.
+                let now = Instant::now();
+                let left = if deadline > now { deadline - now } else { Duration::from_millis(1) };
+                conn.set_read_timeout(Some(left))?;
+            }
             // Wait for CompileFinished.
             match conn.read_one_response() {
                 Ok(Response::CompileFinished(result)) => {
                     return handle_compile_finished(result, stdout, stderr)
                 }
                 Ok(_) => bail!("unexpected response from server"),
+                Err(_) if past_deadline(deadline) => {
+                    writeln!(io::stderr(),
+                             "warning: sccache server didn't finish the compile in time, \
+                              compiling locally instead").unwrap();
+                }
                 Err(Error(ErrorKind::Io(ref e), _))
                     if e.kind() == io::ErrorKind::UnexpectedEof =>
                 {
@@ -503,9 +628,29 @@ fn handle_compile_response<T>(mut creator: T,
         }
         CompileResponse::UnhandledCompile(ref maybe_msg) => {
             debug!("Server sent UnhandledCompile: reason: {:?}", maybe_msg);
+            if debug_cache_decisions() {
+                match *maybe_msg {
+                    Some(ref msg) => writeln!(stderr, "sccache: {}", msg)?,
+                    None => writeln!(stderr, "sccache: not cached")?,
+                }
+            }
         }
     };
 
+    run_compiler_locally(creator, core, exe, cmdline, cwd, stdout, stderr)
+}
+
+/// Run the compilation command `exe` `cmdline` in `cwd` using `creator`,
+/// and return its exit status.
+fn run_compiler_locally<T>(mut creator: T,
+                           core: &mut Core,
+                           exe: &Path,
+                           cmdline: Vec<OsString>,
+                           cwd: &Path,
+                           stdout: &mut Write,
+                           stderr: &mut Write) -> Result<i32>
+    where T: CommandCreatorSync,
+{
     //TODO: possibly capture output here for testing.
     let mut cmd = creator.new_command_sync(exe);
     cmd.args(&cmdline)
@@ -515,28 +660,142 @@ fn handle_compile_response<T>(mut creator: T,
     }
     match core.run(run_input_output(cmd, None)) {
         Ok(output) | Err(Error(ErrorKind::ProcessError(output), _)) => {
-            if !output.stdout.is_empty() {
-                stdout.write_all(&output.stdout)?;
-            }
-            if !output.stderr.is_empty() {
-                stderr.write_all(&output.stderr)?;
-            }
-            Ok(output.status.code().unwrap_or_else(|| {
-                if let Some(sig) = status_signal(output.status) {
-                    println!("Compile terminated by signal {}", sig);
-                }
-                // Arbitrary.
-                2
-            }))
+            write_compiler_output(output, stdout, stderr)
         }
         Err(e) => Err(e),
     }
 }
 
+/// Write the output of a compiler that ran in this process, and return its
+/// exit status.
+fn write_compiler_output(output: process::Output,
+                         stdout: &mut Write,
+                         stderr: &mut Write) -> Result<i32> {
+    if !output.stdout.is_empty() {
+        stdout.write_all(&output.stdout)?;
+    }
+    if !output.stderr.is_empty() {
+        stderr.write_all(&output.stderr)?;
+    }
+    match (output.status.code(), status_signal(output.status)) {
+        (Some(code), _) => Ok(code),
+        (None, Some(signal)) => bail!(ErrorKind::CompilerKilled(signal)),
+        // Arbitrary.
+        (None, None) => Ok(2),
+    }
+}
+
+/// Die from `signal`, like the compiler did, so that the build tool sees the
+/// compile terminate the same way. Unless `SCCACHE_ALLOW_CORE_DUMPS` is set,
+/// this doesn't dump core, since a core of sccache wouldn't be any use.
+/// Returns the shell's exit status for `signal` if it doesn't kill us.
+#[cfg(unix)]
+fn exit_with_signal(signal: i32) -> i32 {
+    use libc;
+
+    unsafe {
+        if env::var("SCCACHE_ALLOW_CORE_DUMPS").ok().map_or(true, |v| v != "1") {
+            let rlim = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            libc::setrlimit(libc::RLIMIT_CORE, &rlim);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    128 + signal
+}
+
+#[cfg(not(unix))]
+fn exit_with_signal(signal: i32) -> i32 {
+    128 + signal
+}
+
+/// Return true if `deadline` has passed.
+fn past_deadline(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |d| Instant::now() >= d)
+}
+
+/// Compiler wrappers that may be given in place of a compiler, which we
+/// look through to find the real compiler.
+const COMPILER_WRAPPERS: &'static [&'static str] = &["ccache"];
+
+/// Return true if `exe` is one of `COMPILER_WRAPPERS`.
+fn is_compiler_wrapper(exe: &Path) -> bool {
+    exe.file_stem()
+        .and_then(|s| s.to_str())
+        .map_or(false, |s| COMPILER_WRAPPERS.contains(&s.to_lowercase().as_str()))
+}
+
+/// Return whether `exe` is MSVC's `cl`, or clang's imitation of it.
+fn is_msvc(exe: &Path) -> bool {
+    exe.file_stem()
+        .and_then(|s| s.to_str())
+        .map_or(false, |s| ["cl", "clang-cl"].contains(&s.to_lowercase().as_str()))
+}
+
+/// Expand the `@file` response files in the arguments `cmdline` to the
+/// compiler `exe`, relative to `cwd`, so that the server sees the full
+/// command line. Files that can't be read are left for the compiler.
+///
+/// This follows the same rules as the server, so that it doesn't matter
+/// which of them expands a response file. MSVC doesn't treat backslashes in
+/// response files as escapes, though, so its response files are split
+/// without them.
+fn expand_response_files(exe: &Path, cmdline: Vec<OsString>, cwd: &Path) -> Vec<OsString> {
+    if !is_msvc(exe) {
+        return compiler::expand_response_files(&cmdline, cwd)
+    }
+    cmdline.into_iter().flat_map(|arg| {
+        let contents = if arg.starts_with(&[b'@']) {
+            let mut contents = String::new();
+            File::open(cwd.join(arg.split_at(1).1))
+                .and_then(|mut f| f.read_to_string(&mut contents))
+                .ok()
+                .map(|_| contents)
+        } else {
+            None
+        };
+        match contents {
+            Some(contents) => SplitArgs::new(OsString::from(contents), false).collect::<Vec<_>>(),
+            None => vec![arg],
+        }
+    }).collect()
+}
+
+/// Find the compiler that the compile command `exe` `raw_cmdline` runs,
+/// looking `exe` up in `path` and looking through compiler wrappers like
+/// ccache, and expand its response files.
+///
+/// Returns the compiler and its arguments, and true, unless a wrapper is
+/// run without a compiler, in which case it returns the wrapper and its
+/// arguments, and false.
+fn find_compiler(exe: &Path,
+                 raw_cmdline: Vec<OsString>,
+                 path: Option<&OsString>,
+                 cwd: &Path) -> Result<(PathBuf, Vec<OsString>, bool)> {
+    let mut exe_path = which_in(exe, path, &cwd)?;
+    let mut raw_cmdline = raw_cmdline;
+    while is_compiler_wrapper(&exe_path) {
+        let has_compiler = raw_cmdline.first().map_or(false, |arg| !arg.starts_with(&[b'-']));
+        if !has_compiler {
+            return Ok((exe_path, raw_cmdline, false))
+        }
+        let compiler = raw_cmdline.remove(0);
+        debug!("Looking through compiler wrapper {:?} to {:?}", exe_path, compiler);
+        exe_path = which_in(compiler, path, &cwd)?;
+    }
+    let cmdline = expand_response_files(&exe_path, raw_cmdline, cwd);
+    Ok((exe_path, cmdline, true))
+}
+
 /// Send a `Compile` request to the sccache server `conn`, and handle the response.
 ///
 /// The first entry in `cmdline` will be looked up in `path` if it is not
 /// an absolute path.
+/// If `exe` is a compiler wrapper like ccache, the compiler it wraps is
+/// used instead.
 /// See `request_compile` and `handle_compile_response`.
 pub fn do_compile<T>(creator: T,
                      core: &mut Core,
@@ -551,21 +810,437 @@ pub fn do_compile<T>(creator: T,
     where T: CommandCreatorSync,
 {
     trace!("do_compile");
-    let exe_path = which_in(exe, path, &cwd)?;
-    let cmdline = raw_cmdline.iter().flat_map(|arg| {
-        if arg.starts_with(&[b'@']) {
-            let mut file = File::open(arg.split_at(1).1).unwrap();
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).unwrap();
+    let (exe_path, cmdline, is_compiler) = find_compiler(exe, raw_cmdline, path.as_ref(), cwd)?;
+    if !is_compiler {
+        // The wrapper isn't being used to run a compiler, so just run it.
+        let res = CompileResponse::UnhandledCompile(Some("compiler wrapper without a compiler".to_owned()));
+        return handle_compile_response(creator, core, &mut conn, res, None, &exe_path, cmdline, cwd, stdout, stderr)
+    }
+
+    // If the server wedges, give up on it and run the compiler directly.
+    let timeout = config::compile_timeout(&env_vars);
+    let deadline = timeout.map(|t| Instant::now() + t);
+    conn.set_read_timeout(timeout)?;
+    let res = match request_compile(&mut conn, &exe_path, &cmdline, &cwd, env_vars) {
+        Ok(res) => res,
+        Err(_) if past_deadline(deadline) => {
+            writeln!(io::stderr(),
+                     "warning: sccache server didn't start the compile in time, \
+                      compiling locally instead").unwrap();
+            CompileResponse::UnhandledCompile(Some("timed out".to_owned()))
+        }
+        Err(e) => return Err(e),
+    };
+    handle_compile_response(creator, core, &mut conn, res, deadline, &exe_path, cmdline, cwd, stdout, stderr)
+}
 
-            SplitArgs::new(OsString::from(contents), false).collect::<Vec<OsString>>().into_iter()
+/// Run a compile like `do_compile`, but in this process instead of on a
+/// server: look up the result in `storage`, or compile and store it.
+pub fn do_compile_direct<T>(creator: T,
+                            core: &mut Core,
+                            storage: Arc<Storage>,
+                            pool: &CpuPool,
+                            exe: &Path,
+                            raw_cmdline: Vec<OsString>,
+                            cwd: &Path,
+                            path: Option<OsString>,
+                            env_vars: Vec<(OsString, OsString)>,
+                            stdout: &mut Write,
+                            stderr: &mut Write) -> Result<i32>
+    where T: CommandCreatorSync,
+{
+    trace!("do_compile_direct");
+    let (exe_path, cmdline, is_compiler) = find_compiler(exe, raw_cmdline, path.as_ref(), cwd)?;
+    if !is_compiler {
+        return run_compiler_locally(creator, core, &exe_path, cmdline, cwd, stdout, stderr)
+    }
+    let info = match config::CONFIG.force_compiler {
+        Some(ref kind) => get_forced_compiler_info(&creator, &exe_path, kind.clone(), pool),
+        None => get_compiler_info(&creator, &exe_path, pool),
+    };
+    let hasher = match core.run(info) {
+        Ok(compiler) => match compiler.parse_arguments(&cmdline, cwd) {
+            CompilerArguments::Ok(hasher) => Some(hasher),
+            args => {
+                debug!("not caching compile: {:?}", args);
+                None
+            }
+        },
+        Err(e) => {
+            debug!("unsupported compiler {:?}: {}", exe_path, e);
+            None
+        }
+    };
+    let hasher = match hasher {
+        Some(hasher) => hasher,
+        None => return run_compiler_locally(creator, core, &exe_path, cmdline, cwd, stdout, stderr),
+    };
+    let cache_control = {
+        let recache = config::find_env_var(&env_vars, "SCCACHE_RECACHE");
+        let recache_paths = config::find_env_var(&env_vars, "SCCACHE_RECACHE_PATHS");
+        CacheControl::from_recache(recache.as_ref().map(|v| v.as_os_str()),
+                                   recache_paths.as_ref().map(|v| v.as_os_str()),
+                                   cwd,
+                                   hasher.input())
+    };
+    let options = CompileOptions {
+        cache_control: cache_control,
+        key_hook: config::CONFIG.key_hook.clone(),
+        nocache_stderr: config::CONFIG.nocache_stderr_regex(),
+        .. Default::default()
+    };
+    let result = hasher.get_cached_or_compile(creator,
+                                              storage,
+                                              cmdline,
+                                              cwd.to_path_buf(),
+                                              env_vars,
+                                              options,
+                                              pool.clone(),
+                                              core.handle());
+    let (compiled, output) = match core.run(result) {
+        Ok((compiled, output, _timings)) => (Some(compiled), output),
+        Err(Error(ErrorKind::ProcessError(output), _)) => (None, output),
+        Err(e) => return Err(e),
+    };
+    debug!("compile result: {:?}", compiled);
+    // Nothing finishes storing the result once this process exits.
+    if let Some(CompileResult::CacheMiss(_, _, cache_write)) = compiled {
+        if let Err(e) = core.run(cache_write) {
+            warn!("failed to store the compile in the cache: {}", e);
+        }
+    }
+    write_compiler_output(output, stdout, stderr)
+}
+
+/// Find the output file of the compile command `cmdline`, given by `-o` or
+/// MSVC-style `-Fo`.
+fn compile_output_path(cmdline: &[OsString], cwd: &Path) -> Option<PathBuf> {
+    let mut args = cmdline.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            return args.next().map(|out| cwd.join(out));
+        }
+        if let Some(arg) = arg.to_str() {
+            for prefix in &["-o", "-Fo", "/Fo"] {
+                if arg.starts_with(prefix) && arg.len() > prefix.len() {
+                    return Some(cwd.join(&arg[prefix.len()..]));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Read the compiler output at `path`, removing it so that the next compile
+/// has to produce it again.
+fn take_output(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .chain_err(|| format!("failed to read compiler output {:?}", path))?;
+    fs::remove_file(path)?;
+    Ok(contents)
+}
+
+/// Wait for the server on `port` to finish writing a cache entry begun after
+/// `before` was taken.
+fn wait_for_cache_write(port: u16, before: &ServerInfo) -> Result<()> {
+    let start = Instant::now();
+    let timeout = Duration::from_millis(VERIFY_CACHE_WRITE_TIMEOUT_MS);
+    loop {
+        let stats = request_stats(connect_to_server(port)?)?.stats;
+        if stats.cache_write_errors > before.stats.cache_write_errors {
+            bail!("failed to write the cache entry");
+        }
+        if stats.cache_writes > before.stats.cache_writes {
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            bail!("timed out waiting for the cache entry to be written");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Run the compile command `cmdline` directly, then twice through the sccache
+/// server on `port`, forcing a cache miss and then a cache hit, and compare
+/// the output of the direct compile with the one restored from the cache.
+///
+/// Returns 0 if the outputs are identical. Differences are reported on
+/// `stderr`.
+pub fn verify_cache<T>(creator: T,
+                       core: &mut Core,
+                       port: u16,
+                       exe: &Path,
+                       cmdline: Vec<OsString>,
+                       cwd: &Path,
+                       path: Option<OsString>,
+                       env_vars: Vec<(OsString, OsString)>,
+                       stdout: &mut Write,
+                       stderr: &mut Write) -> Result<i32>
+    where T: CommandCreatorSync,
+{
+    trace!("verify_cache");
+    let output = compile_output_path(&cmdline, cwd)
+        .ok_or_else(|| Error::from("couldn't find the output file of the compile command"))?;
+
+    let exe_path = which_in(exe, path.as_ref(), &cwd)?;
+    let mut cmd = creator.clone().new_command_sync(&exe_path);
+    cmd.args(&cmdline)
+        .env_clear()
+        .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
+        .current_dir(cwd);
+    match core.run(run_input_output(cmd, None)) {
+        Ok(_) => {}
+        Err(Error(ErrorKind::ProcessError(out), _)) => {
+            stdout.write_all(&out.stdout)?;
+            stderr.write_all(&out.stderr)?;
+            writeln!(stderr, "sccache: compile failed, nothing to verify")?;
+            return Ok(out.status.code().unwrap_or(2))
+        }
+        Err(e) => return Err(e),
+    }
+    let direct = take_output(&output)?;
+
+    // Force a cache miss, so the cache entry is written from this compile.
+    let before = request_stats(connect_to_server(port)?)?;
+    let mut recache_env_vars = env_vars.clone();
+    recache_env_vars.push(("SCCACHE_RECACHE".into(), "1".into()));
+    let status = do_compile(creator.clone(), core, connect_to_server(port)?, exe, cmdline.clone(),
+                            cwd, path.clone(), recache_env_vars, stdout, stderr)?;
+    if status != 0 {
+        writeln!(stderr, "sccache: compile through the cache failed")?;
+        return Ok(status)
+    }
+    take_output(&output)?;
+    wait_for_cache_write(port, &before)?;
+
+    // Now compile again, which should be a cache hit.
+    let before = request_stats(connect_to_server(port)?)?;
+    let status = do_compile(creator, core, connect_to_server(port)?, exe, cmdline,
+                            cwd, path, env_vars, stdout, stderr)?;
+    if status != 0 {
+        writeln!(stderr, "sccache: compile through the cache failed")?;
+        return Ok(status)
+    }
+    let after = request_stats(connect_to_server(port)?)?;
+    if after.stats.cache_hits == before.stats.cache_hits {
+        writeln!(stderr, "sccache: the compile wasn't a cache hit, so the cache can't be verified")?;
+        return Ok(1)
+    }
+    let mut cached = vec![];
+    File::open(&output)
+        .and_then(|mut f| f.read_to_end(&mut cached))
+        .chain_err(|| format!("failed to read compiler output {:?}", output))?;
+
+    let difference = direct.iter().zip(cached.iter()).position(|(a, b)| a != b)
+        .or_else(|| if direct.len() != cached.len() {
+            Some(direct.len().min(cached.len()))
         } else {
-            vec!(arg.clone()).into_iter()
+            None
+        });
+    match difference {
+        None => {
+            writeln!(stdout, "sccache: cached output {:?} matches the direct compile ({} bytes)",
+                     output, direct.len())?;
+            Ok(0)
+        }
+        Some(offset) => {
+            writeln!(stderr, "sccache: cached output {:?} differs from the direct compile at byte {} \
+                              ({} bytes direct, {} bytes cached)",
+                     output, offset, direct.len(), cached.len())?;
+            Ok(1)
         }
-    }).collect::<Vec<_>>();
+    }
+}
 
-    let res = request_compile(&mut conn, &exe_path, &cmdline, &cwd, env_vars)?;
-    handle_compile_response(creator, core, &mut conn, res, &exe_path, cmdline, cwd, stdout, stderr)
+/// Return the name `SCCACHE_FORCE_COMPILER` uses for `kind`.
+fn compiler_kind_name(kind: &CompilerKind) -> &'static str {
+    match *kind {
+        CompilerKind::C(CCompilerKind::GCC) => "gcc",
+        CompilerKind::C(CCompilerKind::Clang) => "clang",
+        CompilerKind::C(CCompilerKind::MSVC) => "msvc",
+        CompilerKind::Rust => "rustc",
+    }
+}
+
+/// Detect the kind and version of the compiler `exe` the same way the
+/// server does, and print them to `stdout`. Returns 1 if sccache doesn't
+/// support the compiler.
+pub fn compiler_check<T>(creator: T,
+                         core: &mut Core,
+                         exe: &Path,
+                         cwd: &Path,
+                         path: Option<OsString>,
+                         stdout: &mut Write) -> Result<i32>
+    where T: CommandCreatorSync,
+{
+    trace!("compiler_check");
+    let exe_path = which_in(exe, path.as_ref(), &cwd)?;
+    let pool = CpuPool::new(1);
+    let info = match config::CONFIG.force_compiler {
+        Some(ref kind) => get_forced_compiler_info(&creator, &exe_path, kind.clone(), &pool),
+        None => get_compiler_info(&creator, &exe_path, &pool),
+    };
+    writeln!(stdout, "Compiler: {}", exe_path.display())?;
+    match core.run(info) {
+        Ok(compiler) => {
+            writeln!(stdout, "Kind: {}", compiler_kind_name(&compiler.kind()))?;
+            writeln!(stdout, "Version: {}", compiler.version().unwrap_or("unknown"))?;
+            Ok(0)
+        }
+        Err(e) => {
+            writeln!(stdout, "Kind: unsupported")?;
+            writeln!(stdout, "Reason: {}", e)?;
+            Ok(1)
+        }
+    }
+}
+
+/// Quote `field` for CSV output if it contains a separator, quote or newline.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Flatten `value` into `(metric, value)` rows, naming nested fields by their
+/// dotted path from `prefix`.
+fn csv_rows(prefix: &str, value: &serde_json::Value, rows: &mut Vec<(String, String)>) {
+    use serde_json::Value;
+
+    let name = |key: &str| if prefix.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", prefix, key)
+    };
+    match *value {
+        Value::Object(ref map) => {
+            for (key, value) in map {
+                csv_rows(&name(key.as_str()), value, rows);
+            }
+        }
+        Value::Array(ref values) => {
+            for (i, value) in values.iter().enumerate() {
+                csv_rows(&name(i.to_string().as_str()), value, rows);
+            }
+        }
+        Value::Null => rows.push((prefix.to_owned(), String::new())),
+        Value::String(ref s) => rows.push((prefix.to_owned(), s.clone())),
+        ref v => rows.push((prefix.to_owned(), v.to_string())),
+    }
+}
+
+/// Write `info` to `out` as CSV, with one `metric,value` row per field.
+///
+/// The rows come from the serialized form of `ServerInfo`, so they follow
+/// its fields without needing to be listed here.
+pub fn write_stats_csv(info: &ServerInfo, out: &mut Write) -> Result<()> {
+    let value = serde_json::to_value(info).chain_err(|| "failed to serialize stats")?;
+    let mut rows = vec![];
+    csv_rows("", &value, &mut rows);
+    writeln!(out, "metric,value")?;
+    for (metric, value) in rows {
+        writeln!(out, "{},{}", csv_field(&metric), csv_field(&value))?;
+    }
+    Ok(())
+}
+
+/// Write `info` to `out` in `fmt`.
+pub fn write_stats(info: &ServerInfo, fmt: StatsFormat, out: &mut Write) -> Result<()> {
+    match fmt {
+        StatsFormat::text => info.write(out)?,
+        StatsFormat::json => serde_json::to_writer(&mut *out, info).chain_err(|| "failed to serialize stats")?,
+        StatsFormat::prometheus => write!(out, "{}", info.openmetrics())?,
+        StatsFormat::csv => write_stats_csv(info, out)?,
+    }
+    Ok(())
+}
+
+/// Stop the server listening on `port`, writing its final stats to `out`
+/// in `fmt`. It's not an error if there's no server to stop, but it is if
+/// there's one that can't be connected to.
+pub fn stop_server(port: u16, fmt: StatsFormat, out: &mut Write) -> Result<()> {
+    let server = match connect_to_server_unchecked(port) {
+        Ok(server) => server,
+        // Nothing is listening on the port, or there's no socket or pipe.
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused || e.kind() == io::ErrorKind::NotFound => {
+            debug!("couldn't connect to server: {}", e);
+            match fmt {
+                StatsFormat::json => writeln!(out, "{{\"running\":false}}")?,
+                _ => writeln!(out, "no server running")?,
+            }
+            return Ok(())
+        }
+        Err(e) => return Err(e).chain_err(|| "couldn't connect to the server to stop it"),
+    };
+    if let StatsFormat::text = fmt {
+        writeln!(out, "Stopping sccache server...")?;
+    }
+    let server = match check_server_version(server) {
+        Ok(server) => server,
+        Err(e) => {
+            // A server from a different sccache still understands
+            // `Shutdown`, but its stats can't be read.
+            debug!("stopping a server from a different sccache: {}", e);
+            let mut server = connect_to_server_unchecked(port)?;
+            drop(server.request(Request::Shutdown));
+            writeln!(out, "Stopped a server from a different version of sccache, whose stats can't be shown")?;
+            return Ok(())
+        }
+    };
+    let stats = request_shutdown(server)?;
+    write_stats(&stats, fmt, out)
+}
+
+/// Write the config `settings` to `out` in `fmt`.
+pub fn write_config(settings: &[ConfigSetting], fmt: StatsFormat, out: &mut Write) -> Result<()> {
+    match fmt {
+        StatsFormat::text => {
+            let width = settings.iter().map(|s| s.name.len()).max().unwrap_or(0);
+            for s in settings {
+                writeln!(out, "{:<width$}  {}  ({})", s.name, s.value, s.source, width = width)?;
+            }
+        }
+        StatsFormat::json => {
+            serde_json::to_writer(&mut *out, settings).chain_err(|| "failed to serialize config")?;
+            writeln!(out, "")?;
+        }
+        StatsFormat::csv => {
+            writeln!(out, "setting,value,source")?;
+            for s in settings {
+                writeln!(out, "{},{},{}", csv_field(&s.name), csv_field(&s.value), csv_field(&s.source))?;
+            }
+        }
+        StatsFormat::prometheus => bail!("the config can't be shown in prometheus format"),
+    }
+    Ok(())
+}
+
+/// Run a dist worker listening on `addr`.
+#[cfg(feature = "dist")]
+fn start_worker(addr: &str) -> Result<()> {
+    use dist;
+    dist::start_worker(addr)
+}
+
+#[cfg(not(feature = "dist"))]
+fn start_worker(_addr: &str) -> Result<()> {
+    bail!("sccache was built without the dist feature")
+}
+
+/// Turn the result of a compile into the exit status of this process, dying
+/// from the compiler's signal if it was killed by one.
+fn finish_compile(res: Result<i32>) -> Result<i32> {
+    match res {
+        Err(Error(ErrorKind::CompilerKilled(signal), _)) => {
+            writeln!(io::stderr(), "sccache: compiler killed by signal {}", signal)?;
+            Ok(exit_with_signal(signal))
+        }
+        res => res.chain_err(|| "failed to execute compile"),
+    }
 }
 
 /// Run `cmd` and return the process exit status.
@@ -577,10 +1252,12 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             let stats = request_stats(srv).chain_err(|| {
                 "failed to get stats from server"
             })?;
-            match fmt {
-                StatsFormat::text => stats.print(),
-                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &stats).unwrap(),
-            }
+            write_stats(&stats, fmt, &mut io::stdout())?;
+        }
+        Command::ShowConfig(fmt) => {
+            trace!("Command::ShowConfig({:?})", fmt);
+            let settings = config::describe_config(&config::CONFIG, config::config_file_path())?;
+            write_config(&settings, fmt, &mut io::stdout())?;
         }
         Command::InternalStartServer => {
             trace!("Command::InternalStartServer");
@@ -598,24 +1275,40 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             match startup {
                 ServerStartup::Ok => {}
                 ServerStartup::TimedOut => {
-                    bail!("Timed out waiting for server startup")
+                    bail!(startup_timeout_error(get_port()))
                 }
                 ServerStartup::Err(e) => {
                     return Err(e).chain_err(|| "Server startup error")
                 }
             }
         }
-        Command::StopServer => {
-            trace!("Command::StopServer");
-            println!("Stopping sccache server...");
-            let server = connect_to_server(get_port()).chain_err(|| {
-                "couldn't connect to server"
-            })?;
-            let stats = request_shutdown(server)?;
-            stats.print();
+        Command::StartWorker(addr) => {
+            trace!("Command::StartWorker({})", addr);
+            start_worker(&addr)?;
+        }
+        Command::StopServer(fmt) => {
+            trace!("Command::StopServer({:?})", fmt);
+            stop_server(get_port(), fmt, &mut io::stdout())?;
         }
         Command::Compile { exe, cmdline, cwd, env_vars } => {
             trace!("Command::Compile {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            if config::CONFIG.no_server {
+                let mut core = Core::new()?;
+                let pool = CpuPool::new(config::CONFIG.num_workers);
+                let storage = storage_from_config(&config::CONFIG, &pool, &core.handle());
+                let res = do_compile_direct(ProcessCommandCreator::new(&core.handle()),
+                                            &mut core,
+                                            storage,
+                                            &pool,
+                                            exe.as_ref(),
+                                            cmdline,
+                                            &cwd,
+                                            env::var_os("PATH"),
+                                            env_vars,
+                                            &mut io::stdout(),
+                                            &mut io::stderr());
+                return finish_compile(res)
+            }
             let conn = connect_or_start_server(get_port())?;
             let mut core = Core::new()?;
             let res = do_compile(ProcessCommandCreator::new(&core.handle()),
@@ -628,10 +1321,38 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                                  env_vars,
                                  &mut io::stdout(),
                                  &mut io::stderr());
+            return finish_compile(res)
+        }
+        Command::VerifyCache { exe, cmdline, cwd, env_vars } => {
+            trace!("Command::VerifyCache {{ {:?}, {:?}, {:?} }}", exe, cmdline, cwd);
+            let port = get_port();
+            // Make sure a server is running before starting.
+            connect_or_start_server(port)?;
+            let mut core = Core::new()?;
+            let res = verify_cache(ProcessCommandCreator::new(&core.handle()),
+                                   &mut core,
+                                   port,
+                                   exe.as_ref(),
+                                   cmdline,
+                                   &cwd,
+                                   env::var_os("PATH"),
+                                   env_vars,
+                                   &mut io::stdout(),
+                                   &mut io::stderr());
             return res.chain_err(|| {
-                "failed to execute compile"
+                "failed to verify cache"
             })
         }
+        Command::CompilerCheck { exe, cwd } => {
+            trace!("Command::CompilerCheck({:?})", exe);
+            let mut core = Core::new()?;
+            return compiler_check(ProcessCommandCreator::new(&core.handle()),
+                                  &mut core,
+                                  exe.as_ref(),
+                                  &cwd,
+                                  env::var_os("PATH"),
+                                  &mut io::stdout())
+        }
         Command::ZeroStats => {
             trace!("Command::ZeroStats");
             let conn = connect_or_start_server(get_port())?;
@@ -640,6 +1361,36 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             })?;
             stats.print();
         }
+        Command::Evict(key) => {
+            trace!("Command::Evict({})", key);
+            let conn = connect_or_start_server(get_port())?;
+            request_evict(conn, key).chain_err(|| {
+                "couldn't evict cache entry"
+            })?;
+        }
+        Command::ClearCache => {
+            trace!("Command::ClearCache");
+            let conn = connect_or_start_server(get_port())?;
+            let cleared = request_clear_cache(conn).chain_err(|| {
+                "couldn't clear cache"
+            })?;
+            match (cleared.entries, cleared.bytes) {
+                (Some(entries), Some(bytes)) => println!("Removed {} entries ({} bytes) from the cache", entries, bytes),
+                (Some(entries), None) => println!("Removed {} entries from the cache", entries),
+                (None, Some(bytes)) => println!("Removed {} bytes from the cache", bytes),
+                (None, None) => println!("Cleared the cache"),
+            }
+        }
+        Command::ReloadConfig => {
+            trace!("Command::ReloadConfig");
+            let conn = connect_or_start_server(get_port())?;
+            let needs_restart = request_reload_config(conn).chain_err(|| {
+                "couldn't reload server configuration"
+            })?;
+            for setting in needs_restart {
+                println!("Changing {} requires restarting the server", setting);
+            }
+        }
     }
 
     Ok(0)
@@ -12,18 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use compiler::{Cacheable, Compiler, CompilerArguments, CompilerHasher, CompilerKind, Compilation, HashResult};
+use cache::{Cache, CacheWrite, Storage};
+use compiler::{Cacheable, Compiler, CompilerArguments, CompilerHasher, CompilerKind, CompilerStamp, Compilation,
+               HashResult};
+use config;
 use futures::Future;
+use futures::future;
 use futures_cpupool::CpuPool;
 use mock_command::CommandCreatorSync;
+use serde_json;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::fs::File;
 use std::hash::Hash;
-use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
 use std::process;
-use util::{HashToDigest, Digest};
+use std::str;
+use std::sync::Arc;
+use std::time::SystemTime;
+use util::{HashToDigest, Digest, SpillOutput, Stdout};
 
 use errors::*;
 
@@ -34,6 +44,7 @@ pub struct CCompiler<I>
 {
     executable: PathBuf,
     executable_digest: String,
+    version: Option<String>,
     compiler: I,
 }
 
@@ -66,6 +77,9 @@ pub struct ParsedArguments {
     pub common_args: Vec<OsString>,
     /// Whether or not the `-showIncludes` argument is passed on MSVC
     pub msvc_show_includes: bool,
+    /// Additional input files whose contents are factored into the hash key,
+    /// like precompiled headers, which don't appear in the preprocessor output.
+    pub extra_hash_files: Vec<PathBuf>,
 }
 
 impl ParsedArguments {
@@ -81,8 +95,11 @@ impl ParsedArguments {
 struct CCompilation<I: CCompilerImpl> {
     parsed_args: ParsedArguments,
     executable: PathBuf,
-    /// The output from running the preprocessor.
-    preprocessor_result: process::Output,
+    /// The compiler binary as it was before preprocessing.
+    executable_stamp: Option<CompilerStamp>,
+    /// The output from running the preprocessor, unless direct mode found
+    /// the key without it.
+    preprocessor_result: Option<SpillOutput>,
     compiler: I,
 }
 
@@ -105,7 +122,9 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
     fn parse_arguments(&self,
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<ParsedArguments>;
-    /// Run the C preprocessor with the specified set of arguments.
+    /// Run the C preprocessor with the specified set of arguments. Output
+    /// over `PREPROCESSOR_SPILL_SIZE` bytes may be written to a file rather
+    /// than kept in memory.
     fn preprocess<T>(&self,
                      creator: &T,
                      executable: &Path,
@@ -113,13 +132,13 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
                      cwd: &Path,
                      env_vars: &[(OsString, OsString)],
                      pool: &CpuPool)
-                     -> SFuture<process::Output> where T: CommandCreatorSync;
+                     -> SFuture<SpillOutput> where T: CommandCreatorSync;
     /// Run the C compiler with the specified set of arguments, using the
     /// previously-generated `preprocessor_output` as input if possible.
     fn compile<T>(&self,
                   creator: &T,
                   executable: &Path,
-                  preprocessor_result: process::Output,
+                  preprocessor_result: SpillOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
                   env_vars: &[(OsString, OsString)],
@@ -131,24 +150,53 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
 impl <I> CCompiler<I>
     where I: CCompilerImpl,
 {
-    pub fn new(compiler: I, executable: PathBuf, pool: &CpuPool) -> SFuture<CCompiler<I>>
+    pub fn new(compiler: I, executable: PathBuf, version: Option<String>, pool: &CpuPool)
+               -> SFuture<CCompiler<I>>
     {
         Box::new(Digest::file(executable.clone(), &pool).map(move |digest| {
+            // A wrapper script stays the same when the compiler it runs is
+            // upgraded, so also key on the version it reported.
+            let digest = match version {
+                Some(ref version) => {
+                    let mut m = Digest::new();
+                    m.update(digest.as_bytes());
+                    m.update(version.as_bytes());
+                    m.finish()
+                }
+                None => digest,
+            };
             CCompiler {
                 executable: executable,
                 executable_digest: digest,
+                version: version,
                 compiler: compiler,
             }
         }))
     }
 }
 
+/// Decline to cache compiles that produce more than `max_outputs` files,
+/// which bounds the size of a cache entry and the time to restore it.
+fn check_output_count(arguments: CompilerArguments<ParsedArguments>,
+                      max_outputs: usize) -> CompilerArguments<ParsedArguments> {
+    match arguments {
+        CompilerArguments::Ok(ref args) if args.outputs.len() > max_outputs => {
+            debug!("{} outputs is over the limit of {}, not caching",
+                   args.outputs.len(), max_outputs);
+            CompilerArguments::CannotCache("too many outputs")
+        }
+        arguments => arguments,
+    }
+}
+
 impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     fn kind(&self) -> CompilerKind { CompilerKind::C(self.compiler.kind()) }
+    fn version(&self) -> Option<&str> { self.version.as_ref().map(|v| &**v) }
     fn parse_arguments(&self,
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<Box<CompilerHasher<T> + 'static>> {
-        match self.compiler.parse_arguments(arguments, cwd) {
+        match check_output_count(self.compiler.parse_arguments(arguments, cwd),
+                                 config::CONFIG.max_outputs) {
             CompilerArguments::Ok(args) => {
                 CompilerArguments::Ok(Box::new(CCompilerHasher {
                     parsed_args: args,
@@ -175,53 +223,120 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
                          creator: &T,
                          cwd: &Path,
                          env_vars: &[(OsString, OsString)],
+                         storage: &Arc<Storage>,
                          pool: &CpuPool)
                          -> SFuture<HashResult<T>>
     {
         let me = *self;
         let CCompilerHasher { parsed_args, executable, executable_digest, compiler } = me;
-        let result = compiler.preprocess(creator, &executable, &parsed_args, cwd, env_vars, pool);
+        let executable_stamp = CompilerStamp::new(&executable).ok();
+        let extra_hashes = future::join_all(parsed_args.extra_hash_files.iter()
+            .map(|f| Digest::file(cwd.join(f), pool))
+            .collect::<Vec<_>>());
+        // Direct mode needs the input's contents to find a previous compile
+        // of it. Without them the compile is preprocessed as usual.
+        let direct_mode = config::direct_mode(env_vars) && direct_mode_compatible(&parsed_args);
+        let input_digest: SFuture<Option<String>> = if direct_mode {
+            Box::new(direct_mode_digest(cwd.join(&parsed_args.input), None, pool).then(|result| {
+                Ok::<_, Error>(result.ok().and_then(|digest| digest))
+            }))
+        } else {
+            f_ok(None)
+        };
         let out_pretty = parsed_args.output_pretty().into_owned();
+        let basedir = config::basedir(env_vars);
+        let creator = creator.clone();
+        let cwd = cwd.to_owned();
         let env_vars = env_vars.to_vec();
-        let result = result.map_err(move |e| {
-            debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
-            e
-        });
-        let out_pretty = parsed_args.output_pretty().into_owned();
-        Box::new(result.or_else(move |err| {
-            match err {
-                Error(ErrorKind::ProcessError(output), _) => {
-                    debug!("[{}]: preprocessor returned error status {:?}",
-                           out_pretty,
-                           output.status.code());
-                    // Drop the stdout since it's the preprocessor output, just hand back stderr and
-                    // the exit status.
-                    bail!(ErrorKind::ProcessError(process::Output {
-                        stdout: vec!(),
-                        .. output
-                    }))
-                }
-                e @ _ => Err(e),
-            }
-        }).and_then(move |preprocessor_result| {
-            trace!("[{}]: Preprocessor output is {} bytes",
-                   parsed_args.output_pretty(),
-                   preprocessor_result.stdout.len());
-
-            let key = {
-                hash_key(&executable_digest,
-                         &parsed_args.common_args,
-                         &env_vars,
-                         &preprocessor_result.stdout)
+        let storage = storage.clone();
+        let pool = pool.clone();
+        Box::new(extra_hashes.join(input_digest).and_then(move |(extra_hashes, input_digest)| {
+            let direct_key = input_digest.map(|digest| {
+                direct_mode_key(&executable_digest, &parsed_args, &cwd, &env_vars, &extra_hashes, &digest)
+            });
+            let found = match direct_key {
+                Some(ref direct_key) => find_manifest(direct_key, &storage, &pool),
+                None => f_ok(None),
             };
-            Ok(HashResult {
-                key: key,
-                compilation: Box::new(CCompilation {
-                    parsed_args: parsed_args,
-                    executable: executable,
-                    preprocessor_result: preprocessor_result,
-                    compiler: compiler,
-                }),
+            found.and_then(move |found| -> SFuture<HashResult<T>> {
+                if let Some(key) = found {
+                    debug!("[{}]: Found key in direct mode, not preprocessing", out_pretty);
+                    return f_ok(HashResult {
+                        key: key,
+                        compilation: Box::new(CCompilation {
+                            parsed_args: parsed_args,
+                            executable: executable,
+                            executable_stamp: executable_stamp,
+                            preprocessor_result: None,
+                            compiler: compiler,
+                        }),
+                    })
+                }
+                let start = SystemTime::now();
+                let result = compiler.preprocess(&creator, &executable, &parsed_args, &cwd, &env_vars, &pool);
+                let result = result.or_else(move |err| preprocess_failed(err, &out_pretty));
+                Box::new(result.and_then(move |preprocessor_result| {
+                    trace!("[{}]: Preprocessor output has {} bytes in memory",
+                           parsed_args.output_pretty(),
+                           preprocessor_result.buffered_size());
+
+                    let mut arguments = hashed_args(&parsed_args);
+                    if let Some(ref basedir) = basedir {
+                        arguments = relative_to_basedir_args(&arguments, basedir, &cwd);
+                        // Debug info records the directory the compile ran
+                        // in, so the object can only be shared between
+                        // compiles in the same one, as with ccache's
+                        // `hash_dir`.
+                        if debug_info_enabled(&parsed_args.common_args) {
+                            arguments.push(cwd.as_os_str().to_owned());
+                        }
+                    }
+                    // A compilation database entry records the whole command and
+                    // the directory it ran in, so they have to match for the
+                    // stored entry to be reused.
+                    if parsed_args.outputs.contains_key("mj") {
+                        arguments.extend(parsed_args.preprocessor_args.iter().cloned());
+                        arguments.push(cwd.as_os_str().to_owned());
+                    }
+                    let m = key_digest(&executable_digest, &arguments, &env_vars, &extra_hashes);
+                    let list_files = direct_key.is_some();
+                    let hashed: SFuture<(String, Vec<PathBuf>)> = match preprocessor_result.stdout {
+                        Stdout::Memory(ref data) => {
+                            Box::new(future::result(hash_preprocessor_output(m, &data[..], basedir.as_ref().map(|b| &**b),
+                                                                             &cwd, list_files)))
+                        }
+                        // Read large outputs back a line at a time, rather
+                        // than all at once.
+                        Stdout::File(_, ref path) => {
+                            let path = path.clone();
+                            let basedir = basedir.clone();
+                            let cwd = cwd.clone();
+                            Box::new(pool.spawn_fn(move || {
+                                let reader = BufReader::new(File::open(&path)?);
+                                hash_preprocessor_output(m, reader, basedir.as_ref().map(|b| &**b), &cwd, list_files)
+                            }))
+                        }
+                    };
+                    let input = cwd.join(&parsed_args.input);
+                    hashed.and_then(move |(key, files)| {
+                        let recorded = match direct_key {
+                            Some(direct_key) => {
+                                record_manifest(direct_key, key.clone(), &input, files, start, &storage, &pool)
+                            }
+                            None => f_ok(()),
+                        };
+                        recorded.map(move |()| HashResult {
+                            key: key,
+                            compilation: Box::new(CCompilation {
+                                parsed_args: parsed_args,
+                                executable: executable,
+                                executable_stamp: executable_stamp,
+                                preprocessor_result: Some(preprocessor_result),
+                                compiler: compiler,
+                            }),
+                        })
+                    })
+                }))
             })
         }))
     }
@@ -231,6 +346,11 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
         self.parsed_args.output_pretty()
     }
 
+    fn input(&self) -> Option<&Path>
+    {
+        Some(&self.parsed_args.input)
+    }
+
     fn box_clone(&self) -> Box<CompilerHasher<T>>
     {
         Box::new((*self).clone())
@@ -246,19 +366,292 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compilation<T> for CCompilation<I>
                -> SFuture<(Cacheable, process::Output)>
     {
         let me = *self;
-        let CCompilation { parsed_args, executable, preprocessor_result, compiler } = me;
-        compiler.compile(creator, &executable, preprocessor_result, &parsed_args, cwd, env_vars,
-                         pool)
+        let CCompilation { parsed_args, executable, executable_stamp, preprocessor_result, compiler } = me;
+        let out_pretty = parsed_args.output_pretty().into_owned();
+        let preprocessor_result: SFuture<SpillOutput> = match preprocessor_result {
+            Some(result) => f_ok(result),
+            // Direct mode found the key without preprocessing, but the
+            // compiler still needs the preprocessor output.
+            None => {
+                let out_pretty = out_pretty.clone();
+                let result = compiler.preprocess(creator, &executable, &parsed_args, cwd, env_vars, pool);
+                Box::new(result.or_else(move |err| preprocess_failed(err, &out_pretty)))
+            }
+        };
+        let creator = creator.clone();
+        let cwd = cwd.to_owned();
+        let env_vars = env_vars.to_vec();
+        let pool = pool.clone();
+        Box::new(preprocessor_result.and_then(move |preprocessor_result| {
+            let compile = compiler.compile(&creator, &executable, preprocessor_result, &parsed_args,
+                                           &cwd, &env_vars, &pool);
+            compile.map(move |(cacheable, mut output)| {
+                // The compiler's stderr isn't a terminal, so it only colors
+                // diagnostics when asked to. Strip anything else that looks like
+                // color, so that hits and misses print the same bytes.
+                if !color_diagnostics_forced(&parsed_args.common_args) {
+                    output.stderr = strip_ansi_escapes(&output.stderr);
+                }
+                // If the compiler changed since it preprocessed the input, say
+                // because it was upgraded mid-build, the key was computed with
+                // one compiler and the object built by another.
+                if cacheable == Cacheable::Yes && CompilerStamp::new(&executable).ok() != executable_stamp {
+                    warn!("[{}]: {:?} changed during the compile, not caching", out_pretty, executable);
+                    return (Cacheable::No, output)
+                }
+                (cacheable, output)
+            })
+        }))
     }
 
     fn outputs<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a Path)> + 'a>
     {
         Box::new(self.parsed_args.outputs.iter().map(|(k, v)| (*k, &**v)))
     }
+
+    fn buffered_size(&self) -> usize
+    {
+        self.preprocessor_result.as_ref().map_or(0, |r| r.buffered_size())
+    }
+}
+
+/// Hand back a preprocessor failure for `out_pretty` without the
+/// preprocessor output, keeping just stderr and the exit status.
+fn preprocess_failed(err: Error, out_pretty: &str) -> Result<SpillOutput> {
+    debug!("[{}]: preprocessor failed: {:?}", out_pretty, err);
+    match err {
+        Error(ErrorKind::ProcessError(output), _) => {
+            debug!("[{}]: preprocessor returned error status {:?}",
+                   out_pretty,
+                   output.status.code());
+            // Drop the stdout since it's the preprocessor output, just hand back stderr and
+            // the exit status.
+            bail!(ErrorKind::ProcessError(process::Output {
+                stdout: vec!(),
+                .. output
+            }))
+        }
+        e @ _ => Err(e),
+    }
+}
+
+/// Arguments that add a directory to the include search path.
+const INCLUDE_DIR_ARGS: &'static [&'static str] = &["-I", "-idirafter", "-iquote", "-isystem"];
+
+/// Spell include directory arguments in `arguments` the same way, so that
+/// `-I include`, `-Iinclude` and `-I./include/` hash alike. The order of the
+/// arguments, and so the include search order, is unchanged.
+///
+/// Only `.` components and trailing separators are removed from the
+/// directories: `..` is kept, since it doesn't cancel out across symlinks.
+/// The preprocessor output is still hashed, so spellings that change the
+/// paths it reports for included headers still get different keys.
+fn normalize_include_args(arguments: &[OsString]) -> Vec<OsString> {
+    fn normalize_dir(dir: &OsStr) -> OsString {
+        let path = Path::new(dir).components()
+            .filter(|c| *c != Component::CurDir)
+            .map(|c| c.as_os_str())
+            .collect::<PathBuf>();
+        if path.as_os_str().is_empty() {
+            OsString::from(".")
+        } else {
+            path.into_os_string()
+        }
+    }
+
+    let mut normalized = vec![];
+    let mut it = arguments.iter();
+    while let Some(arg) = it.next() {
+        match arg.to_str() {
+            Some(a) if INCLUDE_DIR_ARGS.contains(&a) => {
+                normalized.push(arg.clone());
+                if let Some(dir) = it.next() {
+                    normalized.push(normalize_dir(dir));
+                }
+            }
+            Some(a) if a.starts_with("-I") && a.len() > 2 && a != "-I-" => {
+                normalized.push(OsString::from("-I"));
+                normalized.push(normalize_dir(OsStr::new(&a[2..])));
+            }
+            _ => normalized.push(arg.clone()),
+        }
+    }
+    normalized
+}
+
+/// If `path` is an absolute path under `basedir`, return it relative to
+/// `cwd`, so that it's spelled the same in checkouts with the same layout in
+/// different places.
+fn relative_to_basedir(path: &Path, basedir: &Path, cwd: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || !path.starts_with(basedir) {
+        return None
+    }
+    let mut path_components = path.components().peekable();
+    let mut cwd_components = cwd.components().peekable();
+    while path_components.peek().is_some() && path_components.peek() == cwd_components.peek() {
+        path_components.next();
+        cwd_components.next();
+    }
+    let mut relative = cwd_components.map(|_| Component::ParentDir.as_os_str()).collect::<PathBuf>();
+    relative.extend(path_components.map(|c| c.as_os_str()));
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+/// Make absolute paths under `basedir` in `arguments` relative to `cwd`,
+/// whether they're whole arguments or joined to an option, as in
+/// `-I/path/to/include`.
+fn relative_to_basedir_args(arguments: &[OsString], basedir: &Path, cwd: &Path) -> Vec<OsString> {
+    let basedir_str = basedir.to_str();
+    arguments.iter().map(|arg| {
+        if let Some(relative) = relative_to_basedir(Path::new(arg), basedir, cwd) {
+            return relative.into_os_string()
+        }
+        if let (Some(a), Some(b)) = (arg.to_str(), basedir_str) {
+            if let Some(i) = a.find(b) {
+                if let Some(relative) = relative_to_basedir(Path::new(&a[i..]), basedir, cwd) {
+                    let mut rewritten = OsString::from(&a[..i]);
+                    rewritten.push(relative);
+                    return rewritten
+                }
+            }
+        }
+        arg.clone()
+    }).collect()
+}
+
+/// Return whether `args` ask for debug info, like `-g`, `-gdwarf-4` or
+/// MSVC's `-Zi`. The last `-g` option wins, so `-g0` after `-g` turns it off
+/// again.
+fn debug_info_enabled(args: &[OsString]) -> bool {
+    args.iter().filter_map(|arg| arg.to_str()).fold(false, |enabled, arg| {
+        match arg {
+            "-g0" => false,
+            "-Z7" | "-Zi" | "-ZI" | "/Z7" | "/Zi" | "/ZI" => true,
+            a if a.starts_with("-g") => true,
+            _ => enabled,
+        }
+    })
+}
+
+/// Make absolute paths under `basedir` in the line markers of preprocessor
+/// output, like `# 1 "/path/to/foo.h"`, relative to `cwd`.
+fn relative_to_basedir_output(output: &[u8], basedir: &Path, cwd: &Path) -> Vec<u8> {
+    let mut rewritten = Vec::with_capacity(output.len());
+    for (i, line) in output.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            rewritten.push(b'\n');
+        }
+        let relative = if line.starts_with(b"#") {
+            let start = line.iter().position(|&b| b == b'"').map(|p| p + 1);
+            start.and_then(|start| {
+                line[start..].iter().position(|&b| b == b'"').map(|len| (start, start + len))
+            }).and_then(|(start, end)| {
+                str::from_utf8(&line[start..end]).ok().and_then(|path| {
+                    // MSVC escapes the backslashes in its paths.
+                    let path = path.replace("\\\\", "\\");
+                    relative_to_basedir(Path::new(&path), basedir, cwd)
+                }).map(|relative| (start, end, relative.to_string_lossy().replace('\\', "\\\\")))
+            })
+        } else {
+            None
+        };
+        match relative {
+            Some((start, end, relative)) => {
+                rewritten.extend_from_slice(&line[..start]);
+                rewritten.extend_from_slice(relative.as_bytes());
+                rewritten.extend_from_slice(&line[end..]);
+            }
+            None => rewritten.extend_from_slice(line),
+        }
+    }
+    rewritten
+}
+
+/// Preprocessor arguments that choose which headers are listed in the
+/// dependency file, like `-MD` (all headers) and `-MMD` (user headers only).
+const DEPFILE_MODE_ARGS: &'static [&'static str] = &["-M", "-MM", "-MD", "-MMD"];
+
+/// Preprocessor arguments that take a value that shapes the dependency
+/// file: its name, and the targets it lists, including the one named after
+/// the object that's added when none is given.
+const DEPFILE_VALUE_ARGS: &'static [&'static str] = &["-MF", "-MQ", "-MT"];
+
+/// Return the arguments in `preprocessor_args` that affect the contents of
+/// the dependency file. The preprocessor output doesn't depend on them, so
+/// they're hashed separately to keep, say, a `-MD` depfile from being
+/// restored for a `-MMD` compile, or one listing another compile's target.
+/// Those that don't take a value, like `-MP`, are in `common_args`.
+fn depfile_args(preprocessor_args: &[OsString]) -> Vec<OsString> {
+    let mut args = vec![];
+    let mut it = preprocessor_args.iter();
+    while let Some(arg) = it.next() {
+        if DEPFILE_MODE_ARGS.iter().any(|m| arg.as_os_str() == OsStr::new(m)) {
+            args.push(arg.clone());
+        } else if DEPFILE_VALUE_ARGS.iter().any(|m| arg.as_os_str() == OsStr::new(m)) {
+            args.push(arg.clone());
+            args.extend(it.next().cloned());
+        }
+    }
+    args
+}
+
+/// Return the arguments of a compile to hash into its key: those that affect
+/// the compile step, which the preprocessor output doesn't reflect. That's
+/// `common_args`, the dependency file arguments, and the language the input is
+/// compiled as, which `-x` can change without changing the preprocessor
+/// output.
+pub fn hashed_args(parsed_args: &ParsedArguments) -> Vec<OsString> {
+    let mut arguments = parsed_args.common_args.clone();
+    arguments.extend(depfile_args(&parsed_args.preprocessor_args));
+    let mut language = OsString::from("-language=");
+    language.push(&parsed_args.extension);
+    arguments.push(language);
+    arguments
+}
+
+/// Return whether `args` force colored diagnostics on, like
+/// `-fdiagnostics-color=always` or clang's `-fcolor-diagnostics`. The last
+/// such argument wins, as it does for the compiler.
+pub fn color_diagnostics_forced(args: &[OsString]) -> bool {
+    args.iter().filter_map(|arg| arg.to_str()).fold(false, |forced, arg| {
+        match arg {
+            "-fdiagnostics-color" |
+            "-fdiagnostics-color=always" |
+            "-fcolor-diagnostics" => true,
+            "-fdiagnostics-color=auto" |
+            "-fdiagnostics-color=never" |
+            "-fno-diagnostics-color" |
+            "-fno-color-diagnostics" => false,
+            _ => forced,
+        }
+    })
+}
+
+/// Remove ANSI escape sequences, like color codes, from `data`.
+pub fn strip_ansi_escapes(data: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0x1b && data.get(i + 1) == Some(&b'[') {
+            // Skip the parameters up to and including the final byte.
+            i += 2;
+            while i < data.len() && !(0x40 <= data[i] && data[i] <= 0x7e) {
+                i += 1;
+            }
+            i += 1;
+        } else {
+            stripped.push(data[i]);
+            i += 1;
+        }
+    }
+    stripped
 }
 
 /// The cache is versioned by the inputs to `hash_key`.
-pub const CACHE_VERSION : &'static [u8] = b"4";
+pub const CACHE_VERSION : &'static [u8] = b"9";
 
 /// Environment variables that are factored into the cache key.
 pub const CACHED_ENV_VARS : &'static [&'static str] = &[
@@ -266,21 +659,56 @@ pub const CACHED_ENV_VARS : &'static [&'static str] = &[
     "IPHONEOS_DEPLOYMENT_TARGET",
 ];
 
+/// Locale environment variables that are factored into the cache key when
+/// `SCCACHE_HASH_LOCALE` is set, since they affect the language of
+/// diagnostics that are replayed on cache hits.
+pub const LOCALE_ENV_VARS : &'static [&'static str] = &[
+    "LANG",
+    "LC_ALL",
+    "LC_MESSAGES",
+];
+
+/// Preprocessor output larger than this many bytes is written to a temporary
+/// file instead of being kept in memory.
+pub const PREPROCESSOR_SPILL_SIZE: usize = 16 * 1024 * 1024;
+
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
+///
+/// `extra_hashes` are the digests of any additional input files, such as
+/// precompiled headers.
 pub fn hash_key(compiler_digest: &str,
                 arguments: &[OsString],
                 env_vars: &[(OsString, OsString)],
+                extra_hashes: &[String],
                 preprocessor_output: &[u8]) -> String
+{
+    let mut m = key_digest(compiler_digest, arguments, env_vars, extra_hashes);
+    m.update(preprocessor_output);
+    m.finish()
+}
+
+/// Start computing a hash key like `hash_key`, with everything but the
+/// preprocessor output, which the caller adds.
+fn key_digest(compiler_digest: &str,
+              arguments: &[OsString],
+              env_vars: &[(OsString, OsString)],
+              extra_hashes: &[String]) -> Digest
 {
     // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
     let mut m = Digest::new();
     m.update(compiler_digest.as_bytes());
     m.update(CACHE_VERSION);
-    for arg in arguments {
+    for arg in normalize_include_args(arguments) {
         arg.hash(&mut HashToDigest { digest: &mut m });
     }
     //TODO: use lazy_static.
-    let cached_env_vars: HashSet<OsString> = CACHED_ENV_VARS.iter().map(|v| OsStr::new(v).to_os_string()).collect();
+    let mut cached_env_vars: HashSet<OsString> = CACHED_ENV_VARS.iter().map(|v| OsStr::new(v).to_os_string()).collect();
+    let hash_locale = env_vars.iter().any(|&(ref var, ref val)| {
+        var == "SCCACHE_HASH_LOCALE" && val != "0"
+    });
+    if hash_locale {
+        cached_env_vars.extend(LOCALE_ENV_VARS.iter().map(|v| OsStr::new(v).to_os_string()));
+    }
     for &(ref var, ref val) in env_vars.iter() {
         if cached_env_vars.contains(var) {
             var.hash(&mut HashToDigest { digest: &mut m });
@@ -288,8 +716,257 @@ pub fn hash_key(compiler_digest: &str,
             val.hash(&mut HashToDigest { digest: &mut m });
         }
     }
-    m.update(preprocessor_output);
-    m.finish()
+    for hash in extra_hashes {
+        m.update(hash.as_bytes());
+    }
+    m
+}
+
+/// Environment variables that add to the include search path. Direct mode
+/// hashes them, since they change which headers are found without changing
+/// the arguments.
+const INCLUDE_PATH_ENV_VARS: &'static [&'static str] = &[
+    "CPATH",
+    "C_INCLUDE_PATH",
+    "CPLUS_INCLUDE_PATH",
+    "OBJC_INCLUDE_PATH",
+];
+
+/// Macros that expand differently from one compile to the next, so that a
+/// file using them can't be checked by its contents alone.
+const TIME_MACROS: &'static [&'static [u8]] = &[b"__DATE__", b"__TIME__", b"__TIMESTAMP__"];
+
+/// What direct mode remembers about a compile: the key it had, and the
+/// files it included, with digests of their contents at the time. It's
+/// stored in the cache, under the compile's direct mode key.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    key: String,
+    dependencies: Vec<(PathBuf, String)>,
+}
+
+/// The name of the manifest in the cache entry that holds it.
+const MANIFEST_OBJECT: &'static str = "manifest";
+
+/// Return true if direct mode can be used for the compile described by
+/// `parsed_args`. It finds the included files from the line markers in the
+/// preprocessor output, which `-P` leaves out.
+fn direct_mode_compatible(parsed_args: &ParsedArguments) -> bool {
+    !parsed_args.preprocessor_args.iter()
+        .chain(parsed_args.common_args.iter())
+        .any(|arg| arg == "-P")
+}
+
+/// Compute the direct mode key of a compile, which stands in for its key
+/// until the compile is preprocessed. It's made from the same inputs, except
+/// that the preprocessor output is replaced with what it's made from: the
+/// input's contents, the preprocessor arguments and the working directory.
+/// The headers are checked separately, against a manifest.
+fn direct_mode_key(compiler_digest: &str,
+                   parsed_args: &ParsedArguments,
+                   cwd: &Path,
+                   env_vars: &[(OsString, OsString)],
+                   extra_hashes: &[String],
+                   input_digest: &str) -> String {
+    let mut arguments = hashed_args(parsed_args);
+    arguments.extend(parsed_args.preprocessor_args.iter().cloned());
+    arguments.push(parsed_args.input.clone().into_os_string());
+    arguments.push(cwd.as_os_str().to_owned());
+    for &(ref var, ref val) in env_vars.iter() {
+        if var.to_str().map_or(false, |var| INCLUDE_PATH_ENV_VARS.contains(&var)) {
+            let mut arg = var.clone();
+            arg.push("=");
+            arg.push(val);
+            arguments.push(arg);
+        }
+    }
+    hash_key(compiler_digest, &arguments, env_vars, extra_hashes, input_digest.as_bytes())
+}
+
+/// Hash the contents of `path` for direct mode. Returns `None` if the file
+/// can't be checked by its contents: if it uses `__DATE__`, `__TIME__` or
+/// `__TIMESTAMP__`, or if it was modified at or after `modified_since`, in
+/// which case it may not be what the preprocessor read.
+fn direct_mode_digest(path: PathBuf, modified_since: Option<SystemTime>, pool: &CpuPool)
+                      -> SFuture<Option<String>> {
+    Box::new(pool.spawn_fn(move || -> Result<_> {
+        let mut f = File::open(&path)?;
+        if let Some(time) = modified_since {
+            if f.metadata()?.modified()? >= time {
+                return Ok(None)
+            }
+        }
+        let mut contents = vec![];
+        f.read_to_end(&mut contents)?;
+        if TIME_MACROS.iter().any(|m| contents.windows(m.len()).any(|w| w == *m)) {
+            return Ok(None)
+        }
+        let mut m = Digest::new();
+        m.update(&contents);
+        Ok(Some(m.finish()))
+    }))
+}
+
+/// Return the file named in `line` if it's a line marker of preprocessor
+/// output, like `# 1 "/path/to/foo.h"`. Line markers name the input and
+/// every header it included, as a dependency file would list.
+fn included_file(line: &[u8], cwd: &Path) -> Option<PathBuf> {
+    if !line.starts_with(b"#") {
+        return None
+    }
+    let start = match line.iter().position(|&b| b == b'"') {
+        Some(p) => p + 1,
+        None => return None,
+    };
+    let end = match line[start..].iter().position(|&b| b == b'"') {
+        Some(len) => start + len,
+        None => return None,
+    };
+    match str::from_utf8(&line[start..end]) {
+        // Skip pseudo-files like `<built-in>` and `<command-line>`.
+        Ok(name) if !name.is_empty() && !name.starts_with('<') => {
+            // MSVC escapes the backslashes in its paths.
+            Some(cwd.join(name.replace("\\\\", "\\")))
+        }
+        _ => None,
+    }
+}
+
+/// Add the preprocessor output read from `reader` to `m`, the digest from
+/// `key_digest`, and return the key. Absolute paths under `basedir` in line
+/// markers are hashed relative to `cwd`. If `list_files`, also return the
+/// files named in line markers, in the order they first appear.
+///
+/// The output is read a line at a time, so that it needn't all be in memory.
+fn hash_preprocessor_output<R>(mut m: Digest,
+                               mut reader: R,
+                               basedir: Option<&Path>,
+                               cwd: &Path,
+                               list_files: bool) -> Result<(String, Vec<PathBuf>)>
+    where R: BufRead,
+{
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break
+        }
+        let (text, newline) = match line.split_last() {
+            Some((&b'\n', text)) => (text, true),
+            _ => (&line[..], false),
+        };
+        if list_files {
+            if let Some(path) = included_file(text, cwd) {
+                if seen.insert(path.clone()) {
+                    files.push(path);
+                }
+            }
+        }
+        match basedir {
+            Some(basedir) => m.update(&relative_to_basedir_output(text, basedir, cwd)),
+            None => m.update(text),
+        }
+        if newline {
+            m.update(b"\n");
+        }
+    }
+    Ok((m.finish(), files))
+}
+
+/// Return the key stored for `direct_key` in `storage`, if the files the
+/// compile included are all unchanged.
+fn find_manifest(direct_key: &str, storage: &Arc<Storage>, pool: &CpuPool) -> SFuture<Option<String>> {
+    let pool = pool.clone();
+    Box::new(storage.get(direct_key).then(move |result| -> SFuture<Option<String>> {
+        let hit = match result {
+            Ok(Cache::Hit(hit)) => hit,
+            Ok(_) => return f_ok(None),
+            Err(e) => {
+                debug!("Failed to look up a direct mode manifest: {}", e);
+                return f_ok(None)
+            }
+        };
+        let manifest = pool.spawn_fn(move || -> Result<Manifest> {
+            let mut hit = hit;
+            let mut data = vec![];
+            hit.get_object(MANIFEST_OBJECT, &mut data)?;
+            serde_json::from_slice(&data).chain_err(|| "failed to parse direct mode manifest")
+        });
+        Box::new(manifest.then(move |manifest| -> SFuture<Option<String>> {
+            let Manifest { key, dependencies } = match manifest {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    debug!("Failed to read a direct mode manifest: {}", e);
+                    return f_ok(None)
+                }
+            };
+            let checks = dependencies.into_iter().map(|(path, digest)| {
+                direct_mode_digest(path, None, &pool).then(move |result| {
+                    Ok::<_, Error>(result.ok().and_then(|d| d) == Some(digest))
+                })
+            }).collect::<Vec<_>>();
+            Box::new(future::join_all(checks).map(move |unchanged| {
+                if unchanged.iter().all(|&u| u) {
+                    Some(key)
+                } else {
+                    None
+                }
+            }))
+        }))
+    }))
+}
+
+/// Store `key` in `storage` under `direct_key`, along with `files`, those the
+/// compile of `input` included, so that later compiles can find it without
+/// preprocessing. Nothing is stored if `input` isn't among the files, since
+/// then they weren't found, or if any of them can't be checked, including
+/// those modified since `preprocess_start`. Failing to store the manifest
+/// doesn't fail the compile.
+fn record_manifest(direct_key: String,
+                   key: String,
+                   input: &Path,
+                   files: Vec<PathBuf>,
+                   preprocess_start: SystemTime,
+                   storage: &Arc<Storage>,
+                   pool: &CpuPool) -> SFuture<()> {
+    if !files.iter().any(|f| f == input) {
+        debug!("Not storing a direct mode manifest, the preprocessor output doesn't list the input");
+        return f_ok(())
+    }
+    let digests = files.into_iter().map(|path| {
+        direct_mode_digest(path.clone(), Some(preprocess_start), pool).then(move |result| {
+            Ok::<_, Error>(result.ok().and_then(|d| d).map(|digest| (path, digest)))
+        })
+    }).collect::<Vec<_>>();
+    let storage = storage.clone();
+    Box::new(future::join_all(digests).and_then(move |digests| -> SFuture<()> {
+        let dependencies = match digests.into_iter().collect::<Option<Vec<_>>>() {
+            Some(dependencies) => dependencies,
+            None => {
+                debug!("Not storing a direct mode manifest, a dependency can't be checked");
+                return f_ok(())
+            }
+        };
+        let manifest = Manifest {
+            key: key,
+            dependencies: dependencies,
+        };
+        let mut entry = CacheWrite::new();
+        let stored = serde_json::to_vec(&manifest).chain_err(|| "failed to serialize direct mode manifest")
+            .and_then(|data| entry.put_object(MANIFEST_OBJECT, &mut &data[..], None));
+        if let Err(e) = stored {
+            debug!("Failed to store a direct mode manifest: {}", e);
+            return f_ok(())
+        }
+        Box::new(storage.put(&direct_key, entry).then(|result| {
+            if let Err(e) = result {
+                debug!("Failed to store a direct mode manifest: {}", e);
+            }
+            Ok::<(), Error>(())
+        }))
+    }))
 }
 
 #[cfg(test)]
@@ -300,8 +977,8 @@ mod test {
     fn test_hash_key_executable_contents_differs() {
         let args = ovec!["a", "b", "c"];
         const PREPROCESSED : &'static [u8] = b"hello world";
-        assert_neq!(hash_key("abcd",&args, &[], &PREPROCESSED),
-                    hash_key("wxyz",&args, &[], &PREPROCESSED));
+        assert_neq!(hash_key("abcd",&args, &[], &[], &PREPROCESSED),
+                    hash_key("wxyz",&args, &[], &[], &PREPROCESSED));
     }
 
     #[test]
@@ -312,21 +989,50 @@ mod test {
         let ab = ovec!["a", "b"];
         let a = ovec!["a"];
         const PREPROCESSED: &'static [u8] = b"hello world";
-        assert_neq!(hash_key(digest, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, &xyz, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, &abc, &[], &[], &PREPROCESSED),
+                    hash_key(digest, &xyz, &[], &[], &PREPROCESSED));
+
+        assert_neq!(hash_key(digest, &abc, &[], &[], &PREPROCESSED),
+                    hash_key(digest, &ab, &[], &[], &PREPROCESSED));
 
-        assert_neq!(hash_key(digest, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, &ab, &[], &PREPROCESSED));
+        assert_neq!(hash_key(digest, &abc, &[], &[], &PREPROCESSED),
+                    hash_key(digest, &a, &[], &[], &PREPROCESSED));
+    }
 
-        assert_neq!(hash_key(digest, &abc, &[], &PREPROCESSED),
-                    hash_key(digest, &a, &[], &PREPROCESSED));
+    #[test]
+    fn test_hash_key_include_spellings() {
+        let digest = "abcd";
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        let h = hash_key(digest, &ovec!["-I", "include", "-isystem", "sys"], &[], &[], &PREPROCESSED);
+        for args in &[ovec!["-Iinclude", "-isystem", "./sys"],
+                      ovec!["-I./include", "-isystem", "sys/"],
+                      ovec!["-I", "./include/", "-isystem", "sys/."]] {
+            assert_eq!(h, hash_key(digest, args, &[], &[], &PREPROCESSED));
+        }
+        assert_neq!(h, hash_key(digest, &ovec!["-Iother", "-isystem", "sys"], &[], &[], &PREPROCESSED));
+        assert_neq!(h, hash_key(digest, &ovec!["-I", "sys", "-isystem", "include"], &[], &[], &PREPROCESSED));
+        // The search order matters.
+        assert_neq!(hash_key(digest, &ovec!["-Ia", "-Ib"], &[], &[], &PREPROCESSED),
+                    hash_key(digest, &ovec!["-Ib", "-Ia"], &[], &[], &PREPROCESSED));
     }
 
     #[test]
     fn test_hash_key_preprocessed_content_differs() {
         let args = ovec!["a", "b", "c"];
-        assert_neq!(hash_key("abcd", &args, &[], &b"hello world"[..]),
-                    hash_key("abcd", &args, &[], &b"goodbye"[..]));
+        assert_neq!(hash_key("abcd", &args, &[], &[], &b"hello world"[..]),
+                    hash_key("abcd", &args, &[], &[], &b"goodbye"[..]));
+    }
+
+    #[test]
+    fn test_hash_key_extra_hashes_differs() {
+        let args = ovec!["a", "b", "c"];
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        let pch1 = vec!["abcd".to_owned()];
+        let pch2 = vec!["wxyz".to_owned()];
+        assert_neq!(hash_key("abcd", &args, &[], &[], &PREPROCESSED),
+                    hash_key("abcd", &args, &[], &pch1, &PREPROCESSED));
+        assert_neq!(hash_key("abcd", &args, &[], &pch1, &PREPROCESSED),
+                    hash_key("abcd", &args, &[], &pch2, &PREPROCESSED));
     }
 
     #[test]
@@ -335,13 +1041,209 @@ mod test {
         let digest = "abcd";
         const PREPROCESSED: &'static [u8] = b"hello world";
         for var in CACHED_ENV_VARS.iter() {
-            let h1 = hash_key(digest, &args, &[], &PREPROCESSED);
+            let h1 = hash_key(digest, &args, &[], &[], &PREPROCESSED);
             let vars = vec![(OsString::from(var), OsString::from("something"))];
-            let h2 = hash_key(digest, &args, &vars, &PREPROCESSED);
+            let h2 = hash_key(digest, &args, &vars, &[], &PREPROCESSED);
             let vars = vec![(OsString::from(var), OsString::from("something else"))];
-            let h3 = hash_key(digest, &args, &vars, &PREPROCESSED);
+            let h3 = hash_key(digest, &args, &vars, &[], &PREPROCESSED);
             assert_neq!(h1, h2);
             assert_neq!(h2, h3);
         }
     }
+
+    #[test]
+    fn test_hash_key_locale() {
+        let args = ovec!["a", "b", "c"];
+        let digest = "abcd";
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        let hash_locale = (OsString::from("SCCACHE_HASH_LOCALE"), OsString::from("1"));
+        for var in LOCALE_ENV_VARS.iter() {
+            let c = vec![(OsString::from(var), OsString::from("C"))];
+            let de = vec![(OsString::from(var), OsString::from("de_DE.UTF-8"))];
+            // Without SCCACHE_HASH_LOCALE the locale is ignored.
+            assert_eq!(hash_key(digest, &args, &c, &[], &PREPROCESSED),
+                       hash_key(digest, &args, &de, &[], &PREPROCESSED));
+            let c = c.into_iter().chain(Some(hash_locale.clone())).collect::<Vec<_>>();
+            let de = de.into_iter().chain(Some(hash_locale.clone())).collect::<Vec<_>>();
+            assert_neq!(hash_key(digest, &args, &c, &[], &PREPROCESSED),
+                        hash_key(digest, &args, &de, &[], &PREPROCESSED));
+        }
+    }
+
+    #[test]
+    fn test_hash_preprocessor_output() {
+        let cwd = Path::new("/src");
+        let output = b"# 1 \"foo.c\"\n# 1 \"<built-in>\"\n# 1 \"/usr/include/stdio.h\" 1 3 4\nint x;\n# 2 \"foo.c\" 2\n";
+        let args = ovec!["-c"];
+        let m = key_digest("abcd", &args, &[], &[]);
+        let (key, files) = hash_preprocessor_output(m, &output[..], None, cwd, true).unwrap();
+        // Reading the output a line at a time hashes the same as all at once.
+        assert_eq!(hash_key("abcd", &args, &[], &[], output), key);
+        assert_eq!(vec![PathBuf::from("/src/foo.c"), PathBuf::from("/usr/include/stdio.h")], files);
+        // Even without a trailing newline.
+        let m = key_digest("abcd", &args, &[], &[]);
+        let (key, files) = hash_preprocessor_output(m, &b"int x;"[..], None, cwd, false).unwrap();
+        assert_eq!(hash_key("abcd", &args, &[], &[], b"int x;"), key);
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn test_depfile_args() {
+        let args = ovec!["-DFOO", "-MF", "foo.d", "-MMD", "-MT", "foo.o", "-MQ", "$(foo)", "-x", "c"];
+        assert_eq!(ovec!["-MF", "foo.d", "-MMD", "-MT", "foo.o", "-MQ", "$(foo)"], depfile_args(&args));
+        assert!(depfile_args(&ovec!["-DFOO", "-x", "c"]).is_empty());
+    }
+
+    #[test]
+    fn test_check_output_count() {
+        let parsed_args = ParsedArguments {
+            input: "foo.c".into(),
+            extension: "c".into(),
+            depfile: None,
+            outputs: vec![("obj", "foo.o".into()),
+                          ("dwo", "foo.dwo".into())].into_iter().collect(),
+            preprocessor_args: vec![],
+            common_args: vec![],
+            msvc_show_includes: false,
+            extra_hash_files: vec![],
+        };
+        assert_eq!(CompilerArguments::Ok(parsed_args.clone()),
+                   check_output_count(CompilerArguments::Ok(parsed_args.clone()), 2));
+        assert_eq!(CompilerArguments::CannotCache("too many outputs"),
+                   check_output_count(CompilerArguments::Ok(parsed_args), 1));
+        assert_eq!(CompilerArguments::NotCompilation,
+                   check_output_count(CompilerArguments::NotCompilation, 1));
+    }
+
+    #[test]
+    fn test_generate_hash_key_profile_data() {
+        use compiler::gcc::GCC;
+        use mock_command::*;
+        use std::fs::File;
+        use std::io::Write;
+        use test::mock_storage::MockStorage;
+        use test::utils::*;
+
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        let storage: Arc<Storage> = Arc::new(MockStorage::new());
+        // As parsed from `-c foo.c -fprofile-use -o foo.o`.
+        let parsed_args = ParsedArguments {
+            input: "foo.c".into(),
+            extension: "c".into(),
+            depfile: None,
+            outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
+            preprocessor_args: vec![],
+            common_args: ovec!["-fprofile-use"],
+            msvc_show_includes: false,
+            extra_hash_files: vec!["foo.gcda".into()],
+        };
+        let key = |profile: &[u8]| {
+            File::create(f.tempdir.path().join("foo.gcda"))
+                .and_then(|mut file| file.write_all(profile))
+                .unwrap();
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            let hasher = Box::new(CCompilerHasher {
+                parsed_args: parsed_args.clone(),
+                executable: f.bins[0].clone(),
+                executable_digest: "abcd".to_owned(),
+                compiler: GCC,
+            });
+            hasher.generate_hash_key(&creator, f.tempdir.path(), &[], &storage, &pool).wait().unwrap().key
+        };
+        let first = key(b"profile data");
+        // Identical profile data hits.
+        assert_eq!(first, key(b"profile data"));
+        // Changed profile data busts the cache.
+        assert_neq!(first, key(b"new profile data"));
+    }
+
+    #[test]
+    fn test_relative_to_basedir() {
+        let basedir = Path::new("/build/abc");
+        let cwd = Path::new("/build/abc/src");
+        assert_eq!(ovec!["-I", "../include", "-I../gen", "-isystem", "/usr/include", "-isystem../sys", "-c"],
+                   relative_to_basedir_args(&ovec!["-I", "/build/abc/include", "-I/build/abc/gen",
+                                                   "-isystem", "/usr/include",
+                                                   "-isystem/build/abc/sys", "-c"],
+                                            basedir, cwd));
+        assert_eq!(ovec!["foo.c", "."],
+                   relative_to_basedir_args(&ovec!["/build/abc/src/foo.c", "/build/abc/src"], basedir, cwd));
+        // Only whole components match.
+        assert_eq!(ovec!["/build/abcd/include"],
+                   relative_to_basedir_args(&ovec!["/build/abcd/include"], basedir, cwd));
+        assert_eq!(&b"# 1 \"foo.c\"\n# 1 \"../include/foo.h\" 1\n# 1 \"/usr/include/stdio.h\" 3\nint foo;\n"[..],
+                   &relative_to_basedir_output(b"# 1 \"foo.c\"\n# 1 \"/build/abc/include/foo.h\" 1\n\
+                                                 # 1 \"/usr/include/stdio.h\" 3\nint foo;\n",
+                                               basedir, cwd)[..]);
+    }
+
+    #[test]
+    fn test_generate_hash_key_basedir() {
+        use compiler::gcc::GCC;
+        use mock_command::*;
+        use test::mock_storage::MockStorage;
+        use test::utils::*;
+
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        let storage: Arc<Storage> = Arc::new(MockStorage::new());
+        // Compile `foo.c` from `src` in a checkout at `root`, as parsed from
+        // `-c foo.c -I<root>/include -isystem <root>/sys -o foo.o`.
+        let key = |root: &Path, basedir: Option<&Path>, debug: bool| {
+            let mut common_args = vec![OsString::from(format!("-I{}", root.join("include").display())),
+                                       "-isystem".into(),
+                                       root.join("sys").into_os_string()];
+            if debug {
+                common_args.push("-g".into());
+            }
+            let parsed_args = ParsedArguments {
+                input: "foo.c".into(),
+                extension: "c".into(),
+                depfile: None,
+                outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
+                preprocessor_args: vec![],
+                common_args: common_args,
+                msvc_show_includes: false,
+                extra_hash_files: vec![],
+            };
+            let output = format!("# 1 \"foo.c\"\n# 1 \"{}\" 1\nint foo;\n",
+                                 root.join("include").join("foo.h").display());
+            next_command(&creator, Ok(MockChild::new(exit_status(0), output, "")));
+            let env_vars = basedir.into_iter()
+                .map(|b| (OsString::from("SCCACHE_BASEDIR"), b.as_os_str().to_owned()))
+                .collect::<Vec<_>>();
+            let hasher = Box::new(CCompilerHasher {
+                parsed_args: parsed_args,
+                executable: f.bins[0].clone(),
+                executable_digest: "abcd".to_owned(),
+                compiler: GCC,
+            });
+            hasher.generate_hash_key(&creator, &root.join("src"), &env_vars, &storage, &pool).wait().unwrap().key
+        };
+        let abc = f.tempdir.path().join("abc");
+        let xyz = f.tempdir.path().join("xyz");
+        // Checkouts in different places get different keys...
+        assert_neq!(key(&abc, None, false), key(&xyz, None, false));
+        // ...unless they're under the base directory.
+        assert_eq!(key(&abc, Some(abc.as_path()), false), key(&xyz, Some(xyz.as_path()), false));
+        assert_eq!(key(&abc, Some(f.tempdir.path()), false), key(&xyz, Some(f.tempdir.path()), false));
+        // Paths outside the base directory are left alone.
+        assert_neq!(key(&abc, Some(abc.as_path()), false), key(&xyz, Some(abc.as_path()), false));
+        // Objects with debug info record where they were compiled, so
+        // they're never shared between directories.
+        assert_neq!(key(&abc, Some(f.tempdir.path()), true), key(&xyz, Some(f.tempdir.path()), true));
+    }
+
+    #[test]
+    fn test_debug_info_enabled() {
+        assert!(!debug_info_enabled(&ovec!["-O2"]));
+        assert!(debug_info_enabled(&ovec!["-g"]));
+        assert!(debug_info_enabled(&ovec!["-O2", "-gdwarf-4"]));
+        assert!(debug_info_enabled(&ovec!["-Zi"]));
+        assert!(!debug_info_enabled(&ovec!["-g", "-g0"]));
+        assert!(debug_info_enabled(&ovec!["-g0", "-g3"]));
+    }
 }
@@ -16,6 +16,7 @@
 
 use ::compiler::{
     gcc,
+    msvc,
     Cacheable,
     CompilerArguments,
     write_temp_file,
@@ -28,7 +29,7 @@ use mock_command::{
     CommandCreatorSync,
     RunCommand,
 };
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::{
     self,
@@ -36,7 +37,7 @@ use std::io::{
 };
 use std::path::Path;
 use std::process;
-use util::{run_input_output, OsStrExt};
+use util::{run_input_output, OsStrExt, SpillOutput, Stdout};
 
 use errors::*;
 
@@ -50,7 +51,11 @@ impl CCompilerImpl for Clang {
                        arguments: &[OsString],
                        cwd: &Path) -> CompilerArguments<ParsedArguments>
     {
-        gcc::parse_arguments(arguments, cwd, argument_takes_value)
+        if is_cl_mode(arguments) {
+            msvc::parse_arguments(arguments)
+        } else {
+            gcc::parse_arguments(arguments, cwd, argument_takes_value)
+        }
     }
 
     fn preprocess<T>(&self,
@@ -60,15 +65,20 @@ impl CCompilerImpl for Clang {
                      cwd: &Path,
                      env_vars: &[(OsString, OsString)],
                      pool: &CpuPool)
-                     -> SFuture<process::Output> where T: CommandCreatorSync
+                     -> SFuture<SpillOutput> where T: CommandCreatorSync
     {
-        gcc::preprocess(creator, executable, parsed_args, cwd, env_vars, pool)
+        if is_cl_mode(&parsed_args.common_args) {
+            Box::new(msvc::preprocess(creator, executable, parsed_args, cwd, env_vars, CL_MODE_INCLUDES_PREFIX, pool)
+                     .map(SpillOutput::from))
+        } else {
+            gcc::preprocess(creator, executable, parsed_args, cwd, env_vars, pool)
+        }
     }
 
     fn compile<T>(&self,
                   creator: &T,
                   executable: &Path,
-                  preprocessor_result: process::Output,
+                  preprocessor_result: SpillOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
                   env_vars: &[(OsString, OsString)],
@@ -76,14 +86,35 @@ impl CCompilerImpl for Clang {
                   -> SFuture<(Cacheable, process::Output)>
         where T: CommandCreatorSync
     {
-        compile(creator, executable, preprocessor_result, parsed_args, cwd, env_vars, pool)
+        if is_cl_mode(&parsed_args.common_args) {
+            let preprocessor_result = match preprocessor_result.into_output() {
+                Ok(output) => output,
+                Err(e) => return f_err(e),
+            };
+            msvc::compile(creator, executable, preprocessor_result, parsed_args, cwd, env_vars, pool)
+        } else {
+            compile(creator, executable, preprocessor_result, parsed_args, cwd, env_vars, pool)
+        }
     }
 }
 
+/// The prefix of clang's `-showIncludes` output in `cl` driver mode.
+const CL_MODE_INCLUDES_PREFIX: &'static str = "Note: including file:";
+
+/// Return true if `arguments` put clang in `cl` driver mode, where it takes
+/// MSVC-style arguments like `/Fo`.
+///
+/// `clang-cl` itself defines `_MSC_VER`, so it's detected as MSVC, but plain
+/// `clang` can be switched into the same mode per invocation.
+fn is_cl_mode(arguments: &[OsString]) -> bool {
+    arguments.iter().any(|a| a.as_os_str() == OsStr::new("--driver-mode=cl"))
+}
+
 /// Arguments that take a value that aren't in `gcc::ARGS_WITH_VALUE`.
 const ARGS_WITH_VALUE: &'static [&'static str] = &[
     "-arch",
     "-B",
+    "-include-pch",
     "-target",
     "-Xclang",
 ];
@@ -95,7 +126,7 @@ pub fn argument_takes_value(arg: &str) -> bool {
 
 fn compile<T>(creator: &T,
               executable: &Path,
-              preprocessor_result: process::Output,
+              preprocessor_result: SpillOutput,
               parsed_args: &ParsedArguments,
               cwd: &Path,
               env_vars: &[(OsString, OsString)],
@@ -104,14 +135,22 @@ fn compile<T>(creator: &T,
     where T: CommandCreatorSync,
 {
     trace!("compile");
+    // Precompiled headers are built from the original header.
+    if gcc::is_header_language(&parsed_args.extension) {
+        return gcc::compile(creator, executable, preprocessor_result, parsed_args, cwd, env_vars, pool)
+    }
     // Clang needs a temporary file for compilation, otherwise debug info
-    // doesn't have a reference to the input file.
-    let write = {
-        let filename = match Path::new(&parsed_args.input).file_name() {
-            Some(name) => name,
-            None => return future::err("missing input filename".into()).boxed(),
-        };
-        write_temp_file(pool, filename.as_ref(), preprocessor_result.stdout)
+    // doesn't have a reference to the input file. Large preprocessor output
+    // is already in one.
+    let write = match preprocessor_result.stdout {
+        Stdout::Memory(data) => {
+            let filename = match Path::new(&parsed_args.input).file_name() {
+                Some(name) => name,
+                None => return future::err("missing input filename".into()).boxed(),
+            };
+            write_temp_file(pool, filename.as_ref(), data)
+        }
+        Stdout::File(dir, path) => f_ok((dir, path)),
     };
     let input = parsed_args.input.clone();
     let out_file = match parsed_args.outputs.get("obj") {
@@ -227,6 +266,27 @@ mod test {
         parses!("-c", "foo.c", "-target", "x86_64-apple-darwin11", "-o", "foo.o");
     }
 
+    #[test]
+    fn test_parse_arguments_include_pch() {
+        let a = parses!("-c", "foo.c", "-include-pch", "foo.pch", "-o", "foo.o");
+        assert_eq!(Some("foo.c"), a.input.to_str());
+        assert_eq!(ovec!["-include-pch", "foo.pch"], a.common_args);
+        assert_eq!(vec![PathBuf::from("foo.pch")], a.extra_hash_files);
+    }
+
+    #[test]
+    fn test_parse_arguments_cl_mode() {
+        let a = parses!("--driver-mode=cl", "/c", "foo.c", "/Fofoo.obj");
+        assert_eq!(Some("foo.c"), a.input.to_str());
+        assert_eq!("c", a.extension);
+        assert_eq!(Some(&PathBuf::from("foo.obj")), a.outputs.get("obj"));
+        assert_eq!(1, a.outputs.len());
+        assert_eq!(ovec!["--driver-mode=cl"], a.common_args);
+        // Without it, `/Fo` isn't understood.
+        assert_eq!(CompilerArguments::CannotCache("multiple input files"),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "/Fofoo.obj"]));
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -240,13 +300,14 @@ mod test {
             preprocessor_args: vec!(),
             common_args: vec!(),
             msvc_show_includes: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
         let (cacheable, _) = compile(&creator,
                                      &compiler,
-                                     empty_output(),
+                                     empty_output().into(),
                                      &parsed_args,
                                      f.tempdir.path(),
                                      &[],
@@ -269,6 +330,7 @@ mod test {
             preprocessor_args: vec!(),
             common_args: ovec!("-c", "-o", "foo.o", "-Werror=blah", "foo.c"),
             msvc_show_includes: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // First compiler invocation fails.
@@ -277,7 +339,7 @@ mod test {
         next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
         let (cacheable, output) = compile(&creator,
                                           &compiler,
-                                          empty_output(),
+                                          empty_output().into(),
                                           &parsed_args,
                                           f.tempdir.path(),
                                           &[],
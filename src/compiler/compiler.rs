@@ -14,6 +14,7 @@
 
 use cache::{
     Cache,
+    CacheRead,
     CacheWrite,
     Storage,
 };
@@ -23,8 +24,10 @@ use compiler::clang::Clang;
 use compiler::gcc::GCC;
 use compiler::msvc::MSVC;
 use compiler::rust::Rust;
+use filetime::FileTime;
 use futures::{Future, IntoFuture};
 use futures_cpupool::CpuPool;
+use regex::Regex;
 use mock_command::{
     CommandChild,
     CommandCreatorSync,
@@ -33,11 +36,12 @@ use mock_command::{
 };
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::ffi::OsString;
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-#[cfg(unix)]
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{self,Stdio};
@@ -48,8 +52,9 @@ use std::time::{
     Instant,
 };
 use tempdir::TempDir;
-use util::fmt_duration_as_secs;
+use util::{fmt_duration_as_secs, run_input_output, CompileSlots, Digest, MemoryBudget};
 use tokio_core::reactor::{Handle, Timeout};
+use uuid::Uuid;
 
 use errors::*;
 
@@ -68,6 +73,8 @@ pub trait Compiler<T>: Send + 'static
 {
     /// Return the kind of compiler.
     fn kind(&self) -> CompilerKind;
+    /// Return the compiler's version, if it was found during detection.
+    fn version(&self) -> Option<&str>;
     /// Determine whether `arguments` are supported by this compiler.
     fn parse_arguments(&self,
                        arguments: &[OsString],
@@ -87,39 +94,66 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
     /// Given information about a compiler command, generate a hash key
     /// that can be used for cache lookups, as well as any additional
     /// information that can be reused for compilation if necessary.
+    /// `storage` is the cache the key will be looked up in.
     fn generate_hash_key(self: Box<Self>,
                          creator: &T,
                          cwd: &Path,
                          env_vars: &[(OsString, OsString)],
+                         storage: &Arc<Storage>,
                          pool: &CpuPool)
                          -> SFuture<HashResult<T>>;
     /// Look up a cached compile result in `storage`. If not found, run the
-    /// compile and store the result.
+    /// compile and store the result, unless its stderr matches
+    /// `options.nocache_stderr`.
     fn get_cached_or_compile(self: Box<Self>,
                              creator: T,
                              storage: Arc<Storage>,
                              arguments: Vec<OsString>,
                              cwd: PathBuf,
                              env_vars: Vec<(OsString, OsString)>,
-                             cache_control: CacheControl,
+                             options: CompileOptions,
                              pool: CpuPool,
                              handle: Handle)
-                             -> SFuture<(CompileResult, process::Output)>
+                             -> SFuture<(CompileResult, process::Output, CompileTimings)>
     {
+        let CompileOptions { cache_control, key_hook, nocache_stderr, memory, compile_slots } = options;
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
         let start = Instant::now();
-        let result = self.generate_hash_key(&creator, &cwd, &env_vars, &pool);
+        let result = self.generate_hash_key(&creator, &cwd, &env_vars, &storage, &pool);
+        let result = match key_hook {
+            None => result,
+            Some(hook) => {
+                let creator = creator.clone();
+                let cwd = cwd.clone();
+                let arguments = arguments.clone();
+                let handle = handle.clone();
+                Box::new(result.and_then(move |HashResult { key, compilation }| {
+                    transform_key(&creator, &hook, key, &cwd, &arguments, &handle).map(|key| {
+                        HashResult {
+                            key: key,
+                            compilation: compilation,
+                        }
+                    })
+                }))
+            }
+        };
         Box::new(result.then(move |res| -> SFuture<_> {
-            debug!("[{}]: generate_hash_key took {}", out_pretty, fmt_duration_as_secs(&start.elapsed()));
+            let mut timings = CompileTimings {
+                preprocess: start.elapsed(),
+                .. Default::default()
+            };
+            debug!("[{}]: generate_hash_key took {}", out_pretty, fmt_duration_as_secs(&timings.preprocess));
             let (key, compilation) = match res {
                 Err(Error(ErrorKind::ProcessError(output), _)) => {
-                    return f_ok((CompileResult::Error, output));
+                    return f_ok((CompileResult::Error, output, timings));
                 }
                 Err(e) => return f_err(e),
                 Ok(HashResult { key, compilation }) => (key, compilation),
             };
-            trace!("[{}]: Hash key: {}", out_pretty, key);
+            debug!("[{}]: Hash key: {}", out_pretty, key);
+            // Account for the preprocessor output until it's been compiled.
+            let preprocessed = memory.reserve(compilation.buffered_size());
             // If `ForceRecache` is enabled, we won't check the cache.
             let start = Instant::now();
             let cache_status = if cache_control == CacheControl::ForceRecache {
@@ -145,6 +179,7 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
             // Check the result of the cache lookup.
             Box::new(cache_status.then(move |result| {
                 let duration = start.elapsed();
+                timings.cache_read = Some(duration);
                 let pwd = Path::new(&cwd);
                 let outputs = compilation.outputs()
                     .map(|(key, path)| (key.to_string(), pwd.join(path)))
@@ -159,11 +194,7 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                         drop(entry.get_object("stderr", &mut stderr));
                         let write = pool.spawn_fn(move ||{
                             for (key, path) in &outputs {
-                                let mut f = File::create(&path)?;
-                                let mode = entry.get_object(&key, &mut f)?;
-                                if let Some(mode) = mode {
-                                    set_file_mode(&path, mode)?;
-                                }
+                                restore_output(&mut entry, &key, &path)?;
                             }
                             Ok(())
                         });
@@ -173,8 +204,8 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                             stderr: stderr,
                         };
                         let result = CompileResult::CacheHit(duration);
-                        return Box::new(write.map(|_| {
-                            (result, output)
+                        return Box::new(write.map(move |_| {
+                            (result, output, timings)
                         })) as SFuture<_>
                     }
                     Ok(Some(Cache::Miss)) => {
@@ -198,43 +229,72 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                     }
                 };
 
-                // Cache miss, so compile it.
-                let start = Instant::now();
+                // Cache miss, so compile it once a compile slot is free.
                 let out_pretty = out_pretty.clone();
-                let compile = compilation.compile(&creator, &cwd, &env_vars, &pool);
-                Box::new(compile.and_then(move |(cacheable, compiler_result)| {
-                    let duration = start.elapsed();
+                let compile = {
+                    let pool = pool.clone();
+                    compile_slots.acquire().and_then(move |slot| {
+                        let start = Instant::now();
+                        compilation.compile(&creator, &cwd, &env_vars, &pool)
+                            .map(move |(cacheable, compiler_result)| {
+                                drop(slot);
+                                (cacheable, compiler_result, start.elapsed())
+                            })
+                    })
+                };
+                Box::new(compile.and_then(move |(cacheable, compiler_result, duration)| {
+                    drop(preprocessed);
+                    timings.compile = Some(duration);
                     if !compiler_result.status.success() {
                         debug!("[{}]: Compiled but failed, not storing in cache",
                                out_pretty);
-                        return f_ok((CompileResult::CompileFailed, compiler_result))
+                        return f_ok((CompileResult::CompileFailed, compiler_result, timings))
                             as SFuture<_>
                     }
                     if cacheable != Cacheable::Yes {
                         // Not cacheable
                         debug!("[{}]: Compiled but not cacheable",
                                out_pretty);
-                        return f_ok((CompileResult::NotCacheable, compiler_result))
+                        return f_ok((CompileResult::NotCacheable, compiler_result, timings))
+                    }
+                    // Some toolchains warn about nondeterministic output
+                    // while still succeeding, and that output shouldn't be
+                    // replayed to later builds.
+                    if let Some(ref re) = nocache_stderr {
+                        if re.is_match(&String::from_utf8_lossy(&compiler_result.stderr)) {
+                            debug!("[{}]: Compiled but stderr matches {}, not storing in cache",
+                                   out_pretty, re);
+                            return f_ok((CompileResult::NotCacheable, compiler_result, timings))
+                        }
                     }
                     debug!("[{}]: Compiled in {}, storing in cache", out_pretty, fmt_duration_as_secs(&duration));
                     let write = pool.spawn_fn(move || -> Result<_> {
                         let mut entry = CacheWrite::new();
+                        let mut size = 0;
                         for (key, path) in &outputs {
                             let mut f = File::open(&path)?;
+                            size += f.metadata()?.len() as usize;
                             let mode = get_file_mode(&path)?;
                             entry.put_object(key, &mut f, mode).chain_err(|| {
                                 format!("failed to put object `{:?}` in zip", path)
                             })?;
                         }
-                        Ok(entry)
+                        Ok((entry, size))
                     });
                     let write = write.chain_err(|| "failed to zip up compiler outputs");
                     let o = out_pretty.clone();
-                    Box::new(write.and_then(move |mut entry| {
+                    Box::new(write.and_then(move |(mut entry, size)| {
+                        // Account for the entry until it's been written out,
+                        // using the uncompressed size as an upper bound.
+                        let size = size + compiler_result.stdout.len() + compiler_result.stderr.len();
+                        let buffered = memory.reserve(size);
                         if !compiler_result.stdout.is_empty() {
                             let mut stdout = &compiler_result.stdout[..];
                             entry.put_object("stdout", &mut stdout, None)?;
                         }
+                        // C compiles have had any color codes that weren't
+                        // asked for stripped from their stderr by now, so hits
+                        // replay the same bytes that a miss printed.
                         if !compiler_result.stderr.is_empty() {
                             let mut stderr = &compiler_result.stderr[..];
                             entry.put_object("stderr", &mut stderr, None)?;
@@ -245,6 +305,7 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                         let out_pretty = out_pretty.clone();
                         let future = storage.put(&key, entry)
                             .then(move |res| {
+                                drop(buffered);
                                 match res {
                                     Ok(_) => debug!("[{}]: Stored in cache successfully!", out_pretty),
                                     Err(ref e) => debug!("[{}]: Cache write error: {:?}", out_pretty, e),
@@ -255,7 +316,7 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
                                 })
                             });
                         let future = Box::new(future);
-                        Ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result))
+                        Ok((CompileResult::CacheMiss(miss_type, duration, future), compiler_result, timings))
                     }).chain_err(move || {
                         format!("failed to store `{}` to cache", o)
                     }))
@@ -270,6 +331,10 @@ pub trait CompilerHasher<T>: fmt::Debug + Send + 'static
     /// artifact generation.
     fn output_pretty(&self) -> Cow<str>;
 
+    /// The source file being compiled, if known, relative to the working
+    /// directory of the compile.
+    fn input(&self) -> Option<&Path>;
+
     fn box_clone(&self) -> Box<CompilerHasher<T>>;
 }
 
@@ -294,6 +359,10 @@ pub trait Compilation<T>
     /// Each item is a descriptive (and unique) name of the output paired with
     /// the path where it'll show up.
     fn outputs<'a>(&'a self) -> Box<Iterator<Item=(&'a str, &'a Path)> + 'a>;
+
+    /// The approximate number of bytes this compilation is holding in
+    /// memory, such as preprocessor output, until it is compiled.
+    fn buffered_size(&self) -> usize { 0 }
 }
 
 /// Result of generating a hash from a compiler command.
@@ -335,6 +404,17 @@ pub struct CacheWriteInfo {
     pub duration: Duration,
 }
 
+/// How long the stages of handling a compile request took.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompileTimings {
+    /// Running the preprocessor and hashing its output.
+    pub preprocess: Duration,
+    /// Looking up the result in the cache, if it got that far.
+    pub cache_read: Option<Duration>,
+    /// Running the compiler, if the result wasn't found in the cache.
+    pub compile: Option<Duration>,
+}
+
 /// The result of a compilation or cache retrieval.
 pub enum CompileResult {
     /// An error made the compilation not possible.
@@ -409,6 +489,57 @@ fn set_file_mode(_path: &Path, _mode: u32) -> Result<()>
     Ok(())
 }
 
+/// Write the object `key` from `entry` to `path`.
+///
+/// The object is written to a temporary file in the same directory and then
+/// renamed into place, so that anything else writing or reading `path` at the
+/// same time never sees a partially written file.
+fn restore_output(entry: &mut CacheRead, key: &str, path: &Path) -> Result<()>
+{
+    let tmp = {
+        let name = path.file_name().ok_or("Output path has no file name")?;
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}.tmp", Uuid::new_v4().simple()));
+        path.with_file_name(tmp_name)
+    };
+    let res = File::create(&tmp)
+        .map_err(Error::from)
+        .and_then(|mut f| {
+            if let Some(mode) = entry.get_object(key, &mut f)? {
+                set_file_mode(&tmp, mode)?;
+            }
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&tmp, path).map_err(Error::from));
+    if res.is_err() {
+        drop(fs::remove_file(&tmp));
+    }
+    res
+}
+
+/// Identifies the compiler binary found at a path, to tell when it changes.
+/// Symlinks are followed, so pointing one at another compiler counts as a
+/// change even if both binaries have the same mtime and size.
+#[derive(Debug, PartialEq)]
+pub struct CompilerStamp {
+    path: PathBuf,
+    mtime: FileTime,
+    size: u64,
+}
+
+impl CompilerStamp {
+    pub fn new(path: &Path) -> io::Result<CompilerStamp> {
+        let path = fs::canonicalize(path)?;
+        let attr = fs::metadata(&path)?;
+        Ok(CompilerStamp {
+            mtime: FileTime::from_last_modification_time(&attr),
+            size: attr.len(),
+            path: path,
+        })
+    }
+}
+
 /// Can this result be stored in cache?
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Cacheable {
@@ -417,7 +548,7 @@ pub enum Cacheable {
 }
 
 /// Control of caching behavior.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum CacheControl {
     /// Default caching behavior.
     Default,
@@ -425,6 +556,155 @@ pub enum CacheControl {
     ForceRecache,
 }
 
+impl CacheControl {
+    /// Decide how to cache the compile of `input` in `cwd`, given the values
+    /// of `SCCACHE_RECACHE` and `SCCACHE_RECACHE_PATHS` in its environment,
+    /// if set. `SCCACHE_RECACHE`, whatever its value, forces every compile to
+    /// skip the cache. `SCCACHE_RECACHE_PATHS` is a list of glob patterns,
+    /// separated like `PATH` and relative to `cwd`, and only forces compiles
+    /// whose input matches one of them.
+    pub fn from_recache(recache: Option<&OsStr>,
+                        recache_paths: Option<&OsStr>,
+                        cwd: &Path,
+                        input: Option<&Path>) -> CacheControl {
+        if recache.is_some() {
+            return CacheControl::ForceRecache
+        }
+        let (recache_paths, input) = match (recache_paths, input) {
+            (Some(paths), Some(input)) => (paths, input),
+            _ => return CacheControl::Default,
+        };
+        let input: Vec<char> = cwd.join(input).to_string_lossy().chars().collect();
+        let matched = env::split_paths(recache_paths).any(|pattern| {
+            let pattern: Vec<char> = cwd.join(pattern).to_string_lossy().chars().collect();
+            glob_matches(&pattern, &input)
+        });
+        if matched {
+            CacheControl::ForceRecache
+        } else {
+            CacheControl::Default
+        }
+    }
+}
+
+/// How `get_cached_or_compile` looks up and stores a compile, beyond what's
+/// needed to run it.
+#[derive(Clone)]
+pub struct CompileOptions {
+    /// Whether to skip the cache lookup.
+    pub cache_control: CacheControl,
+    /// A program that transforms the hash key, if any.
+    pub key_hook: Option<PathBuf>,
+    /// Compiles whose stderr matches this aren't stored in the cache.
+    pub nocache_stderr: Option<Regex>,
+    /// Accounts for preprocessor output and cache entries held in memory.
+    pub memory: MemoryBudget,
+    /// Limits how many compilers run at once.
+    pub compile_slots: CompileSlots,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            cache_control: CacheControl::Default,
+            key_hook: None,
+            nocache_stderr: None,
+            memory: MemoryBudget::new(None),
+            compile_slots: CompileSlots::new(None),
+        }
+    }
+}
+
+/// Return true if `name` matches the glob `pattern`, where `*` matches any
+/// run of characters, including path separators, and `?` matches any one
+/// character.
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The position after the last `*` seen, and the position in `name` it
+    // is currently matched up to, to go back to on a mismatch. Since a later
+    // `*` can match anything an earlier one can, only the last one needs to
+    // be retried, so this takes at most `pattern.len() * name.len()` steps.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// How long a key hook may run before we give up on it.
+const KEY_HOOK_TIMEOUT_SECS: u64 = 5;
+
+/// Run the key hook program `hook` to transform the cache key `key`.
+///
+/// The hook reads the key on the first line of its stdin, followed by the
+/// working directory and the compiler arguments, one per line, and prints the
+/// new key on stdout. The printed key is hashed, so that whatever it prints,
+/// the cache gets a key of the usual form. If the hook fails, times out or
+/// doesn't print a usable key, `key` is used unchanged.
+fn transform_key<T>(creator: &T,
+                    hook: &Path,
+                    key: String,
+                    cwd: &Path,
+                    arguments: &[OsString],
+                    handle: &Handle)
+                    -> SFuture<String>
+    where T: CommandCreatorSync,
+{
+    let mut input = format!("{}\n{}\n", key, cwd.display());
+    for arg in arguments {
+        input.push_str(&arg.to_string_lossy());
+        input.push('\n');
+    }
+    let mut cmd = creator.clone().new_command_sync(hook);
+    cmd.current_dir(cwd);
+    let output = run_input_output(cmd, Some(input.into_bytes()));
+    let timeout = Duration::from_secs(KEY_HOOK_TIMEOUT_SECS);
+    let timeout = Timeout::new(timeout, handle).into_future().flatten()
+        .chain_err(|| "timeout error")
+        .and_then(|_| -> Result<process::Output> { bail!("timed out") });
+    let hook = hook.to_owned();
+    Box::new(output.select(timeout).then(move |res| -> Result<String> {
+        let new_key = res.map_err(|(e, _)| e).and_then(|(output, _)| {
+            let stdout = String::from_utf8(output.stdout).chain_err(|| "output is not UTF-8")?;
+            let new_key = stdout.trim();
+            if new_key.is_empty() || new_key.contains(char::is_whitespace) {
+                bail!("printed an invalid key: {:?}", stdout);
+            }
+            Ok(new_key.to_owned())
+        });
+        match new_key {
+            Ok(new_key) => {
+                let mut m = Digest::new();
+                m.update(new_key.as_bytes());
+                let hashed = m.finish();
+                trace!("Key hook transformed {} to {} ({})", key, new_key, hashed);
+                Ok(hashed)
+            }
+            Err(e) => {
+                warn!("Key hook {} failed, using the base key: {}", hook.display(), e);
+                Ok(key)
+            }
+        }
+    }))
+}
+
 /// Creates a future that will write `contents` to `path` inside of a temporary
 /// directory.
 ///
@@ -477,23 +757,23 @@ fn detect_compiler<T>(creator: &T, executable: &Path, pool: &CpuPool)
             if output.status.success() {
                 if let Ok(stdout) = String::from_utf8(output.stdout) {
                     if stdout.starts_with("rustc ") {
-                        return true;
+                        return Some(stdout.trim().to_owned());
                     }
                 }
             }
-            false
+            None
         }))
     } else {
-        f_ok(false)
+        f_ok(None)
     };
 
     let creator = creator.clone();
     let executable = executable.to_owned();
     let pool = pool.clone();
-    Box::new(is_rustc.and_then(move |is_rustc| {
-        if is_rustc {
+    Box::new(is_rustc.and_then(move |rustc_version| {
+        if let Some(version) = rustc_version {
             debug!("Found rustc");
-            Box::new(Rust::new(creator, executable, pool).map(|c| Some(Box::new(c) as Box<Compiler<T>>)))
+            Box::new(Rust::new(creator, executable, Some(version), pool).map(|c| Some(Box::new(c) as Box<Compiler<T>>)))
         } else {
             detect_c_compiler(creator, executable, pool)
         }
@@ -506,12 +786,16 @@ fn detect_c_compiler<T>(creator: T, executable: PathBuf, pool: CpuPool)
 {
     trace!("detect_c_compiler");
 
+    // Each compiler kind is followed by its version.
     let test = b"#if defined(_MSC_VER)
 msvc
+_MSC_FULL_VER
 #elif defined(__clang__)
 clang
+__clang_version__
 #elif defined(__GNUC__)
 gcc
+__VERSION__
 #endif
 ".to_vec();
     let write = write_temp_file(&pool, "testfile.c".as_ref(), test);
@@ -538,18 +822,22 @@ gcc
             Ok(s) => s,
             Err(_) => return f_err("Failed to parse output"),
         };
-        for line in stdout.lines() {
+        let mut lines = stdout.lines();
+        while let Some(line) = lines.next() {
             //TODO: do something smarter here.
             if line == "gcc" {
                 debug!("Found GCC");
-                return Box::new(CCompiler::new(GCC, executable, &pool)
+                let version = detected_version(&mut lines);
+                return Box::new(CCompiler::new(GCC, executable, version, &pool)
                                 .map(|c| Some(Box::new(c) as Box<Compiler<T>>)));
             } else if line == "clang" {
                 debug!("Found clang");
-                return Box::new(CCompiler::new(Clang, executable, &pool)
+                let version = detected_version(&mut lines);
+                return Box::new(CCompiler::new(Clang, executable, version, &pool)
                                 .map(|c| Some(Box::new(c) as Box<Compiler<T>>)));
             } else if line == "msvc" {
                 debug!("Found MSVC");
+                let version = detected_version(&mut lines);
                 let prefix = msvc::detect_showincludes_prefix(&creator,
                                                               executable.as_ref(),
                                                               &pool);
@@ -557,7 +845,7 @@ gcc
                     trace!("showIncludes prefix: '{}'", prefix);
                     CCompiler::new(MSVC {
                         includes_prefix: prefix,
-                    }, executable, &pool)
+                    }, executable, version, &pool)
                         .map(|c| Some(Box::new(c) as Box<Compiler<T>>))
                 }))
             }
@@ -567,6 +855,17 @@ gcc
     }))
 }
 
+/// Return the version that follows the compiler kind in the output of
+/// preprocessing the detection test file, skipping blank lines and line
+/// markers.
+fn detected_version<'a, I>(lines: I) -> Option<String>
+    where I: Iterator<Item=&'a str>
+{
+    lines.map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.trim_matches('"').to_owned())
+}
+
 /// If `executable` is a known compiler, return a `Box<Compiler>` containing information about it.
 pub fn get_compiler_info<T>(creator: &T, executable: &Path, pool: &CpuPool)
                             -> SFuture<Box<Compiler<T>>>
@@ -582,6 +881,40 @@ pub fn get_compiler_info<T>(creator: &T, executable: &Path, pool: &CpuPool)
     }))
 }
 
+/// Return a `Box<Compiler>` for `executable`, treating it as a C compiler of
+/// kind `kind` without running it to detect its kind or version.
+///
+/// `rustc` is still detected as usual.
+pub fn get_forced_compiler_info<T>(creator: &T, executable: &Path, kind: CCompilerKind, pool: &CpuPool)
+                                   -> SFuture<Box<Compiler<T>>>
+    where T: CommandCreatorSync
+{
+    let is_rustc = executable.file_stem()
+        .map_or(false, |f| f.to_string_lossy().to_lowercase() == "rustc");
+    if is_rustc {
+        return get_compiler_info(creator, executable, pool)
+    }
+    debug!("Treating {:?} as {:?}", executable, kind);
+    let executable = executable.to_owned();
+    match kind {
+        CCompilerKind::GCC => {
+            Box::new(CCompiler::new(GCC, executable, None, pool)
+                     .map(|c| Box::new(c) as Box<Compiler<T>>))
+        }
+        CCompilerKind::Clang => {
+            Box::new(CCompiler::new(Clang, executable, None, pool)
+                     .map(|c| Box::new(c) as Box<Compiler<T>>))
+        }
+        CCompilerKind::MSVC => {
+            let msvc = MSVC {
+                includes_prefix: msvc::DEFAULT_INCLUDES_PREFIX.to_owned(),
+            };
+            Box::new(CCompiler::new(msvc, executable, None, pool)
+                     .map(|c| Box::new(c) as Box<Compiler<T>>))
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -591,12 +924,14 @@ mod test {
     use futures_cpupool::CpuPool;
     use mock_command::*;
     use std::fs::{self,File};
-    use std::io::Write;
-    use std::sync::Arc;
+    use std::iter;
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
     use std::usize;
     use test::mock_storage::MockStorage;
     use test::utils::*;
+    use util::Digest;
     use tokio_core::reactor::Core;
 
     #[test]
@@ -619,6 +954,17 @@ mod test {
         assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_version() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "# 1 \"testfile.c\"\n\ngcc\n\n\"6.3.0 20170516\"\n", "")));
+        let c = detect_compiler(&creator, &f.bins[0], &pool).wait().unwrap().unwrap();
+        assert_eq!(CompilerKind::C(CCompilerKind::GCC), c.kind());
+        assert_eq!(Some("6.3.0 20170516"), c.version());
+    }
+
     #[test]
     fn test_detect_compiler_kind_msvc() {
         use env_logger;
@@ -689,6 +1035,156 @@ mod test {
         assert_eq!(CompilerKind::C(CCompilerKind::GCC), c.kind());
     }
 
+    #[test]
+    fn test_get_forced_compiler_info() {
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        let f = TestFixture::new();
+        // A probe would say this is GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_forced_compiler_info(&creator,
+                                         &f.bins[0],
+                                         CCompilerKind::Clang,
+                                         &pool).wait().unwrap();
+        assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
+        // The compiler wasn't run.
+        assert_eq!(1, creator.lock().unwrap().children.len());
+        // `-arch` takes a value in clang's argument table, but not GCC's.
+        let arguments = ovec!["-c", "foo.c", "-arch", "x86_64", "-o", "foo.o"];
+        match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(_) => {}
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        }
+        let detected = get_compiler_info(&creator, &f.bins[0], &pool).wait().unwrap();
+        assert_eq!(CompilerKind::C(CCompilerKind::GCC), detected.kind());
+        match detected.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::CannotCache("multiple input files") => {}
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        }
+    }
+
+    /// A mock compiler in a temporary directory, and a cache to store its
+    /// compiles in.
+    struct CompileFixture {
+        creator: Arc<Mutex<MockCommandCreator>>,
+        f: TestFixture,
+        pool: CpuPool,
+        core: Core,
+        storage: Arc<Storage>,
+    }
+
+    impl CompileFixture {
+        /// Create a fixture whose cache is a `DiskCache` in its directory.
+        fn new() -> CompileFixture {
+            use env_logger;
+            drop(env_logger::init());
+            let f = TestFixture::new();
+            let pool = CpuPool::new(1);
+            let storage = DiskCache::new(&f.tempdir.path().join("cache"),
+                                         usize::MAX,
+                                         &pool);
+            CompileFixture {
+                creator: new_creator(),
+                f: f,
+                pool: pool,
+                core: Core::new().unwrap(),
+                storage: Arc::new(storage),
+            }
+        }
+
+        /// Detect the fixture's compiler, pretending it's GCC.
+        fn gcc(&self) -> Box<Compiler<Arc<Mutex<MockCommandCreator>>>> {
+            self.detect("gcc")
+        }
+
+        /// Detect the fixture's compiler, which says it's `kind` when probed.
+        fn detect(&self, kind: &str) -> Box<Compiler<Arc<Mutex<MockCommandCreator>>>> {
+            next_command(&self.creator, Ok(MockChild::new(exit_status(0), kind, "")));
+            get_compiler_info(&self.creator, &self.f.bins[0], &self.pool).wait().unwrap()
+        }
+
+        /// Run `arguments` with `c` in the fixture's directory, looking them
+        /// up in and storing them to the fixture's cache.
+        fn compile(&self,
+                   c: &Box<Compiler<Arc<Mutex<MockCommandCreator>>>>,
+                   arguments: &[OsString],
+                   options: CompileOptions)
+                   -> (CompileResult, process::Output, CompileTimings) {
+            let hasher = match c.parse_arguments(arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            hasher.get_cached_or_compile(self.creator.clone(),
+                                         self.storage.clone(),
+                                         arguments.to_vec(),
+                                         self.f.tempdir.path().to_path_buf(),
+                                         vec![],
+                                         options,
+                                         self.pool.clone(),
+                                         self.core.handle()).wait().unwrap()
+        }
+
+        /// Find the key that `arguments` are cached under with `c`, running
+        /// the preprocessor invocation queued for it.
+        fn key(&self,
+               c: &Box<Compiler<Arc<Mutex<MockCommandCreator>>>>,
+               arguments: &[OsString])
+               -> String {
+            let hasher = match c.parse_arguments(arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            hasher.generate_hash_key(&self.creator,
+                                     self.f.tempdir.path(),
+                                     &[],
+                                     &self.storage,
+                                     &self.pool).wait().unwrap().key
+        }
+    }
+
+    /// Check that `cached` is a cache miss of type `miss_type`, and wait for
+    /// its result to be stored, so that later compiles don't race with it.
+    fn wait_for_miss(cached: CompileResult, miss_type: MissType) {
+        match cached {
+            CompileResult::CacheMiss(t, _, f) => {
+                assert_eq!(miss_type, t);
+                f.wait().unwrap();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+    }
+
+    /// Queue a compiler invocation that writes each of `outputs` and prints
+    /// `stderr`.
+    fn next_compile(creator: &Arc<Mutex<MockCommandCreator>>,
+                    outputs: Vec<(PathBuf, &'static [u8])>,
+                    stderr: &'static [u8]) {
+        next_command_calls(creator, move |_| {
+            for &(ref path, contents) in &outputs {
+                File::create(path).and_then(|mut f| f.write_all(contents))?;
+            }
+            Ok(MockChild::new(exit_status(0), "", stderr))
+        });
+    }
+
+    /// Read the object `name` from the entry stored under `key` in `storage`.
+    fn stored_object(storage: &Arc<Storage>, key: &str, name: &str) -> Vec<u8> {
+        match storage.get(key).wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut contents = vec![];
+                entry.get_object(name, &mut contents).unwrap();
+                contents
+            }
+            _ => panic!("Expected a cache entry for {}", key),
+        }
+    }
+
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut contents = vec![];
+        File::open(path).and_then(|mut f| f.read_to_end(&mut contents)).unwrap();
+        contents
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_uncached() {
         use env_logger;
@@ -729,14 +1225,14 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
-                                                         storage.clone(),
-                                                         arguments.clone(),
-                                                         cwd.to_path_buf(),
-                                                         vec![],
-                                                         CacheControl::Default,
-                                                         pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
+                                                            storage.clone(),
+                                                            arguments.clone(),
+                                                            cwd.to_path_buf(),
+                                                            vec![],
+                                                            CompileOptions::default(),
+                                                            pool.clone(),
+                                                            handle.clone()).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -754,14 +1250,14 @@ mod test {
         // The preprocessor invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
         // There should be no actual compiler invocation.
-        let (cached, res) = hasher2.get_cached_or_compile(creator.clone(),
-                                                          storage.clone(),
-                                                          arguments,
-                                                          cwd.to_path_buf(),
-                                                          vec![],
-                                                          CacheControl::Default,
-                                                          pool.clone(),
-                                                          handle).wait().unwrap();
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator.clone(),
+                                                             storage.clone(),
+                                                             arguments,
+                                                             cwd.to_path_buf(),
+                                                             vec![],
+                                                             CompileOptions::default(),
+                                                             pool.clone(),
+                                                             handle).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
@@ -770,6 +1266,153 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    /// Test that a compile whose stderr matches `nocache_stderr` is returned
+    /// but not stored, and one whose stderr doesn't is stored.
+    fn test_compiler_get_cached_or_compile_nocache_stderr() {
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let obj = t.f.tempdir.path().join("foo.o");
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let options = || CompileOptions {
+            nocache_stderr: Some(Regex::new("fallback stdlib").unwrap()),
+            .. Default::default()
+        };
+        // The compile succeeds, but warns about its output.
+        const MATCHING : &'static [u8] = b"warning: using fallback stdlib";
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_compile(&t.creator, vec![(obj.clone(), &b"file contents"[..])], MATCHING);
+        let (cached, res, _) = t.compile(&c, &arguments, options());
+        assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
+        assert_eq!(CompileResult::NotCacheable, cached);
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(MATCHING, res.stderr.as_slice());
+        assert_eq!(Some(0), t.storage.current_size());
+        // A warning that doesn't match is stored as usual.
+        const OTHER : &'static [u8] = b"warning: unused variable";
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_compile(&t.creator, vec![(obj.clone(), &b"file contents"[..])], OTHER);
+        let (cached, res, _) = t.compile(&c, &arguments, options());
+        wait_for_miss(cached, MissType::Normal);
+        assert_eq!(OTHER, res.stderr.as_slice());
+        assert_neq!(Some(0), t.storage.current_size());
+    }
+
+    #[test]
+    fn test_compiler_contents_affect_hash_key() {
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let storage: Arc<Storage> = Arc::new(MockStorage::new());
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let keys: Vec<String> = ["compiler one", "compiler two"].iter().map(|contents| {
+            let bin = mk_bin_contents(f.tempdir.path(), contents, |mut f| {
+                f.write_all(contents.as_bytes())
+            }).unwrap();
+            // Pretend to be GCC.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+            let c = get_compiler_info(&creator, &bin, &pool).wait().unwrap();
+            let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            };
+            // The preprocessor invocation.
+            next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            hasher.generate_hash_key(&creator, f.tempdir.path(), &[], &storage, &pool).wait().unwrap().key
+        }).collect();
+        assert_neq!(keys[0], keys[1]);
+    }
+
+    #[test]
+    /// Test that direct mode finds the key that preprocessing does, without
+    /// running the preprocessor, until an included header changes.
+    fn test_compiler_direct_mode_key() {
+        let t = CompileFixture::new();
+        let (creator, pool, storage) = (&t.creator, &t.pool, &t.storage);
+        let c = t.gcc();
+        let cwd = t.f.tempdir.path();
+        File::create(cwd.join("foo.c"))
+            .and_then(|mut f| f.write_all(b"#include \"foo.h\"\nint x = FOO;\n")).unwrap();
+        File::create(cwd.join("foo.h"))
+            .and_then(|mut f| f.write_all(b"#define FOO 1\n")).unwrap();
+        let preprocessed = |value: &str| {
+            format!("# 1 \"foo.c\"\n# 1 \"{}\" 1\n# 2 \"foo.c\" 2\nint x = {};\n",
+                    cwd.join("foo.h").display(), value)
+        };
+        let hasher = |arguments: &[OsString]| {
+            match c.parse_arguments(arguments, ".".as_ref()) {
+                CompilerArguments::Ok(h) => h,
+                o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+            }
+        };
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let direct = vec![(OsString::from("SCCACHE_DIRECT_MODE"), OsString::from("1"))];
+        let hash = |arguments: &[OsString], env_vars: &[(OsString, OsString)], storage: &Arc<Storage>| {
+            hasher(arguments).generate_hash_key(creator, cwd, env_vars, storage, pool).wait().unwrap().key
+        };
+        // Preprocess mode.
+        next_command(creator, Ok(MockChild::new(exit_status(0), preprocessed("1"), "")));
+        let key = hash(&arguments, &[], storage);
+        // The first compile in direct mode is preprocessed, and gets the same key.
+        next_command(creator, Ok(MockChild::new(exit_status(0), preprocessed("1"), "")));
+        assert_eq!(key, hash(&arguments, &direct, storage));
+        assert!(creator.lock().unwrap().children.is_empty());
+        // The second isn't, and still gets the same key.
+        assert_eq!(key, hash(&arguments, &direct, storage));
+        // The manifest is in the cache, so a new server finds it too.
+        let reopened: Arc<Storage> = Arc::new(DiskCache::new(&cwd.join("cache"), usize::MAX, pool));
+        assert_eq!(key, hash(&arguments, &direct, &reopened));
+        // Changing the header means preprocessing again.
+        File::create(cwd.join("foo.h"))
+            .and_then(|mut f| f.write_all(b"#define FOO 2\n")).unwrap();
+        next_command(creator, Ok(MockChild::new(exit_status(0), preprocessed("2"), "")));
+        let changed = hash(&arguments, &direct, storage);
+        assert!(creator.lock().unwrap().children.is_empty());
+        assert_neq!(key, changed);
+        assert_eq!(changed, hash(&arguments, &direct, storage));
+        // Output without line markers doesn't list the headers, so no
+        // manifest is stored for it, and every compile is preprocessed.
+        File::create(cwd.join("bar.c"))
+            .and_then(|mut f| f.write_all(b"#include \"foo.h\"\nint x = FOO;\n")).unwrap();
+        let arguments = ovec!["-c", "bar.c", "-o", "bar.o"];
+        for _ in 0..2 {
+            next_command(creator, Ok(MockChild::new(exit_status(0), "int x = 2;\n", "")));
+            hash(&arguments, &direct, storage);
+            assert!(creator.lock().unwrap().children.is_empty());
+        }
+        // Neither does `-P`, so it isn't used in direct mode.
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o", "-P"];
+        for _ in 0..2 {
+            next_command(creator, Ok(MockChild::new(exit_status(0), preprocessed("2"), "")));
+            hash(&arguments, &direct, storage);
+            assert!(creator.lock().unwrap().children.is_empty());
+        }
+    }
+
+    #[test]
+    /// Test that a compile isn't stored if the compiler binary changed
+    /// between preprocessing and compiling.
+    fn test_compiler_changed_during_compile() {
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        // The preprocessor invocation, after which the compiler is replaced.
+        let bin = t.f.bins[0].clone();
+        next_command_calls(&t.creator, move |_| {
+            File::create(&bin)
+                .and_then(|mut f| f.write_all(b"a different compiler"))
+                .map(|_| MockChild::new(exit_status(0), "preprocessor output", ""))
+        });
+        // The compiler invocation.
+        next_compile(&t.creator, vec![(t.f.tempdir.path().join("foo.o"), &b"file contents"[..])], b"");
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let (cached, res, _) = t.compile(&c, &arguments, CompileOptions::default());
+        // The compile still succeeds, but the mismatch is caught and its
+        // result isn't stored.
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(CompileResult::NotCacheable, cached);
+        assert_eq!(Some(0), t.storage.current_size());
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_cached() {
         use env_logger;
@@ -810,14 +1453,14 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
-                                                         storage.clone(),
-                                                         arguments.clone(),
-                                                         cwd.to_path_buf(),
-                                                         vec![],
-                                                         CacheControl::Default,
-                                                         pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+        let (cached, res, timings) = hasher.get_cached_or_compile(creator.clone(),
+                                                                  storage.clone(),
+                                                                  arguments.clone(),
+                                                                  cwd.to_path_buf(),
+                                                                  vec![],
+                                                                  CompileOptions::default(),
+                                                                  pool.clone(),
+                                                                  handle.clone()).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -827,6 +1470,8 @@ mod test {
             }
             _ => assert!(false, "Unexpected compile result: {:?}", cached),
         }
+        assert!(timings.cache_read.is_some());
+        assert!(timings.compile.is_some());
 
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
@@ -836,22 +1481,325 @@ mod test {
         // The preprocessor invocation.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
         // There should be no actual compiler invocation.
-        let (cached, res) = hasher2.get_cached_or_compile(creator,
-                                                          storage,
-                                                          arguments,
-                                                          cwd.to_path_buf(),
-                                                          vec![],
-                                                          CacheControl::Default,
-                                                          pool,
-                                                          handle).wait().unwrap();
+        let (cached, res, timings) = hasher2.get_cached_or_compile(creator,
+                                                                   storage,
+                                                                   arguments,
+                                                                   cwd.to_path_buf(),
+                                                                   vec![],
+                                                                   CompileOptions::default(),
+                                                                   pool,
+                                                                   handle).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert!(timings.cache_read.is_some());
+        assert_eq!(None, timings.compile);
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    /// Test that preprocessor output too large to keep in memory is hashed in
+    /// full, and given to the compiler unchanged.
+    fn test_compiler_get_cached_or_compile_large_preprocessor_output() {
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        // 50 MB of preprocessor output, more than is kept in memory.
+        let line = b"int a_rather_long_variable_name_to_pad_out_the_line;\n";
+        let big = line.iter().cloned().cycle().take(50 * 1024 * 1024).collect::<Vec<_>>();
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), &big, "")));
+        let key = t.key(&c, &arguments);
+        // Changing only the last byte changes the key.
+        let mut changed = big.clone();
+        *changed.last_mut().unwrap() = b' ';
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), &changed, "")));
+        assert_neq!(key, t.key(&c, &arguments));
+        drop(changed);
+        // The compiler reads the output from a file rather than from stdin.
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), &big, "")));
+        let o = t.f.tempdir.path().join("foo.o");
+        next_command_calls(&t.creator, move |args| {
+            assert_eq!(ovec!["-c", "-x", "cpp-output"], &args[..3]);
+            assert_eq!(ovec!["-o", "foo.o"], &args[4..]);
+            let mut contents = vec![];
+            File::open(&args[3]).and_then(|mut f| f.read_to_end(&mut contents))?;
+            assert!(contents == big, "compiler input differs from preprocessor output");
+            File::create(&o).and_then(|mut f| f.write_all(b"file contents"))?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let (cached, res, _) = t.compile(&c, &arguments, CompileOptions::default());
+        assert_eq!(exit_status(0), res.status);
+        wait_for_miss(cached, MissType::Normal);
+        assert!(t.creator.lock().unwrap().children.is_empty());
+        assert_eq!(b"file contents", stored_object(&t.storage, &key, "obj").as_slice());
+    }
+
+    #[test]
+    /// Test that equivalent spellings of an include directory get the same
+    /// key, and other directories don't.
+    fn test_compiler_include_spellings_key() {
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let key = |arguments: Vec<OsString>| {
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            t.key(&c, &arguments)
+        };
+        let base = key(ovec!["-c", "foo.c", "-I", "include", "-o", "foo.o"]);
+        assert_eq!(base, key(ovec!["-c", "foo.c", "-Iinclude", "-o", "foo.o"]));
+        assert_eq!(base, key(ovec!["-c", "foo.c", "-I./include/", "-o", "foo.o"]));
+        assert_neq!(base, key(ovec!["-c", "foo.c", "-I", "other", "-o", "foo.o"]));
+    }
+
+    #[test]
+    /// Test that a compile that writes a dependency file stores it in the
+    /// cache entry, and that a hit restores it without running the compiler.
+    fn test_compiler_get_cached_or_compile_depfile() {
+        const DEPS: &'static [u8] = b"foo.o: foo.c foo.h";
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let arguments = ovec!["-c", "foo.c", "-MMD", "-o", "foo.o"];
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let key = t.key(&c, &arguments);
+        // The preprocessor invocation, then the compiler invocation, which
+        // writes the object and the depfile.
+        let dep = t.f.tempdir.path().join("foo.d");
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_compile(&t.creator,
+                     vec![(t.f.tempdir.path().join("foo.o"), &b"file contents"[..]),
+                          (dep.clone(), DEPS)],
+                     b"");
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        wait_for_miss(cached, MissType::Normal);
+        assert_eq!(DEPS, stored_object(&t.storage, &key, "dep").as_slice());
+        // A hit writes the depfile back without running the compiler.
+        fs::remove_file(&dep).unwrap();
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert!(t.creator.lock().unwrap().children.is_empty());
+        assert_eq!(DEPS, read_file(&dep).as_slice());
+    }
+
+    #[test]
+    /// Test that the compilation database entry from a compile with `-MJ` is
+    /// stored in the cache entry, and recreated on a hit.
+    fn test_compiler_get_cached_or_compile_compilation_db() {
+        // The entry clang writes for the preprocessor...
+        const ENTRY: &'static [u8] = b"{ \"directory\": \"/src\", \"file\": \"foo.c\", \"arguments\": [\"clang\", \"-xc\", \"foo.c\", \"-E\"]},\n";
+        // ...and the one for the compile it's turned into.
+        const COMPILE_ENTRY: &'static [u8] = b"{\"arguments\":[\"clang\",\"-xc\",\"foo.c\",\"-c\",\"-o\",\"foo.o\"],\"directory\":\"/src\",\"file\":\"foo.c\",\"output\":\"foo.o\"},\n";
+        let t = CompileFixture::new();
+        let c = t.detect("clang");
+        let arguments = ovec!["-c", "foo.c", "-MJ", "foo.o.json", "-o", "foo.o"];
+        let mj = t.f.tempdir.path().join("foo.o.json");
+        // The preprocessor invocations, which write the entry.
+        let preprocess = || {
+            let m = mj.clone();
+            next_command_calls(&t.creator, move |_| {
+                File::create(&m).and_then(|mut f| f.write_all(ENTRY))
+                    .map(|_| MockChild::new(exit_status(0), "preprocessor output", ""))
+            });
+        };
+        preprocess();
+        let key = t.key(&c, &arguments);
+        preprocess();
+        next_compile(&t.creator, vec![(t.f.tempdir.path().join("foo.o"), &b"file contents"[..])], b"");
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        wait_for_miss(cached, MissType::Normal);
+        assert_eq!(COMPILE_ENTRY, read_file(&mj).as_slice());
+        assert_eq!(COMPILE_ENTRY, stored_object(&t.storage, &key, "mj").as_slice());
+        // On a hit the preprocessor doesn't write the entry, so it can only
+        // come from the cache.
+        fs::remove_file(&mj).unwrap();
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(COMPILE_ENTRY, read_file(&mj).as_slice());
+    }
+
+    #[test]
+    /// Test that LTO flags are part of the key, and that a fat LTO object
+    /// round-trips through the cache unchanged.
+    fn test_compiler_get_cached_or_compile_fat_lto() {
+        // An object holding both machine code and LTO bitcode.
+        const OBJECT: &'static [u8] = b"\x7fELF machine code\0.gnu.lto_main bitcode";
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let key = |arguments: &[OsString]| {
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            t.key(&c, arguments)
+        };
+        let arguments = ovec!["-c", "foo.c", "-flto", "-ffat-lto-objects", "-o", "foo.o"];
+        let fat_key = key(&arguments);
+        assert_neq!(fat_key, key(&ovec!["-c", "foo.c", "-o", "foo.o"]));
+        assert_neq!(fat_key, key(&ovec!["-c", "foo.c", "-flto", "-o", "foo.o"]));
+        assert_neq!(fat_key, key(&ovec!["-c", "foo.c", "-flto=thin", "-o", "foo.o"]));
+        // The preprocessor invocation, then the compiler invocation.
+        let obj = t.f.tempdir.path().join("foo.o");
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_compile(&t.creator, vec![(obj.clone(), OBJECT)], b"");
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        wait_for_miss(cached, MissType::Normal);
+        assert_eq!(OBJECT, stored_object(&t.storage, &fat_key, "obj").as_slice());
+        // A hit writes back the object byte for byte.
+        fs::remove_file(&obj).unwrap();
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(OBJECT, read_file(&obj).as_slice());
+    }
+
+    #[test]
+    /// Test that the `.dwo` from a `-gsplit-dwarf` compile without `-o` is
+    /// stored in the cache entry and restored on a hit.
+    fn test_compiler_get_cached_or_compile_split_dwarf() {
+        const DEBUG_INFO: &'static [u8] = b"debug info";
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let arguments = ovec!["-gsplit-dwarf", "-c", "foo.c"];
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let key = t.key(&c, &arguments);
+        // The preprocessor invocation, then the compiler invocation, which
+        // writes the object and the `.dwo` under their default names.
+        let dwo = t.f.tempdir.path().join("foo.dwo");
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        next_compile(&t.creator,
+                     vec![(t.f.tempdir.path().join("foo.o"), &b"object"[..]), (dwo.clone(), DEBUG_INFO)],
+                     b"");
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        wait_for_miss(cached, MissType::Normal);
+        assert_eq!(DEBUG_INFO, stored_object(&t.storage, &key, "dwo").as_slice());
+        // A hit writes the `.dwo` back.
+        fs::remove_file(&dwo).unwrap();
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(DEBUG_INFO, read_file(&dwo).as_slice());
+    }
+
+    #[test]
+    /// Test that color codes a compile wasn't asked for are stripped from the
+    /// stderr that's stored, and requested ones are kept, so that a hit
+    /// replays what a miss printed.
+    fn test_compiler_get_cached_or_compile_color_diagnostics() {
+        const COLORED: &'static [u8] = b"\x1b[01m\x1b[Kfoo.c:1:1:\x1b[m\x1b[K \x1b[01;35m\x1b[Kwarning: \x1b[m\x1b[Kfoo\n";
+        const PLAIN: &'static [u8] = b"foo.c:1:1: warning: foo\n";
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let obj = t.f.tempdir.path().join("foo.o");
+        for &(flag, expected) in [("-fdiagnostics-color=auto", PLAIN),
+                                  ("-fdiagnostics-color=always", COLORED)].iter() {
+            let arguments = ovec![flag, "-c", "foo.c", "-o", "foo.o"];
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            let key = t.key(&c, &arguments);
+            // The preprocessor invocation, then the compiler invocation,
+            // which prints color codes either way.
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            next_compile(&t.creator, vec![(obj.clone(), &b"object"[..])], COLORED);
+            let (cached, miss, _) = t.compile(&c, &arguments, CompileOptions::default());
+            wait_for_miss(cached, MissType::Normal);
+            assert_eq!(expected, miss.stderr.as_slice());
+            assert_eq!(expected, stored_object(&t.storage, &key, "stderr").as_slice());
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            let (cached, hit, _) = t.compile(&c, &arguments, CompileOptions::default());
+            assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+            assert_eq!(miss.stderr, hit.stderr);
+        }
+    }
+
+    #[test]
+    /// Test that `-MD` and `-MMD` compiles of the same source, which have the
+    /// same preprocessor output but different depfiles, get separate cache
+    /// entries that each hold their own depfile.
+    fn test_compiler_get_cached_or_compile_depfile_modes() {
+        const MD_DEPS: &'static [u8] = b"foo.o: foo.c /usr/include/stdio.h foo.h";
+        const MMD_DEPS: &'static [u8] = b"foo.o: foo.c foo.h";
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let obj = t.f.tempdir.path().join("foo.o");
+        let dep = t.f.tempdir.path().join("foo.d");
+        let mut keys = vec![];
+        for &(flag, deps) in [("-MD", MD_DEPS), ("-MMD", MMD_DEPS)].iter() {
+            let arguments = ovec!["-c", "foo.c", flag, "-o", "foo.o"];
+            // The preprocessor output is the same either way.
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            let key = t.key(&c, &arguments);
+            // Compile, writing the depfile that the flag would.
+            next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+            next_compile(&t.creator, vec![(obj.clone(), &b"file contents"[..]), (dep.clone(), deps)], b"");
+            let (cached, _, _) = t.compile(&c, &arguments, CompileOptions::default());
+            wait_for_miss(cached, MissType::Normal);
+            keys.push((key, deps));
+        }
+        assert_neq!(keys[0].0, keys[1].0);
+        for &(ref key, deps) in &keys {
+            assert_eq!(deps, stored_object(&t.storage, key, "dep").as_slice());
+        }
+    }
+
+    fn hash_str(s: &str) -> String {
+        let mut m = Digest::new();
+        m.update(s.as_bytes());
+        m.finish()
+    }
+
+    #[test]
+    /// Test that a key hook's output is used as the cache key.
+    fn test_compiler_get_cached_or_compile_key_hook() {
+        let t = CompileFixture::new();
+        let c = t.gcc();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        // Find out the base key.
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        let key = t.key(&c, &arguments);
+        let hook_key = format!("myproject-{}", key);
+        // The preprocessor invocation.
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+        // The key hook, which prefixes the key.
+        next_command(&t.creator, Ok(MockChild::new(exit_status(0), format!("{}\n", hook_key), "")));
+        // The compiler invocation.
+        next_compile(&t.creator, vec![(t.f.tempdir.path().join("foo.o"), &b"file contents"[..])], b"");
+        let options = CompileOptions {
+            key_hook: Some(PathBuf::from("hook")),
+            .. Default::default()
+        };
+        let (cached, _, _) = t.compile(&c, &arguments, options);
+        wait_for_miss(cached, MissType::Normal);
+        // The entry was stored under the transformed key, and not the base key.
+        assert_eq!(b"file contents", stored_object(&t.storage, &hash_str(&hook_key), "obj").as_slice());
+        match t.storage.get(&key).wait().unwrap() {
+            Cache::Miss => {}
+            _ => panic!("Expected a cache miss for the base key"),
+        }
+    }
+
+    #[test]
+    /// Test that the base key is used if the key hook fails.
+    fn test_transform_key_fallback() {
+        let creator = new_creator();
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let cwd = Path::new("/");
+        let hook = Path::new("hook");
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "prefix-abcd", "")));
+        assert_eq!(hash_str("prefix-abcd"), transform_key(&creator, hook, "abcd".to_owned(), cwd, &[], &handle).wait().unwrap());
+        // Keys that would be unsafe as file names are hashed like any other.
+        for printed in &["a", "../../etc/passwd", "ключ"] {
+            next_command(&creator, Ok(MockChild::new(exit_status(0), *printed, "")));
+            let new_key = transform_key(&creator, hook, "abcd".to_owned(), cwd, &[], &handle).wait().unwrap();
+            assert_eq!(hash_str(printed), new_key);
+            assert!(new_key.len() >= 3 && new_key.chars().all(|c| c.is_digit(16)));
+        }
+        next_command(&creator, Ok(MockChild::new(exit_status(1), "prefix-abcd", "")));
+        assert_eq!("abcd", transform_key(&creator, hook, "abcd".to_owned(), cwd, &[], &handle).wait().unwrap());
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
+        assert_eq!("abcd", transform_key(&creator, hook, "abcd".to_owned(), cwd, &[], &handle).wait().unwrap());
+        next_command(&creator, Ok(MockChild::with_error(io::Error::new(io::ErrorKind::NotFound, "no such file"))));
+        assert_eq!("abcd", transform_key(&creator, hook, "abcd".to_owned(), cwd, &[], &handle).wait().unwrap());
+    }
+
     #[test]
     /// Test that a cache read that results in an error is treated as a cache
     /// miss.
@@ -893,14 +1841,14 @@ mod test {
         };
         // The cache will return an error.
         storage.next_get(f_err("Some Error"));
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
-                                                         storage.clone(),
-                                                         arguments.clone(),
-                                                         cwd.to_path_buf(),
-                                                         vec![],
-                                                         CacheControl::Default,
-                                                         pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
+                                                            storage.clone(),
+                                                            arguments.clone(),
+                                                            cwd.to_path_buf(),
+                                                            vec![],
+                                                            CompileOptions::default(),
+                                                            pool.clone(),
+                                                            handle.clone()).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -960,14 +1908,14 @@ mod test {
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
         let hasher2 = hasher.clone();
-        let (cached, res) = hasher.get_cached_or_compile(creator.clone(),
-                                                         storage.clone(),
-                                                         arguments.clone(),
-                                                         cwd.to_path_buf(),
-                                                         vec![],
-                                                         CacheControl::Default,
-                                                         pool.clone(),
-                                                         handle.clone()).wait().unwrap();
+        let (cached, res, _) = hasher.get_cached_or_compile(creator.clone(),
+                                                            storage.clone(),
+                                                            arguments.clone(),
+                                                            cwd.to_path_buf(),
+                                                            vec![],
+                                                            CompileOptions::default(),
+                                                            pool.clone(),
+                                                            handle.clone()).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -982,14 +1930,17 @@ mod test {
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
         // Now compile again, but force recaching.
         fs::remove_file(&obj).unwrap();
-        let (cached, res) = hasher2.get_cached_or_compile(creator,
-                                                          storage,
-                                                          arguments,
-                                                          cwd.to_path_buf(),
-                                                          vec![],
-                                                          CacheControl::ForceRecache,
-                                                          pool,
-                                                          handle).wait().unwrap();
+        let (cached, res, _) = hasher2.get_cached_or_compile(creator,
+                                                             storage,
+                                                             arguments,
+                                                             cwd.to_path_buf(),
+                                                             vec![],
+                                                             CompileOptions {
+                                                                 cache_control: CacheControl::ForceRecache,
+                                                                 .. Default::default()
+                                                             },
+                                                             pool,
+                                                             handle).wait().unwrap();
         // Ensure that the object file was created.
         assert_eq!(true, fs::metadata(&obj).and_then(|m| Ok(m.len() > 0)).unwrap());
         match cached {
@@ -1031,18 +1982,112 @@ mod test {
             CompilerArguments::Ok(h) => h,
             o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
         };
-        let (cached, res) = hasher.get_cached_or_compile(creator,
-                                                         storage,
-                                                         arguments,
-                                                         cwd.to_path_buf(),
-                                                         vec![],
-                                                         CacheControl::Default,
-                                                         pool,
-                                                         handle).wait().unwrap();
+        let (cached, res, _) = hasher.get_cached_or_compile(creator,
+                                                            storage,
+                                                            arguments,
+                                                            cwd.to_path_buf(),
+                                                            vec![],
+                                                            CompileOptions::default(),
+                                                            pool,
+                                                            handle).wait().unwrap();
         assert_eq!(cached, CompileResult::Error);
         assert_eq!(exit_status(1), res.status);
         // Shouldn't get anything on stdout, since that would just be preprocessor spew!
         assert_eq!(b"", res.stdout.as_slice());
         assert_eq!(PREPROCESSOR_STDERR, res.stderr.as_slice());
     }
+
+    #[test]
+    fn test_cache_control_from_recache() {
+        let cwd = Path::new("/build");
+        let input = Some(Path::new("src/foo.c"));
+        let from = |v: Option<&str>, input: Option<&Path>| {
+            CacheControl::from_recache(None, v.map(OsStr::new), cwd, input)
+        };
+        // `SCCACHE_RECACHE` forces every compile, whatever its value.
+        for recache in &["1", "", "yes", "true", "0"] {
+            assert_eq!(CacheControl::ForceRecache,
+                       CacheControl::from_recache(Some(OsStr::new(recache)), None, cwd, None));
+        }
+        assert_eq!(CacheControl::Default, from(None, input));
+        // `SCCACHE_RECACHE_PATHS` only forces compiles of matching inputs.
+        assert_eq!(CacheControl::ForceRecache, from(Some("src/foo.c"), input));
+        assert_eq!(CacheControl::ForceRecache, from(Some("/build/src/*.c"), input));
+        assert_eq!(CacheControl::ForceRecache, from(Some("s?c/f*"), input));
+        assert_eq!(CacheControl::ForceRecache, from(Some("*"), input));
+        assert_eq!(CacheControl::Default, from(Some("foo.c"), input));
+        assert_eq!(CacheControl::Default, from(Some("src/*.cpp"), input));
+        assert_eq!(CacheControl::Default, from(Some("src/foo.c"), Some(Path::new("foo.c"))));
+        assert_eq!(CacheControl::Default, from(Some("*"), None));
+        // Several patterns are separated like `PATH`.
+        let list = env::join_paths(&["lib/*.c", "src/foo.c"]).unwrap();
+        assert_eq!(CacheControl::ForceRecache,
+                   CacheControl::from_recache(None, Some(&list), cwd, input));
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        let matches = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            glob_matches(&pattern, &name)
+        };
+        assert!(matches("", ""));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("*.c", "src/foo.c"));
+        assert!(matches("a?c**", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(!matches("*.c", "foo.cc"));
+        assert!(!matches("", "a"));
+        // This would take exponential time with a backtracking matcher.
+        let name: String = iter::repeat('a').take(100).collect();
+        assert!(!matches("*a*a*a*a*a*a*a*a*a*a*b", &name));
+    }
+
+    #[test]
+    fn test_restore_output_concurrent() {
+        use std::io::Cursor;
+        use std::thread;
+
+        let f = TestFixture::new();
+        let path = f.tempdir.path().join("foo.o");
+        let data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut Cursor::new(data.clone()), None).unwrap();
+        let entry = Arc::new(entry.finish().unwrap());
+        let writers: Vec<_> = (0..4).map(|_| {
+            let entry = entry.clone();
+            let path = path.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    let mut read = CacheRead::from(Cursor::new((*entry).clone())).unwrap();
+                    restore_output(&mut read, "obj", &path).unwrap();
+                }
+            })
+        }).collect();
+        // Whenever the output exists, it should be complete.
+        for _ in 0..100 {
+            let mut contents = vec!();
+            match File::open(&path) {
+                Ok(mut file) => {
+                    file.read_to_end(&mut contents).unwrap();
+                    assert_eq!(data.len(), contents.len());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => panic!("Failed to open output: {}", e),
+            }
+        }
+        for w in writers {
+            w.join().unwrap();
+        }
+        let mut contents = vec!();
+        File::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents == data);
+        // No temporary files should be left behind.
+        let leftovers = fs::read_dir(f.tempdir.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(0, leftovers);
+    }
 }
@@ -16,21 +16,24 @@ use ::compiler::{
     Cacheable,
     CompilerArguments,
 };
-use compiler::c::{CCompilerImpl, CCompilerKind, ParsedArguments};
+use compiler::c::{CCompilerImpl, CCompilerKind, ParsedArguments, PREPROCESSOR_SPILL_SIZE};
+use config;
 use log::LogLevel::Trace;
 use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
 use mock_command::{
     CommandCreatorSync,
     RunCommand,
+    exit_status,
 };
+use serde_json;
 use std::collections::HashMap;
-use std::io::Read;
-use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::process;
-use util::{run_input_output, OsStrExt};
+use util::{run_input_output, run_input_output_spilling, OsStrExt, SpillOutput, Stdout};
 
 use errors::*;
 
@@ -54,7 +57,7 @@ impl CCompilerImpl for GCC {
                      cwd: &Path,
                      env_vars: &[(OsString, OsString)],
                      pool: &CpuPool)
-                     -> SFuture<process::Output> where T: CommandCreatorSync
+                     -> SFuture<SpillOutput> where T: CommandCreatorSync
     {
         preprocess(creator, executable, parsed_args, cwd, env_vars, pool)
     }
@@ -62,7 +65,7 @@ impl CCompilerImpl for GCC {
     fn compile<T>(&self,
                   creator: &T,
                   executable: &Path,
-                  preprocessor_result: process::Output,
+                  preprocessor_result: SpillOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
                   env_vars: &[(OsString, OsString)],
@@ -78,7 +81,7 @@ impl CCompilerImpl for GCC {
 pub const ARGS_WITH_VALUE: &'static [&'static str] = &[
     "--param", "-A", "-D", "-F", "-G", "-I", "-L",
     "-U", "-V", "-Xassembler", "-Xlinker",
-    "-Xpreprocessor", "-aux-info", "-b", "-idirafter",
+    "-Xpreprocessor", "-aux-info", "-b", "-framework", "-idirafter",
     "-iframework", "-imacros", "-imultilib", "-include",
     "-install_name", "-iprefix", "-iquote", "-isysroot",
     "-isystem", "-iwithprefix", "-iwithprefixbefore",
@@ -107,12 +110,16 @@ pub fn parse_arguments<F: Fn(&str) -> bool>(arguments: &[OsString],
                                             cwd: &Path,
                                             argument_takes_value: F)
                                             -> CompilerArguments<ParsedArguments> {
-    _parse_arguments(arguments, cwd, &argument_takes_value)
+    _parse_arguments(arguments, cwd, &argument_takes_value, config::CONFIG.allow_pgo)
 }
 
+/// Like `parse_arguments`, but `allow_pgo` says whether to cache
+/// compilations that use profile data. Those are only cached if the
+/// profile data can be found, to hash into the key.
 fn _parse_arguments(arguments: &[OsString],
                     cwd: &Path,
-                    argument_takes_value: &Fn(&str) -> bool) -> CompilerArguments<ParsedArguments> {
+                    argument_takes_value: &Fn(&str) -> bool,
+                    allow_pgo: bool) -> CompilerArguments<ParsedArguments> {
     let mut output_arg = None;
     let mut input_arg = None;
     let mut dep_target = None;
@@ -121,7 +128,16 @@ fn _parse_arguments(arguments: &[OsString],
     let mut compilation = false;
     let mut split_dwarf = false;
     let mut need_explicit_dep_target = false;
+    let mut dep_file = None;
+    let mut write_dep_file = false;
+    let mut preprocessed = false;
     let mut force_input_type = None;
+    let mut extra_hash_files = vec!();
+    let mut profile_use = None;
+    let mut thin_link_bitcode = None;
+    let mut dump_dir = None;
+    let mut dump_base = false;
+    let mut compilation_db_file = None;
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
@@ -135,10 +151,50 @@ fn _parse_arguments(arguments: &[OsString],
             match s {
                 "-c" => compilation = true,
                 "-o" => output_arg = it.next(),
-                "-gsplit-dwarf" => {
+                "-gsplit-dwarf" | "-gsplit-dwarf=split" => {
                     split_dwarf = true;
                     common_args.push(arg.clone());
                 }
+                // Clang's single-file split DWARF stays in the object.
+                "-gsplit-dwarf=single" => {
+                    split_dwarf = false;
+                    common_args.push(arg.clone());
+                }
+                // GCC writes auxiliary outputs like the `.dwo` under the
+                // `-dumpdir` prefix instead of next to the object.
+                "-dumpdir" => {
+                    common_args.push(arg.clone());
+                    if let Some(arg_val) = it.next() {
+                        dump_dir = Some(arg_val.clone());
+                        common_args.push(arg_val);
+                    }
+                }
+                "-dumpbase" => {
+                    dump_base = true;
+                    common_args.push(arg.clone());
+                    if let Some(arg_val) = it.next() {
+                        common_args.push(arg_val);
+                    }
+                }
+                // If a precompiled header exists for an `-include`d header
+                // the compiler will use it instead, and the preprocessor
+                // output won't reflect its contents, so hash it separately.
+                "-include" => {
+                    common_args.push(arg.clone());
+                    if let Some(arg_val) = it.next() {
+                        if let Some(pch) = find_pch(cwd, &arg_val) {
+                            extra_hash_files.push(pch);
+                        }
+                        common_args.push(arg_val);
+                    }
+                }
+                "-include-pch" => {
+                    common_args.push(arg.clone());
+                    if let Some(arg_val) = it.next() {
+                        extra_hash_files.push(PathBuf::from(&arg_val));
+                        common_args.push(arg_val);
+                    }
+                }
                 // Arguments that take a value.
                 // -MF and -MQ are in this set but are handled separately
                 // because they are also preprocessor options.
@@ -153,13 +209,28 @@ fn _parse_arguments(arguments: &[OsString],
                 // also need to give this to the preprocessor.
                 "-x" => {
                     let arg_val = it.next().unwrap();
+                    // Can't cache C++ header units, they produce a module
+                    // interface rather than an object file.
+                    match arg_val.to_str() {
+                        Some("c++-system-header") |
+                        Some("c++-user-header") => {
+                            return CompilerArguments::CannotCache("header unit")
+                        }
+                        _ => {}
+                    }
                     // the extension/input_type will handle putting this on
                     // the compile command, so put this into preprocessor_args only
                     preprocessor_args.push(arg.clone());
                     preprocessor_args.push(arg_val.clone());
                     force_input_type = Some(arg_val.to_str().unwrap().to_owned());
                 },
-                "-MF" |
+                "-MF" => {
+                    preprocessor_args.push(arg.clone());
+                    if let Some(arg_val) = it.next() {
+                        dep_file = Some(arg_val.clone());
+                        preprocessor_args.push(arg_val);
+                    }
+                }
                 "-MQ" => {
                     preprocessor_args.push(arg.clone());
                     if let Some(arg_val) = it.next() {
@@ -167,13 +238,78 @@ fn _parse_arguments(arguments: &[OsString],
                     }
                 }
                 "-MT" => dep_target = it.next(),
+                // Clang writes a compilation database entry for the compile
+                // to the file given with -MJ. Only the preprocessor sees the
+                // original input, so it writes the entry, and the compile
+                // rewrites it to describe itself. See
+                // `fix_compilation_db_entry`.
+                "-MJ" => {
+                    preprocessor_args.push(arg.clone());
+                    if let Some(arg_val) = it.next() {
+                        compilation_db_file = Some(arg_val.clone());
+                        preprocessor_args.push(arg_val);
+                    }
+                }
+                v if v.starts_with("-MJ") => {
+                    compilation_db_file = Some(OsString::from(&v["-MJ".len()..]));
+                    preprocessor_args.push(arg.clone());
+                }
+                // Options for the preprocessor only. Handle the dependency
+                // options in them as if they were passed to the driver, and
+                // pass the rest to the preprocessor.
+                v if v.starts_with("-Wp,") => {
+                    let (dep_args, rest) = split_wp_args(&v["-Wp,".len()..]);
+                    if !rest.is_empty() {
+                        preprocessor_args.push(format!("-Wp,{}", rest.join(",")).into());
+                    }
+                    it.stack.extend(dep_args.into_iter().rev());
+                }
+                // Options for the assembler, which change the object, so
+                // they're hashed like other common arguments. Listings and
+                // dependency files are outputs we don't store, though.
+                v if v.starts_with("-Wa,") => {
+                    if v["-Wa,".len()..].split(',').any(|a| a.starts_with("-a") || a.starts_with("--MD")) {
+                        return CompilerArguments::CannotCache("assembler listing or dependency file");
+                    }
+                    common_args.push(arg.clone());
+                }
+                // The build system already preprocessed the input.
+                "-fpreprocessed" => {
+                    preprocessed = true;
+                    common_args.push(arg.clone());
+                }
                 // Can't cache Clang modules.
                 "-fcxx-modules" => return CompilerArguments::CannotCache("clang modules"),
                 "-fmodules" => return CompilerArguments::CannotCache("clang modules"),
                 // Can't cache -fsyntax-only, it doesn't produce any output.
                 "-fsyntax-only" => return CompilerArguments::CannotCache("-fsyntax-only"),
-                // Can't cache PGO profiled output.
-                "-fprofile-use" => return CompilerArguments::CannotCache("pgo"),
+                // The profile data used for PGO isn't part of the
+                // preprocessor output, so only cache PGO builds if asked
+                // to, and then hash the profile data.
+                "-fprofile-use" | "-fprofile-instr-use" => {
+                    if !allow_pgo {
+                        return CompilerArguments::CannotCache("pgo");
+                    }
+                    profile_use = Some(None);
+                    common_args.push(arg.clone());
+                }
+                v if v.starts_with("-fprofile-use=") || v.starts_with("-fprofile-instr-use=") => {
+                    if !allow_pgo {
+                        return CompilerArguments::CannotCache("pgo");
+                    }
+                    let path = &v[v.find('=').unwrap() + 1..];
+                    profile_use = Some(Some(PathBuf::from(path)));
+                    common_args.push(arg.clone());
+                }
+                // `-flto`, `-flto=thin` and `-ffat-lto-objects` only change
+                // what goes in the object, so they're hashed and passed on
+                // as common arguments. Clang can also write the summary
+                // ThinLTO's thin link reads to a separate file, which we
+                // store alongside the object.
+                v if v.starts_with("-fthin-link-bitcode=") => {
+                    thin_link_bitcode = Some(PathBuf::from(&v["-fthin-link-bitcode=".len()..]));
+                    common_args.push(arg.clone());
+                }
                 // We already expanded `@` files we could through
                 // `ExpandIncludeFile` above, so if one of those arguments now
                 // makes it this far we won't understand it.
@@ -186,6 +322,11 @@ fn _parse_arguments(arguments: &[OsString],
                     // need -MT on the preprocessor command line, whether it's
                     // been passed already or not
                     need_explicit_dep_target = true;
+                    // -MD and -MMD write a dependency file as a side effect
+                    // of compilation, which we store alongside the object.
+                    if s == "-MD" || s == "-MMD" {
+                        write_dep_file = true;
+                    }
                     preprocessor_args.push(arg.clone());
                 }
                 _ => handled = false,
@@ -209,10 +350,20 @@ fn _parse_arguments(arguments: &[OsString],
         }
     }
 
+    // Building a precompiled header doesn't need `-c`, so treat a header
+    // input with a `.gch` or `.pch` output as compilation too.
+    let pch_build = force_input_type.as_ref().map_or(false, |t| is_header_language(t)) &&
+        output_arg.as_ref().and_then(|o| Path::new(o).extension()).map_or(false, |e| {
+            e == "gch" || e == "pch"
+        });
     // We only support compilation.
-    if !compilation {
+    if !compilation && !pch_build {
         return CompilerArguments::NotCompilation;
     }
+    // `-dumpbase` renames the `.dwo` in ways that depend on the GCC version.
+    if split_dwarf && dump_base {
+        return CompilerArguments::CannotCache("-gsplit-dwarf with -dumpbase");
+    }
     let (input, extension) = match input_arg {
         Some(i) => {
             // When compiling from the preprocessed output given as stdin, we need
@@ -222,7 +373,15 @@ fn _parse_arguments(arguments: &[OsString],
                 (i.to_owned(), input_type.clone())
             } else {
                 match Path::new(&i).extension().and_then(|e| e.to_str()) {
+                    // Treat sources compiled with -fpreprocessed like `.i`
+                    // and `.ii` files, so we don't preprocess them again.
+                    Some("c") if preprocessed => (i.to_owned(), "i".to_owned()),
+                    Some("cc") | Some("cpp") | Some("cxx") | Some("c++") if preprocessed => (i.to_owned(), "ii".to_owned()),
                     Some(e @ "c") | Some(e @ "cc") | Some(e @ "cpp") | Some(e @ "cxx") | Some(e @ "c++") => (i.to_owned(), e.to_owned()),
+                    // Objective-C and Objective-C++.
+                    Some(e @ "m") | Some(e @ "mm") => (i.to_owned(), e.to_owned()),
+                    // Assembly and already-preprocessed sources.
+                    Some(e @ "s") | Some(e @ "S") | Some(e @ "i") | Some(e @ "ii") => (i.to_owned(), e.to_owned()),
                     e => {
                         trace!("Unknown source extension: {}", e.unwrap_or("(None)"));
                         return CompilerArguments::CannotCache("unknown source extension");
@@ -233,19 +392,67 @@ fn _parse_arguments(arguments: &[OsString],
         // We can't cache compilation without an input.
         None => return CompilerArguments::CannotCache("no input file"),
     };
+    // Without `-o`, the object is written to the current directory, named
+    // after the input with its extension replaced by `.o`.
+    let output_arg = output_arg.or_else(|| {
+        Path::new(&input).file_name().map(|name| {
+            Path::new(name).with_extension("o").into_os_string()
+        })
+    });
     let mut outputs = HashMap::new();
+    let mut depfile = None;
     match output_arg {
         // We can't cache compilation that doesn't go to a file
         None => return CompilerArguments::CannotCache("no output file"),
         Some(o) => {
             if split_dwarf {
+                // The `.dwo` is named after the object, even a defaulted
+                // one, and written next to it unless `-dumpdir` is given.
                 let dwo = Path::new(&o).with_extension("dwo");
+                let dwo = match dump_dir {
+                    Some(ref dir) => {
+                        let mut path = dir.clone();
+                        path.push(dwo.file_name().unwrap_or(dwo.as_os_str()));
+                        PathBuf::from(path)
+                    }
+                    None => dwo,
+                };
                 outputs.insert("dwo", dwo);
             }
+            if let Some(bc) = thin_link_bitcode {
+                outputs.insert("thinlink", bc);
+            }
+            if let Some(mj) = compilation_db_file {
+                outputs.insert("mj", PathBuf::from(mj));
+            }
             if need_explicit_dep_target {
                 preprocessor_args.push("-MT".into());
                 preprocessor_args.push(dep_target.unwrap_or(o.clone()));
             }
+            if write_dep_file {
+                // Without -MF the dependency file is named after the object,
+                // but the preprocessor doesn't see -o, so tell it explicitly.
+                let d = match dep_file {
+                    Some(d) => PathBuf::from(d),
+                    None => {
+                        let d = Path::new(&o).with_extension("d");
+                        preprocessor_args.push("-MF".into());
+                        preprocessor_args.push(d.clone().into());
+                        d
+                    }
+                };
+                outputs.insert("dep", d.clone());
+                depfile = Some(d);
+            }
+            if let Some(profile) = profile_use {
+                // Without the profile data there's nothing to tell a
+                // compile that used one profile from another.
+                let profile_data = find_profile_data(cwd, profile, Path::new(&o));
+                if profile_data.is_empty() {
+                    return CompilerArguments::CannotCache("pgo profile not found");
+                }
+                extra_hash_files.extend(profile_data);
+            }
             outputs.insert("obj", PathBuf::from(o));
         }
     }
@@ -253,24 +460,88 @@ fn _parse_arguments(arguments: &[OsString],
     CompilerArguments::Ok(ParsedArguments {
         input: input.into(),
         extension: extension,
-        depfile: None,
+        depfile: depfile,
         outputs: outputs,
         preprocessor_args: preprocessor_args,
         common_args: common_args,
         msvc_show_includes: false,
+        extra_hash_files: extra_hash_files,
     })
 }
 
+/// Return the profile data files that exist for a compilation producing
+/// `output` with `-fprofile-use`, given `profile`, the path passed as
+/// `-fprofile-use=<path>`, if any.
+///
+/// A path may name a profile file, or a directory in which GCC looks for
+/// a `.gcda` file named after the object. Without a path, GCC looks for
+/// a `.gcda` file next to the object and clang uses `default.profdata`.
+fn find_profile_data(cwd: &Path, profile: Option<PathBuf>, output: &Path) -> Vec<PathBuf> {
+    let gcda = output.with_extension("gcda");
+    let candidates = match profile {
+        Some(ref dir) if cwd.join(dir).is_dir() => {
+            gcda.file_name().map(|name| vec![dir.join(name)]).unwrap_or_default()
+        }
+        Some(path) => vec![path],
+        None => vec![gcda, PathBuf::from("default.profdata")],
+    };
+    candidates.into_iter().filter(|p| cwd.join(p).is_file()).collect()
+}
+
+/// Return true if `language`, an argument to `-x`, is a header language,
+/// as used to build precompiled headers.
+pub fn is_header_language(language: &str) -> bool {
+    language == "c-header" || language == "c++-header"
+}
+
+/// Return the path of a precompiled header that would be used in place of
+/// `header`, if one exists.
+fn find_pch(cwd: &Path, header: &OsStr) -> Option<PathBuf> {
+    for ext in &[".gch", ".pch"] {
+        let mut pch = header.to_os_string();
+        pch.push(ext);
+        if cwd.join(&pch).is_file() {
+            return Some(PathBuf::from(pch));
+        }
+    }
+    None
+}
+
+/// Return true if an input with `extension`, which may also be a language
+/// given with `-x`, needs to be run through the preprocessor.
+fn needs_preprocessing(extension: &str) -> bool {
+    match extension {
+        "s" | "i" | "ii" | "assembler" | "cpp-output" | "c++-cpp-output" => false,
+        _ => true,
+    }
+}
+
 pub fn preprocess<T>(creator: &T,
                      executable: &Path,
                      parsed_args: &ParsedArguments,
                      cwd: &Path,
                      env_vars: &[(OsString, OsString)],
-                     _pool: &CpuPool)
-                     -> SFuture<process::Output>
+                     pool: &CpuPool)
+                     -> SFuture<SpillOutput>
     where T: CommandCreatorSync
 {
     trace!("preprocess");
+    if !needs_preprocessing(&parsed_args.extension) {
+        // Use the contents of the input in place of preprocessor output,
+        // so it gets hashed and fed to the compiler the same way.
+        let input = cwd.join(&parsed_args.input);
+        return Box::new(pool.spawn_fn(move || -> Result<_> {
+            let mut bytes = vec!();
+            File::open(&input)
+                .and_then(|mut f| f.read_to_end(&mut bytes))
+                .chain_err(|| format!("failed to read input file {:?}", input))?;
+            Ok(SpillOutput {
+                status: exit_status(0),
+                stdout: Stdout::Memory(bytes),
+                stderr: vec!(),
+            })
+        }))
+    }
     let mut cmd = creator.clone().new_command_sync(executable);
     cmd.arg("-E")
         .args(&parsed_args.preprocessor_args)
@@ -282,17 +553,20 @@ pub fn preprocess<T>(creator: &T,
     if log_enabled!(Trace) {
         trace!("preprocess: {:?}", cmd);
     }
-    run_input_output(cmd, None)
+    // Large output is compiled from a file, named after the input it holds
+    // the preprocessed source of.
+    let name = parsed_args.input.file_name().map_or(Path::new("input"), Path::new);
+    run_input_output_spilling(cmd, None, name, PREPROCESSOR_SPILL_SIZE, pool)
 }
 
-fn compile<T>(creator: &T,
-              executable: &Path,
-              preprocessor_result: process::Output,
-              parsed_args: &ParsedArguments,
-              cwd: &Path,
-              env_vars: &[(OsString, OsString)],
-              _pool: &CpuPool)
-              -> SFuture<(Cacheable, process::Output)>
+pub fn compile<T>(creator: &T,
+                  executable: &Path,
+                  preprocessor_result: SpillOutput,
+                  parsed_args: &ParsedArguments,
+                  cwd: &Path,
+                  env_vars: &[(OsString, OsString)],
+                  pool: &CpuPool)
+                  -> SFuture<(Cacheable, process::Output)>
     where T: CommandCreatorSync
 {
     trace!("compile - {:?} (extension {})", parsed_args.input, parsed_args.extension);
@@ -304,24 +578,215 @@ fn compile<T>(creator: &T,
         }
     };
 
+    let (language, input) = match parsed_args.extension.as_ref() {
+        "c" | "i" | "cpp-output" => ("cpp-output", Some(preprocessor_result.stdout)),
+        "c++" | "cc" | "cpp" | "cxx" | "ii" | "c++-cpp-output" => {
+            ("c++-cpp-output", Some(preprocessor_result.stdout))
+        }
+        "m" | "objective-c" => ("objective-c-cpp-output", Some(preprocessor_result.stdout)),
+        "mm" | "objective-c++" => ("objective-c++-cpp-output", Some(preprocessor_result.stdout)),
+        // `.S` files have been preprocessed by now.
+        "s" | "S" | "assembler" | "assembler-with-cpp" => ("assembler", Some(preprocessor_result.stdout)),
+        // Precompiled headers have to be built from the original header,
+        // since the preprocessor output has lost its macro definitions.
+        e if is_header_language(e) => (e, None),
+        e => {
+            error!("gcc::compile: Got an unexpected file extension {}", e);
+            return future::err("Unexpected file extension".into()).boxed()
+        }
+    };
+    let remote = dist_compile(executable, language, &input, parsed_args, cwd, output, pool);
+
     let mut cmd = creator.clone().new_command_sync(executable);
-    cmd.args(&["-c", "-x"])
-        .arg(match parsed_args.extension.as_ref() {
-            "c" => "cpp-output",
-            "c++" | "cc" | "cpp" | "cxx" => "c++-cpp-output",
-            e => {
-                error!("gcc::compile: Got an unexpected file extension {}", e);
-                return future::err("Unexpected file extension".into()).boxed()
-            }
-        })
-        .args(&["-", "-o"]).arg(&output)
+    cmd.args(&["-c", "-x"]).arg(language);
+    // Output small enough to keep in memory is fed to the compiler on stdin,
+    // larger output is compiled from the file it was written to.
+    let (input, tempdir) = match input {
+        Some(Stdout::Memory(data)) => {
+            cmd.arg("-");
+            (Some(data), None)
+        }
+        Some(Stdout::File(dir, path)) => {
+            cmd.arg(&path);
+            (None, Some(dir))
+        }
+        None => {
+            cmd.arg(&parsed_args.input);
+            (None, None)
+        }
+    };
+    cmd.arg("-o").arg(&output)
         .args(&parsed_args.common_args)
         .env_clear()
         .envs(env_vars.iter().map(|&(ref k, ref v)| (k, v)))
         .current_dir(cwd);
-    Box::new(run_input_output(cmd, Some(preprocessor_result.stdout)).map(|output| {
-        (Cacheable::Yes, output)
-    }))
+    let compile_locally = move || -> SFuture<(Cacheable, process::Output)> {
+        Box::new(run_input_output(cmd, input).map(move |output| {
+            // Keep the preprocessor output around until the compiler's done.
+            drop(tempdir);
+            (Cacheable::Yes, output)
+        }))
+    };
+    let result: SFuture<(Cacheable, process::Output)> = match remote {
+        Some(remote) => {
+            let out_pretty = parsed_args.output_pretty().into_owned();
+            Box::new(remote.map(|output| (Cacheable::Yes, output)).or_else(move |e| {
+                warn!("[{}]: dist compile failed, compiling locally: {}", out_pretty, e);
+                compile_locally()
+            }))
+        }
+        None => compile_locally(),
+    };
+    match parsed_args.outputs.get("mj") {
+        Some(mj) => {
+            let mj = cwd.join(mj);
+            let output = output.clone();
+            let pool = pool.clone();
+            Box::new(result.and_then(move |(cacheable, result)| -> SFuture<_> {
+                if !result.status.success() {
+                    return f_ok((cacheable, result))
+                }
+                Box::new(pool.spawn_fn(move || -> Result<_> {
+                    fix_compilation_db_entry(&mj, &output)?;
+                    Ok((cacheable, result))
+                }))
+            }))
+        }
+        None => result,
+    }
+}
+
+/// Rewrite the compilation database entry that clang wrote to `path` with
+/// `-MJ` while preprocessing, so that it describes the compile to `output`
+/// instead: `-E` becomes `-c`, and the output is added, as clang would have
+/// written them had it compiled the original input.
+fn fix_compilation_db_entry(path: &Path, output: &Path) -> Result<()> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("failed to read compilation database entry {:?}", path))?;
+    // Each entry is followed by a comma, so that they can be joined into an
+    // array.
+    let entry = contents.trim_right().trim_right_matches(',');
+    let mut entry: serde_json::Value = serde_json::from_str(entry)
+        .chain_err(|| format!("failed to parse compilation database entry {:?}", path))?;
+    let output = output.to_string_lossy().into_owned();
+    match entry.as_object_mut() {
+        Some(entry) => {
+            if let Some(arguments) = entry.get_mut("arguments").and_then(|a| a.as_array_mut()) {
+                for arg in arguments.iter_mut() {
+                    if arg.as_str() == Some("-E") {
+                        *arg = serde_json::Value::String("-c".to_owned());
+                    }
+                }
+                arguments.push(serde_json::Value::String("-o".to_owned()));
+                arguments.push(serde_json::Value::String(output.clone()));
+            }
+            entry.insert("output".to_owned(), serde_json::Value::String(output));
+        }
+        None => bail!("compilation database entry {:?} isn't an object", path),
+    }
+    let mut contents = serde_json::to_vec(&entry).chain_err(|| "failed to serialize compilation database entry")?;
+    contents.extend_from_slice(b",\n");
+    File::create(path)
+        .and_then(|mut f| f.write_all(&contents))
+        .chain_err(|| format!("failed to write compilation database entry {:?}", path))
+}
+
+/// Start compiling on a dist worker, if `SCCACHE_DIST_SCHEDULER` and
+/// `SCCACHE_DIST_TOKEN` are set and the compile can run there.
+#[cfg(feature = "dist")]
+fn dist_compile(executable: &Path,
+                language: &str,
+                input: &Option<Stdout>,
+                parsed_args: &ParsedArguments,
+                cwd: &Path,
+                output: &Path,
+                pool: &CpuPool)
+                -> Option<SFuture<process::Output>> {
+    use dist::{self, CompileRequest};
+
+    let (scheduler, token) = match (&config::CONFIG.dist_scheduler, &config::CONFIG.dist_token) {
+        (&Some(ref scheduler), &Some(ref token)) => (scheduler.clone(), token.clone()),
+        (&Some(_), &None) => {
+            debug!("not compiling remotely: SCCACHE_DIST_TOKEN isn't set");
+            return None
+        }
+        _ => return None,
+    };
+    // Workers only get the preprocessed source, so they can't run compiles
+    // that read other files or write more than the object.
+    if parsed_args.outputs.len() != 1 || !parsed_args.extra_hash_files.is_empty() {
+        return None
+    }
+    // Output too large to keep in memory is too large to send.
+    let source = match *input {
+        Some(Stdout::Memory(ref source)) => source.clone(),
+        _ => return None,
+    };
+    // Workers refuse compilers and options that could do more than compile.
+    let compiler = match executable.file_name().and_then(|name| name.to_str()) {
+        Some(name) if dist::allowed_compiler(name) => name.to_owned(),
+        _ => return None,
+    };
+    let arguments = parsed_args.common_args.iter()
+        .map(|arg| arg.to_str().and_then(|arg| {
+            if dist::allowed_argument(arg) { Some(arg.to_owned()) } else { None }
+        }))
+        .collect::<Option<Vec<_>>>();
+    let arguments = match arguments {
+        Some(arguments) => arguments,
+        None => return None,
+    };
+    let request = CompileRequest {
+        compiler: compiler,
+        language: language.to_owned(),
+        arguments: arguments,
+        source: source,
+    };
+    Some(dist::compile(pool, scheduler, token, request, cwd.join(output)))
+}
+
+#[cfg(not(feature = "dist"))]
+fn dist_compile(_executable: &Path,
+                _language: &str,
+                _input: &Option<Stdout>,
+                _parsed_args: &ParsedArguments,
+                _cwd: &Path,
+                _output: &Path,
+                _pool: &CpuPool)
+                -> Option<SFuture<process::Output>> {
+    None
+}
+
+/// Split the comma-separated options `args` passed with `-Wp,` into the
+/// driver options equivalent to the dependency options among them, and the
+/// rest. The preprocessor's `-MD <file>` and `-MMD <file>` are the driver's
+/// `-MD -MF <file>` and `-MMD -MF <file>`.
+fn split_wp_args(args: &str) -> (Vec<OsString>, Vec<&str>) {
+    let mut dep_args = vec![];
+    let mut rest = vec![];
+    let mut it = args.split(',');
+    while let Some(arg) = it.next() {
+        match arg {
+            "-MD" | "-MMD" | "-MF" | "-MT" | "-MQ" => {
+                let value = match it.next() {
+                    Some(value) => value,
+                    None => {
+                        rest.push(arg);
+                        break
+                    }
+                };
+                dep_args.push(arg.into());
+                if arg == "-MD" || arg == "-MMD" {
+                    dep_args.push("-MF".into());
+                }
+                dep_args.push(value.into());
+            }
+            _ => rest.push(arg),
+        }
+    }
+    (dep_args, rest)
 }
 
 struct ExpandIncludeFile<'a> {
@@ -360,9 +825,7 @@ impl<'a> Iterator for ExpandIncludeFile<'a> {
             //     recursively.
             //
             // So here we interpret any I/O errors as "just return this
-            // argument". Currently we don't implement handling of arguments
-            // with quotes, so if those are encountered we just pass the option
-            // through literally anyway.
+            // argument".
             //
             // At this time we interpret all `@` arguments above as non
             // cacheable, so if we fail to interpret this we'll just call the
@@ -377,23 +840,75 @@ impl<'a> Iterator for ExpandIncludeFile<'a> {
                 debug!("failed to read @-file `{}`: {}", file.display(), e);
                 return Some(arg)
             }
-            if contents.contains('"') || contents.contains('\'') {
-                return Some(arg)
+            let new_args = split_response_file(&contents);
+            self.stack.extend(new_args.into_iter().rev().map(|s| s.into()));
+        }
+    }
+}
+
+/// Expand the `@file` arguments in `arguments`, relative to `cwd`, the same
+/// way `parse_arguments` does. Files that can't be read are left as-is.
+pub fn expand_response_files(arguments: &[OsString], cwd: &Path) -> Vec<OsString> {
+    ExpandIncludeFile {
+        stack: arguments.iter().rev().map(|a| a.to_owned()).collect(),
+        cwd: cwd,
+    }.collect()
+}
+
+/// Split the contents of an `@file` into arguments, following the quoting
+/// rules described in `ExpandIncludeFile::next`.
+fn split_response_file(contents: &str) -> Vec<String> {
+    let mut args = vec!();
+    let mut arg = String::new();
+    // Whether we're inside an argument, which may be empty if it's quoted.
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                in_arg = true;
+                if let Some(c) = chars.next() {
+                    arg.push(c);
+                }
+            }
+            c if Some(c) == quote => quote = None,
+            c if quote.is_some() => arg.push(c),
+            '\'' | '"' => {
+                in_arg = true;
+                quote = Some(c);
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(arg);
+                    arg = String::new();
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                arg.push(c);
             }
-            let new_args = contents.split_whitespace().collect::<Vec<_>>();
-            self.stack.extend(new_args.iter().rev().map(|s| s.into()));
         }
     }
+    if in_arg {
+        args.push(arg);
+    }
+    args
 }
 
 #[cfg(test)]
 mod test {
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Write;
 
     use super::*;
     use ::compiler::*;
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use mock_command::*;
     use tempdir::TempDir;
+    use test::utils::*;
 
     fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
         let args = arguments.iter().map(OsString::from).collect::<Vec<_>>();
@@ -411,6 +926,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -437,6 +953,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -454,6 +971,154 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_wp_deps() {
+        let wp = _parse_arguments(&stringvec!["-c", "foo.c", "-Wp,-MD,foo.d", "-Wp,-MT,foo.o", "-o", "foo.o"]);
+        let direct = _parse_arguments(&stringvec!["-c", "foo.c", "-MD", "-MF", "foo.d", "-MT", "foo.o", "-o", "foo.o"]);
+        assert_eq!(direct, wp);
+        match wp {
+            CompilerArguments::Ok(args) => {
+                assert_map_contains!(args.outputs, ("dep", PathBuf::from("foo.d")));
+                assert_eq!(Some(PathBuf::from("foo.d")), args.depfile);
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+        // Other preprocessor options stay bundled, for the preprocessor only.
+        let ParsedArguments { preprocessor_args, common_args, .. } =
+            match _parse_arguments(&stringvec!["-c", "foo.c", "-Wp,-MMD,foo.d,-D_FORTIFY_SOURCE=2", "-Wa,--noexecstack"]) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+        assert_eq!(ovec!["-Wp,-D_FORTIFY_SOURCE=2", "-MMD", "-MF", "foo.d", "-MT", "foo.o"], preprocessor_args);
+        assert_eq!(ovec!["-Wa,--noexecstack"], common_args);
+        assert_eq!(CompilerArguments::CannotCache("assembler listing or dependency file"),
+                   _parse_arguments(&stringvec!["-c", "foo.c", "-Wa,-adhln=foo.lst"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_split_dwarf_default_output() {
+        // Without `-o`, both files are named after the input, in the
+        // current directory.
+        let args = stringvec!["-gsplit-dwarf", "-c", "src/foo.cpp"];
+        let outputs = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args.outputs,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs,
+                             ("obj", PathBuf::from("foo.o")),
+                             ("dwo", PathBuf::from("foo.dwo")));
+        assert_eq!(2, outputs.len());
+        // The `.dwo` goes next to the object.
+        let args = stringvec!["-gsplit-dwarf=split", "-c", "foo.cpp", "-o", "out/bar.o"];
+        let outputs = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args.outputs,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs,
+                             ("obj", PathBuf::from("out/bar.o")),
+                             ("dwo", PathBuf::from("out/bar.dwo")));
+        // Unless `-dumpdir` puts it elsewhere.
+        let args = stringvec!["-gsplit-dwarf", "-dumpdir", "dwo/", "-c", "foo.cpp", "-o", "out/bar.o"];
+        let outputs = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args.outputs,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs,
+                             ("obj", PathBuf::from("out/bar.o")),
+                             ("dwo", PathBuf::from("dwo/bar.dwo")));
+        // Single-file split DWARF doesn't write a `.dwo`.
+        let args = stringvec!["-gsplit-dwarf=single", "-c", "foo.cpp"];
+        let outputs = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args.outputs,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(1, outputs.len());
+        assert_eq!(CompilerArguments::CannotCache("-gsplit-dwarf with -dumpbase"),
+                   _parse_arguments(&stringvec!["-gsplit-dwarf", "-dumpbase", "x", "-c", "foo.cpp"]));
+    }
+
+    #[test]
+    fn test_debug_info_args_affect_hash_key() {
+        use compiler::c::hash_key;
+        // Debug info is generated by the compiler rather than the
+        // preprocessor, so these only reach the key through `common_args`.
+        const PREPROCESSED: &'static [u8] = b"hello world";
+        let keys: Vec<String> = ["-g", "-g0", "-g1", "-g3", "-ggdb"].iter().map(|flag| {
+            let args = stringvec!["-c", "foo.c", "-o", "foo.o", *flag];
+            match _parse_arguments(&args) {
+                CompilerArguments::Ok(parsed) => {
+                    assert_eq!(ovec![*flag], parsed.common_args);
+                    hash_key("abcd", &parsed.common_args, &[], &[], PREPROCESSED)
+                }
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }).collect();
+        let plain = hash_key("abcd", &[], &[], &[], PREPROCESSED);
+        for (i, key) in keys.iter().enumerate() {
+            assert_neq!(plain, *key);
+            for other in &keys[i + 1..] {
+                assert_neq!(key, other);
+            }
+        }
+    }
+
+    /// Return the key for compiling `args`, when the preprocessor output
+    /// is the same.
+    fn hash_key_for(args: &[&str]) -> String {
+        use compiler::c::{hash_key, hashed_args};
+        let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        match _parse_arguments(&args) {
+            CompilerArguments::Ok(parsed) => {
+                hash_key("abcd", &hashed_args(&parsed), &[], &[], b"hello world")
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_codegen_args_affect_hash_key() {
+        // These only apply when compiling, not preprocessing.
+        let variants: &[&[&str]] = &[&[],
+                                     &["-O0"],
+                                     &["-O2"],
+                                     &["-fPIC"],
+                                     &["-ffast-math"],
+                                     &["-O2", "-fPIC", "-ffast-math"],
+                                     &["-x", "c++"]];
+        let keys: Vec<String> = variants.iter().map(|extra| {
+            let args: Vec<&str> = extra.iter().chain(&["-c", "foo.c", "-o", "foo.o"]).cloned().collect();
+            hash_key_for(&args)
+        }).collect();
+        for (i, key) in keys.iter().enumerate() {
+            for other in &keys[i + 1..] {
+                assert_neq!(key, other);
+            }
+        }
+        // The same flags give the same key.
+        assert_eq!(keys[3], hash_key_for(&["-fPIC", "-c", "foo.c", "-o", "foo.o"]));
+    }
+
+    #[test]
+    fn test_depfile_args_affect_hash_key() {
+        // These give the same preprocessor output, but different depfiles.
+        let variants: &[&[&str]] = &[&["-MD", "-o", "foo.o"],
+                                     &["-MD", "-o", "bar.o"],
+                                     &["-MD", "-MT", "foo", "-o", "foo.o"],
+                                     &["-MD", "-MQ", "$(foo)", "-o", "foo.o"],
+                                     &["-MD", "-MP", "-o", "foo.o"],
+                                     &["-MD", "-MF", "foo.dep", "-o", "foo.o"]];
+        let keys: Vec<String> = variants.iter().map(|extra| {
+            let args: Vec<&str> = ["-c", "foo.c"].iter().chain(extra.iter()).cloned().collect();
+            hash_key_for(&args)
+        }).collect();
+        for (i, key) in keys.iter().enumerate() {
+            for other in &keys[i + 1..] {
+                assert_neq!(key, other);
+            }
+        }
+    }
+
     #[test]
     fn test_parse_arguments_extra() {
         let args = stringvec!["-c", "foo.cc", "-fabc", "-o", "foo.o", "-mxyz"];
@@ -465,6 +1130,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -491,6 +1157,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -517,6 +1184,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -532,6 +1200,26 @@ mod test {
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_compilation_db() {
+        for args in vec![stringvec!["-c", "foo.c", "-MJ", "foo.o.json", "-o", "foo.o"],
+                         stringvec!["-c", "foo.c", "-MJfoo.o.json", "-o", "foo.o"]] {
+            let ParsedArguments { outputs, preprocessor_args, common_args, .. } =
+                match _parse_arguments(&args) {
+                    CompilerArguments::Ok(args) => args,
+                    o @ _ => panic!("Got unexpected parse result: {:?}", o),
+                };
+            assert_map_contains!(outputs,
+                                 ("obj", PathBuf::from("foo.o")),
+                                 ("mj", PathBuf::from("foo.o.json")));
+            assert_eq!(2, outputs.len());
+            // Only the preprocessor writes the entry.
+            assert_eq!(args[2..args.len() - 2].iter().map(OsString::from).collect::<Vec<_>>(),
+                       preprocessor_args);
+            assert!(common_args.is_empty());
+        }
+    }
+
     #[test]
     fn test_parse_arguments_explicit_dep_target() {
         let args = stringvec!["-c", "foo.c", "-MT", "depfile", "-fabc", "-MF", "file", "-o", "foo.o"];
@@ -543,6 +1231,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -569,6 +1258,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -576,9 +1266,11 @@ mod test {
         assert!(true, "Parsed ok");
         assert_eq!(Some("foo.c"), input.to_str());
         assert_eq!("c", extension);
-        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_map_contains!(outputs,
+                             ("obj", PathBuf::from("foo.o")),
+                             ("dep", PathBuf::from("file")));
         //TODO: fix assert_map_contains to assert no extra keys!
-        assert_eq!(1, outputs.len());
+        assert_eq!(2, outputs.len());
         assert_eq!(ovec!["-MF", "file", "-MD", "-MT", "depfile"], preprocessor_args);
         assert_eq!(ovec!["-fabc"], common_args);
         assert!(!msvc_show_includes);
@@ -595,6 +1287,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -602,14 +1295,36 @@ mod test {
         assert!(true, "Parsed ok");
         assert_eq!(Some("foo.c"), input.to_str());
         assert_eq!("c", extension);
-        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_map_contains!(outputs,
+                             ("obj", PathBuf::from("foo.o")),
+                             ("dep", PathBuf::from("file")));
         //TODO: fix assert_map_contains to assert no extra keys!
-        assert_eq!(1, outputs.len());
+        assert_eq!(2, outputs.len());
         assert_eq!(ovec!["-MF", "file", "-MD", "-MT", "foo.o"], preprocessor_args);
         assert_eq!(ovec!["-fabc"], common_args);
         assert!(!msvc_show_includes);
     }
 
+    #[test]
+    fn test_parse_arguments_default_depfile() {
+        let args = stringvec!["-c", "foo.c", "-MMD", "-o", "out/foo.o"];
+        let ParsedArguments {
+            depfile,
+            outputs,
+            preprocessor_args,
+            ..
+        } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some(PathBuf::from("out/foo.d")), depfile);
+        assert_map_contains!(outputs,
+                             ("obj", PathBuf::from("out/foo.o")),
+                             ("dep", PathBuf::from("out/foo.d")));
+        assert_eq!(2, outputs.len());
+        assert_eq!(ovec!["-MMD", "-MT", "out/foo.o", "-MF", "out/foo.d"], preprocessor_args);
+    }
+
     #[test]
     fn test_parse_arguments_empty_args() {
         assert_eq!(CompilerArguments::NotCompilation,
@@ -636,10 +1351,300 @@ mod test {
                    _parse_arguments(&stringvec!["-c", "foo.c", "-fmodules", "-o", "foo.o"]));
     }
 
+    #[test]
+    fn test_parse_arguments_pch_build() {
+        let args = stringvec!["-x", "c++-header", "foo.h", "-o", "foo.h.gch"];
+        let ParsedArguments {
+            input,
+            extension,
+            depfile: _,
+            outputs,
+            preprocessor_args,
+            msvc_show_includes,
+            common_args,
+            extra_hash_files,
+        } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert!(true, "Parsed ok");
+        assert_eq!(Some("foo.h"), input.to_str());
+        assert_eq!("c++-header", extension);
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.h.gch")));
+        //TODO: fix assert_map_contains to assert no extra keys!
+        assert_eq!(1, outputs.len());
+        assert_eq!(ovec!["-x", "c++-header"], preprocessor_args);
+        assert!(common_args.is_empty());
+        assert!(!msvc_show_includes);
+        assert!(extra_hash_files.is_empty());
+    }
+
+    #[test]
+    fn test_parse_arguments_header_not_pch() {
+        assert_eq!(CompilerArguments::NotCompilation,
+                   _parse_arguments(&stringvec!["-x", "c++-header", "foo.h", "-o", "foo.i"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_include_pch() {
+        let td = TempDir::new("sccache").unwrap();
+        File::create(td.path().join("foo.h.gch")).unwrap();
+        let args = ovec!["-c", "foo.c", "-include", "foo.h", "-include", "bar.h", "-o", "foo.o"];
+        let ParsedArguments {
+            common_args,
+            extra_hash_files,
+            ..
+        } = match parse_arguments(&args, td.path(), argument_takes_value) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(ovec!["-include", "foo.h", "-include", "bar.h"], common_args);
+        assert_eq!(vec![PathBuf::from("foo.h.gch")], extra_hash_files);
+    }
+
+    #[test]
+    fn test_parse_arguments_header_unit() {
+        assert_eq!(CompilerArguments::CannotCache("header unit"),
+                   _parse_arguments(&stringvec!["-fmodules-ts", "-x", "c++-system-header", "vector", "-c", "-o", "vector.o"]));
+        assert_eq!(CompilerArguments::CannotCache("header unit"),
+                   _parse_arguments(&stringvec!["-fmodules-ts", "-x", "c++-user-header", "foo.h", "-c", "-o", "foo.o"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_asm_and_preprocessed() {
+        for &(input, ext) in &[("foo.s", "s"), ("foo.S", "S"), ("foo.i", "i"), ("foo.ii", "ii")] {
+            let a = match _parse_arguments(&stringvec!["-c", input, "-o", "foo.o"]) {
+                CompilerArguments::Ok(a) => a,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+            assert_eq!(Some(input), a.input.to_str());
+            assert_eq!(ext, a.extension);
+            assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_fpreprocessed() {
+        for &(input, ext) in &[("foo.c", "i"), ("foo.cpp", "ii")] {
+            let a = match _parse_arguments(&stringvec!["-c", input, "-fpreprocessed", "-o", "foo.o"]) {
+                CompilerArguments::Ok(a) => a,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            };
+            assert_eq!(Some(input), a.input.to_str());
+            assert_eq!(ext, a.extension);
+            assert_eq!(ovec!["-fpreprocessed"], a.common_args);
+        }
+    }
+
+    #[test]
+    fn test_preprocess_skipped_for_preprocessed_input() {
+        // No processes should be run for inputs that don't need preprocessing.
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        let f = TestFixture::new();
+        for &(input, ext) in &[("foo.s", "s"), ("foo.i", "i"), ("foo.ii", "ii")] {
+            File::create(f.tempdir.path().join(input)).unwrap()
+                .write_all(b"already preprocessed").unwrap();
+            let parsed_args = ParsedArguments {
+                input: input.into(),
+                extension: ext.into(),
+                depfile: None,
+                outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
+                preprocessor_args: vec!(),
+                common_args: vec!(),
+                msvc_show_includes: false,
+                extra_hash_files: vec!(),
+            };
+            let output = preprocess(&creator,
+                                    &f.bins[0],
+                                    &parsed_args,
+                                    f.tempdir.path(),
+                                    &[],
+                                    &pool).wait().unwrap();
+            assert_eq!(exit_status(0), output.status);
+            match output.stdout {
+                Stdout::Memory(ref data) => assert_eq!(b"already preprocessed", data.as_slice()),
+                Stdout::File(..) => panic!("Unexpected file output"),
+            }
+        }
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_compile_asm() {
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        let f = TestFixture::new();
+        let parsed_args = ParsedArguments {
+            input: "foo.S".into(),
+            extension: "S".into(),
+            depfile: None,
+            outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
+            preprocessor_args: vec!(),
+            common_args: vec!(),
+            msvc_show_includes: false,
+            extra_hash_files: vec!(),
+        };
+        // Compiler invocation.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "", "")));
+        let (cacheable, _) = compile(&creator,
+                                     &f.bins[0],
+                                     empty_output().into(),
+                                     &parsed_args,
+                                     f.tempdir.path(),
+                                     &[],
+                                     &pool).wait().unwrap();
+        assert_eq!(Cacheable::Yes, cacheable);
+        // Ensure that we ran all processes.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
+    #[test]
+    fn test_parse_arguments_objc() {
+        let args = stringvec!["-c", "foo.mm", "-fobjc-arc", "-framework", "Foundation",
+                              "-iframework", "/Library/Frameworks", "-o", "foo.o"];
+        let ParsedArguments {
+            input,
+            extension,
+            depfile: _,
+            outputs,
+            preprocessor_args,
+            msvc_show_includes: _,
+            common_args,
+            extra_hash_files: _,
+        } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("foo.mm"), input.to_str());
+        assert_eq!("mm", extension);
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert!(preprocessor_args.is_empty());
+        assert_eq!(ovec!["-fobjc-arc", "-framework", "Foundation",
+                         "-iframework", "/Library/Frameworks"], common_args);
+    }
+
+    #[test]
+    fn test_compile_objc() {
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        let f = TestFixture::new();
+        for &(ext, lang) in &[("m", "objective-c-cpp-output"), ("mm", "objective-c++-cpp-output")] {
+            let parsed_args = ParsedArguments {
+                input: format!("foo.{}", ext).into(),
+                extension: ext.into(),
+                depfile: None,
+                outputs: vec![("obj", "foo.o".into())].into_iter().collect(),
+                preprocessor_args: vec!(),
+                common_args: ovec!["-fobjc-arc"],
+                msvc_show_includes: false,
+                extra_hash_files: vec!(),
+            };
+            // Compiler invocation.
+            next_command_calls(&creator, move |args| {
+                assert_eq!(ovec!["-c", "-x", lang, "-", "-o", "foo.o", "-fobjc-arc"], args);
+                Ok(MockChild::new(exit_status(0), "", ""))
+            });
+            let (cacheable, _) = compile(&creator,
+                                         &f.bins[0],
+                                         empty_output().into(),
+                                         &parsed_args,
+                                         f.tempdir.path(),
+                                         &[],
+                                         &pool).wait().unwrap();
+            assert_eq!(Cacheable::Yes, cacheable);
+        }
+        // Ensure that we ran all processes.
+        assert_eq!(0, creator.lock().unwrap().children.len());
+    }
+
     #[test]
     fn test_parse_arguments_pgo() {
+        let parse = |args: Vec<&str>, allow_pgo| {
+            let args = args.into_iter().map(OsString::from).collect::<Vec<_>>();
+            super::_parse_arguments(&args, ".".as_ref(), &argument_takes_value, allow_pgo)
+        };
+        assert_eq!(CompilerArguments::CannotCache("pgo"),
+                   parse(vec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"], false));
         assert_eq!(CompilerArguments::CannotCache("pgo"),
-                   _parse_arguments(&stringvec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]));
+                   parse(vec!["-c", "foo.c", "-fprofile-use=foo.profdata", "-o", "foo.o"], false));
+        // Profile data that can't be found can't be hashed.
+        assert_eq!(CompilerArguments::CannotCache("pgo profile not found"),
+                   parse(vec!["-c", "foo.c", "-fprofile-use=missing.profdata", "-o", "foo.o"], true));
+    }
+
+    #[test]
+    fn test_parse_arguments_allow_pgo() {
+        let f = TestFixture::new();
+        let parse = |args: Vec<&str>| {
+            let args = args.into_iter().map(OsString::from).collect::<Vec<_>>();
+            match super::_parse_arguments(&args, f.tempdir.path(), &argument_takes_value, true) {
+                CompilerArguments::Ok(args) => args,
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        };
+        // GCC looks for a .gcda file next to the object.
+        f.touch("foo.gcda").unwrap();
+        let a = parse(vec!["-c", "foo.c", "-fprofile-use", "-o", "foo.o"]);
+        assert_eq!(vec![PathBuf::from("foo.gcda")], a.extra_hash_files);
+        assert!(a.common_args.contains(&OsString::from("-fprofile-use")));
+        // Or in the directory given with -fprofile-use.
+        fs::create_dir(f.tempdir.path().join("profile")).unwrap();
+        f.touch("profile/foo.gcda").unwrap();
+        let a = parse(vec!["-c", "foo.c", "-fprofile-use=profile", "-o", "foo.o"]);
+        assert_eq!(vec![PathBuf::from("profile/foo.gcda")], a.extra_hash_files);
+        // Clang takes a profile file.
+        f.touch("foo.profdata").unwrap();
+        let a = parse(vec!["-c", "foo.c", "-fprofile-instr-use=foo.profdata", "-o", "foo.o"]);
+        assert_eq!(vec![PathBuf::from("foo.profdata")], a.extra_hash_files);
+        let a = parse(vec!["-c", "foo.c", "-fprofile-use=foo.profdata", "-o", "foo.o"]);
+        assert_eq!(vec![PathBuf::from("foo.profdata")], a.extra_hash_files);
+    }
+
+    #[test]
+    fn test_parse_arguments_default_output() {
+        let args = stringvec!["-c", "src/foo.c"];
+        let ParsedArguments {
+            input,
+            outputs,
+            ..
+        } = match _parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("src/foo.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(1, outputs.len());
+        assert_eq!(CompilerArguments::CannotCache("no input file"),
+                   _parse_arguments(&stringvec!["-c"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_lto() {
+        for flags in [&["-flto"][..], &["-flto=thin"][..], &["-flto", "-ffat-lto-objects"][..]].iter() {
+            let mut args = stringvec!["-c", "foo.c", "-o", "foo.o"];
+            args.extend(flags.iter().map(|f| f.to_string()));
+            match _parse_arguments(&args) {
+                CompilerArguments::Ok(ParsedArguments { common_args, preprocessor_args, outputs, .. }) => {
+                    assert_eq!(flags.iter().map(|f| OsString::from(*f)).collect::<Vec<_>>(), common_args);
+                    assert!(preprocessor_args.is_empty());
+                    assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+                    assert_eq!(1, outputs.len());
+                }
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+        match _parse_arguments(&stringvec!["-c", "foo.c", "-flto=thin",
+                                           "-fthin-link-bitcode=foo.thinlto.bc", "-o", "foo.o"]) {
+            CompilerArguments::Ok(ParsedArguments { common_args, outputs, .. }) => {
+                assert_eq!(ovec!["-flto=thin", "-fthin-link-bitcode=foo.thinlto.bc"], common_args);
+                assert_map_contains!(outputs,
+                                     ("obj", PathBuf::from("foo.o")),
+                                     ("thinlink", PathBuf::from("foo.thinlto.bc")));
+                assert_eq!(2, outputs.len());
+            }
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        }
     }
 
     #[test]
@@ -648,6 +1653,48 @@ mod test {
                    _parse_arguments(&stringvec!["-c", "foo.c", "@foo", "-o", "foo.o"]));
     }
 
+    #[test]
+    fn test_split_response_file() {
+        assert_eq!(stringvec!["-c", "foo.c"], split_response_file("  -c\n\tfoo.c "));
+        assert_eq!(stringvec!["-I/path with spaces", "-DFOO=a b", "-Ia b", "x\\y", ""],
+                   split_response_file("\"-I/path with spaces\" '-DFOO=a b' -Ia\\ b x\\\\y ''"));
+        assert_eq!(stringvec!["it's", "say \"hi\""],
+                   split_response_file("it\\'s \"say \\\"hi\\\"\""));
+    }
+
+    #[test]
+    fn test_expand_response_files() {
+        let td = TempDir::new("sccache").unwrap();
+        File::create(td.path().join("outer")).unwrap().write_all(b"-c @inner -o 'foo bar.o'").unwrap();
+        File::create(td.path().join("inner")).unwrap().write_all(b"foo.c -DFOO").unwrap();
+        assert_eq!(ovec!["-c", "foo.c", "-DFOO", "-o", "foo bar.o", "-g"],
+                   expand_response_files(&ovec!["@outer", "-g"], td.path()));
+        // Missing files are passed through, like gcc does.
+        assert_eq!(ovec!["@missing", "-g"],
+                   expand_response_files(&ovec!["@missing", "-g"], td.path()));
+    }
+
+    #[test]
+    fn test_parse_arguments_response_file_quoted() {
+        let td = TempDir::new("sccache").unwrap();
+        File::create(td.path().join("foo")).unwrap().write_all(b"\
+            -c foo.c \"-I/path with spaces\" -I/other\\ path -o foo.o\
+        ").unwrap();
+        let arg = format!("@{}", td.path().join("foo").display());
+        let ParsedArguments {
+            input,
+            outputs,
+            common_args,
+            ..
+        } = match _parse_arguments(&[arg]) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Some("foo.c"), input.to_str());
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(ovec!["-I/path with spaces", "-I/other path"], common_args);
+    }
+
     #[test]
     fn at_signs() {
         let td = TempDir::new("sccache").unwrap();
@@ -663,6 +1710,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match _parse_arguments(&[arg]) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -20,3 +20,5 @@ mod msvc;
 mod rust;
 
 pub use compiler::compiler::*;
+pub use compiler::c::CCompilerKind;
+pub use compiler::gcc::expand_response_files;
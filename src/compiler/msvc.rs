@@ -37,11 +37,15 @@ use std::io::{
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::process::{self,Stdio};
-use util::{run_input_output, OsStrExt};
+use util::{run_input_output, OsStrExt, SpillOutput};
 
 use errors::*;
 use config::CONFIG;
 
+/// The prefix of `-showIncludes` output from an English-language MSVC,
+/// used when the prefix isn't detected.
+pub const DEFAULT_INCLUDES_PREFIX: &'static str = "Note: including file:";
+
 /// A struct on which to implement `CCompilerImpl`.
 ///
 /// Needs a little bit of state just to persist `includes_prefix`.
@@ -67,15 +71,18 @@ impl CCompilerImpl for MSVC {
                      cwd: &Path,
                      env_vars: &[(OsString, OsString)],
                      pool: &CpuPool)
-                     -> SFuture<process::Output> where T: CommandCreatorSync
+                     -> SFuture<SpillOutput> where T: CommandCreatorSync
     {
-        preprocess(creator, executable, parsed_args, cwd, env_vars, &self.includes_prefix, pool)
+        // Unlike gcc and clang, MSVC's preprocessor output is always kept
+        // in memory.
+        Box::new(preprocess(creator, executable, parsed_args, cwd, env_vars, &self.includes_prefix, pool)
+                 .map(SpillOutput::from))
     }
 
     fn compile<T>(&self,
                   creator: &T,
                   executable: &Path,
-                  preprocessor_result: process::Output,
+                  preprocessor_result: SpillOutput,
                   parsed_args: &ParsedArguments,
                   cwd: &Path,
                   env_vars: &[(OsString, OsString)],
@@ -83,6 +90,10 @@ impl CCompilerImpl for MSVC {
                   -> SFuture<(Cacheable, process::Output)>
         where T: CommandCreatorSync
     {
+        let preprocessor_result = match preprocessor_result.into_output() {
+            Ok(output) => output,
+            Err(e) => return f_err(e),
+        };
         compile(creator, executable, preprocessor_result, parsed_args, cwd, env_vars, pool)
     }
 }
@@ -189,6 +200,18 @@ fn encode_path(dst: &mut Write, path: &Path) -> io::Result<()> {
     dst.write_all(&bytes)
 }
 
+/// Prefixes of arguments that cause the compiler to write extra output files.
+const MULTI_FILE_OUTPUT_PREFIXES: &'static [&'static str] = &[
+    "-FA",
+    "-Fa",
+    "-Fe",
+    "-Fm",
+    "-FR",
+    "-Fr",
+    "-Fx",
+    "-Yc",
+];
+
 pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArguments> {
     let mut output_arg = None;
     let mut input_arg = None;
@@ -248,6 +271,17 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
                         common_args.push(arg_val);
                     }
                 }
+                // Preprocessor options whose value may be passed as a
+                // separate argument, e.g. `/I include` or `/D FOO`.
+                "-D" | "-I" | "-U" => {
+                    common_args.push(s.into());
+                    if let Some((arg_val, flag)) = it.next() {
+                        if flag.is_some() {
+                            return CompilerArguments::CannotCache("extra argument value")
+                        }
+                        common_args.push(arg_val);
+                    }
+                }
                 "-showIncludes" => show_includes = true,
                 "-Fo" => output_arg = arg.take(),
                 "-deps" => depfile = arg.take(),
@@ -269,12 +303,14 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
                 "-FR" |
                 "-Fx" |
                 "-Yc" => return CompilerArguments::CannotCache("multi-file output"),
-                "-Zi" => {
+                // `-ZI` is `-Zi` with edit and continue, which also writes
+                // its debug info to a PDB.
+                "-Zi" | "-ZI" => {
                     debug_info = true;
                     if CONFIG.msvc_force_z7 {
                         common_args.push("-Z7".into());
                     } else {
-                        common_args.push("-Zi".into());
+                        common_args.push(s.into());
                     }
                 }
                 _ => handled = false,
@@ -290,6 +326,11 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
         if flag.starts_with("@") {
             return CompilerArguments::CannotCache("@file")
         }
+        // The same multi-file outputs as above, but with a suffix or an
+        // attached filename, like `-FAcs` or `-Fafoo.asm`.
+        if MULTI_FILE_OUTPUT_PREFIXES.iter().any(|p| flag.starts_with(p)) {
+            return CompilerArguments::CannotCache("multi-file output")
+        }
 
         // Other options.
         if flag.starts_with("-") && flag.len() > 1 {
@@ -344,12 +385,13 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
             outputs.insert("obj", PathBuf::from(o));
             // -Fd is not taken into account unless -Zi is given
             if debug_info && !CONFIG.msvc_force_z7 {
-                match pdb {
-                    Some(p) => outputs.insert("pdb", PathBuf::from(p)),
+                match pdb.and_then(|p| pdb_path(&p)) {
+                    Some(p) => outputs.insert("pdb", p),
                     None => {
-                        // -Zi without -Fd defaults to vcxxx.pdb (where xxx depends on the
-                        // MSVC version), and that's used for all compilations with the same
-                        // working directory. We can't cache such a pdb.
+                        // -Zi without -Fd, or with a directory as -Fd, defaults to
+                        // vcxxx.pdb (where xxx depends on the MSVC version), and that's
+                        // used for all compilations with the same working directory.
+                        // We can't cache such a pdb.
                         return CompilerArguments::CannotCache("shared pdb");
                     }
                 };
@@ -364,6 +406,7 @@ pub fn parse_arguments(arguments: &[OsString]) -> CompilerArguments<ParsedArgume
         preprocessor_args: preprocessor_args,
         common_args: common_args,
         msvc_show_includes: show_includes,
+        extra_hash_files: vec!(),
     })
 }
 
@@ -486,7 +529,25 @@ pub fn preprocess<T>(creator: &T,
     }))
 }
 
-fn compile<T>(creator: &T,
+/// Return the PDB that `-Fd<value>` names, or `None` if `value` is a
+/// directory, where the compiler would write the shared `vcxxx.pdb`. Like
+/// the compiler, add a `.pdb` extension to a name without one.
+fn pdb_path(value: &OsStr) -> Option<PathBuf> {
+    let ends_with_separator = value.to_str().map_or(false, |v| {
+        v.is_empty() || v.ends_with('/') || v.ends_with('\\')
+    });
+    if ends_with_separator {
+        return None;
+    }
+    let path = PathBuf::from(value);
+    if path.extension().is_some() {
+        Some(path)
+    } else {
+        Some(path.with_extension("pdb"))
+    }
+}
+
+pub fn compile<T>(creator: &T,
               executable: &Path,
               preprocessor_result: process::Output,
               parsed_args: &ParsedArguments,
@@ -639,6 +700,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -665,6 +727,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -691,6 +754,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -717,6 +781,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -743,6 +808,7 @@ mod test {
             preprocessor_args,
             msvc_show_includes,
             common_args,
+            extra_hash_files: _,
         } = match parse_arguments(&args) {
             CompilerArguments::Ok(args) => args,
             o @ _ => panic!("Got unexpected parse result: {:?}", o),
@@ -788,6 +854,59 @@ mod test {
 
         assert_eq!(CompilerArguments::CannotCache("multi-file output"),
                    parse_arguments(&ovec!["-c", "foo.c", "-FR", "-Fofoo.obj"]));
+
+        assert_eq!(CompilerArguments::CannotCache("multi-file output"),
+                   parse_arguments(&ovec!["-c", "foo.c", "/FAcs", "-Fofoo.obj"]));
+
+        assert_eq!(CompilerArguments::CannotCache("multi-file output"),
+                   parse_arguments(&ovec!["-c", "foo.c", "/Fafoo.asm", "-Fofoo.obj"]));
+    }
+
+    #[test]
+    fn test_parse_arguments_separate_values() {
+        let args = ovec!["-c", "foo.c", "/I", "include", "-D", "FOO=1", "/DBAR", "-Iother", "/Fofoo.obj"];
+        let ParsedArguments {
+            input,
+            extension,
+            depfile: _,
+            outputs,
+            preprocessor_args,
+            msvc_show_includes,
+            common_args,
+            extra_hash_files: _,
+        } = match parse_arguments(&args) {
+            CompilerArguments::Ok(args) => args,
+            o @ _ => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert!(true, "Parsed ok");
+        assert_eq!(Some("foo.c"), input.to_str());
+        assert_eq!("c", extension);
+        assert_map_contains!(outputs, ("obj", PathBuf::from("foo.obj")));
+        //TODO: fix assert_map_contains to assert no extra keys!
+        assert_eq!(1, outputs.len());
+        assert!(preprocessor_args.is_empty());
+        assert_eq!(common_args, ovec!["-I", "include", "-D", "FOO=1", "-DBAR", "-Iother"]);
+        assert!(!msvc_show_includes);
+    }
+
+    #[test]
+    fn test_parse_arguments_pdb_spellings() {
+        for &(args, expected) in [
+            (&["/c", "foo.c", "/Zi", "/Fdout/foo.pdb", "/Fofoo.obj"][..], "out/foo.pdb"),
+            (&["-c", "foo.c", "-ZI", "-Fdfoo.pdb", "-Fofoo.obj"][..], "foo.pdb"),
+            // The compiler adds the extension if it's missing.
+            (&["-c", "foo.c", "-Zi", "-Fdfoo", "-Fofoo.obj"][..], "foo.pdb"),
+        ].iter() {
+            match parse_arguments(&args.iter().map(|a| OsString::from(*a)).collect::<Vec<_>>()) {
+                CompilerArguments::Ok(ParsedArguments { outputs, .. }) => {
+                    assert_map_contains!(outputs,
+                                         ("obj", PathBuf::from("foo.obj")),
+                                         ("pdb", PathBuf::from(expected)));
+                    assert_eq!(2, outputs.len());
+                }
+                o @ _ => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
     }
 
     #[test]
@@ -796,6 +915,15 @@ mod test {
                    parse_arguments(&ovec!["-c", "foo.c", "-Zi", "-Fofoo.obj"]));
     }
 
+    #[test]
+    fn test_parse_arguments_shared_pdb() {
+        // A directory as `-Fd` means the default, shared, `vcxxx.pdb` in it.
+        for fd in ["-Fd", "-Fdout/", "-Fdout\\", "/Fdout\\"].iter() {
+            assert_eq!(CompilerArguments::CannotCache("shared pdb"),
+                       parse_arguments(&ovec!["-c", "foo.c", "-Zi", *fd, "-Fofoo.obj"]));
+        }
+    }
+
     #[test]
     fn test_compile_simple() {
         let creator = new_creator();
@@ -809,6 +937,7 @@ mod test {
             preprocessor_args: vec!(),
             common_args: vec!(),
             msvc_show_includes: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -841,6 +970,7 @@ mod test {
             preprocessor_args: vec!(),
             common_args: vec!(),
             msvc_show_includes: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
@@ -871,6 +1001,7 @@ mod test {
             preprocessor_args: vec!(),
             common_args: vec!(),
             msvc_show_includes: false,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // First compiler invocation fails.
@@ -902,6 +1033,7 @@ mod test {
             preprocessor_args: vec!(),
             common_args: vec!(),
             msvc_show_includes: true,
+            extra_hash_files: vec!(),
         };
         let compiler = &f.bins[0];
         // Compiler invocation.
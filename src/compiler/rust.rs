@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use cache::Storage;
 use compiler::{Cacheable, Compiler, CompilerArguments, CompilerHasher, CompilerKind, Compilation,
                HashResult};
 use futures::{Future, future};
@@ -29,6 +30,7 @@ use std::iter::{self, FromIterator};
 use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
 use std::slice;
+use std::sync::Arc;
 use std::time::Instant;
 use tempdir::TempDir;
 use util::{fmt_duration_as_secs, run_input_output, Digest};
@@ -49,6 +51,8 @@ const LIBS_DIR: &'static str = "bin";
 pub struct Rust {
     /// The path to the rustc executable.
     executable: PathBuf,
+    /// The output of `rustc --version`, if known.
+    version: Option<String>,
     /// The SHA-1 digests of all the shared libraries in rustc's $sysroot/lib (or /bin on Windows).
     compiler_shlibs_digests: Vec<String>,
 }
@@ -256,7 +260,8 @@ fn get_compiler_outputs<T>(creator: &T,
 impl Rust {
     /// Create a new Rust compiler instance, calculating the hashes of
     /// all the shared libraries in its sysroot.
-    pub fn new<T>(mut creator: T, executable: PathBuf, pool: CpuPool) -> SFuture<Rust>
+    pub fn new<T>(mut creator: T, executable: PathBuf, version: Option<String>, pool: CpuPool)
+                  -> SFuture<Rust>
         where T: CommandCreatorSync,
     {
         let mut cmd = creator.new_command_sync(&executable);
@@ -286,6 +291,7 @@ impl Rust {
             hash_all(libs, &pool).map(move |digests| {
                 Rust {
                     executable: executable,
+                    version: version,
                     compiler_shlibs_digests: digests,
                 }
             })
@@ -297,6 +303,7 @@ impl<T> Compiler<T> for Rust
     where T: CommandCreatorSync,
 {
     fn kind(&self) -> CompilerKind { CompilerKind::Rust }
+    fn version(&self) -> Option<&str> { self.version.as_ref().map(|v| &**v) }
     /// Parse `arguments` as rustc command-line arguments, determine if
     /// we can cache the result of compilation. This is only intended to
     /// cover a subset of rustc invocations, primarily focused on those
@@ -506,6 +513,7 @@ impl<T> CompilerHasher<T> for RustHasher
                          creator: &T,
                          cwd: &Path,
                          env_vars: &[(OsString, OsString)],
+                         _storage: &Arc<Storage>,
                          pool: &CpuPool)
                          -> SFuture<HashResult<T>>
     {
@@ -623,6 +631,11 @@ impl<T> CompilerHasher<T> for RustHasher
         Cow::Borrowed(&self.parsed_args.crate_name)
     }
 
+    /// The crate root isn't kept after argument parsing.
+    fn input(&self) -> Option<&Path> {
+        None
+    }
+
     fn box_clone(&self) -> Box<CompilerHasher<T>> {
         Box::new((*self).clone())
     }
@@ -668,6 +681,7 @@ mod test {
     use std::fs::File;
     use std::io::Write;
     use std::sync::{Arc,Mutex};
+    use test::mock_storage::MockStorage;
     use test::utils::*;
 
     fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments>
@@ -941,11 +955,13 @@ c:/foo/bar.rs:
         mock_dep_info(&creator, &["foo.rs", "bar.rs"]);
         mock_file_names(&creator, &["foo.rlib", "foo.a"]);
         let pool = CpuPool::new(1);
+        let storage: Arc<Storage> = Arc::new(MockStorage::new());
         let res = hasher.generate_hash_key(&creator,
                                            f.tempdir.path(),
                                            &[(OsString::from("CARGO_PKG_NAME"), OsString::from("foo")),
                                              (OsString::from("FOO"), OsString::from("bar")),
                                              (OsString::from("CARGO_BLAH"), OsString::from("abc"))],
+                                           &storage,
                                            &pool).wait().unwrap();
         let m = Digest::new();
         let empty_digest = m.finish();
@@ -1001,9 +1017,10 @@ c:/foo/bar.rs:
 
         let creator = new_creator();
         let pool = CpuPool::new(1);
+        let storage: Arc<Storage> = Arc::new(MockStorage::new());
         mock_dep_info(&creator, &["foo.rs"]);
         mock_file_names(&creator, &["foo.rlib"]);
-        hasher.generate_hash_key(&creator, f.tempdir.path(), env_vars, &pool).wait().unwrap().key
+        hasher.generate_hash_key(&creator, f.tempdir.path(), env_vars, &storage, &pool).wait().unwrap().key
     }
 
     #[test]
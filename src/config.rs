@@ -17,16 +17,50 @@ use app_dirs::{
     AppInfo,
     app_dir,
 };
+use compiler::CCompilerKind;
+#[cfg(unix)]
+use libc;
+use num_cpus;
 use regex::Regex;
+use serde_json;
 use std::env;
-use std::io::Read;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use toml;
 
+use errors::*;
+
 lazy_static! {
-    pub static ref CONFIG: Config = { Config::create() };
+    /// The result of loading the config. Errors are flattened to a message
+    /// so that the result can be shared between threads.
+    static ref CONFIG_RESULT: ::std::result::Result<Config, String> = {
+        Config::create().map_err(|e| {
+            e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
+        })
+    };
+
+    /// The config, which panics on access if it is invalid. Call
+    /// `check_config` first to report that more gracefully.
+    pub static ref CONFIG: Config = {
+        match *CONFIG_RESULT {
+            Ok(ref config) => config.clone(),
+            Err(ref e) => panic!("invalid sccache config: {}", e),
+        }
+    };
+}
+
+/// Return an error describing what's wrong with the config, if it couldn't
+/// be loaded.
+pub fn check_config() -> Result<()> {
+    match *CONFIG_RESULT {
+        Ok(_) => Ok(()),
+        Err(ref e) => Err(e.clone().into()),
+    }
 }
 
 //TODO: might need to put this somewhere more central
@@ -37,76 +71,630 @@ const APP_INFO: AppInfo = AppInfo {
 
 const TEN_GIGS: usize = 10 * 1024 * 1024 * 1024;
 
+/// The default limit on the number of outputs of a cached compile.
+pub const DEFAULT_MAX_OUTPUTS: usize = 16;
+
+/// If the server is idle for this many seconds, it shuts down.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 600;
+
+/// The default number of times to try a network cache operation.
+pub const DEFAULT_CACHE_RETRY: u32 = 3;
+
+/// The user who ran `sudo`, when sccache is running elevated under it.
+#[derive(Debug)]
+pub struct SudoUser {
+    /// Their home directory.
+    pub home: PathBuf,
+    /// Their user id, from `SUDO_UID`.
+    pub uid: u32,
+    /// Their group id, from `SUDO_GID`.
+    pub gid: u32,
+}
+
+lazy_static! {
+    /// The user who ran `sudo`, if sccache is running elevated under it.
+    /// `sudo` often sets `HOME` to root's home, which would otherwise give
+    /// elevated builds a cache of their own.
+    pub static ref SUDO_USER: Option<SudoUser> = sudo_user();
+}
+
+/// Return the home directory of the user `sudo_user`, if `elevated`.
+#[cfg(unix)]
+fn sudo_user_home_dir(sudo_user: Option<OsString>, elevated: bool) -> Option<PathBuf> {
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::ptr;
+
+    let user = match sudo_user {
+        Some(ref user) if elevated => user,
+        _ => return None,
+    };
+    let name = match CString::new(user.as_bytes()) {
+        Ok(name) => name,
+        Err(_) => return None,
+    };
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        unsafe {
+            let mut pw: libc::passwd = mem::zeroed();
+            let mut result = ptr::null_mut();
+            let err = libc::getpwnam_r(name.as_ptr(), &mut pw, buf.as_mut_ptr(), buf.len(), &mut result);
+            if err == libc::ERANGE {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+                continue;
+            }
+            if err != 0 || result.is_null() || pw.pw_dir.is_null() {
+                warn!("Couldn't find the home directory of SUDO_USER {:?}", user);
+                return None;
+            }
+            let dir = CStr::from_ptr(pw.pw_dir).to_bytes().to_vec();
+            return Some(PathBuf::from(OsString::from_vec(dir)));
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sudo_user() -> Option<SudoUser> {
+    let elevated = unsafe { libc::geteuid() == 0 };
+    let home = match sudo_user_home_dir(env::var_os("SUDO_USER"), elevated) {
+        Some(home) => home,
+        None => return None,
+    };
+    let id = |name: &str| env::var(name).ok().and_then(|v| v.parse().ok());
+    match (id("SUDO_UID"), id("SUDO_GID")) {
+        (Some(uid), Some(gid)) => Some(SudoUser { home: home, uid: uid, gid: gid }),
+        // Without them, files in the user's cache would be left owned by
+        // root, so keep root's own cache instead.
+        _ => {
+            warn!("SUDO_UID or SUDO_GID is missing, so not using the cache of SUDO_USER");
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn sudo_user() -> Option<SudoUser> {
+    None
+}
+
+fn sudo_home_dir() -> Option<PathBuf> {
+    SUDO_USER.as_ref().map(|user| user.home.clone())
+}
+
+/// Return the entries from the home directory `home` down to `path` that
+/// are safe to give away: regular files and directories owned by `owner`.
+/// Nothing at or below a symlink is returned, since whoever ran `sudo` can
+/// point it anywhere, like at `/etc`.
+#[cfg(unix)]
+fn sudo_chown_targets(path: &Path, home: &Path, owner: u32) -> Vec<PathBuf> {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut targets = vec![];
+    let relative = match path.strip_prefix(home) {
+        Ok(relative) => relative,
+        Err(_) => return targets,
+    };
+    let mut p = home.to_path_buf();
+    for component in relative.components() {
+        p.push(component);
+        let metadata = match fs::symlink_metadata(&p) {
+            Ok(metadata) => metadata,
+            Err(_) => break,
+        };
+        let file_type = metadata.file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            break
+        }
+        if metadata.uid() == owner {
+            targets.push(p.clone());
+        }
+        if !file_type.is_dir() {
+            break
+        }
+    }
+    targets
+}
+
+/// Give `path`, and the directories above it up to the home directory of
+/// the user who ran `sudo`, to that user, if sccache is running elevated
+/// under `sudo` and `path` is in their home. Files written there as root
+/// otherwise couldn't be replaced or evicted by their later unelevated runs.
+/// Only entries that root owns are given away, and symlinks aren't followed.
+#[cfg(unix)]
+pub fn chown_to_sudo_user(path: &Path) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let user = match *SUDO_USER {
+        Some(ref user) => user,
+        None => return,
+    };
+    for p in sudo_chown_targets(path, &user.home, 0) {
+        if let Ok(name) = CString::new(p.as_os_str().as_bytes()) {
+            if unsafe { libc::lchown(name.as_ptr(), user.uid, user.gid) } != 0 {
+                debug!("Couldn't chown {:?}: {}", p, io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn chown_to_sudo_user(_path: &Path) {}
+
+/// Return the home directory of the user sccache is running for: the user
+/// who ran `sudo` when running elevated, otherwise the current user.
+fn home_dir() -> Option<PathBuf> {
+    sudo_home_dir().or_else(env::home_dir)
+}
+
+/// Return the per-user directory sccache keeps its cache and state in.
+fn user_cache_dir() -> Option<PathBuf> {
+    match sudo_home_dir() {
+        Some(home) => Some(if cfg!(target_os = "macos") {
+            home.join("Library").join("Caches").join(APP_INFO.name)
+        } else {
+            home.join(".cache").join(APP_INFO.name)
+        }),
+        None => app_dir(AppDataType::UserCache, &APP_INFO, "").ok(),
+    }
+}
+
 pub fn default_disk_cache_dir() -> PathBuf {
-    app_dir(AppDataType::UserCache, &APP_INFO, "")
+    user_cache_dir()
         // Fall back to something, even if it's not very good.
         .unwrap_or(env::temp_dir().join("sccache_cache"))
 }
 
-fn parse_size(val: &str) -> Option<usize> {
-    let re = Regex::new(r"^(\d+)([KMGT])$").unwrap();
-    re.captures(val)
-        .and_then(|caps| caps.at(1).and_then(|size| usize::from_str(size).ok()).and_then(|size| Some((size, caps.at(2)))))
-        .and_then(|(size, suffix)| {
-            match suffix {
-                Some("K") => Some(1024 * size),
-                Some("M") => Some(1024 * 1024 * size),
-                Some("G") => Some(1024 * 1024 * 1024 * size),
-                Some("T") => Some(1024 * 1024 * 1024 * 1024 * size),
-                _ => None,
+/// Expand a leading `~` in `path` to the home directory, and `$VAR` or
+/// `${VAR}` to the value of the environment variable `VAR`. Unset variables
+/// expand to nothing, with a warning.
+fn expand_path(path: &str) -> PathBuf {
+    let tilde = path == "~" || path.starts_with("~/") || (cfg!(windows) && path.starts_with("~\\"));
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, remaining) = if after.starts_with('{') {
+            match after.find('}') {
+                Some(end) => (&after[1..end], &after[end + 1..]),
+                None => ("", after),
+            }
+        } else {
+            let end = after.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            match env::var(name) {
+                Ok(val) => expanded.push_str(&val),
+                Err(_) => warn!("Environment variable `{}` in `{}` is not set", name, path),
+            }
+        }
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+
+    if tilde {
+        if let Some(home) = home_dir() {
+            return if expanded.len() > 2 {
+                home.join(&expanded[2..])
+            } else {
+                home
+            };
+        }
+    }
+    PathBuf::from(expanded)
+}
+
+/// Return the S3 endpoint for `bucket`: under `endpoint` if given, otherwise
+/// the AWS endpoint for `region`, defaulting to `us-east-1`.
+fn s3_endpoint(bucket: &str, endpoint: Option<&str>, region: Option<&str>) -> String {
+    match (endpoint, region) {
+        (Some(endpoint), _) => format!("{}/{}", endpoint, bucket),
+        // us-east-1 has no region in its endpoint.
+        (None, Some(region)) if region != "us-east-1" =>
+            format!("{}.s3.{}.amazonaws.com", bucket, region),
+        (None, _) => format!("{}.s3.amazonaws.com", bucket),
+    }
+}
+
+/// Parse a size such as `10G`, `1.5g`, `2KiB`, `2KB` or `500000000`.
+///
+/// A bare suffix or one ending in `iB` is a binary (1024) multiple, and a
+/// suffix ending in `B` is a decimal (1000) multiple. A number without a
+/// suffix is a count of bytes.
+pub fn parse_size(val: &str) -> Option<usize> {
+    let re = Regex::new(r"^(\d+(?:\.\d+)?)([KMGTkmgt]?)(B|iB)?$").unwrap();
+    re.captures(val.trim())
+        .and_then(|caps| {
+            caps.at(1)
+                .and_then(|size| f64::from_str(size).ok())
+                .map(|size| (size, caps.at(2).unwrap_or(""), caps.at(3)))
+        })
+        .and_then(|(size, prefix, unit)| {
+            let exp = match prefix {
+                "" => 0,
+                "K" | "k" => 1,
+                "M" | "m" => 2,
+                "G" | "g" => 3,
+                "T" | "t" => 4,
+                _ => return None,
+            };
+            let base: f64 = match unit {
+                Some("iB") if exp == 0 => return None,
+                Some("B") if exp > 0 => 1000.0,
+                _ => 1024.0,
+            };
+            let bytes = (size * base.powi(exp)).round();
+            if bytes <= usize::max_value() as f64 {
+                Some(bytes as usize)
+            } else {
+                None
             }
         })
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DiskCacheConfig {
     pub cache_dir: PathBuf,
     pub cache_size: usize,
+    /// The maximum number of cache entries, if limited.
+    pub max_files: Option<usize>,
+    /// Directories to spread the cache across instead of `cache_dir`, if any.
+    pub shard_dirs: Vec<PathBuf>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct RedisCacheConfig {
+    /// The Redis url, which may include a password and database number.
     pub url: String,
+    /// A prefix for every key, so several caches can share a database.
+    pub key_prefix: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct S3CacheConfig {
     pub endpoint: String,
     pub bucket: String,
+    /// A prefix for every object, so several caches can share a bucket.
+    pub key_prefix: String,
+    /// Server-side encryption for uploaded objects: `AES256` or `aws:kms`.
+    pub server_side_encryption: Option<String>,
+    /// The KMS key to encrypt with when using `aws:kms`.
+    pub kms_key_id: Option<String>,
+    /// Whether to find the size of the cache by listing the bucket.
+    pub compute_size: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum CacheType {
     Invalid, // internal
     Disk(DiskCacheConfig),
     S3(S3CacheConfig),
     Redis(RedisCacheConfig),
+    /// Several caches, tried in order.
+    Chain(Vec<CacheType>),
 }
 
-#[derive(Debug)]
+/// Options for the socket the server listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocketOptions {
+    /// The length of the queue of connections waiting to be accepted.
+    pub backlog: i32,
+    /// Whether to set `SO_REUSEADDR` on the listening socket.
+    pub reuse_address: bool,
+    /// Whether to set `TCP_NODELAY` on accepted connections.
+    pub nodelay: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            // Large enough that a burst of clients from a parallel build
+            // isn't turned away while the server is busy.
+            backlog: 1024,
+            // On Windows `SO_REUSEADDR` lets another process steal the port.
+            reuse_address: !cfg!(windows),
+            nodelay: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Config {
     pub stats_path: Option<PathBuf>,
+    pub port_file: Option<PathBuf>,
     pub cache_type: CacheType,
     pub no_daemon: bool,
     pub force_recache: bool,
     pub msvc_force_z7: bool,
+    pub allow_pgo: bool,
     pub compiler_dir: Option<PathBuf>,
+    pub http_timeout: Option<Duration>,
+    pub cache_ttl: Option<Duration>,
+    /// How many times to try S3 and Redis operations that fail with
+    /// transient errors, from `SCCACHE_CACHE_RETRY`.
+    pub cache_retry: u32,
+    pub max_memory: Option<usize>,
+    /// How many bytes of recently used entries to keep in memory in front of
+    /// the cache, from `SCCACHE_MEMCACHE_SIZE`.
+    pub memcache_size: Option<usize>,
+    pub socket_options: SocketOptions,
+    /// A program that transforms cache keys, from `SCCACHE_KEY_HOOK`.
+    pub key_hook: Option<PathBuf>,
+    /// Successful compiles whose stderr matches this regex aren't cached,
+    /// from `SCCACHE_NOCACHE_ON_STDERR_REGEX`.
+    pub nocache_on_stderr: Option<String>,
+    /// Compilers whose cache misses usually take less than this are run
+    /// without the cache.
+    pub min_compile_time: Option<Duration>,
+    /// The number of threads hashing, compiling and doing cache I/O.
+    pub num_workers: usize,
+    /// The maximum number of compilers to run at once.
+    pub max_parallel: Option<usize>,
+    /// Treat C compilers as this kind instead of detecting it, from
+    /// `SCCACHE_FORCE_COMPILER`.
+    pub force_compiler: Option<CCompilerKind>,
+    /// Where to serve stats for Prometheus, from `SCCACHE_PROMETHEUS_PORT`.
+    pub prometheus_addr: Option<SocketAddr>,
+    /// Compiles with more outputs than this aren't cached.
+    pub max_outputs: usize,
+    /// Whether to compress large messages between the client and server.
+    pub compress_protocol: bool,
+    /// Whether clients and the server talk over TCP instead of a UNIX domain
+    /// socket, from `SCCACHE_SERVER_TCP`.
+    pub server_tcp: bool,
+    /// How long a client waits for the server to finish a compile before
+    /// running the compiler itself, from `SCCACHE_COMPILE_TIMEOUT`.
+    pub compile_timeout: Option<Duration>,
+    /// How long the server waits for a request before shutting down, from
+    /// `SCCACHE_IDLE_TIMEOUT`. `None` if it runs until it's stopped.
+    pub idle_timeout: Option<Duration>,
+    /// Absolute paths under this directory are made relative before they're
+    /// hashed, from `SCCACHE_BASEDIR`.
+    pub basedir: Option<PathBuf>,
+    /// The URL of the scheduler to send cache misses to for compiling
+    /// remotely, from `SCCACHE_DIST_SCHEDULER`.
+    pub dist_scheduler: Option<String>,
+    /// The secret that dist workers require with each compile, from
+    /// `SCCACHE_DIST_TOKEN`.
+    pub dist_token: Option<String>,
+    /// Whether clients compile and use the cache themselves, without a
+    /// server, from `SCCACHE_IGNORE_SERVER`.
+    pub no_server: bool,
+    /// Whether C and C++ compiles look up their key from the headers a
+    /// previous compile of the same source included, instead of running the
+    /// preprocessor, from `SCCACHE_DIRECT_MODE`.
+    pub direct_mode: bool,
 }
 
-impl Config {
-    pub fn create() -> Config {
-        // read from SCCACHE_CONF if present, otherwise
-        // from ~/.sccache if present.
-        let conf_data = env::var("SCCACHE_CONF").ok()
-            .and_then(|env_path| Some(PathBuf::from(env_path)))
-            .or_else(|| env::home_dir().map(|d| d.join(".sccache")))
-            .and_then(|path| File::open(path).ok())
-            .map(|mut file| {
-                let mut data = String::new();
-                file.read_to_string(&mut data).unwrap();
-                data.parse::<toml::Value>().expect("error parsing sccache config")
+/// Return how long to wait for the server to finish a compile run with the
+/// environment `env_vars`: `SCCACHE_COMPILE_TIMEOUT` seconds if it's set
+/// there, and the configured `compile_timeout` otherwise. Both the client and
+/// the server use this, so that the server gives up on the compile once the
+/// client has.
+pub fn compile_timeout(env_vars: &[(OsString, OsString)]) -> Option<Duration> {
+    let from_env = env_vars.iter()
+        .find(|&&(ref k, _)| k.as_os_str() == OsStr::new("SCCACHE_COMPILE_TIMEOUT"))
+        .and_then(|&(_, ref v)| v.to_str())
+        .and_then(|v| v.parse::<u64>().ok());
+    match from_env {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => CONFIG.compile_timeout,
+    }
+}
+
+/// ccache's environment variables, and the sccache ones they stand in for
+/// when those aren't set, so that builds moving from ccache keep working.
+const CCACHE_ENV_ALIASES: &'static [(&'static str, &'static str)] = &[
+    ("SCCACHE_DIR", "CCACHE_DIR"),
+    ("SCCACHE_SIZE", "CCACHE_MAXSIZE"),
+    ("SCCACHE_MAX_FILES", "CCACHE_MAXFILES"),
+    ("SCCACHE_RECACHE", "CCACHE_RECACHE"),
+    ("SCCACHE_BASEDIR", "CCACHE_BASEDIR"),
+];
+
+/// Return the ccache environment variable that stands in for `name`.
+fn ccache_alias(name: &str) -> Option<&'static str> {
+    CCACHE_ENV_ALIASES.iter().find(|&&(n, _)| n == name).map(|&(_, alias)| alias)
+}
+
+/// Parse a size in ccache's units: a number with a decimal (`k`, `M`, `G`,
+/// `T`) or binary (`Ki`, `Mi`, `Gi`, `Ti`) suffix, where no suffix means
+/// gigabytes.
+fn parse_ccache_size(val: &str) -> Option<usize> {
+    let re = Regex::new(r"^(\d+(?:\.\d+)?)([kKMGT]?)(i?)$").unwrap();
+    re.captures(val.trim())
+        .and_then(|caps| {
+            let size = caps.at(1).and_then(|size| f64::from_str(size).ok());
+            size.map(|size| (size, caps.at(2).unwrap_or(""), caps.at(3) == Some("i")))
+        })
+        .and_then(|(size, prefix, binary)| {
+            let exp = match prefix {
+                "" if !binary => 3,
+                "k" | "K" => 1,
+                "M" => 2,
+                "G" => 3,
+                "T" => 4,
+                _ => return None,
+            };
+            let base: f64 = if binary { 1024.0 } else { 1000.0 };
+            let bytes = (size * base.powi(exp)).round();
+            if bytes <= usize::max_value() as f64 {
+                Some(bytes as usize)
+            } else {
+                None
+            }
+        })
+}
+
+/// Convert `value` of the ccache variable `alias` to a value of the sccache
+/// variable it stands in for, or `None` if it has no equivalent.
+fn from_ccache_value(alias: &str, value: OsString) -> Option<OsString> {
+    match alias {
+        // ccache's default unit is gigabytes, where sccache's is bytes.
+        "CCACHE_MAXSIZE" => {
+            let size = value.to_str().and_then(parse_ccache_size);
+            if size.is_none() {
+                warn!("Ignoring unparsable {}: {:?}", alias, value);
+            }
+            // 0 means no limit to ccache, which sccache doesn't have.
+            size.and_then(|n| if n > 0 { Some(n.to_string().into()) } else { None })
+        }
+        // sccache evicts whatever is in its cache directory, so keep out of
+        // ccache's own files.
+        "CCACHE_DIR" => Some(Path::new(&value).join("sccache").into_os_string()),
+        _ => Some(value),
+    }
+}
+
+/// Return the value of the variable `name` as found by `get`, or of its
+/// ccache equivalent if it's unset.
+fn lookup_env_var<F>(name: &str, get: F) -> Option<OsString>
+    where F: Fn(&str) -> Option<OsString>,
+{
+    get(name).or_else(|| {
+        ccache_alias(name).and_then(|alias| {
+            get(alias).and_then(|value| {
+                debug!("Using {} for {}", alias, name);
+                from_ccache_value(alias, value)
             })
-            .unwrap_or_else(|| "".parse::<toml::Value>().unwrap());
+        })
+    })
+}
+
+/// Return the value of the environment variable `name`, or of its ccache
+/// equivalent if it's unset.
+fn env_var_os(name: &str) -> Option<OsString> {
+    lookup_env_var(name, |name| env::var_os(name))
+}
+
+/// Find the variable `name` in `env_vars`, or its ccache equivalent if it's
+/// not there.
+pub fn find_env_var(env_vars: &[(OsString, OsString)], name: &str) -> Option<OsString> {
+    lookup_env_var(name, |name| {
+        env_vars.iter()
+            .find(|&&(ref k, _)| k.as_os_str() == OsStr::new(name))
+            .map(|&(_, ref v)| v.clone())
+    })
+}
+
+/// Return the directory under which absolute paths are made relative before
+/// hashing a compile run with the environment `env_vars`: `SCCACHE_BASEDIR`
+/// if it's set there, and the configured `basedir` otherwise. An empty value
+/// turns it off, and so does a relative one, which couldn't match anything.
+pub fn basedir(env_vars: &[(OsString, OsString)]) -> Option<PathBuf> {
+    let from_env = find_env_var(env_vars, "SCCACHE_BASEDIR").map(PathBuf::from);
+    from_env.or_else(|| CONFIG.basedir.clone())
+        .and_then(|dir| if dir.is_absolute() { Some(dir) } else { None })
+}
+
+/// Return whether a C or C++ compile run with the environment `env_vars`
+/// uses direct mode: `SCCACHE_DIRECT_MODE` if it's set there, and the
+/// configured `direct_mode` otherwise.
+pub fn direct_mode(env_vars: &[(OsString, OsString)]) -> bool {
+    find_env_var(env_vars, "SCCACHE_DIRECT_MODE").map(|v| v.as_os_str() != "0")
+        .unwrap_or(CONFIG.direct_mode)
+}
+
+/// Return the path of the config file: `SCCACHE_CONF` if set, otherwise
+/// `~/.sccache`.
+pub fn config_file_path() -> Option<PathBuf> {
+    env::var("SCCACHE_CONF").ok()
+        .and_then(|env_path| Some(PathBuf::from(env_path)))
+        .or_else(|| home_dir().map(|d| d.join(".sccache")))
+}
+
+/// The formats the config file can be written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// Return the format of the config file at `path`: `SCCACHE_CONF_FORMAT` if
+/// set, otherwise JSON for a `.json` file and TOML for anything else.
+fn config_format(path: &Path) -> Result<ConfigFormat> {
+    match env::var("SCCACHE_CONF_FORMAT").ok() {
+        Some(ref format) if format == "toml" => Ok(ConfigFormat::Toml),
+        Some(ref format) if format == "json" => Ok(ConfigFormat::Json),
+        Some(format) => bail!("SCCACHE_CONF_FORMAT must be 'toml' or 'json' (got '{}')", format),
+        None if path.extension().map_or(false, |e| e == "json") => Ok(ConfigFormat::Json),
+        None => Ok(ConfigFormat::Toml),
+    }
+}
+
+/// Convert a JSON config value to the equivalent TOML, so that both formats
+/// are read the same way. TOML has no null, so null settings are left out.
+fn json_to_toml(value: serde_json::Value) -> Option<toml::Value> {
+    use serde_json::Value as Json;
+    match value {
+        Json::Null => None,
+        Json::Bool(b) => Some(toml::Value::Boolean(b)),
+        Json::Number(n) => {
+            n.as_i64().map(toml::Value::Integer).or_else(|| n.as_f64().map(toml::Value::Float))
+        }
+        Json::String(s) => Some(toml::Value::String(s)),
+        Json::Array(a) => Some(toml::Value::Array(a.into_iter().filter_map(json_to_toml).collect())),
+        Json::Object(o) => {
+            Some(toml::Value::Table(o.into_iter().filter_map(|(k, v)| {
+                json_to_toml(v).map(|v| (k, v))
+            }).collect()))
+        }
+    }
+}
+
+/// Parse the contents of a config file in `format`.
+fn parse_config(data: &str, format: ConfigFormat) -> Result<toml::Value> {
+    match format {
+        ConfigFormat::Toml => data.parse::<toml::Value>().chain_err(|| "invalid TOML"),
+        ConfigFormat::Json => {
+            let value = serde_json::from_str(data).chain_err(|| "invalid JSON")?;
+            match json_to_toml(value) {
+                Some(value @ toml::Value::Table(_)) => Ok(value),
+                _ => bail!("the config must be a JSON object"),
+            }
+        }
+    }
+}
+
+/// Read the config file at `path`. A missing file is an empty config.
+fn read_config_file(path: Option<PathBuf>) -> Result<toml::Value> {
+    match path.and_then(|path| File::open(&path).ok().map(|f| (path, f))) {
+        Some((path, mut file)) => {
+            let mut data = String::new();
+            file.read_to_string(&mut data)
+                .chain_err(|| format!("failed to read {}", path.display()))?;
+            config_format(&path)
+                .and_then(|format| parse_config(&data, format))
+                .chain_err(|| format!("failed to parse {}", path.display()))
+        }
+        None => Ok("".parse::<toml::Value>().unwrap()),
+    }
+}
+
+impl Config {
+    pub fn create() -> Result<Config> {
+        Config::from_file(config_file_path())
+    }
+
+    /// The regex that keeps compiles out of the cache when their stderr
+    /// matches it, if any.
+    pub fn nocache_stderr_regex(&self) -> Option<Regex> {
+        self.nocache_on_stderr.as_ref().and_then(|re| Regex::new(re).ok())
+    }
+
+    /// Create a `Config` from the config file at `path`, if any, and
+    /// the environment.
+    pub fn from_file(path: Option<PathBuf>) -> Result<Config> {
+        let conf_data = read_config_file(path)?;
 
         let string_from_config = |conf_name: &str| -> Option<&str> {
             conf_data.get(conf_name).and_then(|v| v.as_str())
@@ -120,113 +708,799 @@ impl Config {
             conf_data.get(conf_name).and_then(|v| v.as_bool())
         };
 
+        let u64_from_config = |conf_name: &str| -> Option<u64> {
+            conf_data.get(conf_name).and_then(|v| v.as_integer()).and_then(|v| {
+                if v < 0 {
+                    warn!("Ignoring negative `{}` in config: {}", conf_name, v);
+                    None
+                } else {
+                    Some(v as u64)
+                }
+            })
+        };
+
         fn string_from_env(env_name: &str) -> Option<String> {
-            env::var(env_name).ok()
+            env_var_os(env_name).and_then(|v| v.into_string().ok())
         }
 
         fn bool_from_env(env_name: &str) -> Option<bool> {
-            env::var(env_name).ok().map(|v| v != "0")
+            string_from_env(env_name).map(|v| v != "0")
         }
 
         fn usize_from_env(env_name: &str) -> Option<usize> {
-            env::var(env_name).ok().and_then(|v| parse_size(&v))
+            string_from_env(env_name).and_then(|v| parse_size(&v))
+        }
+
+        fn u64_from_env(env_name: &str) -> Option<u64> {
+            string_from_env(env_name).and_then(|v| match v.parse() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    warn!("Ignoring unparsable {}: {:?}", env_name, v);
+                    None
+                }
+            })
         }
 
 
         let mut conf = Config {
             stats_path: None,
+            port_file: None,
             cache_type: CacheType::Invalid,
             no_daemon: false,
             force_recache: false,
             msvc_force_z7: false,
+            allow_pgo: true,
             compiler_dir: None,
+            http_timeout: None,
+            cache_ttl: None,
+            cache_retry: DEFAULT_CACHE_RETRY,
+            max_memory: None,
+            memcache_size: None,
+            socket_options: SocketOptions::default(),
+            key_hook: None,
+            nocache_on_stderr: None,
+            min_compile_time: None,
+            num_workers: num_cpus::get(),
+            max_parallel: None,
+            force_compiler: None,
+            prometheus_addr: None,
+            max_outputs: DEFAULT_MAX_OUTPUTS,
+            compress_protocol: true,
+            server_tcp: false,
+            compile_timeout: None,
+            idle_timeout: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT)),
+            basedir: None,
+            dist_scheduler: None,
+            dist_token: None,
+            no_server: false,
+            direct_mode: false,
         };
 
-        conf.stats_path = app_dir(AppDataType::UserCache, &APP_INFO, "").map(|p| p.join("saved-stats.json")).ok();
+        conf.stats_path = user_cache_dir().map(|p| p.join("saved-stats.json"));
+        conf.port_file = user_cache_dir().map(|p| p.join("server-port"));
 
         //println!("Cache type from config: {:?}", conf_data.get("cache_type"));
 
+        let cache_type_from_config = |name: &str| -> Result<CacheType> {
+            Ok(match name {
+                "disk" => {
+                    let cache_dir = string_from_config("cache_dir")
+                        .map(|s| expand_path(s))
+                        .unwrap_or_else(|| default_disk_cache_dir());
+                    CacheType::Disk(DiskCacheConfig { cache_dir: cache_dir, cache_size: TEN_GIGS, max_files: None, shard_dirs: vec![] })
+                },
+                "redis" => {
+                    let redis_url = string_from_config("redis_url").ok_or("missing redis_url for redis cache")?;
+                    CacheType::Redis(RedisCacheConfig { url: redis_url.to_owned(), key_prefix: String::new() })
+                },
+                "s3" => {
+                    let s3_bucket = string_from_config("s3_bucket").ok_or("missing s3_bucket in config")?;
+                    let s3_endpoint = string_from_config("s3_endpoint").ok_or("missing s3_endpoint in config")?;
+                    CacheType::S3(S3CacheConfig {
+                        bucket: s3_bucket.to_owned(),
+                        endpoint: s3_endpoint.to_owned(),
+                        key_prefix: String::new(),
+                        server_side_encryption: None,
+                        kms_key_id: None,
+                        compute_size: false,
+                    })
+                },
+                s => {
+                    bail!("cache_type must be 'disk', 'redis', or 's3' (got '{}')", s);
+                },
+            })
+        };
+
         conf.cache_type = match conf_data.get("cache_type").and_then(|s| s.as_str()) {
             None => CacheType::Invalid,
-            Some("disk") => {
-                let cache_dir = string_from_config("cache_dir")
-                    .map(|s| PathBuf::from(s))
-                    .unwrap_or_else(|| default_disk_cache_dir());
-                CacheType::Disk(DiskCacheConfig { cache_dir: cache_dir, cache_size: TEN_GIGS })
-            },
-            Some("redis") => {
-                let redis_url = string_from_config("redis_url").expect("missing redis_url for redis cache");
-                CacheType::Redis(RedisCacheConfig { url: redis_url.to_owned() })
-            },
-            Some("s3") => {
-                let s3_bucket = string_from_config("s3_bucket").expect("missing s3_bucket in config");
-                let s3_endpoint = string_from_config("s3_endpoint").expect("missing s3_endpoint in config");
-                CacheType::S3(S3CacheConfig {
-                    bucket: s3_bucket.to_owned(),
-                    endpoint: s3_endpoint.to_owned(),
-                })
-            },
-            Some(s) => {
-                panic!("cache_type must be 'disk', 'redis', or 's3' (got '{}')", s);
-            },
+            Some(s) => cache_type_from_config(s)?,
         };
 
+        // An ordered list of caches, each set up like it was the only
+        // `cache_type`, overrides any single cache type.
+        let cache_backends = string_from_env("SCCACHE_CACHE_BACKENDS")
+            .map(|s| s.split(',').map(|s| s.trim().to_owned()).filter(|s| !s.is_empty()).collect())
+            .or_else(|| conf_data.get("cache_backends").and_then(|v| v.as_array()).map(|a| {
+                a.iter().filter_map(|v| v.as_str()).map(|s| s.to_owned()).collect::<Vec<_>>()
+            }))
+            .unwrap_or_default();
+
         // Handle legacy env vars for cache type setup; don't add any more of these!
 
-        if env::var("SCCACHE_REDIS").is_ok() {
+        if !cache_backends.is_empty() {
+            let tiers = cache_backends.iter()
+                .map(|name| cache_type_from_config(name.as_str()))
+                .collect::<Result<Vec<_>>>()?;
+            conf.cache_type = CacheType::Chain(tiers);
+        } else if env::var("SCCACHE_REDIS").is_ok() {
             let redis_url = string_from_env("SCCACHE_REDIS").unwrap();
-            conf.cache_type = CacheType::Redis(RedisCacheConfig { url: redis_url });
+            conf.cache_type = CacheType::Redis(RedisCacheConfig { url: redis_url, key_prefix: String::new() });
         } else if env::var("SCCACHE_BUCKET").is_ok() ||
             env::var("SCCACHE_ENDPOINT").is_ok() ||
             env::var("SCCACHE_REGION").is_ok()
         {
             if let Ok(bucket) = env::var("SCCACHE_BUCKET") {
-                let endpoint = match env::var("SCCACHE_ENDPOINT") {
-                    Ok(endpoint) => format!("{}/{}", endpoint, bucket),
-                    _ => match env::var("SCCACHE_REGION") {
-                        Ok(ref region) if region != "us-east-1" =>
-                            format!("{}.s3-{}.amazonaws.com", bucket, region),
-                        _ => format!("{}.s3.amazonaws.com", bucket),
-                    },
-                };
+                let endpoint = env::var("SCCACHE_ENDPOINT").ok();
+                let region = env::var("SCCACHE_REGION").ok();
+                let endpoint = s3_endpoint(&bucket,
+                                           endpoint.as_ref().map(|s| s.as_str()),
+                                           region.as_ref().map(|s| s.as_str()));
 
                 conf.cache_type = CacheType::S3(S3CacheConfig {
                     bucket: bucket,
                     endpoint: endpoint,
+                    key_prefix: String::new(),
+                    server_side_encryption: None,
+                    kms_key_id: None,
+                    compute_size: false,
                 });
             }
         } else if conf.cache_type == CacheType::Invalid {
             let cache_dir = string_from_env("SCCACHE_DIR")
-                .map(|s| PathBuf::from(s))
+                .map(|s| expand_path(&s))
                 .unwrap_or_else(|| default_disk_cache_dir());
-            conf.cache_type = CacheType::Disk(DiskCacheConfig { cache_dir: cache_dir, cache_size: TEN_GIGS });
+            conf.cache_type = CacheType::Disk(DiskCacheConfig { cache_dir: cache_dir, cache_size: TEN_GIGS, max_files: None, shard_dirs: vec![] });
         }
 
         // Handle common conf/env var configs
+        let apply_common_config = |cache_type: &mut CacheType| -> Result<()> {
+            match *cache_type {
+                CacheType::Disk(ref mut c) => {
+                    c.cache_size = usize_from_env("SCCACHE_SIZE")
+                        .or_else(|| usize_from_config("cache_size"))
+                        .unwrap_or(TEN_GIGS);
+                    c.max_files = string_from_env("SCCACHE_MAX_FILES").and_then(|v| v.parse().ok())
+                        .or_else(|| conf_data.get("cache_max_files").and_then(|v| v.as_integer()))
+                        .and_then(|n: i64| if n > 0 { Some(n as usize) } else { None });
+                    c.shard_dirs = match env::var_os("SCCACHE_DIRS") {
+                        Some(dirs) => env::split_paths(&dirs)
+                            .filter(|d| !d.as_os_str().is_empty())
+                            .collect(),
+                        None => conf_data.get("cache_dirs").and_then(|v| v.as_array())
+                            .map(|dirs| dirs.iter().filter_map(|d| d.as_str()).map(expand_path).collect())
+                            .unwrap_or_default(),
+                    };
+                }
+                CacheType::Redis(ref mut c) => {
+                    c.key_prefix = string_from_env("SCCACHE_REDIS_PREFIX")
+                        .or_else(|| string_from_config("redis_prefix").map(|s| s.to_owned()))
+                        .unwrap_or_default();
+                }
+                CacheType::S3(ref mut c) => {
+                    c.key_prefix = string_from_env("SCCACHE_S3_KEY_PREFIX")
+                        .or_else(|| string_from_config("s3_key_prefix").map(|s| s.to_owned()))
+                        .unwrap_or_default();
+                    c.server_side_encryption = string_from_env("SCCACHE_S3_SSE")
+                        .or_else(|| string_from_config("s3_sse").map(|s| s.to_owned()));
+                    c.kms_key_id = string_from_env("SCCACHE_S3_KMS_KEY_ID")
+                        .or_else(|| string_from_config("s3_kms_key_id").map(|s| s.to_owned()));
+                    c.compute_size = bool_from_env("SCCACHE_S3_COMPUTE_SIZE")
+                        .or(bool_from_config("s3_compute_size"))
+                        .unwrap_or(false);
+                    match c.server_side_encryption.as_ref().map(|s| s.as_str()) {
+                        None | Some("AES256") if c.kms_key_id.is_some() => {
+                            bail!("s3_kms_key_id requires s3_sse to be 'aws:kms'");
+                        }
+                        None | Some("AES256") => {}
+                        // S3 only accepts SSE-KMS over HTTPS with SigV4
+                        // signatures, and this backend makes plain HTTP
+                        // requests signed with SigV2.
+                        Some("aws:kms") => bail!("s3_sse 'aws:kms' requires HTTPS and SigV4 requests, which the S3 cache doesn't support yet; use 'AES256'"),
+                        Some(s) => bail!("s3_sse must be 'AES256' or 'aws:kms' (got '{}')", s),
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        };
         match conf.cache_type {
-            CacheType::Disk(ref mut c) => {
-                c.cache_size = usize_from_env("SCCACHE_SIZE")
-                    .or_else(|| usize_from_config("cache_size"))
-                    .unwrap_or(TEN_GIGS);
+            CacheType::Chain(ref mut tiers) => {
+                for tier in tiers.iter_mut() {
+                    apply_common_config(tier)?;
+                }
             }
-            _ => {}
+            ref mut cache_type => apply_common_config(cache_type)?,
         }
-                
+
         conf.no_daemon = bool_from_env("SCCACHE_NO_DAEMON").or(bool_from_config("no_daemon")).unwrap_or(false);
         conf.force_recache = bool_from_env("SCCACHE_RECACHE").or(bool_from_config("force_recache")).unwrap_or(false);
         conf.msvc_force_z7 = bool_from_config("msvc_force_z7").unwrap_or(false);
-        conf.compiler_dir = string_from_config("compiler_dir").map(|s| PathBuf::from(s));
+        conf.allow_pgo = bool_from_env("SCCACHE_ALLOW_PGO").or(bool_from_config("allow_pgo")).unwrap_or(true);
+        conf.compress_protocol = bool_from_env("SCCACHE_COMPRESS_PROTOCOL").or(bool_from_config("compress_protocol")).unwrap_or(true);
+        conf.server_tcp = bool_from_env("SCCACHE_SERVER_TCP").or(bool_from_config("server_tcp")).unwrap_or(false);
+        conf.compiler_dir = string_from_config("compiler_dir").map(|s| expand_path(s));
+        conf.http_timeout = u64_from_env("SCCACHE_HTTP_TIMEOUT").or_else(|| u64_from_config("http_timeout"))
+            .map(Duration::from_secs);
+        conf.cache_ttl = u64_from_env("SCCACHE_CACHE_TTL").or_else(|| u64_from_config("cache_ttl"))
+            .and_then(|v| if v > 0 { Some(Duration::from_secs(v)) } else { None });
+        if let Some(cache_retry) = env::var("SCCACHE_CACHE_RETRY").ok().and_then(|v| v.parse().ok())
+            .or_else(|| conf_data.get("cache_retry").and_then(|v| v.as_integer()))
+            .and_then(|n: i64| if n > 0 { Some(n as u32) } else { None })
+        {
+            conf.cache_retry = cache_retry;
+        }
+        conf.compile_timeout = u64_from_env("SCCACHE_COMPILE_TIMEOUT").or_else(|| u64_from_config("compile_timeout"))
+            .and_then(|v| if v > 0 { Some(Duration::from_secs(v)) } else { None });
+        let idle_timeout = u64_from_env("SCCACHE_IDLE_TIMEOUT").or_else(|| u64_from_config("idle_timeout"));
+        if let Some(secs) = idle_timeout {
+            conf.idle_timeout = if secs > 0 { Some(Duration::from_secs(secs)) } else { None };
+        }
+        conf.basedir = string_from_env("SCCACHE_BASEDIR").map(|s| expand_path(&s))
+            .or_else(|| string_from_config("basedir").map(|s| expand_path(s)));
+        conf.dist_scheduler = string_from_env("SCCACHE_DIST_SCHEDULER")
+            .or_else(|| string_from_config("dist_scheduler").map(|s| s.to_owned()))
+            .and_then(|s| if s.is_empty() { None } else { Some(s) });
+        conf.dist_token = string_from_env("SCCACHE_DIST_TOKEN")
+            .or_else(|| string_from_config("dist_token").map(|s| s.to_owned()))
+            .and_then(|s| if s.is_empty() { None } else { Some(s) });
+        conf.no_server = bool_from_env("SCCACHE_IGNORE_SERVER").unwrap_or(false);
+        conf.direct_mode = bool_from_env("SCCACHE_DIRECT_MODE").or(bool_from_config("direct_mode")).unwrap_or(false);
+        conf.key_hook = string_from_env("SCCACHE_KEY_HOOK").map(|s| expand_path(&s))
+            .or_else(|| string_from_config("key_hook").map(|s| expand_path(s)));
+        conf.nocache_on_stderr = string_from_env("SCCACHE_NOCACHE_ON_STDERR_REGEX")
+            .or_else(|| string_from_config("nocache_on_stderr_regex").map(|s| s.to_owned()))
+            .and_then(|s| if s.is_empty() { None } else { Some(s) });
+        if let Some(ref re) = conf.nocache_on_stderr {
+            Regex::new(re).chain_err(|| format!("invalid nocache_on_stderr_regex '{}'", re))?;
+        }
+        conf.min_compile_time = u64_from_env("SCCACHE_MIN_COMPILE_MS").or_else(|| u64_from_config("min_compile_ms"))
+            .and_then(|v| if v > 0 { Some(Duration::from_millis(v)) } else { None });
+        conf.max_memory = usize_from_env("SCCACHE_MAX_MEMORY").or_else(|| usize_from_config("max_memory"));
+        conf.memcache_size = usize_from_env("SCCACHE_MEMCACHE_SIZE").or_else(|| usize_from_config("memcache_size"))
+            .and_then(|n| if n > 0 { Some(n) } else { None });
+        if let Some(num_workers) = env::var("SCCACHE_MAX_WORKERS").ok().and_then(|v| v.parse().ok())
+            .or_else(|| conf_data.get("num_workers").and_then(|v| v.as_integer()))
+            .and_then(|n: i64| if n > 0 { Some(n as usize) } else { None })
+        {
+            conf.num_workers = num_workers;
+        }
+        conf.max_parallel = env::var("SCCACHE_MAX_PARALLEL").ok().and_then(|v| v.parse().ok())
+            .or_else(|| conf_data.get("max_parallel").and_then(|v| v.as_integer()))
+            .and_then(|n: i64| if n > 0 { Some(n as usize) } else { None });
+        if let Some(max_outputs) = env::var("SCCACHE_MAX_OUTPUTS").ok().and_then(|v| v.parse().ok())
+            .or_else(|| conf_data.get("max_outputs").and_then(|v| v.as_integer()))
+            .and_then(|n: i64| if n > 0 { Some(n as usize) } else { None })
+        {
+            conf.max_outputs = max_outputs;
+        }
+        conf.force_compiler = match string_from_env("SCCACHE_FORCE_COMPILER")
+            .or_else(|| string_from_config("force_compiler").map(|s| s.to_owned()))
+        {
+            None => None,
+            Some(s) => Some(match s.as_str() {
+                "gcc" => CCompilerKind::GCC,
+                "clang" => CCompilerKind::Clang,
+                "msvc" => CCompilerKind::MSVC,
+                s => bail!("force_compiler must be 'gcc', 'clang', or 'msvc' (got '{}')", s),
+            }),
+        };
+        let prometheus_port = match env::var("SCCACHE_PROMETHEUS_PORT").ok() {
+            Some(v) => Some(v.parse::<u16>().chain_err(|| format!("invalid SCCACHE_PROMETHEUS_PORT '{}'", v))?),
+            None => conf_data.get("prometheus_port").and_then(|v| v.as_integer()).map(|v| v as u16),
+        };
+        if let Some(port) = prometheus_port {
+            let address = match string_from_env("SCCACHE_PROMETHEUS_ADDRESS")
+                .or_else(|| string_from_config("prometheus_address").map(|s| s.to_owned()))
+            {
+                Some(a) => a.parse::<IpAddr>().chain_err(|| format!("invalid prometheus_address '{}'", a))?,
+                None => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            };
+            conf.prometheus_addr = Some(SocketAddr::new(address, port));
+        }
+        if let Some(backlog) = env::var("SCCACHE_LISTEN_BACKLOG").ok().and_then(|v| v.parse().ok())
+            .or_else(|| conf_data.get("listen_backlog").and_then(|v| v.as_integer()).map(|v| v as i32))
+        {
+            conf.socket_options.backlog = backlog;
+        }
+        if let Some(reuse_address) = bool_from_env("SCCACHE_REUSE_ADDRESS").or(bool_from_config("reuse_address")) {
+            conf.socket_options.reuse_address = reuse_address;
+        }
+        if let Some(nodelay) = bool_from_env("SCCACHE_TCP_NODELAY").or(bool_from_config("tcp_nodelay")) {
+            conf.socket_options.nodelay = nodelay;
+        }
+
+        Ok(conf)
+    }
+}
+
+/// A setting of a loaded config, for `sccache --show-config`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigSetting {
+    pub name: String,
+    pub value: String,
+    /// Where the value came from: `env <VAR>`, `config <key>` or `default`.
+    pub source: String,
+}
+
+/// Hide the password in a URL like `redis://:password@host/`.
+fn redact_url(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(start), Some(end)) if start + 3 < end => {
+            format!("{}***{}", &url[..start + 3], &url[end..])
+        }
+        _ => url.to_owned(),
+    }
+}
+
+fn show_option<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_else(|| "none".to_owned())
+}
+
+fn show_path(path: &Option<PathBuf>) -> String {
+    show_option(&path.as_ref().map(|p| p.display()))
+}
 
-        conf
+fn show_duration(duration: &Option<Duration>) -> String {
+    show_option(&duration.map(|d| if d.subsec_nanos() == 0 {
+        format!("{}s", d.as_secs())
+    } else {
+        format!("{}ms", d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64)
+    }))
+}
+
+/// List the settings in `config`, which was loaded from the config file at
+/// `path` and the environment, along with where each one came from.
+pub fn describe_config(config: &Config, path: Option<PathBuf>) -> Result<Vec<ConfigSetting>> {
+    let conf_data = read_config_file(path.clone())?;
+    // The first of `env_names` that's set, or else the first of `conf_names`
+    // in the config file, is where a setting came from.
+    let source = |env_names: &[&str], conf_names: &[&str]| -> String {
+        for &name in env_names {
+            if env::var_os(name).is_some() {
+                return format!("env {}", name)
+            }
+            if let Some(alias) = ccache_alias(name) {
+                if env::var_os(alias).is_some() {
+                    return format!("env {}", alias)
+                }
+            }
+        }
+        for &name in conf_names {
+            if conf_data.get(name).is_some() {
+                return format!("config {}", name)
+            }
+        }
+        "default".to_owned()
+    };
+    let mut settings = vec![];
+    {
+        let mut add = |name: &str, value: String, source: String| {
+            settings.push(ConfigSetting {
+                name: name.to_owned(),
+                value: value,
+                source: source,
+            });
+        };
+        let config_file = match path {
+            Some(ref path) if path.exists() => path.display().to_string(),
+            Some(ref path) => format!("{} (not found)", path.display()),
+            None => "none".to_owned(),
+        };
+        add("config_file", config_file,
+            if env::var_os("SCCACHE_CONF").is_some() { "env SCCACHE_CONF".to_owned() } else { "default".to_owned() });
+
+        // Caches set up in the config file take their settings from it, and
+        // the environment only picks the directory of the default disk cache.
+        let cache_from_config = conf_data.get("cache_type").is_some() ||
+            conf_data.get("cache_backends").is_some() ||
+            env::var_os("SCCACHE_CACHE_BACKENDS").is_some();
+        {
+            let mut add_cache = |prefix: &str, cache_type: &CacheType| {
+                let name = |setting: &str| format!("{}{}", prefix, setting);
+                match *cache_type {
+                    CacheType::Disk(ref c) => {
+                        add(&name("cache_type"), "disk".to_owned(),
+                            source(&["SCCACHE_CACHE_BACKENDS"], &["cache_backends", "cache_type"]));
+                        add(&name("cache_dir"), c.cache_dir.display().to_string(),
+                            if cache_from_config { source(&[], &["cache_dir"]) } else { source(&["SCCACHE_DIR"], &[]) });
+                        add(&name("cache_size"), c.cache_size.to_string(), source(&["SCCACHE_SIZE"], &["cache_size"]));
+                        add(&name("cache_max_files"), show_option(&c.max_files),
+                            source(&["SCCACHE_MAX_FILES"], &["cache_max_files"]));
+                        let dirs = c.shard_dirs.iter().map(|d| d.display().to_string()).collect::<Vec<_>>();
+                        add(&name("cache_dirs"), format!("[{}]", dirs.join(", ")), source(&["SCCACHE_DIRS"], &["cache_dirs"]));
+                    }
+                    CacheType::Redis(ref c) => {
+                        add(&name("cache_type"), "redis".to_owned(),
+                            source(&["SCCACHE_CACHE_BACKENDS", "SCCACHE_REDIS"], &["cache_backends", "cache_type"]));
+                        add(&name("redis_url"), redact_url(&c.url), source(&["SCCACHE_REDIS"], &["redis_url"]));
+                        add(&name("redis_prefix"), c.key_prefix.clone(), source(&["SCCACHE_REDIS_PREFIX"], &["redis_prefix"]));
+                    }
+                    CacheType::S3(ref c) => {
+                        add(&name("cache_type"), "s3".to_owned(),
+                            source(&["SCCACHE_CACHE_BACKENDS", "SCCACHE_BUCKET"], &["cache_backends", "cache_type"]));
+                        add(&name("s3_bucket"), c.bucket.clone(), source(&["SCCACHE_BUCKET"], &["s3_bucket"]));
+                        add(&name("s3_endpoint"), c.endpoint.clone(),
+                            source(&["SCCACHE_ENDPOINT", "SCCACHE_REGION"], &["s3_endpoint"]));
+                        add(&name("s3_key_prefix"), c.key_prefix.clone(),
+                            source(&["SCCACHE_S3_KEY_PREFIX"], &["s3_key_prefix"]));
+                        add(&name("s3_sse"), show_option(&c.server_side_encryption), source(&["SCCACHE_S3_SSE"], &["s3_sse"]));
+                        add(&name("s3_kms_key_id"), show_option(&c.kms_key_id),
+                            source(&["SCCACHE_S3_KMS_KEY_ID"], &["s3_kms_key_id"]));
+                        add(&name("s3_compute_size"), c.compute_size.to_string(),
+                            source(&["SCCACHE_S3_COMPUTE_SIZE"], &["s3_compute_size"]));
+                    }
+                    CacheType::Chain(_) | CacheType::Invalid => {}
+                }
+            };
+            match config.cache_type {
+                CacheType::Chain(ref tiers) => {
+                    for (i, tier) in tiers.iter().enumerate() {
+                        add_cache(&format!("cache.{}.", i), tier);
+                    }
+                }
+                ref cache_type => add_cache("", cache_type),
+            }
+        }
+        add("cache_ttl", show_duration(&config.cache_ttl), source(&["SCCACHE_CACHE_TTL"], &["cache_ttl"]));
+        add("cache_retry", config.cache_retry.to_string(), source(&["SCCACHE_CACHE_RETRY"], &["cache_retry"]));
+        add("stats_path", show_path(&config.stats_path), "default".to_owned());
+        add("port_file", show_path(&config.port_file), "default".to_owned());
+        add("no_daemon", config.no_daemon.to_string(), source(&["SCCACHE_NO_DAEMON"], &["no_daemon"]));
+        add("force_recache", config.force_recache.to_string(), source(&["SCCACHE_RECACHE"], &["force_recache"]));
+        add("msvc_force_z7", config.msvc_force_z7.to_string(), source(&[], &["msvc_force_z7"]));
+        add("allow_pgo", config.allow_pgo.to_string(), source(&["SCCACHE_ALLOW_PGO"], &["allow_pgo"]));
+        add("compress_protocol", config.compress_protocol.to_string(),
+            source(&["SCCACHE_COMPRESS_PROTOCOL"], &["compress_protocol"]));
+        add("server_tcp", config.server_tcp.to_string(), source(&["SCCACHE_SERVER_TCP"], &["server_tcp"]));
+        add("compiler_dir", show_path(&config.compiler_dir), source(&[], &["compiler_dir"]));
+        add("http_timeout", show_duration(&config.http_timeout), source(&["SCCACHE_HTTP_TIMEOUT"], &["http_timeout"]));
+        add("compile_timeout", show_duration(&config.compile_timeout),
+            source(&["SCCACHE_COMPILE_TIMEOUT"], &["compile_timeout"]));
+        add("idle_timeout", show_duration(&config.idle_timeout), source(&["SCCACHE_IDLE_TIMEOUT"], &["idle_timeout"]));
+        add("basedir", show_path(&config.basedir), source(&["SCCACHE_BASEDIR"], &["basedir"]));
+        add("dist_scheduler", show_option(&config.dist_scheduler), source(&["SCCACHE_DIST_SCHEDULER"], &["dist_scheduler"]));
+        add("dist_token", show_option(&config.dist_token.as_ref().map(|_| "***")),
+            source(&["SCCACHE_DIST_TOKEN"], &["dist_token"]));
+        add("no_server", config.no_server.to_string(), source(&["SCCACHE_IGNORE_SERVER"], &[]));
+        add("direct_mode", config.direct_mode.to_string(), source(&["SCCACHE_DIRECT_MODE"], &["direct_mode"]));
+        add("key_hook", show_path(&config.key_hook), source(&["SCCACHE_KEY_HOOK"], &["key_hook"]));
+        add("nocache_on_stderr_regex", show_option(&config.nocache_on_stderr),
+            source(&["SCCACHE_NOCACHE_ON_STDERR_REGEX"], &["nocache_on_stderr_regex"]));
+        add("min_compile_time", show_duration(&config.min_compile_time),
+            source(&["SCCACHE_MIN_COMPILE_MS"], &["min_compile_ms"]));
+        add("max_memory", show_option(&config.max_memory), source(&["SCCACHE_MAX_MEMORY"], &["max_memory"]));
+        add("memcache_size", show_option(&config.memcache_size), source(&["SCCACHE_MEMCACHE_SIZE"], &["memcache_size"]));
+        add("num_workers", config.num_workers.to_string(), source(&["SCCACHE_MAX_WORKERS"], &["num_workers"]));
+        add("max_parallel", show_option(&config.max_parallel), source(&["SCCACHE_MAX_PARALLEL"], &["max_parallel"]));
+        add("max_outputs", config.max_outputs.to_string(), source(&["SCCACHE_MAX_OUTPUTS"], &["max_outputs"]));
+        add("force_compiler", show_option(&config.force_compiler.as_ref().map(|k| format!("{:?}", k).to_lowercase())),
+            source(&["SCCACHE_FORCE_COMPILER"], &["force_compiler"]));
+        add("prometheus_addr", show_option(&config.prometheus_addr),
+            source(&["SCCACHE_PROMETHEUS_PORT", "SCCACHE_PROMETHEUS_ADDRESS"], &["prometheus_port", "prometheus_address"]));
+        add("listen_backlog", config.socket_options.backlog.to_string(),
+            source(&["SCCACHE_LISTEN_BACKLOG"], &["listen_backlog"]));
+        add("reuse_address", config.socket_options.reuse_address.to_string(),
+            source(&["SCCACHE_REUSE_ADDRESS"], &["reuse_address"]));
+        add("tcp_nodelay", config.socket_options.nodelay.to_string(), source(&["SCCACHE_TCP_NODELAY"], &["tcp_nodelay"]));
     }
+    Ok(settings)
 }
 
 #[test]
 fn test_parse_size() {
     assert_eq!(None, parse_size(""));
-    assert_eq!(None, parse_size("100"));
+    assert_eq!(None, parse_size("G"));
+    assert_eq!(None, parse_size("10X"));
+    assert_eq!(None, parse_size("1.G"));
+    assert_eq!(None, parse_size("10iB"));
+    assert_eq!(Some(100), parse_size("100"));
+    assert_eq!(Some(500000000), parse_size("500000000"));
+    assert_eq!(Some(TEN_GIGS), parse_size("10g"));
+    assert_eq!(Some(1536 * 1024 * 1024), parse_size("1.5G"));
+    assert_eq!(Some(2048), parse_size("2KiB"));
+    assert_eq!(Some(2000), parse_size("2KB"));
+    assert_eq!(Some(10), parse_size("10B"));
     assert_eq!(Some(2048), parse_size("2K"));
     assert_eq!(Some(10 * 1024 * 1024), parse_size("10M"));
     assert_eq!(Some(TEN_GIGS), parse_size("10G"));
     assert_eq!(Some(1024 * TEN_GIGS), parse_size("10T"));
 }
+
+#[test]
+fn test_config_errors() {
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_config").unwrap();
+    let path = tempdir.path().join("config");
+    let read_config = |contents: &str| {
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        Config::from_file(Some(path.clone()))
+    };
+
+    // Malformed TOML names the file, and the cause has the parser's error.
+    let err = read_config("cache_type = \"disk\n").unwrap_err();
+    assert_eq!(format!("failed to parse {}", path.display()), err.to_string());
+    assert!(err.iter().count() > 1);
+
+    let err = read_config("cache_type = \"ftp\"\n").unwrap_err();
+    assert_eq!("cache_type must be 'disk', 'redis', or 's3' (got 'ftp')", err.to_string());
+
+    let err = read_config("cache_type = \"redis\"\n").unwrap_err();
+    assert_eq!("missing redis_url for redis cache", err.to_string());
+
+    let s3 = "cache_type = \"s3\"\ns3_bucket = \"bucket\"\ns3_endpoint = \"endpoint\"\n";
+    let err = read_config(&format!("{}s3_sse = \"rot13\"\n", s3)).unwrap_err();
+    assert_eq!("s3_sse must be 'AES256' or 'aws:kms' (got 'rot13')", err.to_string());
+    let err = read_config(&format!("{}s3_kms_key_id = \"key\"\n", s3)).unwrap_err();
+    assert_eq!("s3_kms_key_id requires s3_sse to be 'aws:kms'", err.to_string());
+    let err = read_config(&format!("{}s3_sse = \"aws:kms\"\ns3_kms_key_id = \"key\"\n", s3)).unwrap_err();
+    assert!(err.to_string().starts_with("s3_sse 'aws:kms' requires HTTPS and SigV4"));
+    assert!(read_config(&format!("{}s3_sse = \"AES256\"\n", s3)).is_ok());
+
+    assert!(read_config("cache_type = \"disk\"\n").is_ok());
+    assert!(Config::from_file(Some(tempdir.path().join("missing"))).is_ok());
+
+    let chain = read_config("cache_backends = [\"redis\", \"disk\"]\nredis_url = \"redis://cache\"\n").unwrap();
+    match chain.cache_type {
+        CacheType::Chain(ref tiers) => {
+            assert_eq!(2, tiers.len());
+            match (&tiers[0], &tiers[1]) {
+                (&CacheType::Redis(ref r), &CacheType::Disk(_)) => assert_eq!("redis://cache", r.url),
+                t => panic!("Unexpected cache types: {:?}", t),
+            }
+        }
+        ref t => panic!("Unexpected cache type: {:?}", t),
+    }
+    let err = read_config("cache_backends = [\"disk\", \"ftp\"]\n").unwrap_err();
+    assert_eq!("cache_type must be 'disk', 'redis', or 's3' (got 'ftp')", err.to_string());
+}
+
+#[test]
+fn test_config_json() {
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_config").unwrap();
+    let read_config = |name: &str, contents: &str| {
+        let path = tempdir.path().join(name);
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        Config::from_file(Some(path))
+    };
+    let toml = read_config("config.toml", r#"
+cache_type = "s3"
+s3_bucket = "bucket"
+s3_endpoint = "endpoint"
+s3_sse = "AES256"
+num_workers = 3
+msvc_force_z7 = true
+"#).unwrap();
+    let json = read_config("config.json", r#"{
+    "cache_type": "s3",
+    "s3_bucket": "bucket",
+    "s3_endpoint": "endpoint",
+    "s3_sse": "AES256",
+    "num_workers": 3,
+    "msvc_force_z7": true,
+    "compiler_dir": null
+}"#).unwrap();
+    assert_eq!(toml, json);
+    assert_eq!(3, json.num_workers);
+    assert!(json.msvc_force_z7);
+
+    // Arrays become TOML arrays.
+    let json = read_config("config.json", r#"{"cache_type": "disk", "cache_dirs": ["/a", "/b"]}"#).unwrap();
+    match json.cache_type {
+        CacheType::Disk(ref c) => assert_eq!(vec![PathBuf::from("/a"), PathBuf::from("/b")], c.shard_dirs),
+        ref t => panic!("Unexpected cache type: {:?}", t),
+    }
+
+    let err = read_config("config.json", "cache_type = \"disk\"\n").unwrap_err();
+    assert!(err.iter().count() > 1);
+    let err = read_config("config.json", "[1, 2]").unwrap_err();
+    assert_eq!("the config must be a JSON object", err.iter().nth(1).unwrap().to_string());
+}
+
+#[test]
+fn test_config_num_workers() {
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_config").unwrap();
+    let path = tempdir.path().join("config");
+    let read_config = |contents: &str| {
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        Config::from_file(Some(path.clone())).unwrap()
+    };
+    assert_eq!(3, read_config("num_workers = 3\n").num_workers);
+    // Zero means the default, one worker per CPU.
+    assert_eq!(num_cpus::get(), read_config("num_workers = 0\n").num_workers);
+    assert_eq!(num_cpus::get(), read_config("").num_workers);
+}
+
+#[test]
+fn test_config_max_outputs() {
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_config").unwrap();
+    let path = tempdir.path().join("config");
+    let read_config = |contents: &str| {
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        Config::from_file(Some(path.clone())).unwrap()
+    };
+    assert_eq!(4, read_config("max_outputs = 4\n").max_outputs);
+    assert_eq!(DEFAULT_MAX_OUTPUTS, read_config("max_outputs = 0\n").max_outputs);
+    assert_eq!(DEFAULT_MAX_OUTPUTS, read_config("").max_outputs);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_config_compiler_dir() {
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_config").unwrap();
+    let path = tempdir.path().join("config");
+    let read_config = |contents: &str| {
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        Config::from_file(Some(path.clone())).unwrap()
+    };
+    let home = env::home_dir().unwrap();
+    assert_eq!(Some(home.join("tc")), read_config("compiler_dir = \"~/tc\"\n").compiler_dir);
+    assert_eq!(None, read_config("").compiler_dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_compile_timeout() {
+    let env = |v: &str| vec![(OsString::from("SCCACHE_COMPILE_TIMEOUT"), OsString::from(v))];
+    assert_eq!(Some(Duration::from_secs(30)), compile_timeout(&env("30")));
+    assert_eq!(None, compile_timeout(&env("0")));
+    assert_eq!(CONFIG.compile_timeout, compile_timeout(&env("soon")));
+    assert_eq!(CONFIG.compile_timeout, compile_timeout(&[]));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_basedir() {
+    let env = |v: &str| vec![(OsString::from("SCCACHE_BASEDIR"), OsString::from(v))];
+    assert_eq!(Some(PathBuf::from("/build/abc")), basedir(&env("/build/abc")));
+    assert_eq!(None, basedir(&env("")));
+    assert_eq!(None, basedir(&env("build")));
+    assert_eq!(CONFIG.basedir, basedir(&[]));
+}
+
+#[test]
+fn test_direct_mode() {
+    let env = |v: &str| vec![(OsString::from("SCCACHE_DIRECT_MODE"), OsString::from(v))];
+    assert!(direct_mode(&env("1")));
+    assert!(!direct_mode(&env("0")));
+    assert_eq!(CONFIG.direct_mode, direct_mode(&[]));
+}
+
+#[test]
+fn test_ccache_env_aliases() {
+    let var = |k: &str, v: &str| (OsString::from(k), OsString::from(v));
+    let find = |env: &[(OsString, OsString)], name: &str| {
+        find_env_var(env, name).map(|v| v.into_string().unwrap())
+    };
+    let env = vec![var("CCACHE_RECACHE", "true")];
+    assert_eq!(Some("true".to_owned()), find(&env, "SCCACHE_RECACHE"));
+    // The sccache variable wins if both are set.
+    let env = vec![var("CCACHE_RECACHE", "1"), var("SCCACHE_RECACHE", "yes")];
+    assert_eq!(Some("yes".to_owned()), find(&env, "SCCACHE_RECACHE"));
+    assert_eq!(None, find(&env, "SCCACHE_COMPILE_TIMEOUT"));
+    // Sizes are in ccache's units, where a bare number is in gigabytes.
+    let size = |v: &str| find(&[var("CCACHE_MAXSIZE", v)], "SCCACHE_SIZE");
+    assert_eq!(Some("5000000000".to_owned()), size("5"));
+    assert_eq!(Some("5000000000".to_owned()), size("5G"));
+    assert_eq!(Some("1536".to_owned()), size("1.5Ki"));
+    assert_eq!(Some("2000000".to_owned()), size("2M"));
+    assert_eq!(None, size("0"));
+    assert_eq!(None, size("lots"));
+    // sccache keeps to a directory of its own in ccache's.
+    let dir = find(&[var("CCACHE_DIR", "/home/user/.ccache")], "SCCACHE_DIR");
+    assert_eq!(Some(Path::new("/home/user/.ccache").join("sccache")), dir.map(PathBuf::from));
+}
+
+#[test]
+fn test_config_force_compiler() {
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_config").unwrap();
+    let path = tempdir.path().join("config");
+    let read_config = |contents: &str| {
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        Config::from_file(Some(path.clone()))
+    };
+    assert_eq!(Some(CCompilerKind::Clang), read_config("force_compiler = \"clang\"\n").unwrap().force_compiler);
+    assert_eq!(None, read_config("").unwrap().force_compiler);
+    assert!(read_config("force_compiler = \"icc\"\n").is_err());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sudo_user_home_dir() {
+    use std::ffi::CStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Pretend the current user ran sudo.
+    let (user, home) = unsafe {
+        let pw = libc::getpwuid(libc::getuid());
+        assert!(!pw.is_null());
+        (OsString::from(CStr::from_ptr((*pw).pw_name).to_str().unwrap()),
+         PathBuf::from(::std::ffi::OsStr::from_bytes(CStr::from_ptr((*pw).pw_dir).to_bytes())))
+    };
+    assert_eq!(Some(home), sudo_user_home_dir(Some(user.clone()), true));
+    // Without elevation, or without SUDO_USER, the usual home is used.
+    assert_eq!(None, sudo_user_home_dir(Some(user), false));
+    assert_eq!(None, sudo_user_home_dir(None, true));
+    assert_eq!(None, sudo_user_home_dir(Some(OsString::from("sccache-test-no-such-user")), true));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sudo_chown_targets() {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempdir::TempDir;
+
+    let tempdir = TempDir::new("sccache_test_chown").unwrap();
+    let home = tempdir.path().join("home");
+    let owner = unsafe { libc::geteuid() };
+    fs::create_dir_all(home.join(".cache").join("sccache")).unwrap();
+    File::create(home.join(".cache").join("sccache").join("entry")).unwrap();
+    // Everything below the home directory is given away.
+    let entry = home.join(".cache").join("sccache").join("entry");
+    assert_eq!(vec![home.join(".cache"), home.join(".cache").join("sccache"), entry.clone()],
+               sudo_chown_targets(&entry, &home, owner));
+    // Nothing is given away that isn't owned by `owner`.
+    assert!(sudo_chown_targets(&entry, &home, owner + 1).is_empty());
+    // A symlink in the chain, and what it points to, are left alone.
+    fs::create_dir(tempdir.path().join("etc")).unwrap();
+    File::create(tempdir.path().join("etc").join("shadow")).unwrap();
+    symlink(tempdir.path().join("etc"), home.join(".cache").join("link")).unwrap();
+    assert_eq!(vec![home.join(".cache")],
+               sudo_chown_targets(&home.join(".cache").join("link").join("shadow"), &home, owner));
+    symlink(tempdir.path().join("etc").join("shadow"), home.join(".cache").join("sccache").join("lock")).unwrap();
+    assert_eq!(vec![home.join(".cache"), home.join(".cache").join("sccache")],
+               sudo_chown_targets(&home.join(".cache").join("sccache").join("lock"), &home, owner));
+    // Paths outside the home directory aren't given away.
+    assert!(sudo_chown_targets(&tempdir.path().join("etc"), &home, owner).is_empty());
+}
+
+#[test]
+fn test_expand_path() {
+    let home = env::home_dir().unwrap();
+    assert_eq!(home.join("foo"), expand_path("~/foo"));
+    assert_eq!(home, expand_path("~"));
+    env::set_var("SCCACHE_TEST_EXPAND_PATH", "/some/dir");
+    assert_eq!(PathBuf::from("/some/dir/foo"), expand_path("$SCCACHE_TEST_EXPAND_PATH/foo"));
+    assert_eq!(PathBuf::from("/some/dir_foo"), expand_path("${SCCACHE_TEST_EXPAND_PATH}_foo"));
+    if let Ok(home) = env::var("HOME") {
+        assert_eq!(PathBuf::from(format!("{}/foo", home)), expand_path("$HOME/foo"));
+    }
+    assert_eq!(PathBuf::from("/foo"), expand_path("$SCCACHE_TEST_UNSET_VARIABLE/foo"));
+    assert_eq!(PathBuf::from("/tmp/cache"), expand_path("/tmp/cache"));
+    assert_eq!(PathBuf::from("relative/~/$"), expand_path("relative/~/$"));
+}
+
+#[test]
+fn test_s3_endpoint() {
+    assert_eq!("bucket.s3.amazonaws.com", s3_endpoint("bucket", None, None));
+    assert_eq!("bucket.s3.amazonaws.com", s3_endpoint("bucket", None, Some("us-east-1")));
+    assert_eq!("bucket.s3.eu-west-1.amazonaws.com", s3_endpoint("bucket", None, Some("eu-west-1")));
+    assert_eq!("localhost:9000/bucket", s3_endpoint("bucket", Some("localhost:9000"), Some("eu-west-1")));
+}
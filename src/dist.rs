@@ -0,0 +1,471 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compiling cache misses on remote workers.
+//!
+//! The server sends the preprocessed source of a compile, which doesn't need
+//! any headers, to the scheduler in `SCCACHE_DIST_SCHEDULER` over HTTP. The
+//! scheduler is either a worker started with `sccache --start-worker`, or
+//! anything that forwards requests to one. The worker compiles the source
+//! with its own compiler of the same name, and sends back the object.
+//!
+//! Workers only accept compiles sent with the secret in `SCCACHE_DIST_TOKEN`,
+//! and only run well-known compilers with options that can't make them run
+//! other programs or touch other files.
+
+use bincode;
+use futures::{future, Future, Stream};
+use config;
+use futures_cpupool::CpuPool;
+use hyper::{self, Client, Method};
+use hyper::client::Request;
+use hyper::header::{Authorization, Bearer, ContentLength};
+use hyper::server::{Http, Response};
+use hyper::status::{StatusClass, StatusCode};
+use num_cpus;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::thread;
+use tempdir::TempDir;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use tokio_service::Service;
+use which::which_in;
+
+use errors::*;
+
+/// The compilers a worker runs, which may also have a version suffix, like
+/// `gcc-7` or `clang-5.0`.
+const COMPILERS: &'static [&'static str] = &["cc", "c++", "gcc", "g++", "clang", "clang++"];
+
+/// The languages a worker compiles, all of which are already preprocessed.
+const LANGUAGES: &'static [&'static str] = &[
+    "cpp-output",
+    "c++-cpp-output",
+    "objective-c-cpp-output",
+    "objective-c++-cpp-output",
+    "assembler",
+];
+
+/// The prefixes of the options a worker passes to the compiler.
+const ALLOWED_ARGUMENTS: &'static [&'static str] = &[
+    "-O", "-g", "-f", "-m", "-W", "-w", "-std=", "-pedantic", "-ansi",
+];
+
+/// The prefixes of options that are otherwise allowed, but that load code,
+/// pass options to other programs, or read or write files named by the
+/// option.
+const DENIED_ARGUMENTS: &'static [&'static str] = &[
+    "-fauto-profile",
+    "-fcoverage",
+    "-fcrash-diagnostics",
+    "-fdump",
+    "-fmodule",
+    "-fopt-info",
+    "-fpass-plugin",
+    "-fplugin",
+    "-fprebuilt",
+    "-fprofile",
+    "-fsanitize-blacklist",
+    "-fsanitize-coverage",
+    "-fsanitize-ignorelist",
+    "-fsave-optimization-record",
+    "-ftime-trace",
+    "-fuse-ld",
+    "-fxray",
+    "-gsplit-dwarf",
+    "-mllvm",
+    "-Wa,",
+    "-Wl,",
+    "-Wp,",
+];
+
+/// The largest compile request a worker reads, in bytes. The preprocessed
+/// source of a unity build can be over 100 MB.
+const MAX_REQUEST_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Return true if a worker may run the compiler with file name `name`.
+pub fn allowed_compiler(name: &str) -> bool {
+    let name = match name.rfind('-') {
+        Some(i) if i + 1 < name.len()
+            && name[i + 1..].chars().all(|c| c.is_digit(10) || c == '.') => &name[..i],
+        _ => name,
+    };
+    COMPILERS.contains(&name)
+}
+
+/// Return true if a worker may pass `arg` to the compiler.
+pub fn allowed_argument(arg: &str) -> bool {
+    // Paths would name files on the worker, and `native` would compile for
+    // the worker's machine instead of this one.
+    !arg.contains('/') && !arg.contains('\\') && !arg.ends_with("=native")
+        && ALLOWED_ARGUMENTS.iter().any(|prefix| arg.starts_with(prefix))
+        && !DENIED_ARGUMENTS.iter().any(|prefix| arg.starts_with(prefix))
+}
+
+/// Compare `token` with `expected` in time that doesn't depend on where
+/// they differ.
+fn token_matches(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// A compile for a worker to run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompileRequest {
+    /// The file name of the compiler, which the worker looks up in its `PATH`.
+    pub compiler: String,
+    /// The language of `source`, as passed to `-x`.
+    pub language: String,
+    /// Arguments to pass besides the input, output and language.
+    pub arguments: Vec<String>,
+    /// The preprocessed source to compile.
+    pub source: Vec<u8>,
+}
+
+/// The result of a compile run by a worker.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompileResponse {
+    /// The compiler's exit code, if it exited normally.
+    pub status: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The object the compiler wrote, if it succeeded.
+    pub object: Option<Vec<u8>>,
+}
+
+/// Send `request` to the scheduler at `scheduler`, with the secret `token`,
+/// and wait for the result.
+pub fn compile_remote(scheduler: &str, token: &str, request: &CompileRequest) -> Result<CompileResponse> {
+    let url = if scheduler.contains("://") {
+        format!("{}/compile", scheduler.trim_right_matches('/'))
+    } else {
+        format!("http://{}/compile", scheduler.trim_right_matches('/'))
+    };
+    let uri = url.parse().chain_err(|| format!("invalid dist scheduler '{}'", scheduler))?;
+    let body = bincode::serialize(request, bincode::Infinite)?;
+    let mut req = Request::new(Method::Post, uri);
+    req.headers_mut().set(ContentLength(body.len() as u64));
+    req.headers_mut().set(Authorization(Bearer { token: token.to_owned() }));
+    req.set_body(body);
+
+    let mut core = Core::new()?;
+    let client = Client::new(&core.handle());
+    let response = client.request(req).chain_err(move || {
+        format!("failed POST: {}", url)
+    }).and_then(|res| {
+        if res.status().class() == StatusClass::Success {
+            Ok(res.body())
+        } else {
+            Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+        }
+    }).and_then(|body| {
+        body.fold(Vec::new(), |mut body, chunk| {
+            body.extend_from_slice(&chunk);
+            Ok::<_, hyper::Error>(body)
+        }).chain_err(|| "failed to read HTTP body")
+    });
+    let body = core.run(response)?;
+    Ok(bincode::deserialize(&body)?)
+}
+
+/// Compile `request` on a worker, and write the object it sends back to
+/// `output`. Fails unless the compile succeeded, so that the caller can
+/// compile locally instead.
+pub fn compile(pool: &CpuPool, scheduler: String, token: String, request: CompileRequest,
+               output: PathBuf) -> SFuture<process::Output> {
+    Box::new(pool.spawn_fn(move || {
+        let response = compile_remote(&scheduler, &token, &request)?;
+        let object = match (response.status, response.object) {
+            (Some(0), Some(object)) => object,
+            (status, _) => bail!("remote compile failed with status {:?}", status),
+        };
+        File::create(&output).and_then(|mut f| f.write_all(&object))?;
+        Ok(process::Output {
+            status: success(),
+            stdout: response.stdout,
+            stderr: response.stderr,
+        })
+    }))
+}
+
+#[cfg(unix)]
+fn success() -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(0)
+}
+
+#[cfg(windows)]
+fn success() -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(0)
+}
+
+/// Run a worker listening on `addr` until the process is killed.
+pub fn start_worker(addr: &str) -> Result<()> {
+    let addr = addr.parse::<SocketAddr>()
+        .chain_err(|| format!("invalid worker address '{}'", addr))?;
+    let token = match config::CONFIG.dist_token {
+        Some(ref token) => token.clone(),
+        None => bail!("a dist worker needs SCCACHE_DIST_TOKEN set to the secret clients send"),
+    };
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle)?;
+    println!("sccache: dist worker listening on {}", listener.local_addr()?);
+    core.run(serve_worker(listener, env::var_os("PATH"), token, handle))?;
+    Ok(())
+}
+
+/// Run compiles sent with the secret `token` on connections accepted by
+/// `listener`, with compilers found in `path`.
+pub fn serve_worker(listener: TcpListener, path: Option<OsString>, token: String, handle: Handle)
+                    -> Box<Future<Item=(), Error=io::Error>> {
+    let pool = CpuPool::new(num_cpus::get());
+    Box::new(listener.incoming().for_each(move |(socket, addr)| {
+        trace!("incoming dist connection from {}", addr);
+        let service = WorkerService {
+            pool: pool.clone(),
+            path: path.clone(),
+            token: token.clone(),
+        };
+        Http::new().bind_connection(&handle, socket, addr, service);
+        Ok(())
+    }))
+}
+
+/// Runs the compiles POSTed to `/compile`.
+struct WorkerService {
+    pool: CpuPool,
+    path: Option<OsString>,
+    /// The secret that requests must be sent with.
+    token: String,
+}
+
+impl Service for WorkerService {
+    type Request = hyper::server::Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item=Response, Error=hyper::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if *req.method() != Method::Post || req.path() != "/compile" {
+            return Box::new(future::ok(Response::new().with_status(StatusCode::NotFound)))
+        }
+        let authorized = req.headers().get::<Authorization<Bearer>>()
+            .map_or(false, |auth| token_matches(&self.token, &auth.0.token));
+        if !authorized {
+            warn!("dist compile from {:?} without the right token", req.remote_addr());
+            return Box::new(future::ok(Response::new().with_status(StatusCode::Unauthorized)))
+        }
+        // Check the size of the request before reading it, and don't read
+        // more than it said.
+        let size = match req.headers().get::<ContentLength>() {
+            Some(&ContentLength(size)) if size <= MAX_REQUEST_SIZE => size as usize,
+            Some(&ContentLength(size)) => {
+                warn!("dist compile from {:?} too large: {} bytes", req.remote_addr(), size);
+                return Box::new(future::ok(Response::new().with_status(StatusCode::PayloadTooLarge)))
+            }
+            None => return Box::new(future::ok(Response::new().with_status(StatusCode::LengthRequired))),
+        };
+        let pool = self.pool.clone();
+        let path = self.path.clone();
+        Box::new(req.body().fold(Vec::new(), move |mut body, chunk| {
+            if body.len() + chunk.len() > size {
+                return Err(hyper::Error::TooLarge)
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        }).and_then(move |body| {
+            pool.spawn_fn(move || -> Result<Vec<u8>> {
+                let request = bincode::deserialize(&body)?;
+                let response = run_compile(request, path.as_ref().map(|p| p.as_os_str()))?;
+                Ok(bincode::serialize(&response, bincode::Infinite)?)
+            }).then(|result| {
+                let response = match result {
+                    Ok(body) => {
+                        Response::new().with_header(ContentLength(body.len() as u64))
+                                       .with_body(body)
+                    }
+                    Err(e) => {
+                        warn!("dist compile failed: {}", e);
+                        Response::new().with_status(StatusCode::InternalServerError)
+                    }
+                };
+                Ok::<_, hyper::Error>(response)
+            })
+        }))
+    }
+}
+
+/// Run the compile `request` with a compiler found in `path`.
+fn run_compile(request: CompileRequest, path: Option<&OsStr>) -> Result<CompileResponse> {
+    // Only run well-known compilers, with options that keep them to
+    // compiling the source they're sent.
+    if !allowed_compiler(&request.compiler) {
+        bail!("not an allowed compiler: '{}'", request.compiler);
+    }
+    if !LANGUAGES.contains(&request.language.as_str()) {
+        bail!("not an allowed language: '{}'", request.language);
+    }
+    if let Some(arg) = request.arguments.iter().find(|arg| !allowed_argument(arg)) {
+        bail!("not an allowed argument: '{}'", arg);
+    }
+    let cwd = env::current_dir()?;
+    let compiler = which_in(&request.compiler, path, &cwd)?;
+    let tempdir = TempDir::new("sccache_dist")?;
+    let object = tempdir.path().join("out.o");
+    let mut child = Command::new(&compiler)
+        .args(&["-c", "-x"])
+        .arg(&request.language)
+        .arg("-")
+        .arg("-o").arg(&object)
+        .args(&request.arguments)
+        .current_dir(tempdir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Write the source on another thread, so that a compiler with a lot to
+    // say on stderr can't deadlock us.
+    let mut stdin = child.stdin.take().unwrap();
+    let source = request.source;
+    let writer = thread::spawn(move || stdin.write_all(&source));
+    let output = child.wait_with_output()?;
+    // The compiler may not read all of its input if it fails.
+    drop(writer.join());
+    let object = if output.status.success() {
+        let mut contents = vec![];
+        File::open(&object).and_then(|mut f| f.read_to_end(&mut contents))?;
+        Some(contents)
+    } else {
+        None
+    };
+    Ok(CompileResponse {
+        status: output.status.code(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+        object: object,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use test::utils::*;
+
+    /// Run a worker with the token "secret" and compilers found in `path`
+    /// on a new thread, and return the address it listens on.
+    fn run_worker(path: Option<OsString>) -> SocketAddr {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+            tx.send(listener.local_addr().unwrap()).unwrap();
+            core.run(serve_worker(listener, path, "secret".to_owned(), handle)).unwrap();
+        });
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dist_compile() {
+        let f = TestFixture::new();
+        // A "compiler" that writes its input to the object.
+        mk_bin_contents(f.tempdir.path(), "gcc", |mut f| {
+            f.write_all(b"#!/bin/sh\nwhile [ \"$1\" != -o ]; do shift; done\ncat > \"$2\"\necho compiled >&2\n")
+        }).unwrap();
+        let scheduler = run_worker(Some(f.tempdir.path().as_os_str().to_owned())).to_string();
+        let request = CompileRequest {
+            compiler: "gcc".to_owned(),
+            language: "cpp-output".to_owned(),
+            arguments: vec!["-O2".to_owned()],
+            source: b"int main() { return 0; }".to_vec(),
+        };
+        let response = compile_remote(&scheduler, "secret", &request).unwrap();
+        assert_eq!(Some(0), response.status);
+        assert_eq!(b"compiled\n", response.stderr.as_slice());
+        assert_eq!(Some(request.source.clone()), response.object);
+        // The object is written out locally.
+        let obj = f.tempdir.path().join("foo.o");
+        let pool = CpuPool::new(1);
+        // Requests without the right token are refused.
+        assert!(compile_remote(&scheduler, "guess", &request).is_err());
+        let output = compile(&pool, scheduler.clone(), "secret".to_owned(), request, obj.clone())
+            .wait().unwrap();
+        assert!(output.status.success());
+        let mut contents = vec![];
+        File::open(&obj).and_then(|mut f| f.read_to_end(&mut contents)).unwrap();
+        assert_eq!(b"int main() { return 0; }", contents.as_slice());
+        // Workers only run known compilers, with options that can't run
+        // other programs.
+        for &(compiler, arg) in &[("/bin/sh", "-O2"), ("sh", "-O2"), ("python", "-O2"),
+                                  ("gcc", "-wrapper"), ("gcc", "-fplugin=evil"),
+                                  ("gcc", "-B/tmp"), ("gcc", "-specs=evil"), ("gcc", "@args")] {
+            let request = CompileRequest {
+                compiler: compiler.to_owned(),
+                language: "cpp-output".to_owned(),
+                arguments: vec![arg.to_owned()],
+                source: vec![],
+            };
+            assert!(compile_remote(&scheduler, "secret", &request).is_err(),
+                    "{} {} was compiled", compiler, arg);
+        }
+    }
+
+    #[test]
+    fn test_worker_request_size() {
+        use std::net::TcpStream;
+
+        let addr = run_worker(None);
+        // Send a request with `headers` and no body, and return the status
+        // line of the response.
+        let status = |headers: &str| {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            write!(stream, "POST /compile HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer secret\r\n{}\r\n",
+                   addr, headers).unwrap();
+            let mut response = vec![];
+            let _ = stream.read_to_end(&mut response);
+            let response = String::from_utf8_lossy(&response).into_owned();
+            response.lines().next().unwrap_or("").to_owned()
+        };
+        assert!(status(&format!("Content-Length: {}\r\nConnection: close\r\n", MAX_REQUEST_SIZE + 1))
+                .contains("413"));
+        assert!(status("Connection: close\r\n").contains("411"));
+    }
+
+    #[test]
+    fn test_allowed() {
+        assert!(allowed_compiler("gcc"));
+        assert!(allowed_compiler("clang++-5.0"));
+        assert!(allowed_compiler("g++-7"));
+        assert!(!allowed_compiler("sh"));
+        assert!(!allowed_compiler("gcc-wrapper"));
+        assert!(!allowed_compiler("/usr/bin/gcc"));
+        for arg in &["-O2", "-g", "-std=c++14", "-fPIC", "-Wall", "-Werror=format", "-march=x86-64"] {
+            assert!(allowed_argument(arg), "{} not allowed", arg);
+        }
+        for arg in &["-wrapper", "-fplugin=foo.so", "-B", "-specs=foo", "@file", "-Wl,-rpath",
+                     "-fprofile-use=x.profdata", "-mllvm", "-march=native", "-Xclang", "-I/usr"] {
+            assert!(!allowed_argument(arg), "{} allowed", arg);
+        }
+    }
+}
@@ -16,6 +16,7 @@ use std::boxed::Box;
 use std::error;
 use std::io;
 use std::process;
+use std::time::Duration;
 
 use bincode;
 use futures::Future;
@@ -23,6 +24,7 @@ use futures::future;
 #[cfg(feature = "hyper")]
 use hyper;
 use lru_disk_cache;
+use util::fmt_duration_as_secs;
 #[cfg(feature = "serde_json")]
 use serde_json;
 #[cfg(feature = "redis")]
@@ -44,7 +46,23 @@ error_chain! {
             description("failed to get a successful HTTP status")
             display("didn't get a successful HTTP status, got `{}`", status)
         }
+        RequestTimedOut(timeout: Duration) {
+            description("a request didn't finish in time")
+            display("HTTP request timed out after {}", fmt_duration_as_secs(timeout))
+        }
         ProcessError(output: process::Output)
+        CompileTimedOut {
+            description("the compile took longer than SCCACHE_COMPILE_TIMEOUT")
+            display("the compile took longer than SCCACHE_COMPILE_TIMEOUT")
+        }
+        ServerVersionMismatch(server: u32, client: u32) {
+            description("sccache server speaks a different protocol version")
+            display("sccache server version {} differs from client {}; run --stop-server", server, client)
+        }
+        CompilerKilled(signal: i32) {
+            description("the compiler was killed by a signal")
+            display("the compiler was killed by signal {}", signal)
+        }
     }
 }
 
@@ -0,0 +1,252 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The server's log file, which can be rotated once it gets too big, so
+//! that a long-lived server doesn't fill the disk with logs.
+
+use fern;
+use log;
+use serde_json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time;
+
+/// How many rotated log files are kept unless `SCCACHE_LOG_KEEP` says
+/// otherwise.
+pub const DEFAULT_KEEP: usize = 3;
+
+/// How log records are written out, chosen by `SCCACHE_LOG_FORMAT`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `[date][time][level] message`, for people to read.
+    Text,
+    /// One JSON object per record, for tools to read.
+    Json,
+}
+
+/// A log record, as written in the JSON format.
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    message: &'a str,
+}
+
+impl LogFormat {
+    /// Format the record `msg`, logged at `level` by the module `target`, as
+    /// a single line (unless it's text with newlines in it).
+    pub fn format(&self, msg: &str, level: &log::LogLevel, target: &str) -> String {
+        match *self {
+            LogFormat::Text => {
+                format!("[{}][{}] {}", time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(), level, msg)
+            }
+            LogFormat::Json => {
+                let record = JsonRecord {
+                    timestamp: time::now_utc().rfc3339().to_string(),
+                    level: level.to_string(),
+                    target: target,
+                    message: msg,
+                };
+                serde_json::to_string(&record).unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// Log output to stdout, in the text format.
+pub struct StdoutLog;
+
+impl fern::Logger for StdoutLog {
+    fn log(&self, msg: &str, level: &log::LogLevel, location: &log::LogLocation)
+           -> Result<(), fern::LogError> {
+        let line = LogFormat::Text.format(msg, level, location.module_path()) + "\n";
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        Ok(stdout.write_all(line.as_bytes())?)
+    }
+}
+
+/// A log file that, if `max_size` is set, is renamed once writing to it
+/// would take it over `max_size` bytes: `sccache.log` becomes
+/// `sccache.log.1`, `sccache.log.1` becomes `sccache.log.2`, and so on,
+/// keeping at most `keep` of them.
+pub struct RotatingLogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    keep: usize,
+    format: LogFormat,
+    /// The log file, once it's open, and its size.
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl RotatingLogFile {
+    pub fn new<P: Into<PathBuf>>(path: P, max_size: Option<u64>, keep: usize, format: LogFormat)
+                                 -> RotatingLogFile {
+        RotatingLogFile {
+            path: path.into(),
+            max_size: max_size,
+            keep: keep,
+            format: format,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Return the path of the `n`th most recently rotated log file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn open(&self) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return remove_if_exists(&self.path)
+        }
+        remove_if_exists(&self.rotated_path(self.keep))?;
+        for n in (1..self.keep).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    /// Append `line` to the log, rotating it first if the line would take
+    /// it over the size limit. A single line longer than the limit is still
+    /// written, to a file of its own. The line is written all at once, so
+    /// that it isn't interleaved with lines from other processes.
+    pub fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut state = match self.file.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if state.is_none() {
+            *state = Some(self.open()?);
+        }
+        let line = format!("{}\n", line);
+        let len = line.len() as u64;
+        let max_size = self.max_size;
+        if state.as_ref().map_or(false, |&(_, size)| {
+            max_size.map_or(false, |max_size| size > 0 && size + len > max_size)
+        }) {
+            *state = None;
+            self.rotate()?;
+            *state = Some(self.open()?);
+        }
+        if let Some((ref mut file, ref mut size)) = *state {
+            file.write_all(line.as_bytes())?;
+            *size += len;
+        }
+        Ok(())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl fern::Logger for RotatingLogFile {
+    fn log(&self, msg: &str, level: &log::LogLevel, location: &log::LogLocation)
+           -> Result<(), fern::LogError> {
+        Ok(self.write_line(&self.format.format(msg, level, location.module_path()))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::thread;
+    use tempdir::TempDir;
+
+    fn read(path: &Path) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_rotate() {
+        let td = TempDir::new("sccache_test_log").unwrap();
+        let path = td.path().join("sccache.log");
+        // Room for two 9-byte lines per file.
+        let log = RotatingLogFile::new(&path, Some(20), 2, LogFormat::Text);
+        for i in 0..9 {
+            log.write_line(&format!("line {:03}", i)).unwrap();
+        }
+        assert_eq!("line 008\n", read(&path));
+        assert_eq!("line 006\nline 007\n", read(&log.rotated_path(1)));
+        assert_eq!("line 004\nline 005\n", read(&log.rotated_path(2)));
+        // Older files were removed.
+        assert!(!log.rotated_path(3).exists());
+        assert_eq!(3, fs::read_dir(td.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_rotate_existing() {
+        let td = TempDir::new("sccache_test_log").unwrap();
+        let path = td.path().join("sccache.log");
+        File::create(&path).unwrap().write_all(b"from a previous server\n").unwrap();
+        let log = RotatingLogFile::new(&path, Some(20), 0, LogFormat::Text);
+        log.write_line("line 000").unwrap();
+        log.write_line("line 001").unwrap();
+        // Without any rotated files to keep, the log starts over.
+        assert_eq!("line 000\nline 001\n", read(&path));
+        assert_eq!(1, fs::read_dir(td.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_json_format() {
+        let td = TempDir::new("sccache_test_log").unwrap();
+        let path = td.path().join("sccache.log");
+        let log = Arc::new(RotatingLogFile::new(&path, None, 0, LogFormat::Json));
+        // Log from several threads at once, like concurrent compiles do.
+        let threads = (0..4).map(|t| {
+            let log = log.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let msg = format!("thread {} says \"{}\"\nover two lines", t, i);
+                    let line = log.format.format(&msg, &log::LogLevel::Debug, "sccache::server");
+                    log.write_line(&line).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let contents = read(&path);
+        assert_eq!(200, contents.lines().count());
+        for line in contents.lines() {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(Some("DEBUG"), record["level"].as_str());
+            assert_eq!(Some("sccache::server"), record["target"].as_str());
+            assert!(record["message"].as_str().unwrap().ends_with("\nover two lines"));
+            assert!(record["timestamp"].is_string());
+        }
+    }
+}
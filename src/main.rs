@@ -15,6 +15,7 @@
 extern crate app_dirs;
 extern crate bincode;
 extern crate byteorder;
+extern crate bytes;
 #[cfg(feature = "chrono")]
 extern crate chrono;
 #[macro_use]
@@ -27,6 +28,7 @@ extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
 extern crate filetime;
+extern crate flate2;
 #[macro_use]
 extern crate futures;
 extern crate futures_cpupool;
@@ -35,11 +37,15 @@ extern crate hyper;
 #[cfg(feature = "hyper-tls")]
 extern crate hyper_tls;
 #[cfg(windows)]
+extern crate advapi32;
+#[cfg(windows)]
 extern crate kernel32;
 extern crate local_encoding;
 #[macro_use]
 extern crate log;
+extern crate lru_cache;
 extern crate lru_disk_cache;
+extern crate net2;
 extern crate fern;
 #[cfg(test)]
 extern crate itertools;
@@ -48,6 +54,7 @@ extern crate lazy_static;
 extern crate libc;
 #[cfg(windows)]
 extern crate mio_named_pipes;
+extern crate num_cpus;
 extern crate number_prefix;
 extern crate ring;
 #[cfg(feature = "redis")]
@@ -58,6 +65,8 @@ extern crate rustc_serialize;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(unix)]
+extern crate syslog;
 extern crate tempdir;
 extern crate time;
 extern crate tokio_core;
@@ -66,6 +75,8 @@ extern crate tokio_process;
 extern crate tokio_proto;
 extern crate tokio_service;
 extern crate tokio_serde_bincode;
+#[cfg(unix)]
+extern crate tokio_uds;
 extern crate toml;
 extern crate uuid;
 #[cfg(windows)]
@@ -87,11 +98,18 @@ mod cmdline;
 mod commands;
 mod compiler;
 mod config;
+#[cfg(feature = "dist")]
+mod dist;
+mod log_file;
 mod mock_command;
+#[cfg(windows)]
+mod named_pipe;
 mod protocol;
 mod server;
 #[cfg(feature = "simple-s3")]
 mod simples3;
+#[cfg(unix)]
+mod unix_socket;
 mod util;
 mod env_splitter;
 mod osstringext;
@@ -104,15 +122,22 @@ fn main() {
     std::process::exit(match cmdline::parse() {
         Ok(cmd) => {
             //println!("Cache config: {:?}", *config::CONFIG);
-            match commands::run_command(cmd) {
-                Ok(s) => s,
-                Err(e) =>  {
-                    let stderr = &mut std::io::stderr();
-                    writeln!(stderr, "error: {}", e).unwrap();
+            match config::check_config() {
+                Ok(()) => match commands::run_command(cmd) {
+                    Ok(s) => s,
+                    Err(e) =>  {
+                        let stderr = &mut std::io::stderr();
+                        writeln!(stderr, "error: {}", e).unwrap();
 
-                    for e in e.iter().skip(1) {
-                        writeln!(stderr, "caused by: {}", e).unwrap();
+                        for e in e.iter().skip(1) {
+                            writeln!(stderr, "caused by: {}", e).unwrap();
+                        }
+                        2
                     }
+                },
+                Err(e) => {
+                    let stderr = &mut std::io::stderr();
+                    writeln!(stderr, "error: invalid sccache config: {}", e).unwrap();
                     2
                 }
             }
@@ -143,16 +168,31 @@ fn init_logging() {
                     _ => panic!("Invalid log level {}", log_level),
                 };
 
-                let logger_config = fern::DispatchConfig {
-                    format: Box::new(|msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
-                        format!("[{}][{}] {}", time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(), level, msg)
-                    }),
-                    //TODO: only the server process should output to the log file.
-                    output: vec![fern::OutputConfig::stdout(), fern::OutputConfig::file("sccache.log")],
-                    level: log::LogLevelFilter::Trace,
+                let log_format = match env::var("SCCACHE_LOG_FORMAT") {
+                    Err(_) => log_file::LogFormat::Text,
+                    Ok(ref f) if f.to_lowercase() == "text" => log_file::LogFormat::Text,
+                    Ok(ref f) if f.to_lowercase() == "json" => log_file::LogFormat::Json,
+                    Ok(f) => panic!("Invalid log format {}", f),
                 };
-                fern::init_global_logger(logger_config, log_level)
-                    .map_err(|e| format!("{:?}", e))
+
+                init_syslog(log_level).unwrap_or_else(|| {
+                    let mut output = vec![fern::OutputConfig::custom(Box::new(log_file::StdoutLog))];
+                    // Only the server writes the log file, so that clients
+                    // don't scatter logs over every directory they run in.
+                    if env::var("SCCACHE_START_SERVER").ok().map_or(false, |v| v == "1") {
+                        output.push(log_file_output(log_format));
+                    }
+                    let logger_config = fern::DispatchConfig {
+                        // Each output formats records itself.
+                        format: Box::new(|msg: &str, _level: &log::LogLevel, _location: &log::LogLocation| {
+                            msg.to_owned()
+                        }),
+                        output: output,
+                        level: log::LogLevelFilter::Trace,
+                    };
+                    fern::init_global_logger(logger_config, log_level)
+                        .map_err(|e| format!("{:?}", e))
+                })
             },
             Err(_) => Ok(()),
         }
@@ -161,3 +201,47 @@ fn init_logging() {
         Err(e) => panic!(format!("Failed to initalize logging: {}", e)),
     }
 }
+
+/// Return the output for the log file, `SCCACHE_LOG_FILE` or `sccache.log`
+/// in the current directory, with records in `format`. If
+/// `SCCACHE_LOG_MAX_SIZE` is set to a size like `10M`, the file is rotated
+/// once it would grow past that, keeping `SCCACHE_LOG_KEEP` old files.
+fn log_file_output(format: log_file::LogFormat) -> fern::OutputConfig {
+    let path = env::var_os("SCCACHE_LOG_FILE")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| "sccache.log".into());
+    // The file is opened later, maybe after the server daemonizes and
+    // changes directory.
+    let path = match env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path,
+    };
+    let max_size = env::var("SCCACHE_LOG_MAX_SIZE").ok()
+        .and_then(|s| config::parse_size(&s))
+        .and_then(|s| if s > 0 { Some(s as u64) } else { None });
+    let keep = env::var("SCCACHE_LOG_KEEP").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(log_file::DEFAULT_KEEP);
+    let file = log_file::RotatingLogFile::new(path, max_size, keep, format);
+    fern::OutputConfig::custom(Box::new(file))
+}
+
+/// Send log output to syslog instead of stdout and `sccache.log` if
+/// `SCCACHE_SYSLOG` is set, which is friendlier for a daemonized server.
+///
+/// Returns `None` if syslog logging was not requested.
+#[cfg(unix)]
+fn init_syslog(log_level: log::LogLevelFilter) -> Option<Result<(), String>> {
+    match env::var("SCCACHE_SYSLOG") {
+        Ok(ref val) if val != "0" => {
+            Some(syslog::init(syslog::Facility::LOG_USER, log_level, Some("sccache"))
+                 .map_err(|e| format!("{:?}", e)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn init_syslog(_log_level: log::LogLevelFilter) -> Option<Result<(), String>> {
+    None
+}
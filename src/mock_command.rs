@@ -48,6 +48,7 @@
 #[cfg(unix)]
 use libc;
 use futures::future::{self, Future};
+use futures::sync::oneshot;
 use std::boxed::Box;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
@@ -320,6 +321,9 @@ pub struct MockChild {
     pub stderr: Option<io::Cursor<Vec<u8>>>,
     /// The `Result` to be handed out when `wait` is called.
     pub wait_result: Option<io::Result<ExitStatus>>,
+    /// If set, `wait` doesn't finish until this receives a value, as if the
+    /// process were still running.
+    pub running_until: Option<oneshot::Receiver<()>>,
 }
 
 /// A mocked child process that simply returns stored values for its status and output.
@@ -332,6 +336,7 @@ impl MockChild {
             stdout: Some(io::Cursor::new(stdout.as_ref().to_vec())),
             stderr: Some(io::Cursor::new(stderr.as_ref().to_vec())),
             wait_result: Some(Ok(status)),
+            running_until: None,
         }
     }
 
@@ -343,8 +348,16 @@ impl MockChild {
             stdout: None,
             stderr: None,
             wait_result: Some(Err(err)),
+            running_until: None,
         }
     }
+
+    /// Keep this `MockChild` running until `finish` receives a value.
+    #[allow(dead_code)]
+    pub fn running_until(mut self, finish: oneshot::Receiver<()>) -> MockChild {
+        self.running_until = Some(finish);
+        self
+    }
 }
 
 impl CommandChild for MockChild {
@@ -357,12 +370,16 @@ impl CommandChild for MockChild {
     fn take_stderr(&mut self) -> Option<io::Cursor<Vec<u8>>> { self.stderr.take() }
 
     fn wait(mut self) -> Box<Future<Item = ExitStatus, Error = io::Error>> {
-        future::result(self.wait_result.take().unwrap()).boxed()
+        let result = self.wait_result.take().unwrap();
+        match self.running_until.take() {
+            Some(finish) => finish.then(move |_| result).boxed(),
+            None => future::result(result).boxed(),
+        }
     }
 
 
     fn wait_with_output(self) -> Box<Future<Item = Output, Error = io::Error>> {
-        let MockChild { stdout, stderr, wait_result, .. } = self;
+        let MockChild { stdout, stderr, wait_result, running_until, .. } = self;
         let result = wait_result.unwrap().and_then(|status| {
             Ok(Output {
                 status: status,
@@ -370,7 +387,10 @@ impl CommandChild for MockChild {
                 stderr: stderr.map(|c| c.into_inner()).unwrap_or(vec!()),
             })
         });
-        future::result(result).boxed()
+        match running_until {
+            Some(finish) => finish.then(move |_| result).boxed(),
+            None => future::result(result).boxed(),
+        }
     }
 }
 
@@ -0,0 +1,225 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The named pipes that clients and the server talk over on Windows, which
+//! unlike a TCP socket don't trigger a firewall prompt.
+
+use advapi32;
+use commands::DEFAULT_PORT;
+use futures::{Async, Poll, Stream};
+use kernel32;
+use mio_named_pipes::NamedPipe;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::io::FromRawHandle;
+use std::ptr;
+use std::slice;
+use tokio_core::reactor::{Handle, PollEvented};
+use uuid::Uuid;
+use winapi::{DWORD, ERROR_PIPE_BUSY, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, HLOCAL};
+use winapi::{INVALID_HANDLE_VALUE, LPVOID, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE};
+use winapi::{PIPE_UNLIMITED_INSTANCES, PIPE_WAIT, TOKEN_QUERY, TOKEN_USER, TokenUser};
+
+/// The size of the pipe's input and output buffers.
+const PIPE_BUFFER_SIZE: DWORD = 64 * 1024;
+
+/// How long a client waits for a pipe instance each time all of them are busy.
+const PIPE_BUSY_WAIT_MS: DWORD = 500;
+
+/// How many times a client waits for a busy pipe before giving up.
+const PIPE_BUSY_RETRIES: usize = 10;
+
+/// How many ports `PipeListener::bind` tries when asked for any free port.
+const BIND_ANY_ATTEMPTS: usize = 100;
+
+/// Return the security identifier of the user running this process, like
+/// `S-1-5-21-...`. Unlike the user name, it's unique across domains.
+fn user_sid() -> io::Result<String> {
+    unsafe {
+        let mut token = ptr::null_mut();
+        if advapi32::OpenProcessToken(kernel32::GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return Err(io::Error::last_os_error())
+        }
+        // The first call only gets the size of the user's information.
+        let mut size = 0;
+        advapi32::GetTokenInformation(token, TokenUser, ptr::null_mut(), 0, &mut size);
+        // A `TOKEN_USER` holds pointers, so align the buffer for them.
+        let mut buf = vec![0u64; (size as usize + 7) / 8];
+        let ok = advapi32::GetTokenInformation(token, TokenUser, buf.as_mut_ptr() as LPVOID, size, &mut size);
+        let error = io::Error::last_os_error();
+        kernel32::CloseHandle(token);
+        if ok == 0 {
+            return Err(error)
+        }
+        let user = &*(buf.as_ptr() as *const TOKEN_USER);
+        let mut sid = ptr::null_mut();
+        if advapi32::ConvertSidToStringSidW(user.User.Sid, &mut sid) == 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let len = (0..).take_while(|&i| *sid.offset(i) != 0).count();
+        let name = OsString::from_wide(slice::from_raw_parts(sid, len)).to_string_lossy().into_owned();
+        kernel32::LocalFree(sid as HLOCAL);
+        Ok(name)
+    }
+}
+
+/// Return the name of the pipe the server for `port` listens on. Pipes are
+/// named after the user's security identifier, so that users of a shared
+/// machine don't collide. Ports are only used to tell servers apart, like
+/// `SCCACHE_SERVER_PORT` does with TCP.
+pub fn pipe_name(port: u16) -> io::Result<String> {
+    let user = user_sid()?;
+    if port == DEFAULT_PORT {
+        Ok(format!(r"\\.\pipe\sccache-{}", user))
+    } else {
+        Ok(format!(r"\\.\pipe\sccache-{}-{}", user, port))
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+/// Create a new instance of the pipe `name`. If `first` is set, fail if
+/// the pipe already exists, which means another server is using it.
+fn create_pipe(name: &str, first: bool) -> io::Result<NamedPipe> {
+    let name = to_wide(name);
+    let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+    let handle = unsafe {
+        kernel32::CreateNamedPipeW(name.as_ptr(),
+                                   open_mode,
+                                   PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+                                   PIPE_UNLIMITED_INSTANCES,
+                                   PIPE_BUFFER_SIZE,
+                                   PIPE_BUFFER_SIZE,
+                                   0,
+                                   ptr::null_mut())
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(unsafe { NamedPipe::from_raw_handle(handle) })
+}
+
+/// Create a new instance of the pipe `name` and start waiting for a client
+/// to connect to it. Returns the pipe and whether a client is already
+/// connected.
+fn listen(name: &str, first: bool, handle: &Handle) -> io::Result<(PollEvented<NamedPipe>, bool)> {
+    let pipe = PollEvented::new(create_pipe(name, first)?, handle)?;
+    let connected = match pipe.get_ref().connect() {
+        Ok(()) => true,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+        Err(e) => return Err(e),
+    };
+    Ok((pipe, connected))
+}
+
+/// A server's named pipe. This is a `Stream` of pipe instances that clients
+/// have connected to.
+pub struct PipeListener {
+    name: String,
+    port: u16,
+    /// The instance waiting for the next client.
+    pending: PollEvented<NamedPipe>,
+    /// Whether a client has connected to `pending`.
+    connected: bool,
+    handle: Handle,
+}
+
+impl PipeListener {
+    /// Listen on the pipe for `port`. If `port` is zero, pick a port whose
+    /// pipe isn't in use.
+    pub fn bind(port: u16, handle: &Handle) -> io::Result<PipeListener> {
+        if port != 0 {
+            return PipeListener::bind_port(port, handle)
+        }
+        let bytes = Uuid::new_v4();
+        let bytes = bytes.as_bytes();
+        let start = (bytes[0] as u16) << 8 | bytes[1] as u16;
+        let mut last_error = None;
+        for i in 0..BIND_ANY_ATTEMPTS {
+            let port = start.wrapping_add(i as u16);
+            if port == 0 || port == DEFAULT_PORT {
+                continue
+            }
+            match PipeListener::bind_port(port, handle) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrInUse, "no free pipe name")
+        }))
+    }
+
+    fn bind_port(port: u16, handle: &Handle) -> io::Result<PipeListener> {
+        let name = pipe_name(port)?;
+        let (pending, connected) = listen(&name, true, handle)?;
+        Ok(PipeListener {
+            name: name,
+            port: port,
+            pending: pending,
+            connected: connected,
+            handle: handle.clone(),
+        })
+    }
+
+    /// Return the port this pipe is named after.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Stream for PipeListener {
+    type Item = PollEvented<NamedPipe>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        // The pipe becomes writable once a client connects.
+        if !self.connected {
+            if let Async::NotReady = self.pending.poll_write() {
+                return Ok(Async::NotReady)
+            }
+        }
+        // Create the next instance before handing this one off, so that
+        // there's always one for clients to open. Otherwise a client could
+        // find no pipe, and start a second server.
+        let (next, connected) = listen(&self.name, false, &self.handle)?;
+        let pipe = mem::replace(&mut self.pending, next);
+        self.connected = connected;
+        Ok(Async::Ready(Some(pipe)))
+    }
+}
+
+/// Open the client end of the pipe `name`, waiting a while if all of its
+/// instances are busy.
+pub fn connect(name: &str) -> io::Result<File> {
+    let mut retries = 0;
+    loop {
+        match OpenOptions::new().read(true).write(true).open(name) {
+            Err(ref e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) &&
+                          retries < PIPE_BUSY_RETRIES => {
+                retries += 1;
+                unsafe { kernel32::WaitNamedPipeW(to_wide(name).as_ptr(), PIPE_BUSY_WAIT_MS); }
+            }
+            result => return result,
+        }
+    }
+}
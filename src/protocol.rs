@@ -1,6 +1,28 @@
+use bincode;
 use std::ffi::OsString;
+use std::io::{Read, Write};
+use cache::ClearStats;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use server::ServerInfo;
 
+use errors::*;
+
+/// The version of the client-server protocol. Bump this whenever `Request`
+/// or `Response` change incompatibly, so that clients notice when they're
+/// talking to a server from a different sccache.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Messages at least this big are compressed on the wire, unless
+/// `SCCACHE_COMPRESS_PROTOCOL=0`.
+pub const COMPRESS_THRESHOLD: usize = 64 * 1024;
+
+/// The flag byte that starts an uncompressed frame.
+const FRAME_RAW: u8 = 0;
+/// The flag byte that starts a deflate-compressed frame.
+const FRAME_DEFLATE: u8 = 1;
+
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
@@ -10,8 +32,18 @@ pub enum Request {
     GetStats,
     /// Shut the server down gracefully.
     Shutdown,
+    /// Remove a single entry from the cache, by key.
+    Evict(String),
+    /// Re-read the server configuration.
+    ReloadConfig,
+    /// Remove every entry from the cache.
+    ClearCache,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Check that the server speaks the client's protocol version.
+    Hello {
+        version: u32,
+    },
 }
 
 /// A server response.
@@ -23,8 +55,22 @@ pub enum Response {
     Stats(ServerInfo),
     /// Response for `Request::Shutdown`, containing server statistics.
     ShuttingDown(ServerInfo),
+    /// Response for `Request::Evict`.
+    Evicted,
+    /// Response for `Request::ReloadConfig`, containing the names of
+    /// changed settings that require a server restart.
+    ConfigReloaded(Vec<String>),
+    /// Response for `Request::ClearCache`, containing what was removed.
+    CacheCleared(ClearStats),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::ReloadConfig` if the new configuration is
+    /// invalid, containing a description of the problem.
+    ConfigReloadFailed(String),
+    /// Response for `Request::Hello`, containing the server's protocol version.
+    Hello {
+        version: u32,
+    },
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -47,6 +93,26 @@ pub struct CompileFinished {
     pub stdout: Vec<u8>,
     /// The compiler's stderr.
     pub stderr: Vec<u8>,
+    /// Whether this compile was served from the cache.
+    #[serde(default)]
+    pub cache_status: CacheStatus,
+}
+
+/// The cache status of a single finished compile.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    /// The compile result was fetched from the cache.
+    Hit,
+    /// The compile result was not in the cache, so the compiler was run.
+    Miss,
+    /// The cache was not consulted, e.g. because the compile failed.
+    Unknown,
+}
+
+impl Default for CacheStatus {
+    fn default() -> CacheStatus {
+        CacheStatus::Unknown
+    }
 }
 
 /// The contents of a compile request from a client.
@@ -60,4 +126,99 @@ pub struct Compile {
     pub args: Vec<OsString>,
     /// The environment variables present when the compiler was executed, as (var, val).
     pub env_vars: Vec<(OsString, OsString)>,
+    /// A label for the build this compile is part of, like a CI build id or
+    /// branch, from `SCCACHE_BUILD_TAG`. It doesn't affect the cache key.
+    #[serde(default)]
+    pub build_tag: Option<String>,
+}
+
+/// Return whether `bytes` is a serialized `Request::Hello` for this protocol
+/// version.
+///
+/// Every connection starts with messages sent as they're serialized, as all
+/// versions of sccache understand them. Once the client says hello with the
+/// server's version, and the server answers, both switch to the frames made
+/// by `encode_frame`, so that neither sends a frame to an older peer.
+pub fn is_current_hello(bytes: &[u8]) -> bool {
+    match bincode::deserialize(bytes) {
+        Ok(Request::Hello { version }) => version == PROTOCOL_VERSION,
+        _ => false,
+    }
+}
+
+/// Make a frame to send from the serialized message `bytes`: a flag byte,
+/// followed by the message, deflated if `compress` is set and the message is
+/// at least `COMPRESS_THRESHOLD` bytes.
+pub fn encode_frame(bytes: &[u8], compress: bool) -> Result<Vec<u8>> {
+    if compress && bytes.len() >= COMPRESS_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(vec![FRAME_DEFLATE], Compression::Default);
+        encoder.write_all(bytes)?;
+        Ok(encoder.finish()?)
+    } else {
+        let mut frame = Vec::with_capacity(bytes.len() + 1);
+        frame.push(FRAME_RAW);
+        frame.extend_from_slice(bytes);
+        Ok(frame)
+    }
+}
+
+/// Return the serialized message in a frame made by `encode_frame`.
+pub fn decode_frame(frame: &[u8]) -> Result<Vec<u8>> {
+    match frame.split_first() {
+        Some((&FRAME_RAW, bytes)) => Ok(bytes.to_vec()),
+        Some((&FRAME_DEFLATE, bytes)) => {
+            let mut out = vec![];
+            DeflateDecoder::new(bytes).read_to_end(&mut out)?;
+            Ok(out)
+        }
+        Some((flag, _)) => bail!("unknown frame type {}", flag),
+        None => bail!("empty frame"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bincode;
+
+    #[test]
+    fn test_frame_round_trip_large() {
+        // Compiler output is usually quite repetitive.
+        let stdout = (0..10 * 1024 * 1024).map(|i| b"warning: unused variable\n"[i % 25]).collect::<Vec<u8>>();
+        let response = Response::CompileFinished(CompileFinished {
+            retcode: Some(0),
+            signal: None,
+            stdout: stdout.clone(),
+            stderr: b"stderr".to_vec(),
+            cache_status: CacheStatus::Hit,
+        });
+        let bytes = bincode::serialize(&response, bincode::Infinite).unwrap();
+        let frame = encode_frame(&bytes, true).unwrap();
+        assert_eq!(FRAME_DEFLATE, frame[0]);
+        assert!(frame.len() < bytes.len() / 10);
+        match bincode::deserialize(&decode_frame(&frame).unwrap()).unwrap() {
+            Response::CompileFinished(res) => {
+                assert_eq!(Some(0), res.retcode);
+                assert_eq!(None, res.signal);
+                assert!(stdout == res.stdout);
+                assert_eq!(b"stderr".to_vec(), res.stderr);
+                assert_eq!(CacheStatus::Hit, res.cache_status);
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+        // Without compression the message is sent as-is.
+        let frame = encode_frame(&bytes, false).unwrap();
+        assert_eq!(FRAME_RAW, frame[0]);
+        assert!(bytes[..] == frame[1..]);
+        assert!(bytes == decode_frame(&frame).unwrap());
+    }
+
+    #[test]
+    fn test_frame_small_not_compressed() {
+        let frame = encode_frame(b"hello", true).unwrap();
+        assert_eq!(vec![FRAME_RAW, b'h', b'e', b'l', b'l', b'o'], frame);
+        assert_eq!(b"hello".to_vec(), decode_frame(&frame).unwrap());
+        assert!(decode_frame(&[]).is_err());
+        assert!(decode_frame(&[2, 0]).is_err());
+    }
 }
@@ -12,45 +12,62 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use bytes::BytesMut;
 use cache::{
     Storage,
+    storage_from_config,
     storage_from_environment,
 };
 use compiler::{
     CacheControl,
+    CompileOptions,
     Compiler,
     CompilerArguments,
     CompilerHasher,
     CompileResult,
+    CompilerStamp,
+    CCompilerKind,
     MissType,
     get_compiler_info,
+    get_forced_compiler_info,
 };
-use config;
-use filetime::FileTime;
+use config::{self, CacheType, Config, SocketOptions};
 use futures::future;
 use futures::sync::mpsc;
 use futures::task::{self, Task};
 use futures::{Stream, Sink, Async, AsyncSink, Poll, StartSend, Future};
 use futures_cpupool::CpuPool;
+#[cfg(feature = "hyper")]
+use hyper;
 use mock_command::{
     CommandCreatorSync,
     ProcessCommandCreator,
 };
+#[cfg(windows)]
+use named_pipe::PipeListener;
+#[cfg(unix)]
+use net2::TcpBuilder;
 use number_prefix::{binary_prefix, Prefixed, Standalone};
-use protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
+use protocol::{CacheStatus, Compile, CompileFinished, CompileResponse, PROTOCOL_VERSION, Request, Response,
+               decode_frame, encode_frame, is_current_hello};
 use serde_json;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::HashMap;
 use std::env;
-use std::ffi::{OsStr, OsString};
-use std::fs::{self, File, metadata};
-use std::io::{self, Write};
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::{self, SocketAddrV4, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::process::{Output, ExitStatus};
 use std::rc::Rc;
+#[cfg(feature = "hyper")]
+use std::rc::Weak;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio_core::net::TcpListener;
 use tokio_core::reactor::{Handle, Core, Timeout};
 use tokio_io::codec::length_delimited::Framed;
@@ -60,33 +77,41 @@ use tokio_proto::streaming::pipeline::{Frame, ServerProto, Transport};
 use tokio_proto::streaming::{Body, Message};
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_service::Service;
-use util::fmt_duration_as_secs;
+#[cfg(unix)]
+use unix_socket::SocketListener;
+use util::{fmt_duration_as_secs, CompileSlots, MemoryBudget};
 
 use errors::*;
 
-/// If the server is idle for this many milliseconds, shut down.
-const DEFAULT_IDLE_TIMEOUT: u64 = 600_000;
-
-fn notify_server_startup_internal<W: Write>(mut w: W, success: bool) -> io::Result<()> {
-    let data = [ if success { 0 } else { 1 }; 1];
-    try!(w.write_all(&data));
-    Ok(())
+/// Tell the client waiting for the server to start whether it's listening
+/// for clients. A zero byte means it is. Otherwise, a non-zero byte is
+/// followed by the big-endian 16-bit length of the error, and the error.
+fn notify_server_startup_internal<W: Write>(mut w: W, error: Option<&str>) -> io::Result<()> {
+    match error {
+        None => w.write_all(&[0]),
+        Some(error) => {
+            let error = &error.as_bytes()[..cmp::min(error.len(), u16::max_value() as usize)];
+            let len = error.len();
+            w.write_all(&[1, (len >> 8) as u8, len as u8])?;
+            w.write_all(error)
+        }
+    }
 }
 
 #[cfg(unix)]
-fn notify_server_startup(name: &Option<OsString>, success: bool) -> io::Result<()> {
+fn notify_server_startup(name: &Option<OsString>, error: Option<&str>) -> io::Result<()> {
     use std::os::unix::net::UnixStream;
     let name = match *name {
         Some(ref s) => s,
         None => return Ok(()),
     };
-    debug!("notify_server_startup(success: {})", success);
+    debug!("notify_server_startup(error: {:?})", error);
     let stream = try!(UnixStream::connect(name));
-    notify_server_startup_internal(stream, success)
+    notify_server_startup_internal(stream, error)
 }
 
 #[cfg(windows)]
-fn notify_server_startup(name: &Option<OsString>, success: bool) -> io::Result<()> {
+fn notify_server_startup(name: &Option<OsString>, error: Option<&str>) -> io::Result<()> {
     use std::fs::OpenOptions;
 
     let name = match *name {
@@ -94,7 +119,7 @@ fn notify_server_startup(name: &Option<OsString>, success: bool) -> io::Result<(
         None => return Ok(()),
     };
     let pipe = try!(OpenOptions::new().write(true).read(true).open(name));
-    notify_server_startup_internal(pipe, success)
+    notify_server_startup_internal(pipe, error)
 }
 
 #[cfg(unix)]
@@ -114,28 +139,173 @@ fn get_signal(_status: ExitStatus) -> i32 {
 pub fn start_server(port: u16) -> Result<()> {
     trace!("start_server");
     let core = Core::new()?;
-    let pool = CpuPool::new(20);
+    let pool = CpuPool::new(config::CONFIG.num_workers);
     let storage = storage_from_environment(&pool, &core.handle());
-    let res = SccacheServer::<ProcessCommandCreator>::new(port, pool, core, storage);
+    let res = SccacheServer::<ProcessCommandCreator>::new(port, pool, core, storage,
+                                                          &config::CONFIG.socket_options);
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
-        Ok(srv) => {
-            notify_server_startup(&notify, true)?;
+        Ok(mut srv) => {
+            if let Some(ref addr) = config::CONFIG.prometheus_addr {
+                listen_metrics_from_config(&mut srv, addr);
+            }
+            let port = srv.port();
+            if let Some(ref port_file) = config::CONFIG.port_file {
+                write_port_file(port_file, port)?;
+            }
+            notify_server_startup(&notify, None)?;
             srv.run(future::empty::<(), ()>())?;
+            // Only remove the port file if a newer server hasn't taken over.
+            if let Some(ref port_file) = config::CONFIG.port_file {
+                if read_port_file(port_file) == Some(port) {
+                    fs::remove_file(port_file).ok();
+                }
+            }
             Ok(())
         }
         Err(e) => {
-            notify_server_startup(&notify, false)?;
+            let error = e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ");
+            notify_server_startup(&notify, Some(&error))?;
             Err(e)
         }
     }
 }
 
+/// Serve stats for Prometheus on `addr`, as configured.
+#[cfg(feature = "hyper")]
+fn listen_metrics_from_config<C: CommandCreatorSync>(srv: &mut SccacheServer<C>, addr: &SocketAddr) {
+    match srv.listen_metrics(addr) {
+        Ok(port) => info!("serving Prometheus metrics on port {}", port),
+        Err(e) => warn!("failed to serve Prometheus metrics on {}: {}", addr, e),
+    }
+}
+
+#[cfg(not(feature = "hyper"))]
+fn listen_metrics_from_config<C: CommandCreatorSync>(_srv: &mut SccacheServer<C>, addr: &SocketAddr) {
+    warn!("not serving Prometheus metrics on {}: this build doesn't support it", addr);
+}
+
+/// Record `port` in `path`, so that clients that don't specify a port will
+/// connect to this server. Starting a new server on a different port thus
+/// hands off subsequent clients to it while the old server drains.
+pub fn write_port_file(path: &Path, port: u16) -> Result<()> {
+    // Write to a temporary file and rename it into place so that clients
+    // never see a partially written port.
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        write!(file, "{}", port)?;
+    }
+    fs::rename(&tmp, path)?;
+    config::chown_to_sudo_user(path);
+    Ok(())
+}
+
+/// Read the port of the most recently started server from `path`, if any.
+pub fn read_port_file(path: &Path) -> Option<u16> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .ok()
+        .and_then(|_| contents.trim().parse().ok())
+}
+
+/// Bind a listening socket to `addr` with `options`.
+#[cfg(unix)]
+fn bind_listener(addr: &SocketAddr, options: &SocketOptions) -> io::Result<net::TcpListener> {
+    let builder = TcpBuilder::new_v4()?;
+    builder.reuse_address(options.reuse_address)?;
+    builder.bind(addr)?;
+    builder.listen(options.backlog)
+}
+
+/// What the server listens for clients on: a UNIX domain socket, or a TCP
+/// socket on localhost if `SCCACHE_SERVER_TCP` is set.
+#[cfg(unix)]
+enum Listener {
+    Tcp(TcpListener),
+    Unix(SocketListener),
+}
+
+/// What the server listens for clients on: a named pipe, which unlike a TCP
+/// socket doesn't trigger a firewall prompt.
+#[cfg(windows)]
+type Listener = PipeListener;
+
+/// Listen for clients of the server for `port`, returning the listener and
+/// the port it's for. Only the backlog applies to UNIX domain sockets.
+#[cfg(unix)]
+fn listen_for_clients(port: u16, socket_options: &SocketOptions, handle: &Handle)
+                      -> io::Result<(Listener, u16)> {
+    if !config::CONFIG.server_tcp {
+        let listener = SocketListener::bind(port, socket_options.backlog, handle)?;
+        let port = listener.port();
+        return Ok((Listener::Unix(listener), port))
+    }
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port));
+    let listener = bind_listener(&addr, socket_options)?;
+    let listener = TcpListener::from_listener(listener, &addr, handle)?;
+    let port = listener.local_addr()?.port();
+    Ok((Listener::Tcp(listener), port))
+}
+
+/// Listen for clients of the server for `port`, returning the listener and
+/// the port it's for. The TCP socket options don't apply to pipes.
+#[cfg(windows)]
+fn listen_for_clients(port: u16, _socket_options: &SocketOptions, handle: &Handle)
+                      -> io::Result<(Listener, u16)> {
+    let listener = PipeListener::bind(port, handle)?;
+    let port = listener.port();
+    Ok((listener, port))
+}
+
+/// Serve the requests of clients that connect to `listener`.
+#[cfg(unix)]
+fn serve_clients<C>(listener: Listener, nodelay: bool, handle: Handle, service: SccacheService<C>)
+                    -> Box<Future<Item=(), Error=io::Error>>
+    where C: CommandCreatorSync,
+{
+    match listener {
+        Listener::Tcp(listener) => Box::new(listener.incoming().for_each(move |(socket, _addr)| {
+            trace!("incoming connection");
+            if nodelay {
+                if let Err(e) = socket.set_nodelay(true) {
+                    warn!("failed to set TCP_NODELAY: {}", e);
+                }
+            }
+            SccacheProto.bind_server(&handle, socket, service.clone());
+            Ok(())
+        })),
+        Listener::Unix(listener) => Box::new(listener.for_each(move |socket| {
+            trace!("incoming connection");
+            SccacheProto.bind_server(&handle, socket, service.clone());
+            Ok(())
+        })),
+    }
+}
+
+/// Serve the requests of clients that connect to `listener`.
+#[cfg(windows)]
+fn serve_clients<C>(listener: Listener, _nodelay: bool, handle: Handle, service: SccacheService<C>)
+                    -> Box<Future<Item=(), Error=io::Error>>
+    where C: CommandCreatorSync,
+{
+    Box::new(listener.for_each(move |pipe| {
+        trace!("incoming connection");
+        SccacheProto.bind_server(&handle, pipe, service.clone());
+        Ok(())
+    }))
+}
+
 pub struct SccacheServer<C: CommandCreatorSync> {
     core: Core,
-    listener: TcpListener,
+    listener: Listener,
+    /// The port clients connect to this server with.
+    port: u16,
+    /// Listener for Prometheus scrapes, if enabled.
+    metrics_listener: Option<TcpListener>,
+    nodelay: bool,
     rx: mpsc::Receiver<ServerMessage>,
-    timeout: Duration,
     service: SccacheService<C>,
     wait: WaitUntilZero,
 }
@@ -144,10 +314,12 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     pub fn new(port: u16,
                pool: CpuPool,
                core: Core,
-               storage: Arc<Storage>) -> Result<SccacheServer<C>> {
+               storage: Arc<Storage>,
+               socket_options: &SocketOptions) -> Result<SccacheServer<C>> {
         let handle = core.handle();
-        let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
-        let listener = TcpListener::bind(&SocketAddr::V4(addr), &handle)?;
+        let (listener, port) = listen_for_clients(port, socket_options, &handle).chain_err(|| {
+            format!("failed to listen on port {}", port)
+        })?;
 
         // Prepare the service which we'll use to service all incoming TCP
         // connections.
@@ -158,23 +330,70 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         Ok(SccacheServer {
             core: core,
             listener: listener,
+            port: port,
+            metrics_listener: None,
+            nodelay: socket_options.nodelay,
             rx: rx,
             service: service,
-            timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT),
             wait: wait,
         })
     }
 
+    /// Serve stats in the Prometheus text format at `/metrics` on `addr`.
+    ///
+    /// Returns the port that it's bound to.
+    #[cfg(feature = "hyper")]
+    pub fn listen_metrics(&mut self, addr: &SocketAddr) -> Result<u16> {
+        let listener = TcpListener::bind(addr, &self.core.handle())?;
+        let port = listener.local_addr()?.port();
+        self.metrics_listener = Some(listener);
+        Ok(port)
+    }
+
     /// Configures how long this server will be idle before shutting down.
+    /// A zero timeout means it never shuts down by itself.
     #[allow(dead_code)]
     pub fn set_idle_timeout(&mut self, timeout: Duration) {
-        self.timeout = timeout;
+        self.service.idle_timeout = if timeout == Duration::new(0, 0) { None } else { Some(timeout) };
     }
 
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<Storage>) {
-        self.service.storage = storage;
+        *self.service.storage.borrow_mut() = storage;
+    }
+
+    /// Run compiles without the cache for compilers whose cache misses
+    /// usually take less than `min_compile_time`.
+    #[allow(dead_code)]
+    pub fn set_min_compile_time(&mut self, min_compile_time: Option<Duration>) {
+        self.service.min_compile_time = min_compile_time;
+    }
+
+    /// Set the number of threads in the pool this server was given, to
+    /// report in its stats.
+    #[allow(dead_code)]
+    pub fn set_max_workers(&mut self, max_workers: usize) {
+        self.service.max_workers = max_workers;
+    }
+
+    /// Set the soft limit on memory held by in-flight compiles.
+    #[allow(dead_code)]
+    pub fn set_max_memory(&mut self, max_memory: Option<usize>) {
+        self.service.memory = MemoryBudget::new(max_memory);
+    }
+
+    /// Set the maximum number of compilers to run at once.
+    #[allow(dead_code)]
+    pub fn set_max_parallel(&mut self, max_parallel: Option<usize>) {
+        self.service.compile_slots = CompileSlots::new(max_parallel);
+    }
+
+    /// Set the config file this server will read when asked to reload
+    /// its configuration.
+    #[allow(dead_code)]
+    pub fn set_config_path(&mut self, path: PathBuf) {
+        self.service.config_path = Some(path);
     }
 
     /// Returns a reference to a thread pool to run work on
@@ -192,7 +411,7 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     /// Returns the port that this server is bound to
     #[allow(dead_code)]
     pub fn port(&self) -> u16 {
-        self.listener.local_addr().unwrap().port()
+        self.port
     }
 
     /// Runs this server to completion.
@@ -209,22 +428,24 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     fn _run<'a>(self, shutdown: Box<Future<Item = (), Error = ()> + 'a>)
                 -> io::Result<()>
     {
-        let SccacheServer { mut core, listener, rx, service, timeout, wait } = self;
+        let SccacheServer { mut core, listener, port: _, metrics_listener, nodelay, rx, service, wait } = self;
+        let timeout = service.idle_timeout;
 
         // Keep a ref of stats around
         let stats = service.stats.clone();
 
         // are we for testing?
-        let test_service = service.storage.is_test();
+        let test_service = service.storage().is_test();
+
+        // Serve metrics until the server shuts down, if asked to.
+        let metrics = match metrics_listener {
+            Some(listener) => serve_metrics(listener, service.clone(), core.handle()),
+            None => Box::new(future::empty()),
+        };
 
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
-        let handle = core.handle();
-        let server = listener.incoming().for_each(move |(socket, _addr)| {
-            trace!("incoming connection");
-            SccacheProto.bind_server(&handle, socket, service.clone());
-            Ok(())
-        });
+        let server = serve_clients(listener, nodelay, core.handle(), service);
 
         // Right now there's a whole bunch of ways to shut down this server for
         // various purposes. These include:
@@ -240,7 +461,10 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         let handle = core.handle();
         let shutdown_idle = ShutdownOrInactive {
             rx: rx,
-            timeout: Timeout::new(timeout, &handle)?,
+            timeout: match timeout {
+                Some(timeout) => Some(Timeout::new(timeout, &handle)?),
+                None => None,
+            },
             handle: handle.clone(),
             timeout_dur: timeout,
         };
@@ -255,7 +479,8 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         });
 
         let server = future::select_all(vec![
-            Box::new(server) as Box<Future<Item=_, Error=_>>,
+            server,
+            metrics,
             Box::new(shutdown_idle),
             Box::new(shutdown.map_err(|()| {
                 io::Error::new(io::ErrorKind::Other, "shutdown signal failed")
@@ -283,6 +508,7 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
                 if let Ok(mut file) = File::create(stats_path) {
                     let stats = stats.borrow().clone();
                     serde_json::to_writer(&mut file, &stats).ok();
+                    config::chown_to_sudo_user(stats_path);
                 }
             }
         }
@@ -293,21 +519,133 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     }
 }
 
+/// Serve `/metrics` to connections accepted by `listener`.
+///
+/// Connections only hold a weak reference to `service`, so that they don't
+/// keep the server from shutting down once the returned future is dropped.
+#[cfg(feature = "hyper")]
+fn serve_metrics<C>(listener: TcpListener, service: SccacheService<C>, handle: Handle)
+                    -> Box<Future<Item=(), Error=io::Error>>
+    where C: CommandCreatorSync,
+{
+    use hyper::server::Http;
+
+    let service = Rc::new(service);
+    Box::new(listener.incoming().for_each(move |(socket, addr)| {
+        trace!("incoming metrics connection");
+        let metrics = MetricsService { service: Rc::downgrade(&service) };
+        Http::new().keep_alive(false).bind_connection(&handle, socket, addr, metrics);
+        Ok(())
+    }).or_else(|e| {
+        // Don't take the rest of the server down with it.
+        warn!("stopped serving metrics: {}", e);
+        future::empty()
+    }))
+}
+
+#[cfg(not(feature = "hyper"))]
+fn serve_metrics<C>(_listener: TcpListener, _service: SccacheService<C>, _handle: Handle)
+                    -> Box<Future<Item=(), Error=io::Error>>
+    where C: CommandCreatorSync,
+{
+    Box::new(future::empty())
+}
+
+/// The content type of the OpenMetrics text format.
+#[cfg(feature = "hyper")]
+const OPENMETRICS_CONTENT_TYPE: &'static str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Serves stats in the OpenMetrics text format at `/metrics`.
+#[cfg(feature = "hyper")]
+struct MetricsService<C: CommandCreatorSync> {
+    service: Weak<SccacheService<C>>,
+}
+
+#[cfg(feature = "hyper")]
+impl<C> Service for MetricsService<C>
+    where C: CommandCreatorSync,
+{
+    type Request = hyper::server::Request;
+    type Response = hyper::server::Response;
+    type Error = hyper::Error;
+    type Future = future::FutureResult<Self::Response, Self::Error>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        use hyper::header::{ContentLength, ContentType};
+        use hyper::status::StatusCode;
+
+        let response = hyper::server::Response::new();
+        if *req.method() != hyper::Method::Get || req.path() != "/metrics" {
+            return future::ok(response.with_status(StatusCode::NotFound))
+        }
+        match self.service.upgrade() {
+            Some(service) => {
+                let body = service.get_info().openmetrics();
+                future::ok(response.with_header(ContentLength(body.len() as u64))
+                                   .with_header(ContentType(OPENMETRICS_CONTENT_TYPE.parse().unwrap()))
+                                   .with_body(body))
+            }
+            // The server is shutting down.
+            None => future::ok(response.with_status(StatusCode::ServiceUnavailable)),
+        }
+    }
+}
+
 /// Service implementation for sccache
 #[derive(Clone)]
 struct SccacheService<C: CommandCreatorSync> {
     /// Server statistics.
     stats: Rc<RefCell<ServerStats>>,
 
-    /// Cache storage.
-    storage: Arc<Storage>,
+    /// Cache storage. Shared between all connections so that reloading the
+    /// configuration can swap it out; compiles that are already running keep
+    /// using the storage they started with.
+    storage: Rc<RefCell<Arc<Storage>>>,
+
+    /// The cache type `storage` was created from, if known.
+    cache_type: Rc<RefCell<Option<CacheType>>>,
+
+    /// The config file to read when reloading the configuration.
+    config_path: Option<PathBuf>,
+
+    /// The configuration last loaded, which a reloaded one is compared with.
+    config: Rc<RefCell<Config>>,
+
+    /// Estimated memory held by in-flight compiles. New compiles wait to
+    /// start while this is over its limit.
+    memory: MemoryBudget,
+
+    /// Limits how many compilers run at once. Compiles past the limit
+    /// queue for a slot.
+    compile_slots: CompileSlots,
 
-    /// A cache of known compiler info.
-    compilers: Rc<RefCell<HashMap<PathBuf, Option<(Box<Compiler<C>>, FileTime)>>>>,
+    /// Treat C compilers as this kind instead of detecting it.
+    force_compiler: Option<CCompilerKind>,
+
+    /// A cache of known compiler info, including compilers we couldn't
+    /// detect, along with the stamp of the binary it was found for.
+    compilers: Rc<RefCell<HashMap<PathBuf, (Option<Box<Compiler<C>>>, CompilerStamp)>>>,
+
+    /// Compilers whose cache misses usually take less than this are run
+    /// by the client without the cache.
+    min_compile_time: Option<Duration>,
+
+    /// How long cache misses take for each compiler.
+    compile_times: Rc<RefCell<HashMap<PathBuf, CompileTime>>>,
 
     /// Thread pool to execute work in
     pool: CpuPool,
 
+    /// The number of threads in `pool`.
+    max_workers: usize,
+
+    /// How long the server waits for a request before shutting down, if it
+    /// ever does.
+    idle_timeout: Option<Duration>,
+
+    /// The number of compiles in progress, which are what keep `pool` busy.
+    active_compiles: Rc<Cell<usize>>,
+
     /// Handle to the event loop that we're running on.
     handle: Handle,
 
@@ -328,6 +666,18 @@ struct SccacheService<C: CommandCreatorSync> {
     info: ActiveInfo,
 }
 
+/// How many compiles in a row may skip the cache for being too fast before
+/// one goes through it again to update the estimate.
+const FAST_COMPILE_SAMPLE_INTERVAL: u32 = 10;
+
+/// How long cache misses take with one compiler.
+struct CompileTime {
+    /// A moving average of the time taken.
+    average: Duration,
+    /// The number of compiles that skipped the cache since the last miss.
+    skipped: u32,
+}
+
 type SccacheRequest = Message<Request, Body<(), Error>>;
 type SccacheResponse = Message<Response, Body<Response, Error>>;
 
@@ -374,6 +724,32 @@ impl<C> Service for SccacheService<C>
                 self.zero_stats();
                 Response::Stats(self.get_info())
             }
+            Request::Evict(key) => {
+                debug!("handle_client: evict({})", key);
+                return Box::new(self.storage().delete(&key).map(|()| {
+                    Message::WithoutBody(Response::Evicted)
+                }))
+            }
+            Request::ClearCache => {
+                debug!("handle_client: clear_cache");
+                return Box::new(self.storage().clear().map(|cleared| {
+                    Message::WithoutBody(Response::CacheCleared(cleared))
+                }))
+            }
+            Request::ReloadConfig => {
+                debug!("handle_client: reload_config");
+                match self.reload_config() {
+                    Ok(needs_restart) => Response::ConfigReloaded(needs_restart),
+                    Err(e) => {
+                        let message = e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ");
+                        Response::ConfigReloadFailed(message)
+                    }
+                }
+            }
+            Request::Hello { version } => {
+                debug!("handle_client: hello({})", version);
+                Response::Hello { version: PROTOCOL_VERSION }
+            }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
                 let future = self.tx.clone().send(ServerMessage::Shutdown);
@@ -397,19 +773,44 @@ impl<C> SccacheService<C>
                tx: mpsc::Sender<ServerMessage>,
                info: ActiveInfo) -> SccacheService<C> {
         let mut starting_stats = ServerStats::default();
+        let mut cache_type = None;
+        let mut max_memory = None;
+        let mut min_compile_time = None;
+        let mut max_workers = 0;
+        let mut max_parallel = None;
+        let mut force_compiler = None;
+        let mut idle_timeout = Some(Duration::from_secs(config::DEFAULT_IDLE_TIMEOUT));
         if !storage.is_test() {
             if let Some(ref stats_path) = config::CONFIG.stats_path {
                 if let Ok(file) = File::open(stats_path) {
                     starting_stats = serde_json::from_reader(file).unwrap_or(ServerStats::default());
                 }
             }
+            cache_type = Some(config::CONFIG.cache_type.clone());
+            max_memory = config::CONFIG.max_memory;
+            min_compile_time = config::CONFIG.min_compile_time;
+            max_workers = config::CONFIG.num_workers;
+            max_parallel = config::CONFIG.max_parallel;
+            force_compiler = config::CONFIG.force_compiler.clone();
+            idle_timeout = config::CONFIG.idle_timeout;
         }
 
         SccacheService {
             stats: Rc::new(RefCell::new(starting_stats)),
-            storage: storage,
+            storage: Rc::new(RefCell::new(storage)),
+            cache_type: Rc::new(RefCell::new(cache_type)),
+            config_path: config::config_file_path(),
+            config: Rc::new(RefCell::new(config::CONFIG.clone())),
+            memory: MemoryBudget::new(max_memory),
+            compile_slots: CompileSlots::new(max_parallel),
+            force_compiler: force_compiler,
             compilers: Rc::new(RefCell::new(HashMap::new())),
+            min_compile_time: min_compile_time,
+            compile_times: Rc::new(RefCell::new(HashMap::new())),
             pool: pool,
+            max_workers: max_workers,
+            idle_timeout: idle_timeout,
+            active_compiles: Rc::new(Cell::new(0)),
             creator: C::new(&handle),
             handle: handle,
             tx: tx,
@@ -417,20 +818,187 @@ impl<C> SccacheService<C>
         }
     }
 
+    /// Return true if compiles with the compiler at `path` are expected to
+    /// be faster than looking them up in and storing them to the cache.
+    ///
+    /// Every so often such a compile is let through anyway, so the estimate
+    /// keeps up with the compiles actually being run.
+    fn predicted_too_fast(&self, path: &Path) -> bool {
+        let min_compile_time = match self.min_compile_time {
+            Some(t) => t,
+            None => return false,
+        };
+        let mut compile_times = self.compile_times.borrow_mut();
+        let time = match compile_times.get_mut(path) {
+            Some(time) => time,
+            None => return false,
+        };
+        if time.average >= min_compile_time || time.skipped >= FAST_COMPILE_SAMPLE_INTERVAL {
+            time.skipped = 0;
+            false
+        } else {
+            time.skipped += 1;
+            true
+        }
+    }
+
+    /// Record that a cache miss with the compiler at `path` took `duration`.
+    fn record_compile_time(&self, path: PathBuf, duration: Duration) {
+        let mut compile_times = self.compile_times.borrow_mut();
+        let time = compile_times.entry(path).or_insert(CompileTime {
+            average: duration,
+            skipped: 0,
+        });
+        // An exponential moving average, weighting recent compiles most.
+        time.average = (time.average * 3 + duration) / 4;
+    }
+
+    /// Get the storage currently in use.
+    fn storage(&self) -> Arc<Storage> {
+        self.storage.borrow().clone()
+    }
+
     /// Get info and stats about the cache.
     fn get_info(&self) -> ServerInfo {
+        let storage = self.storage();
+        let compiler_versions = self.compilers.borrow().iter()
+            .filter_map(|(path, info)| {
+                info.0.as_ref()
+                    .and_then(|c| c.version())
+                    .map(|v| (path.to_string_lossy().into_owned(), v.to_owned()))
+            })
+            .collect();
+        let stats = self.stats.borrow().clone();
         ServerInfo {
-            stats: self.stats.borrow().clone(),
-            cache_location: self.storage.location(),
-            cache_size: self.storage.current_size(),
-            max_cache_size: self.storage.max_size(),
+            timings: stats.timings(),
+            stats: stats,
+            cache_location: storage.location(),
+            cache_size: storage.current_size(),
+            max_cache_size: storage.max_size(),
+            cache_entries: storage.current_entries(),
+            max_cache_entries: storage.max_entries(),
+            cache_ttl: storage.ttl().map(|d| d.as_secs()),
+            memory_in_use: self.memory.in_use(),
+            max_memory: self.memory.limit(),
+            active_workers: self.active_compiles.get(),
+            max_workers: self.max_workers,
+            compilations_running: self.compile_slots.running(),
+            compilations_queued: self.compile_slots.queued(),
+            max_parallel: self.compile_slots.limit(),
+            idle_timeout: self.idle_timeout.map(|d| d.as_secs()),
+            compiler_versions: compiler_versions,
         }
     }
 
+    /// Re-read the configuration, and replace the storage if the cache
+    /// configuration changed.
+    ///
+    /// Returns the names of settings that changed since the configuration
+    /// was last loaded but only take effect after restarting the server. If the new configuration is invalid,
+    /// the current one is kept.
+    fn reload_config(&self) -> Result<Vec<String>> {
+        let new_config = Config::from_file(self.config_path.clone())?;
+        let changed = *self.cache_type.borrow() != Some(new_config.cache_type.clone());
+        if changed {
+            info!("cache configuration changed, switching to {:?}", new_config.cache_type);
+            *self.storage.borrow_mut() = storage_from_config(&new_config, &self.pool, &self.handle);
+            *self.cache_type.borrow_mut() = Some(new_config.cache_type.clone());
+        }
+        let mut old_config = self.config.borrow_mut();
+        let mut needs_restart = vec!();
+        if new_config.force_recache != old_config.force_recache {
+            needs_restart.push("force_recache".to_owned());
+        }
+        if new_config.msvc_force_z7 != old_config.msvc_force_z7 {
+            needs_restart.push("msvc_force_z7".to_owned());
+        }
+        if new_config.allow_pgo != old_config.allow_pgo {
+            needs_restart.push("allow_pgo".to_owned());
+        }
+        if new_config.compiler_dir != old_config.compiler_dir {
+            needs_restart.push("compiler_dir".to_owned());
+        }
+        if new_config.key_hook != old_config.key_hook {
+            needs_restart.push("key_hook".to_owned());
+        }
+        if new_config.nocache_on_stderr != old_config.nocache_on_stderr {
+            needs_restart.push("nocache_on_stderr_regex".to_owned());
+        }
+        if new_config.min_compile_time != old_config.min_compile_time {
+            needs_restart.push("min_compile_ms".to_owned());
+        }
+        if new_config.cache_ttl != old_config.cache_ttl {
+            needs_restart.push("cache_ttl".to_owned());
+        }
+        if new_config.cache_retry != old_config.cache_retry {
+            needs_restart.push("cache_retry".to_owned());
+        }
+        if new_config.max_memory != old_config.max_memory {
+            needs_restart.push("max_memory".to_owned());
+        }
+        if new_config.memcache_size != old_config.memcache_size {
+            needs_restart.push("memcache_size".to_owned());
+        }
+        if new_config.num_workers != old_config.num_workers {
+            needs_restart.push("num_workers".to_owned());
+        }
+        if new_config.max_parallel != old_config.max_parallel {
+            needs_restart.push("max_parallel".to_owned());
+        }
+        if new_config.force_compiler != old_config.force_compiler {
+            needs_restart.push("force_compiler".to_owned());
+        }
+        if new_config.prometheus_addr != old_config.prometheus_addr {
+            needs_restart.push("prometheus_port".to_owned());
+        }
+        if new_config.compress_protocol != old_config.compress_protocol {
+            needs_restart.push("compress_protocol".to_owned());
+        }
+        if new_config.server_tcp != old_config.server_tcp {
+            needs_restart.push("server_tcp".to_owned());
+        }
+        if new_config.basedir != old_config.basedir {
+            needs_restart.push("basedir".to_owned());
+        }
+        if new_config.dist_scheduler != old_config.dist_scheduler {
+            needs_restart.push("dist_scheduler".to_owned());
+        }
+        if new_config.dist_token != old_config.dist_token {
+            needs_restart.push("dist_token".to_owned());
+        }
+        if new_config.idle_timeout != old_config.idle_timeout {
+            needs_restart.push("idle_timeout".to_owned());
+        }
+        if new_config.direct_mode != old_config.direct_mode {
+            needs_restart.push("direct_mode".to_owned());
+        }
+        if new_config.http_timeout != old_config.http_timeout {
+            needs_restart.push("http_timeout".to_owned());
+        }
+        if new_config.compile_timeout != old_config.compile_timeout {
+            needs_restart.push("compile_timeout".to_owned());
+        }
+        if new_config.max_outputs != old_config.max_outputs {
+            needs_restart.push("max_outputs".to_owned());
+        }
+        if new_config.socket_options.backlog != old_config.socket_options.backlog {
+            needs_restart.push("listen_backlog".to_owned());
+        }
+        if new_config.socket_options.reuse_address != old_config.socket_options.reuse_address {
+            needs_restart.push("reuse_address".to_owned());
+        }
+        if new_config.socket_options.nodelay != old_config.socket_options.nodelay {
+            needs_restart.push("tcp_nodelay".to_owned());
+        }
+        // Report each change once, rather than on every later reload.
+        *old_config = new_config;
+        Ok(needs_restart)
+    }
+
     /// Zero stats about the cache.
     fn zero_stats(&self) {
         *self.stats.borrow_mut() = ServerStats::default();
-        if !self.storage.is_test() {
+        if !self.storage().is_test() {
             if let Some(ref stats_path) = config::CONFIG.stats_path {
                 fs::remove_file(stats_path).ok();
             }
@@ -450,9 +1018,15 @@ impl<C> SccacheService<C>
         let cmd = compile.args;
         let cwd = compile.cwd;
         let env_vars = compile.env_vars;
+        let build_tag = compile.build_tag;
+        if let Some(ref tag) = build_tag {
+            self.stats.borrow_mut().build_tags.entry(tag.clone()).or_insert_with(Default::default)
+                .compile_requests += 1;
+        }
         let me = self.clone();
+        let path = PathBuf::from(&exe);
         Box::new(self.compiler_info(exe.into()).map(move |info| {
-            me.check_compiler(info, cmd, cwd.into(), env_vars)
+            me.check_compiler(info, path, cmd, cwd.into(), env_vars, build_tag)
         }))
     }
 
@@ -461,15 +1035,14 @@ impl<C> SccacheService<C>
     fn compiler_info(&self, path: PathBuf)
                      -> SFuture<Option<Box<Compiler<C>>>> {
         trace!("compiler_info");
-        let mtime = ftry!(metadata(&path).map(|attr| FileTime::from_last_modification_time(&attr)));
+        let stamp = ftry!(CompilerStamp::new(&path));
         //TODO: properly handle rustup overrides. Currently this will
         // cache based on the rustup rustc path, ignoring overrides.
         // https://github.com/mozilla/sccache/issues/87
         let result = match self.compilers.borrow().get(&path) {
-            // It's a hit only if the mtime matches.
-            Some(&Some((ref c, ref cached_mtime))) if *cached_mtime == mtime => Some(Some(c.clone())),
-            // We cache non-results.
-            Some(&None) => Some(None),
+            // It's a hit only if the binary hasn't changed. We cache
+            // non-results too.
+            Some(&(ref info, ref cached_stamp)) if *cached_stamp == stamp => Some(info.clone()),
             _ => None,
         };
         match result {
@@ -484,23 +1057,28 @@ impl<C> SccacheService<C>
                 // so do it asynchronously.
                 let me = self.clone();
 
-                let info = get_compiler_info(&self.creator, &path, &self.pool);
+                let info = match self.force_compiler {
+                    Some(ref kind) => get_forced_compiler_info(&self.creator, &path, kind.clone(), &self.pool),
+                    None => get_compiler_info(&self.creator, &path, &self.pool),
+                };
                 Box::new(info.then(move |info| {
                     let info = info.ok();
-                    me.compilers.borrow_mut().insert(path, info.clone().map(|i| (i, mtime)));
+                    me.compilers.borrow_mut().insert(path, (info.clone(), stamp));
                     Ok(info)
                 }))
             }
         }
     }
 
-    /// Check that we can handle and cache `cmd` when run with `compiler`.
-    /// If so, run `start_compile_task` to execute it.
+    /// Check that we can handle and cache `cmd` when run with `compiler`,
+    /// found at `path`. If so, run `start_compile_task` to execute it.
     fn check_compiler(&self,
                       compiler: Option<Box<Compiler<C>>>,
+                      path: PathBuf,
                       cmd: Vec<OsString>,
                       cwd: PathBuf,
-                      env_vars: Vec<(OsString, OsString)>) -> SccacheResponse
+                      env_vars: Vec<(OsString, OsString)>,
+                      build_tag: Option<String>) -> SccacheResponse
     {
         let mut stats = self.stats.borrow_mut();
         let mut cannot_cache = None;
@@ -514,19 +1092,25 @@ impl<C> SccacheService<C>
                 // Now check that we can handle this compiler with
                 // the provided commandline.
                 match c.parse_arguments(&cmd, &cwd) {
+                    CompilerArguments::Ok(_) if self.predicted_too_fast(&path) => {
+                        debug!("check_compiler: {:?} compiles faster than caching", path);
+                        cannot_cache = Some("Compiles faster than caching".to_owned());
+                        stats.requests_too_fast += 1;
+                    }
                     CompilerArguments::Ok(hasher) => {
                         debug!("parse_arguments: Ok");
                         stats.requests_executed += 1;
                         let (tx, rx) = Body::pair();
-                        self.start_compile_task(hasher, cmd, cwd, env_vars, tx);
+                        self.start_compile_task(hasher, path, cmd, cwd, env_vars, build_tag, tx);
                         let res = CompileResponse::CompileStarted;
                         return Message::WithBody(Response::Compile(res), rx)
                     }
                     CompilerArguments::CannotCache(why) => {
-                        //TODO: save counts of why
                         cannot_cache = Some(format!("Cannot cache: {}", why));
                         debug!("parse_arguments: CannotCache({})", why);
                         stats.requests_not_cacheable += 1;
+                        stats.non_cacheable_compilations += 1;
+                        *stats.cannot_cache_reasons.entry(why.to_owned()).or_insert(0) += 1;
                     }
                     CompilerArguments::NotCompilation => {
                         debug!("parse_arguments: NotCompilation");
@@ -545,34 +1129,71 @@ impl<C> SccacheService<C>
     /// the result in the cache.
     fn start_compile_task(&self,
                           hasher: Box<CompilerHasher<C>>,
+                          path: PathBuf,
                           arguments: Vec<OsString>,
                           cwd: PathBuf,
                           env_vars: Vec<(OsString, OsString)>,
+                          build_tag: Option<String>,
                           tx: mpsc::Sender<Result<Response>>) {
-        let force_recache = env_vars.iter().any(|&(ref k, ref _v)| {
-            k.as_os_str() == OsStr::new("SCCACHE_RECACHE")
-        });
-        let cache_control = if force_recache {
-            CacheControl::ForceRecache
-        } else {
-            CacheControl::Default
+        let cache_control = {
+            let recache = config::find_env_var(&env_vars, "SCCACHE_RECACHE");
+            let recache_paths = config::find_env_var(&env_vars, "SCCACHE_RECACHE_PATHS");
+            CacheControl::from_recache(recache.as_ref().map(|v| v.as_os_str()),
+                                       recache_paths.as_ref().map(|v| v.as_os_str()),
+                                       &cwd,
+                                       hasher.input())
         };
+        let options = CompileOptions {
+            cache_control: cache_control,
+            key_hook: config::CONFIG.key_hook.clone(),
+            nocache_stderr: config::CONFIG.nocache_stderr_regex(),
+            memory: self.memory.clone(),
+            compile_slots: self.compile_slots.clone(),
+        };
+        let compile_timeout = config::compile_timeout(&env_vars);
         let out_pretty = hasher.output_pretty().into_owned();
-        let result = hasher.get_cached_or_compile(self.creator.clone(),
-                                                  self.storage.clone(),
-                                                  arguments,
-                                                  cwd,
-                                                  env_vars,
-                                                  cache_control,
-                                                  self.pool.clone(),
-                                                  self.handle.clone());
+        let creator = self.creator.clone();
+        let storage = self.storage();
+        let pool = self.pool.clone();
+        let handle = self.handle.clone();
+        let start = Instant::now();
+        let active_compiles = self.active_compiles.clone();
+        active_compiles.set(active_compiles.get() + 1);
+        // Hold off starting while in-flight compiles are using too much memory.
+        let result = self.memory.wait_for_room().and_then(move |_| {
+            hasher.get_cached_or_compile(creator,
+                                         storage,
+                                         arguments,
+                                         cwd,
+                                         env_vars,
+                                         options,
+                                         pool,
+                                         handle)
+        });
+        // Once the client stops waiting, drop the compile, which kills any
+        // processes it's running.
+        let result: SFuture<_> = match compile_timeout.and_then(|t| Timeout::new(t, &self.handle).ok()) {
+            Some(timeout) => {
+                let timed_out = timeout.then(|_| Err(ErrorKind::CompileTimedOut.into()));
+                Box::new(result.select(timed_out).map(|(r, _)| r).map_err(|(e, _)| e))
+            }
+            None => Box::new(result),
+        };
         let me = self.clone();
         let task = result.then(move |result| {
+            let mut timed_out = false;
             let mut cache_write = None;
             let mut stats = me.stats.borrow_mut();
+            stats.compile_duration_histogram.record(start.elapsed());
             let mut res = CompileFinished::default();
             match result {
-                Ok((compiled, out)) => {
+                Ok((compiled, out, timings)) => {
+                    stats.preprocess_count += 1;
+                    stats.preprocess_duration += timings.preprocess;
+                    if let Some(duration) = timings.compile {
+                        stats.compiler_count += 1;
+                        stats.compiler_duration += duration;
+                    }
                     match compiled {
                         CompileResult::Error => {
                             stats.cache_errors += 1;
@@ -580,27 +1201,42 @@ impl<C> SccacheService<C>
                         CompileResult::CacheHit(duration) => {
                             stats.cache_hits += 1;
                             stats.cache_read_hit_duration += duration;
+                            stats.cache_read_duration_histogram.record(duration);
+                            res.cache_status = CacheStatus::Hit;
                         },
-                        CompileResult::CacheMiss(miss_type, duration, future) => {
-                            match miss_type {
-                                MissType::Normal => {}
+                        CompileResult::CacheMiss(miss_type, _, future) => {
+                            // A forced recache doesn't look in the cache, so
+                            // it isn't counted as a miss.
+                            let looked_up = match miss_type {
+                                MissType::Normal => true,
                                 MissType::ForcedRecache => {
                                     stats.forced_recaches += 1;
+                                    false
                                 }
                                 MissType::TimedOut => {
                                     stats.cache_timeouts += 1;
+                                    true
                                 }
                                 MissType::CacheReadError => {
                                     stats.cache_errors += 1;
+                                    true
                                 }
+                            };
+                            if looked_up {
+                                stats.cache_misses += 1;
+                                let duration = timings.cache_read.unwrap_or_default();
+                                stats.cache_read_miss_duration += duration;
+                                stats.cache_read_duration_histogram.record(duration);
                             }
-                            stats.cache_misses += 1;
-                            stats.cache_read_miss_duration += duration;
+                            me.record_compile_time(path, start.elapsed());
                             cache_write = Some(future);
+                            res.cache_status = CacheStatus::Miss;
                         }
                         CompileResult::NotCacheable => {
-                            stats.cache_misses += 1;
+                            // Keep compiles that couldn't be cached out of
+                            // the misses, so they don't lower the hit rate.
                             stats.non_cacheable_compilations += 1;
+                            res.cache_status = CacheStatus::Miss;
                         }
                         CompileResult::CompileFailed => {
                             stats.compile_fails += 1;
@@ -625,6 +1261,10 @@ impl<C> SccacheService<C>
                     res.stdout = output.stdout;
                     res.stderr = output.stderr;
                 }
+                Err(Error(ErrorKind::CompileTimedOut, _)) => {
+                    warn!("[{:?}]: gave up on the compile after the client's timeout", out_pretty);
+                    timed_out = true;
+                }
                 Err(err) => {
                     use std::fmt::Write;
 
@@ -642,7 +1282,21 @@ impl<C> SccacheService<C>
                     res.stderr = error.into_bytes();
                 }
             };
-            let send = tx.send(Ok(Response::CompileFinished(res)));
+            if let Some(tag) = build_tag {
+                info!("[{}]: cache {:?} for build tag {}", out_pretty, res.cache_status, tag);
+                let tag_stats = stats.build_tags.entry(tag).or_insert_with(Default::default);
+                match res.cache_status {
+                    CacheStatus::Hit => tag_stats.cache_hits += 1,
+                    CacheStatus::Miss => tag_stats.cache_misses += 1,
+                    CacheStatus::Unknown => {}
+                }
+            }
+            // A client that timed out has run the compiler itself, so don't answer it.
+            let send: Box<Future<Item = (), Error = ()>> = if timed_out {
+                Box::new(future::ok(()))
+            } else {
+                Box::new(tx.send(Ok(Response::CompileFinished(res))).map(|_| ()).map_err(|_| ()))
+            };
 
             let me = me.clone();
             let cache_write = cache_write.then(move |result| {
@@ -658,6 +1312,7 @@ impl<C> SccacheService<C>
                                fmt_duration_as_secs(&info.duration));
                         me.stats.borrow_mut().cache_writes += 1;
                         me.stats.borrow_mut().cache_write_duration += info.duration;
+                        me.stats.borrow_mut().cache_write_duration_histogram.record(info.duration);
                     }
 
                     Ok(None) => {}
@@ -665,13 +1320,68 @@ impl<C> SccacheService<C>
                 Ok(())
             });
 
-            send.join(cache_write).then(|_| Ok(()))
+            send.join(cache_write).then(move |_| {
+                active_compiles.set(active_compiles.get() - 1);
+                Ok(())
+            })
         });
 
         self.handle.spawn(task);
     }
 }
 
+/// Upper bounds, in seconds, of the buckets of a `DurationHistogram`.
+const DURATION_BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5,
+                                           1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+fn duration_as_secs_f64(duration: &Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// A histogram of durations, bucketed by `DURATION_BUCKETS`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DurationHistogram {
+    /// The count of durations that fell in each bucket, not including
+    /// those in smaller buckets.
+    pub buckets: Vec<u64>,
+    /// The count of all durations, including those larger than every bucket.
+    pub count: u64,
+    /// The sum of all durations.
+    pub sum: Duration,
+}
+
+impl DurationHistogram {
+    /// Add `duration` to the histogram.
+    pub fn record(&mut self, duration: Duration) {
+        if self.buckets.len() < DURATION_BUCKETS.len() {
+            self.buckets.resize(DURATION_BUCKETS.len(), 0);
+        }
+        let secs = duration_as_secs_f64(&duration);
+        if let Some(i) = DURATION_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += duration;
+    }
+
+    /// Append this histogram to `out` in the OpenMetrics text format, as
+    /// the metric family `name`.
+    pub fn write_openmetrics(&self, name: &str, out: &mut String) {
+        use std::fmt::Write;
+
+        drop(writeln!(out, "# TYPE {} histogram", name));
+        drop(writeln!(out, "# UNIT {} seconds", name));
+        let mut cumulative = 0;
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            cumulative += self.buckets.get(i).cloned().unwrap_or(0);
+            drop(writeln!(out, "{}_bucket{{le=\"{:?}\"}} {}", name, bound, cumulative));
+        }
+        drop(writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count));
+        drop(writeln!(out, "{}_sum {:?}", name, duration_as_secs_f64(&self.sum)));
+        drop(writeln!(out, "{}_count {}", name, self.count));
+    }
+}
+
 /// Statistics about the server.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerStats {
@@ -683,6 +1393,10 @@ pub struct ServerStats {
     pub requests_not_compile: u64,
     /// The count of client requests that were not cacheable.
     pub requests_not_cacheable: u64,
+    /// The count of client requests run without the cache because the
+    /// compiler is usually faster than caching.
+    #[serde(default)]
+    pub requests_too_fast: u64,
     /// The count of client requests that were executed.
     pub requests_executed: u64,
     /// The count of errors handling compile requests.
@@ -695,9 +1409,11 @@ pub struct ServerStats {
     pub cache_timeouts: u64,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
-    /// The count of compilations which were successful but couldn't be cached.
+    /// The count of compilations which couldn't be cached, because of their
+    /// arguments or their output. These aren't counted as cache misses.
     pub non_cacheable_compilations: u64,
-    /// The count of compilations which forcibly ignored the cache.
+    /// The count of compilations which forcibly ignored the cache. These
+    /// aren't counted as cache misses.
     pub forced_recaches: u64,
     /// The count of errors writing to cache.
     pub cache_write_errors: u64,
@@ -711,6 +1427,44 @@ pub struct ServerStats {
     pub cache_read_miss_duration: Duration,
     /// The count of compilation failures.
     pub compile_fails: u64,
+    /// The count of compile requests that ran the preprocessor.
+    #[serde(default)]
+    pub preprocess_count: u64,
+    /// The total time spent running the preprocessor and hashing its output.
+    #[serde(default)]
+    pub preprocess_duration: Duration,
+    /// The count of compile requests that ran the compiler.
+    #[serde(default)]
+    pub compiler_count: u64,
+    /// The total time spent running the compiler.
+    #[serde(default)]
+    pub compiler_duration: Duration,
+    /// How long handled compile requests took, from start to finish.
+    #[serde(default)]
+    pub compile_duration_histogram: DurationHistogram,
+    /// How long cache lookups took, for both hits and misses.
+    #[serde(default)]
+    pub cache_read_duration_histogram: DurationHistogram,
+    /// How long successful cache writes took.
+    #[serde(default)]
+    pub cache_write_duration_histogram: DurationHistogram,
+    /// Stats for compile requests with a `SCCACHE_BUILD_TAG`, keyed by tag.
+    #[serde(default)]
+    pub build_tags: HashMap<String, BuildTagStats>,
+    /// The count of client requests that were not cacheable, keyed by why.
+    #[serde(default)]
+    pub cannot_cache_reasons: HashMap<String, u64>,
+}
+
+/// Stats for the compile requests with one build tag.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BuildTagStats {
+    /// The count of compile requests with the tag.
+    pub compile_requests: u64,
+    /// The count of cache hits for compile requests with the tag.
+    pub cache_hits: u64,
+    /// The count of cache misses for compile requests with the tag.
+    pub cache_misses: u64,
 }
 
 /// Info and stats about the server.
@@ -720,6 +1474,64 @@ pub struct ServerInfo {
     pub cache_location: String,
     pub cache_size: Option<usize>,
     pub max_cache_size: Option<usize>,
+    /// How many entries are in the cache, if known.
+    pub cache_entries: Option<usize>,
+    /// The maximum number of entries in the cache, if limited.
+    pub max_cache_entries: Option<usize>,
+    /// How many seconds new cache entries are kept, if they expire.
+    pub cache_ttl: Option<u64>,
+    pub memory_in_use: usize,
+    pub max_memory: Option<usize>,
+    /// The number of compiles in progress. When this is over `max_workers`,
+    /// work is queueing for the thread pool.
+    pub active_workers: usize,
+    /// The number of threads in the pool.
+    pub max_workers: usize,
+    /// The number of compilers running.
+    pub compilations_running: usize,
+    /// The number of compiles waiting for a compiler to finish, because
+    /// `max_parallel` are already running.
+    pub compilations_queued: usize,
+    /// The maximum number of compilers to run at once, if limited.
+    pub max_parallel: Option<usize>,
+    /// How many seconds the server waits for a request before shutting
+    /// down, unless it runs until it's stopped.
+    pub idle_timeout: Option<u64>,
+    /// The versions of the compilers used by this server, keyed by path.
+    pub compiler_versions: HashMap<String, String>,
+    /// How long the stages of handling compile requests took.
+    pub timings: StatsTimings,
+}
+
+/// Total and average time spent in one stage of handling compile requests.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageTiming {
+    /// How many times the stage ran.
+    pub count: u64,
+    /// The total time spent in the stage, in microseconds.
+    pub total_us: u64,
+    /// The average time spent in the stage, in microseconds.
+    pub average_us: u64,
+}
+
+impl StageTiming {
+    fn new(total: Duration, count: u64) -> StageTiming {
+        let total_us = total.as_secs() * 1_000_000 + (total.subsec_nanos() / 1000) as u64;
+        StageTiming {
+            count: count,
+            total_us: total_us,
+            average_us: if count > 0 { total_us / count } else { 0 },
+        }
+    }
+}
+
+/// Aggregate timings of the stages of handling compile requests.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct StatsTimings {
+    pub preprocess: StageTiming,
+    pub compile: StageTiming,
+    pub cache_read: StageTiming,
+    pub cache_write: StageTiming,
 }
 
 impl Default for ServerStats {
@@ -729,6 +1541,7 @@ impl Default for ServerStats {
             requests_unsupported_compiler: u64::default(),
             requests_not_compile: u64::default(),
             requests_not_cacheable: u64::default(),
+            requests_too_fast: u64::default(),
             requests_executed: u64::default(),
             cache_errors: u64::default(),
             cache_hits: u64::default(),
@@ -743,15 +1556,47 @@ impl Default for ServerStats {
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
             compile_fails: u64::default(),
+            preprocess_count: u64::default(),
+            preprocess_duration: Duration::new(0, 0),
+            compiler_count: u64::default(),
+            compiler_duration: Duration::new(0, 0),
+            compile_duration_histogram: DurationHistogram::default(),
+            cache_read_duration_histogram: DurationHistogram::default(),
+            cache_write_duration_histogram: DurationHistogram::default(),
+            build_tags: HashMap::new(),
+            cannot_cache_reasons: HashMap::new(),
         }
     }
 }
 
 impl ServerStats {
-    /// Print stats to stdout in a human-readable format.
+    /// Return the total and average time spent in each stage of handling
+    /// compile requests.
+    pub fn timings(&self) -> StatsTimings {
+        StatsTimings {
+            preprocess: StageTiming::new(self.preprocess_duration, self.preprocess_count),
+            compile: StageTiming::new(self.compiler_duration, self.compiler_count),
+            cache_read: StageTiming::new(self.cache_read_hit_duration + self.cache_read_miss_duration,
+                                         self.cache_hits + self.cache_misses),
+            cache_write: StageTiming::new(self.cache_write_duration, self.cache_writes),
+        }
+    }
+
+    /// Return the percentage of cache lookups that were hits, if there
+    /// were any lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        if lookups > 0 {
+            Some(self.cache_hits as f64 * 100.0 / lookups as f64)
+        } else {
+            None
+        }
+    }
+
+    /// Write stats to `out` in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
-    fn print(&self) -> (usize, usize) {
+    fn write(&self, out: &mut Write) -> io::Result<(usize, usize)> {
         macro_rules! set_stat {
             ($vec:ident, $var:expr, $name:expr) => {{
                 // name, value, suffix length
@@ -777,6 +1622,10 @@ impl ServerStats {
         set_stat!(stats_vec, self.requests_executed, "Compile requests executed");
         set_stat!(stats_vec, self.cache_hits, "Cache hits");
         set_stat!(stats_vec, self.cache_misses, "Cache misses");
+        if let Some(rate) = self.hit_rate() {
+            // name, value, suffix length
+            stats_vec.push(("Cache hit rate", format!("{:.2} %", rate), 2));
+        }
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
@@ -785,35 +1634,131 @@ impl ServerStats {
         set_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(stats_vec, self.non_cacheable_compilations, "Non-cacheable compilations");
         set_stat!(stats_vec, self.requests_not_cacheable, "Non-cacheable calls");
+        set_stat!(stats_vec, self.requests_too_fast, "Calls faster than caching");
         set_stat!(stats_vec, self.requests_not_compile, "Non-compilation calls");
         set_stat!(stats_vec, self.requests_unsupported_compiler, "Unsupported compiler calls");
         set_duration_stat!(stats_vec, self.cache_write_duration, self.cache_writes, "Average cache write");
         set_duration_stat!(stats_vec, self.cache_read_miss_duration, self.cache_misses, "Average cache read miss");
         set_duration_stat!(stats_vec, self.cache_read_hit_duration, self.cache_hits, "Average cache read hit");
+        set_duration_stat!(stats_vec, self.preprocess_duration, self.preprocess_count, "Average preprocess");
+        set_duration_stat!(stats_vec, self.compiler_duration, self.compiler_count, "Average compile");
         let name_width = stats_vec.iter().map(|&(ref n, _, _)| n.len()).max().unwrap();
         let stat_width = stats_vec.iter().map(|&(_, ref s, _)| s.len()).max().unwrap();
         for (name, stat, suffix_len) in stats_vec {
-            println!("{:<name_width$} {:>stat_width$}", name, stat, name_width=name_width, stat_width=stat_width + suffix_len);
+            writeln!(out, "{:<name_width$} {:>stat_width$}", name, stat, name_width=name_width, stat_width=stat_width + suffix_len)?;
         }
-        (name_width, stat_width)
+        Ok((name_width, stat_width))
     }
 }
 
 impl ServerInfo {
+    /// Return stats in the OpenMetrics text format, for Prometheus.
+    pub fn openmetrics(&self) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        macro_rules! counter {
+            ($var:expr, $name:expr) => {{
+                drop(writeln!(out, "# TYPE sccache_{} counter", $name));
+                drop(writeln!(out, "sccache_{}_total {}", $name, $var));
+            }};
+        }
+        let stats = &self.stats;
+        counter!(stats.compile_requests, "compile_requests");
+        counter!(stats.requests_executed, "requests_executed");
+        counter!(stats.cache_hits, "cache_hits");
+        counter!(stats.cache_misses, "cache_misses");
+        counter!(stats.cache_timeouts, "cache_timeouts");
+        counter!(stats.cache_read_errors, "cache_read_errors");
+        counter!(stats.forced_recaches, "forced_recaches");
+        counter!(stats.cache_write_errors, "cache_write_errors");
+        counter!(stats.cache_writes, "cache_writes");
+        counter!(stats.compile_fails, "compile_fails");
+        counter!(stats.cache_errors, "cache_errors");
+        counter!(stats.non_cacheable_compilations, "non_cacheable_compilations");
+        counter!(stats.requests_not_cacheable, "requests_not_cacheable");
+        counter!(stats.requests_too_fast, "requests_too_fast");
+        counter!(stats.requests_not_compile, "requests_not_compile");
+        counter!(stats.requests_unsupported_compiler, "requests_unsupported_compiler");
+        drop(writeln!(out, "# TYPE sccache_requests_not_cacheable_by_reason counter"));
+        for (reason, count) in &stats.cannot_cache_reasons {
+            drop(writeln!(out, "sccache_requests_not_cacheable_by_reason_total{{reason=\"{}\"}} {}",
+                          reason.replace('\\', "\\\\").replace('"', "\\\""), count));
+        }
+        stats.compile_duration_histogram.write_openmetrics("sccache_compile_duration_seconds", &mut out);
+        stats.cache_read_duration_histogram.write_openmetrics("sccache_cache_read_duration_seconds", &mut out);
+        stats.cache_write_duration_histogram.write_openmetrics("sccache_cache_write_duration_seconds", &mut out);
+        for &(name, val) in &[("cache_size_bytes", &self.cache_size),
+                             ("max_cache_size_bytes", &self.max_cache_size),
+                             ("cache_entries", &self.cache_entries),
+                             ("max_cache_entries", &self.max_cache_entries),
+                             ("in_flight_memory_bytes", &Some(self.memory_in_use)),
+                             ("max_memory_bytes", &self.max_memory),
+                             ("active_workers", &Some(self.active_workers)),
+                             ("max_workers", &Some(self.max_workers)),
+                             ("compilations_running", &Some(self.compilations_running)),
+                             ("compilations_queued", &Some(self.compilations_queued)),
+                             ("max_parallel_compilations", &self.max_parallel)] {
+            if let &Some(val) = val {
+                drop(writeln!(out, "# TYPE sccache_{} gauge", name));
+                drop(writeln!(out, "sccache_{} {}", name, val));
+            }
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+
     /// Print info to stdout in a human-readable format.
     pub fn print(&self) {
-        let (name_width, stat_width) = self.stats.print();
-        println!("{:<name_width$} {}", "Cache location", self.cache_location, name_width=name_width);
+        drop(self.write(&mut io::stdout()));
+    }
+
+    /// Write info to `out` in a human-readable format.
+    pub fn write(&self, out: &mut Write) -> io::Result<()> {
+        let (name_width, stat_width) = self.stats.write(out)?;
+        writeln!(out, "{:<name_width$} {}", "Cache location", self.cache_location, name_width=name_width)?;
         for &(name, val) in &[("Cache size", &self.cache_size),
-                             ("Max cache size", &self.max_cache_size)] {
+                             ("Max cache size", &self.max_cache_size),
+                             ("In-flight memory", &Some(self.memory_in_use)),
+                             ("Max memory", &self.max_memory)] {
             if let &Some(val) = val {
                 let (val, suffix) = match binary_prefix(val as f64) {
                     Standalone(bytes) => (bytes.to_string(), "bytes".to_string()),
                     Prefixed(prefix, n) => (format!("{:.0}", n), format!("{}B", prefix)),
                 };
-                println!("{:<name_width$} {:>stat_width$} {}", name, val, suffix, name_width=name_width, stat_width=stat_width);
+                writeln!(out, "{:<name_width$} {:>stat_width$} {}", name, val, suffix, name_width=name_width, stat_width=stat_width)?;
             }
         }
+        for &(name, val) in &[("Cache entries", &self.cache_entries),
+                             ("Max cache entries", &self.max_cache_entries),
+                             ("Active workers", &Some(self.active_workers)),
+                             ("Max workers", &Some(self.max_workers)),
+                             ("Compilations running", &Some(self.compilations_running)),
+                             ("Compilations queued", &Some(self.compilations_queued)),
+                             ("Max parallel compilations", &self.max_parallel)] {
+            if let &Some(val) = val {
+                writeln!(out, "{:<name_width$} {:>stat_width$}", name, val, name_width=name_width, stat_width=stat_width)?;
+            }
+        }
+        if let Some(ttl) = self.cache_ttl {
+            writeln!(out, "{:<name_width$} {:>stat_width$} s", "Cache entry TTL", ttl, name_width=name_width, stat_width=stat_width)?;
+        }
+        match self.idle_timeout {
+            Some(timeout) => writeln!(out, "{:<name_width$} {:>stat_width$} s", "Idle timeout", timeout,
+                                      name_width=name_width, stat_width=stat_width)?,
+            None => writeln!(out, "{:<name_width$} {:>stat_width$}", "Idle timeout", "never",
+                             name_width=name_width, stat_width=stat_width)?,
+        }
+        if !self.stats.cannot_cache_reasons.is_empty() {
+            writeln!(out, "")?;
+            writeln!(out, "Non-cacheable reasons:")?;
+            let mut reasons = self.stats.cannot_cache_reasons.iter().collect::<Vec<_>>();
+            reasons.sort_by(|&(a, a_count), &(b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+            for (reason, count) in reasons {
+                writeln!(out, "{:<name_width$} {:>stat_width$}", reason, count, name_width=name_width, stat_width=stat_width)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -833,7 +1778,12 @@ impl<I> ServerProto<I> for SccacheProto
 
     fn bind_transport(&self, io: I) -> Self::BindTransport {
         future::ok(SccacheTransport {
-            inner: WriteBincode::new(ReadBincode::new(Framed::new(io))),
+            inner: WriteBincode::new(ReadBincode::new(CompressedFrames {
+                inner: Framed::new(io),
+                compress: config::CONFIG.compress_protocol,
+                framed: false,
+                frame_after_response: false,
+            })),
         })
     }
 }
@@ -846,6 +1796,9 @@ impl<I> ServerProto<I> for SccacheProto
 ///   `AsyncWrite`
 /// * Next that's framed using the `length_delimited` module in tokio-io giving
 ///   us a `Sink` and `Stream` of `BytesMut`.
+/// * Next that's wrapped in `CompressedFrames`, which, once the client has
+///   said hello with this protocol version, compresses large frames and adds
+///   a flag byte saying whether they're compressed.
 /// * Next that sink/stream is wrapped in `ReadBincode` which will cause the
 ///   `Stream` implementation to switch from `BytesMut` to `Request` by parsing
 ///   the bytes  bincode.
@@ -854,7 +1807,7 @@ impl<I> ServerProto<I> for SccacheProto
 ///   all `Response` types pushed in will be converted to `BytesMut` and pushed
 ///   below.
 struct SccacheTransport<I: AsyncRead + AsyncWrite> {
-    inner: WriteBincode<ReadBincode<Framed<I>, Request>, Response>,
+    inner: WriteBincode<ReadBincode<CompressedFrames<Framed<I>>, Request>, Response>,
 }
 
 impl<I: AsyncRead + AsyncWrite> Stream for SccacheTransport<I> {
@@ -925,11 +1878,86 @@ impl<I: AsyncRead + AsyncWrite> Sink for SccacheTransport<I> {
 
 impl<I: AsyncRead + AsyncWrite + 'static> Transport for SccacheTransport<I> {}
 
+/// A `Stream + Sink` of frames in the format of `protocol::encode_frame`,
+/// after the client's hello. See `protocol::is_current_hello`.
+struct CompressedFrames<T> {
+    inner: T,
+    /// Whether to compress large frames.
+    compress: bool,
+    /// Whether messages are in frames yet.
+    framed: bool,
+    /// Whether to start using frames once the next response, the answer to
+    /// the client's hello, is sent.
+    frame_after_response: bool,
+}
+
+impl<T> Stream for CompressedFrames<T>
+    where T: Stream<Item = BytesMut, Error = io::Error>,
+{
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, io::Error> {
+        match try_ready!(self.inner.poll()) {
+            Some(frame) => {
+                if !self.framed {
+                    if is_current_hello(&frame) {
+                        self.frame_after_response = true;
+                    }
+                    return Ok(Async::Ready(Some(frame)))
+                }
+                let bytes = decode_frame(&frame).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+                })?;
+                Ok(Async::Ready(Some(BytesMut::from(bytes))))
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl<T> Sink for CompressedFrames<T>
+    where T: Sink<SinkItem = BytesMut, SinkError = io::Error>,
+{
+    type SinkItem = BytesMut;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: BytesMut) -> StartSend<BytesMut, io::Error> {
+        let frame = if self.framed {
+            BytesMut::from(encode_frame(&item, self.compress).map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, e.to_string())
+            })?)
+        } else {
+            item.clone()
+        };
+        match self.inner.start_send(frame)? {
+            AsyncSink::Ready => {
+                if self.frame_after_response {
+                    self.framed = true;
+                    self.frame_after_response = false;
+                }
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(item)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.inner.close()
+    }
+}
+
 struct ShutdownOrInactive {
     rx: mpsc::Receiver<ServerMessage>,
     handle: Handle,
-    timeout: Timeout,
-    timeout_dur: Duration,
+    /// Fires once the server has been idle for `timeout_dur`, unless the
+    /// server only shuts down when asked to.
+    timeout: Option<Timeout>,
+    timeout_dur: Option<Duration>,
 }
 
 impl Future for ShutdownOrInactive {
@@ -943,13 +1971,18 @@ impl Future for ShutdownOrInactive {
                 // Shutdown received!
                 Async::Ready(Some(ServerMessage::Shutdown)) => return Ok(().into()),
                 Async::Ready(Some(ServerMessage::Request)) => {
-                    self.timeout = Timeout::new(self.timeout_dur, &self.handle)?;
+                    if let Some(dur) = self.timeout_dur {
+                        self.timeout = Some(Timeout::new(dur, &self.handle)?);
+                    }
                 }
                 // All services have shut down, in theory this isn't possible...
                 Async::Ready(None) => return Ok(().into()),
             }
         }
-        self.timeout.poll()
+        match self.timeout {
+            Some(ref mut timeout) => timeout.poll(),
+            None => Ok(Async::NotReady),
+        }
     }
 }
 
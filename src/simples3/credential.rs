@@ -70,9 +70,9 @@ impl AwsCredentials {
 
     /// Determine whether or not the credentials are expired.
     fn credentials_are_expired(&self) -> bool {
-        // This is a rough hack to hopefully avoid someone requesting creds then sitting on them
-        // before issuing the request:
-        self.expires_at < UTC::now() + Duration::seconds(20)
+        // Refresh well before the credentials actually expire, so that requests
+        // which are queued or slow to upload don't get rejected mid-flight.
+        self.expires_at < UTC::now() + Duration::minutes(5)
     }
 }
 
@@ -147,7 +147,7 @@ impl ProfileProvider {
         Ok(ProfileProvider {
             credentials: None,
             file_path: profile_location,
-            profile: "default".to_owned(),
+            profile: default_profile(),
         })
     }
 
@@ -193,6 +193,14 @@ impl ProvideAwsCredentials for ProfileProvider {
     }
 }
 
+/// The name of the profile to use from a credentials file: `AWS_PROFILE` if
+/// set, otherwise `default`.
+pub fn default_profile() -> String {
+    var("AWS_PROFILE").ok()
+        .and_then(|p| if p.is_empty() { None } else { Some(p) })
+        .unwrap_or_else(|| "default".to_owned())
+}
+
 fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredentials>> {
     let metadata = fs::metadata(file_path).chain_err(|| {
         "couldn't stat credentials file"
@@ -207,6 +215,7 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
     let mut profiles: HashMap<String, AwsCredentials> = HashMap::new();
     let mut access_key: Option<String> = None;
     let mut secret_key: Option<String> = None;
+    let mut session_token: Option<String> = None;
     let mut profile_name: Option<String> = None;
 
     let file_lines = BufReader::new(&file);
@@ -223,12 +232,13 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
         if profile_regex.is_match(&unwrapped_line) {
 
             if profile_name.is_some() && access_key.is_some() && secret_key.is_some() {
-                let creds = AwsCredentials::new(access_key.unwrap(), secret_key.unwrap(), None, in_ten_minutes());
+                let creds = AwsCredentials::new(access_key.unwrap(), secret_key.unwrap(), session_token, in_ten_minutes());
                 profiles.insert(profile_name.unwrap(), creds);
             }
 
             access_key = None;
             secret_key = None;
+            session_token = None;
 
             let caps = profile_regex.captures(&unwrapped_line).unwrap();
             profile_name = Some(caps.at(1).unwrap().to_string());
@@ -252,6 +262,14 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
             if !v.is_empty() {
                 secret_key = Some(v[1].trim_matches(' ').to_string());
             }
+        } else if lower_case_line.contains("aws_session_token") &&
+            session_token.is_none()
+        {
+            // Session tokens are base64, and may end in `=`.
+            let v: Vec<&str> = unwrapped_line.splitn(2, '=').collect();
+            if v.len() == 2 {
+                session_token = Some(v[1].trim_matches(' ').to_string());
+            }
         }
 
         // we could potentially explode here to indicate that the file is invalid
@@ -259,7 +277,7 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
     }
 
     if profile_name.is_some() && access_key.is_some() && secret_key.is_some() {
-        let creds = AwsCredentials::new(access_key.unwrap(), secret_key.unwrap(), None, in_ten_minutes());
+        let creds = AwsCredentials::new(access_key.unwrap(), secret_key.unwrap(), session_token, in_ten_minutes());
         profiles.insert(profile_name.unwrap(), creds);
     }
 
@@ -270,23 +288,34 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
     Ok(profiles)
 }
 
+/// The EC2 instance metadata service.
+const IAM_ENDPOINT: &'static str = "http://169.254.169.254";
+
 /// Provides AWS credentials from a resource's IAM role.
 pub struct IamProvider {
     client: Client<HttpConnector>,
+    endpoint: String,
     handle: Handle,
 }
 
 impl IamProvider {
     pub fn new(handle: &Handle) -> IamProvider {
+        IamProvider::with_endpoint(IAM_ENDPOINT, handle)
+    }
+
+    /// Create a new `IamProvider` using the metadata service at `endpoint`.
+    pub fn with_endpoint<E>(endpoint: E, handle: &Handle) -> IamProvider
+    where E: Into<String> {
         IamProvider {
             client: Client::new(handle),
+            endpoint: endpoint.into(),
             handle: handle.clone(),
         }
     }
 
     fn iam_role(&self) -> SFuture<String> {
         // First get the IAM role
-        let address = "http://169.254.169.254/latest/meta-data/iam/security-credentials/";
+        let address = format!("{}/latest/meta-data/iam/security-credentials/", self.endpoint);
         let mut req = Request::new(Method::Get, address.parse().unwrap());
         req.headers_mut().set(Connection::close());
         let response = self.client.request(req).and_then(|response| {
@@ -304,8 +333,8 @@ impl IamProvider {
                 "Didn't get a parsable response body from metadata service"
             })
         }).map(move |body| {
-            let mut address = address.to_string();
-            address.push_str(&body);
+            let mut address = address;
+            address.push_str(body.trim());
             address
         }))
     }
@@ -426,10 +455,14 @@ impl<P: ProvideAwsCredentials> AutoRefreshingProvider<P> {
 impl <P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider<P> {
     fn credentials(&self) -> SFuture<AwsCredentials> {
         let mut future = self.cached_credentials.borrow_mut();
-        if let Ok(Async::Ready(creds)) = future.poll() {
-            if creds.credentials_are_expired() {
-                *future = self.credentials_provider.credentials().shared();
-            }
+        let refresh = match future.poll() {
+            Ok(Async::Ready(creds)) => creds.credentials_are_expired(),
+            Ok(Async::NotReady) => false,
+            // Try again rather than failing every request from now on.
+            Err(_) => true,
+        };
+        if refresh {
+            *future = self.credentials_provider.credentials().shared();
         }
         Box::new(future.clone().then(|result| {
             match result {
@@ -452,6 +485,7 @@ impl <P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider
 #[derive(Clone)]
 pub struct ChainProvider {
     profile_providers: Vec<ProfileProvider>,
+    iam_endpoint: String,
     handle: Handle,
 }
 
@@ -466,9 +500,10 @@ impl ProvideAwsCredentials for ChainProvider {
             let alternate = provider.credentials();
             creds = Box::new(creds.or_else(|_| alternate));
         }
+        let iam_endpoint = self.iam_endpoint.clone();
         let handle = self.handle.clone();
         Box::new(creds.or_else(move |_| {
-		    IamProvider::new(&handle).credentials().map(|c| {
+		    IamProvider::with_endpoint(iam_endpoint, &handle).credentials().map(|c| {
                 debug!("Using AWS credentials from IAM");
                 c
             })
@@ -483,6 +518,7 @@ impl ChainProvider {
     pub fn new(handle: &Handle) -> ChainProvider {
         ChainProvider {
             profile_providers: ProfileProvider::new().into_iter().collect(),
+            iam_endpoint: IAM_ENDPOINT.to_owned(),
             handle: handle.clone(),
         }
     }
@@ -493,11 +529,132 @@ impl ChainProvider {
     -> ChainProvider {
         ChainProvider {
             profile_providers: profile_providers,
+            iam_endpoint: IAM_ENDPOINT.to_owned(),
             handle: handle.clone(),
         }
     }
+
+    /// Set the address of the metadata service to get IAM role credentials from.
+    pub fn set_iam_endpoint<E>(&mut self, endpoint: E) where E: Into<String> {
+        self.iam_endpoint = endpoint.into();
+    }
 }
 
 fn in_ten_minutes() -> DateTime<UTC> {
     UTC::now() + Duration::seconds(600)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    /// Serve IAM role credentials like the EC2 metadata service, returning
+    /// its address.
+    fn mock_metadata_service() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let body = if request.starts_with("GET /latest/meta-data/iam/security-credentials/role ") {
+                    r#"{"AccessKeyId": "iam-key", "SecretAccessKey": "iam-secret", "Token": "iam-token", "Expiration": "2100-01-01T00:00:00Z"}"#
+                } else if request.starts_with("GET /latest/meta-data/iam/security-credentials/ ") {
+                    "role"
+                } else {
+                    ""
+                };
+                drop(write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(), body));
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_chain_provider_order() {
+        for v in &["AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY", "AWS_SESSION_TOKEN", "AWS_IAM_CREDENTIALS_URL"] {
+            env::remove_var(v);
+        }
+        let mut core = Core::new().unwrap();
+        let tempdir = TempDir::new("sccache_test_credentials").unwrap();
+        let credentials = tempdir.path().join("credentials");
+        File::create(&credentials).unwrap().write_all(b"[default]
+aws_access_key_id = default-key
+aws_secret_access_key = default-secret
+[test]
+aws_access_key_id = profile-key
+aws_secret_access_key = profile-secret
+aws_session_token = profile/token==
+").unwrap();
+        let profile = ProfileProvider::with_configuration(&credentials, "test");
+        let mut chain = ChainProvider::with_profile_providers(vec![profile], &core.handle());
+        chain.set_iam_endpoint(mock_metadata_service());
+
+        // The environment comes first.
+        env::set_var("AWS_ACCESS_KEY_ID", "env-key");
+        env::set_var("AWS_SECRET_ACCESS_KEY", "env-secret");
+        let creds = core.run(chain.credentials()).unwrap();
+        assert_eq!("env-key", creds.aws_access_key_id());
+        assert_eq!("env-secret", creds.aws_secret_access_key());
+        env::remove_var("AWS_ACCESS_KEY_ID");
+        env::remove_var("AWS_SECRET_ACCESS_KEY");
+
+        // Then the profile from the credentials file.
+        let creds = core.run(chain.credentials()).unwrap();
+        assert_eq!("profile-key", creds.aws_access_key_id());
+        assert_eq!("profile-secret", creds.aws_secret_access_key());
+        assert_eq!(&Some("profile/token==".to_owned()), creds.token());
+
+        // Then the IAM role from the metadata service.
+        fs::remove_file(&credentials).unwrap();
+        let creds = core.run(chain.credentials()).unwrap();
+        assert_eq!("iam-key", creds.aws_access_key_id());
+        assert_eq!("iam-secret", creds.aws_secret_access_key());
+        assert_eq!(&Some("iam-token".to_owned()), creds.token());
+    }
+
+    /// A provider that fails the first time it's asked for credentials.
+    struct FailOnceProvider {
+        calls: Cell<usize>,
+    }
+
+    impl ProvideAwsCredentials for FailOnceProvider {
+        fn credentials(&self) -> SFuture<AwsCredentials> {
+            self.calls.set(self.calls.get() + 1);
+            if self.calls.get() == 1 {
+                f_err("no credentials yet")
+            } else {
+                f_ok(AwsCredentials::new("key", "secret", None, in_ten_minutes()))
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_refreshing_provider_retries_errors() {
+        let provider = AutoRefreshingProvider::new(FailOnceProvider { calls: Cell::new(0) });
+        let creds = provider.credentials().wait().unwrap();
+        assert_eq!("key", creds.aws_access_key_id());
+        assert_eq!(2, provider.credentials_provider.calls.get());
+        // Unexpired credentials are reused.
+        provider.credentials().wait().unwrap();
+        assert_eq!(2, provider.credentials_provider.calls.get());
+    }
+}
@@ -16,7 +16,8 @@ use hyper_tls::HttpsConnector;
 use rustc_serialize::base64::{ToBase64, STANDARD};
 use simples3::credential::*;
 use time;
-use tokio_core::reactor::Handle;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 
 use errors::*;
 
@@ -30,6 +31,16 @@ pub enum Ssl {
     No,
 }
 
+/// Server-side encryption to request for uploaded objects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sse {
+    /// Encrypt with keys managed by S3 (`AES256`).
+    Aes256,
+    /// Encrypt with AWS KMS (`aws:kms`), using the given key ID, or the
+    /// account's default key if none is given.
+    Kms(Option<String>),
+}
+
 fn base_url(endpoint: &str, ssl: Ssl) -> String {
     format!("{}://{}/",
             match ssl {
@@ -49,11 +60,81 @@ fn signature(string_to_sign: &str, signing_key: &str) -> String {
     hmac(Sha1::new(), signing_key.as_bytes(), string_to_sign.as_bytes()).to_base64(STANDARD)
 }
 
+/// One page of the result of listing a bucket with `Bucket::list`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ObjectList {
+    /// The keys of the objects listed.
+    pub keys: Vec<String>,
+    /// The total size of the objects listed, in bytes.
+    pub bytes: u64,
+    /// The key to list the next page after, if there are more objects.
+    pub next_marker: Option<String>,
+}
+
+/// Percent-encode `s` for use in a URL query.
+fn query_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                escaped.push(b as char)
+            }
+            b => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+/// Return the text of each `<tag>` element in `xml`.
+fn tag_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    values
+}
+
+/// Replace the entities S3 uses in XML text with the characters they stand for.
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+     .replace("&gt;", ">")
+     .replace("&quot;", "\"")
+     .replace("&apos;", "'")
+     .replace("&amp;", "&")
+}
+
+/// Parse the body of a `ListObjects` response.
+fn parse_object_list(xml: &str) -> ObjectList {
+    let keys: Vec<String> = tag_values(xml, "Key").iter().map(|k| xml_unescape(k)).collect();
+    let sizes = tag_values(xml, "Size");
+    let truncated = tag_values(xml, "IsTruncated").first() == Some(&"true");
+    ObjectList {
+        bytes: sizes.iter().filter_map(|s| s.parse::<u64>().ok()).sum(),
+        next_marker: if truncated { keys.last().cloned() } else { None },
+        keys: keys,
+    }
+}
+
 /// An S3 bucket.
 pub struct Bucket {
     name: String,
     base_url: String,
     client: Client<HttpsConnector>,
+    /// The maximum time a single request may take.
+    timeout: Option<Duration>,
+    /// Server-side encryption for uploaded objects.
+    sse: Option<Sse>,
+    handle: Handle,
 }
 
 impl fmt::Display for Bucket {
@@ -63,7 +144,7 @@ impl fmt::Display for Bucket {
 }
 
 impl Bucket {
-    pub fn new(name: &str, endpoint: &str, ssl: Ssl, handle: &Handle) -> Bucket {
+    pub fn new(name: &str, endpoint: &str, ssl: Ssl, timeout: Option<Duration>, handle: &Handle) -> Bucket {
         let base_url = base_url(&endpoint, ssl);
         Bucket {
             name: name.to_owned(),
@@ -71,15 +152,45 @@ impl Bucket {
             client: Client::configure()
                         .connector(HttpsConnector::new(1, handle))
                         .build(handle),
+            timeout: timeout,
+            sse: None,
+            handle: handle.clone(),
         }
     }
 
+    /// Ask S3 to encrypt objects uploaded with `put` using `sse`.
+    pub fn set_server_side_encryption(&mut self, sse: Sse) {
+        self.sse = Some(sse);
+    }
+
+    /// Fail `request` if it doesn't finish within the configured timeout.
+    /// The `RequestTimedOut` error is transient, so `S3Cache` retries it
+    /// with `cache::retry::retry_future`.
+    fn with_timeout<T: 'static>(&self, request: SFuture<T>) -> SFuture<T> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return request,
+        };
+        let timer = match Timeout::new(timeout, &self.handle) {
+            Ok(timer) => timer,
+            Err(e) => return f_err(e),
+        };
+        let timer = timer.then(move |res| -> Result<T> {
+            res?;
+            bail!(ErrorKind::RequestTimedOut(timeout))
+        });
+        Box::new(request.select(timer).map(|(v, _)| v).map_err(|(e, _)| e))
+    }
+
     pub fn get(&self, key: &str) -> SFuture<Vec<u8>> {
         let url = format!("{}{}", self.base_url, key);
         debug!("GET {}", url);
         let url2 = url.clone();
-        Box::new(self.client.get(url.parse().unwrap()).chain_err(move || {
-            format!("failed GET: {}", url)
+        // Errors are returned as they are, so that `is_transient` can tell
+        // whether to retry them.
+        self.with_timeout(Box::new(self.client.get(url.parse().unwrap()).map_err(move |e| {
+            debug!("failed GET: {}: {}", url, e);
+            Error::from(e)
         }).and_then(|res| {
             if res.status().class() == hyper::status::StatusClass::Success {
                 let content_length = res.headers().get::<header::ContentLength>()
@@ -92,9 +203,7 @@ impl Bucket {
             body.fold(Vec::new(), |mut body, chunk| {
                 body.extend_from_slice(&chunk);
                 Ok::<_, hyper::Error>(body)
-            }).chain_err(|| {
-                "failed to read HTTP body"
-            }).and_then(move |bytes| {
+            }).map_err(Error::from).and_then(move |bytes| {
                 if let Some(len) = content_length {
                     if len != bytes.len() as u64 {
                         bail!(format!("Bad HTTP body size read: {}, expected {}", bytes.len(), len));
@@ -104,7 +213,7 @@ impl Bucket {
                 }
                 Ok(bytes)
             })
-        }))
+        })))
     }
 
     pub fn put(&self, key: &str, content: Vec<u8>, creds: &AwsCredentials)
@@ -117,9 +226,16 @@ impl Bucket {
         let date = time::now_utc().rfc822().to_string();
         let mut canonical_headers = String::new();
         let token = creds.token().as_ref().map(|s| s.as_str());
+        let (sse, kms_key_id) = match self.sse {
+            None => (None, None),
+            Some(Sse::Aes256) => (Some("AES256"), None),
+            Some(Sse::Kms(ref key_id)) => (Some("aws:kms"), key_id.as_ref().map(|s| s.as_str())),
+        };
         // Keep the list of header values sorted!
         for (header, maybe_value) in vec![
             ("x-amz-security-token", token),
+            ("x-amz-server-side-encryption", sse),
+            ("x-amz-server-side-encryption-aws-kms-key-id", kms_key_id),
             ("x-amz-storage-class", Some("REDUCED_REDUNDANCY")),
             ] {
             if let Some(ref value) = maybe_value {
@@ -139,7 +255,7 @@ impl Bucket {
         request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
         request.set_body(content);
 
-        Box::new(self.client.request(request).then(|result| {
+        self.with_timeout(Box::new(self.client.request(request).then(|result| {
             match result {
                 Ok(res) => {
                     if res.status().class() == hyper::status::StatusClass::Success {
@@ -155,7 +271,84 @@ impl Bucket {
                     Err(e.into())
                 }
             }
-        }))
+        })))
+    }
+
+    pub fn delete(&self, key: &str, creds: &AwsCredentials) -> SFuture<()> {
+        let url = format!("{}{}", self.base_url, key);
+        debug!("DELETE {}", url);
+        let mut request = Request::new(Method::Delete, url.parse().unwrap());
+
+        let date = time::now_utc().rfc822().to_string();
+        let mut canonical_headers = String::new();
+        if let Some(ref token) = *creds.token() {
+            request.headers_mut()
+                   .set_raw("x-amz-security-token", vec!(token.as_bytes().to_vec()));
+            canonical_headers.push_str(format!("x-amz-security-token:{}\n", token).as_ref());
+        }
+        let auth = self.auth("DELETE", &date, key, "", &canonical_headers, "", creds);
+        request.headers_mut().set_raw("Date", vec!(date.into_bytes()));
+        request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
+
+        // S3 reports success when deleting an object that doesn't exist.
+        self.with_timeout(Box::new(self.client.request(request).then(|result| {
+            match result {
+                Ok(res) => {
+                    if res.status().class() == hyper::status::StatusClass::Success {
+                        trace!("DELETE succeeded");
+                        Ok(())
+                    } else {
+                        trace!("DELETE failed with HTTP status: {}", res.status());
+                        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+                    }
+                }
+                Err(e) => {
+                    trace!("DELETE failed with error: {:?}", e);
+                    Err(e.into())
+                }
+            }
+        })))
+    }
+
+    /// List the objects whose keys start with `prefix`, starting after the
+    /// key `marker` if given. S3 returns at most 1000 objects at a time.
+    pub fn list(&self, prefix: &str, marker: Option<&str>, creds: &AwsCredentials)
+                -> SFuture<ObjectList> {
+        let mut url = format!("{}?prefix={}", self.base_url, query_escape(prefix));
+        if let Some(marker) = marker {
+            url.push_str(&format!("&marker={}", query_escape(marker)));
+        }
+        debug!("GET {}", url);
+        let mut request = Request::new(Method::Get, url.parse().unwrap());
+
+        let date = time::now_utc().rfc822().to_string();
+        let mut canonical_headers = String::new();
+        if let Some(ref token) = *creds.token() {
+            request.headers_mut()
+                   .set_raw("x-amz-security-token", vec!(token.as_bytes().to_vec()));
+            canonical_headers.push_str(format!("x-amz-security-token:{}\n", token).as_ref());
+        }
+        // Query parameters other than subresources aren't signed.
+        let auth = self.auth("GET", &date, "", "", &canonical_headers, "", creds);
+        request.headers_mut().set_raw("Date", vec!(date.into_bytes()));
+        request.headers_mut().set_raw("Authorization", vec!(auth.into_bytes()));
+
+        self.with_timeout(Box::new(self.client.request(request).chain_err(move || {
+            format!("failed GET: {}", url)
+        }).and_then(|res| {
+            if res.status().class() == hyper::status::StatusClass::Success {
+                Ok(res.body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, hyper::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            }).map(|bytes| parse_object_list(&String::from_utf8_lossy(&bytes)))
+        })))
     }
 
     // http://docs.aws.amazon.com/AmazonS3/latest/dev/RESTAuthentication.html
@@ -172,3 +365,97 @@ impl Bucket {
         format!("AWS {}:{}", creds.aws_access_key_id(), signature)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_request_timeout() {
+        // A server that accepts connections but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let mut core = Core::new().unwrap();
+        let timeout = Duration::from_millis(200);
+        let bucket = Bucket::new("bucket", &endpoint, Ssl::No, Some(timeout), &core.handle());
+        let start = Instant::now();
+        assert!(core.run(bucket.get("foo")).is_err());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= timeout);
+        assert!(elapsed < Duration::from_secs(5), "request took {:?}", elapsed);
+        drop(listener);
+    }
+
+    /// Upload an object to a server that records the request headers, and
+    /// return them lowercased.
+    fn put_request_headers(sse: Option<Sse>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            tx.send(String::from_utf8_lossy(&request).to_lowercase()).unwrap();
+            drop(stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"));
+        });
+        let mut core = Core::new().unwrap();
+        let mut bucket = Bucket::new("bucket", &endpoint, Ssl::No, None, &core.handle());
+        if let Some(sse) = sse {
+            bucket.set_server_side_encryption(sse);
+        }
+        let creds = AwsCredentials::new("key", "secret", None, ::chrono::UTC::now());
+        core.run(bucket.put("foo", b"data".to_vec(), &creds)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn test_parse_object_list() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name><Prefix>project</Prefix><Marker></Marker><MaxKeys>1000</MaxKeys>
+  <IsTruncated>true</IsTruncated>
+  <Contents><Key>project/a/b/c/abcdef</Key><Size>1234</Size></Contents>
+  <Contents><Key>project/0/1/2/012&amp;345</Key><Size>10</Size></Contents>
+</ListBucketResult>"#;
+        assert_eq!(ObjectList {
+            keys: vec!["project/a/b/c/abcdef".to_owned(), "project/0/1/2/012&345".to_owned()],
+            bytes: 1244,
+            next_marker: Some("project/0/1/2/012&345".to_owned()),
+        }, parse_object_list(xml));
+        let xml = "<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>";
+        assert_eq!(ObjectList::default(), parse_object_list(xml));
+        assert_eq!("a%2Fb%20c-d_e.f~", query_escape("a/b c-d_e.f~"));
+    }
+
+    #[test]
+    fn test_put_server_side_encryption() {
+        let headers = put_request_headers(None);
+        assert!(headers.starts_with("put /foo "));
+        assert!(!headers.contains("x-amz-server-side-encryption"));
+
+        let headers = put_request_headers(Some(Sse::Aes256));
+        assert!(headers.contains("\r\nx-amz-server-side-encryption: aes256\r\n"));
+        assert!(!headers.contains("x-amz-server-side-encryption-aws-kms-key-id"));
+
+        let headers = put_request_headers(Some(Sse::Kms(None)));
+        assert!(headers.contains("\r\nx-amz-server-side-encryption: aws:kms\r\n"));
+        assert!(!headers.contains("x-amz-server-side-encryption-aws-kms-key-id"));
+
+        let headers = put_request_headers(Some(Sse::Kms(Some("my-key".to_owned()))));
+        assert!(headers.contains("\r\nx-amz-server-side-encryption: aws:kms\r\n"));
+        assert!(headers.contains("\r\nx-amz-server-side-encryption-aws-kms-key-id: my-key\r\n"));
+    }
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use cache::{Cache, CacheWrite, Storage};
+use cache::{Cache, CacheWrite, ClearStats, Storage};
 use errors::*;
 use std::cell::RefCell;
 use std::time::Duration;
@@ -45,6 +45,12 @@ impl Storage for MockStorage {
     fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
         f_ok(Duration::from_secs(0))
     }
+    fn delete(&self, _key: &str) -> SFuture<()> {
+        f_ok(())
+    }
+    fn clear(&self) -> SFuture<ClearStats> {
+        f_ok(ClearStats::default())
+    }
     fn location(&self) -> String { "Mock Storage".to_string() }
     fn current_size(&self) -> Option<usize> { None }
     fn max_size(&self) -> Option<usize> { None }
@@ -167,6 +167,49 @@ fn run_sccache_command_test(sccache: &Path, compiler: Compiler, tempdir: &Path)
     assert_eq!(2, info.stats.requests_executed);
     assert_eq!(1, info.stats.cache_hits);
     assert_eq!(1, info.stats.cache_misses);
+    trace!("request stats as CSV");
+    let csv = run_stdout(sccache, &["--show-stats", "--stats-format=csv"], tempdir, &[]);
+    let records = parse_csv(&csv);
+    assert_eq!(vec!["metric", "value"], records[0]);
+    assert!(records.contains(&vec!["stats.cache_hits".to_owned(), "1".to_owned()]));
+    assert!(records.contains(&vec!["stats.cache_misses".to_owned(), "1".to_owned()]));
+    if name == "gcc" || name == "clang" {
+        trace!("compile with depfile");
+        let dep_file = tempdir.join("test.d");
+        let mut args = compile_cmdline(name, &exe, &input, &output);
+        args.extend(vec_from!(OsString, "-MD", "-MF", "test.d"));
+        assert_eq!(true, run(sccache, &args, tempdir, &env_vars));
+        assert_eq!(true, fs::metadata(&dep_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        trace!("compile with depfile, cache hit");
+        fs::remove_file(&out_file).unwrap();
+        fs::remove_file(&dep_file).unwrap();
+        assert_eq!(true, run(sccache, &args, tempdir, &env_vars));
+        assert_eq!(true, fs::metadata(&out_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        assert_eq!(true, fs::metadata(&dep_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        let info = get_stats(sccache, tempdir);
+        assert_eq!(2, info.stats.cache_hits);
+        assert_eq!(2, info.stats.cache_misses);
+    }
+    if name == "clang" {
+        // A source with its includes already inlined by -frewrite-includes
+        // still has to be preprocessed, and is cached like any other source.
+        trace!("compile rewritten-includes source");
+        let rewritten = "test_rewritten.c";
+        assert_eq!(true, run(Path::new(&exe), &["-E", "-frewrite-includes", input, "-o", rewritten], tempdir, &env_vars));
+        let args = compile_cmdline(name, &exe, rewritten, &output);
+        assert_eq!(true, run(sccache, &args, tempdir, &env_vars));
+        assert_eq!(true, fs::metadata(&out_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        let info = get_stats(sccache, tempdir);
+        assert_eq!(2, info.stats.cache_hits);
+        assert_eq!(3, info.stats.cache_misses);
+        trace!("compile rewritten-includes source, cache hit");
+        fs::remove_file(&out_file).unwrap();
+        assert_eq!(true, run(sccache, &args, tempdir, &env_vars));
+        assert_eq!(true, fs::metadata(&out_file).and_then(|m| Ok(m.len() > 0)).unwrap());
+        let info = get_stats(sccache, tempdir);
+        assert_eq!(3, info.stats.cache_hits);
+        assert_eq!(3, info.stats.cache_misses);
+    }
     trace!("stop server");
     assert_eq!(true, run(sccache, &["--stop-server"], tempdir, &[]));
 }
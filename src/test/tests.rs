@@ -13,29 +13,53 @@
 // limitations under the License.
 
 use ::cache::disk::DiskCache;
+use ::cmdline::StatsFormat;
 use ::client::{
     connect_to_server,
 };
 use ::commands::{
+    compiler_check,
     do_compile,
+    do_compile_direct,
+    request_clear_cache,
+    request_compile,
+    request_evict,
+    request_reload_config,
     request_shutdown,
     request_stats,
+    stop_server,
+    verify_cache,
+    write_stats_csv,
 };
+use ::config::{DEFAULT_IDLE_TIMEOUT, SocketOptions};
 use env_logger;
 use futures::sync::oneshot::{self, Sender};
 use futures_cpupool::CpuPool;
 use ::mock_command::*;
+use ::protocol::{
+    CacheStatus,
+    CompileFinished,
+    CompileResponse,
+    Response,
+};
 use ::server::{
+    BuildTagStats,
+    DurationHistogram,
+    ServerInfo,
     ServerMessage,
     SccacheServer,
 };
-use std::fs::File;
+use serde_json;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
 use std::io::{
     Cursor,
     Write,
 };
+#[cfg(unix)]
 use std::net::TcpListener;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::{Arc,Mutex,mpsc};
 use std::thread;
@@ -51,6 +75,16 @@ struct ServerOptions {
     idle_timeout: Option<u64>,
     /// The maximum size of the disk cache.
     cache_size: Option<usize>,
+    /// The config file to read when reloading the configuration.
+    config_path: Option<PathBuf>,
+    /// Options for the server's listening socket.
+    socket_options: SocketOptions,
+    /// Run compiles without the cache when they're faster than this.
+    min_compile_time: Option<Duration>,
+    /// The number of threads in the server's pool.
+    num_workers: Option<usize>,
+    /// The maximum number of compilers to run at once.
+    max_parallel: Option<usize>,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
@@ -72,7 +106,13 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
                             .and_then(|o| o.cache_size.as_ref())
                             .map(|s| *s)
                             .unwrap_or(usize::MAX);
-    let pool = CpuPool::new(1);
+    let socket_options = options.as_ref()
+                                .map(|o| o.socket_options.clone())
+                                .unwrap_or_default();
+    let num_workers = options.as_ref()
+                             .and_then(|o| o.num_workers)
+                             .unwrap_or(1);
+    let pool = CpuPool::new(num_workers);
     let storage = Arc::new(DiskCache::new_for_testing(&cache_dir, cache_size, &pool));
 
     // Create a server on a background thread, get some useful bits from it.
@@ -80,13 +120,19 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let handle = thread::spawn(move || {
         let core = Core::new().unwrap();
-        let srv = SccacheServer::new(0, pool, core, storage).unwrap();
+        let srv = SccacheServer::new(0, pool, core, storage, &socket_options).unwrap();
         let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
         assert!(srv.port() > 0);
+        srv.set_max_workers(num_workers);
         if let Some(options) = options {
             if let Some(timeout) = options.idle_timeout {
                  srv.set_idle_timeout(Duration::from_millis(timeout));
             }
+            if let Some(config_path) = options.config_path {
+                srv.set_config_path(config_path);
+            }
+            srv.set_min_compile_time(options.min_compile_time);
+            srv.set_max_parallel(options.max_parallel);
         }
         let port = srv.port();
         let creator = srv.command_creator().clone();
@@ -97,6 +143,55 @@ fn run_server_thread<T>(cache_dir: &Path, options: T)
     (port, shutdown_tx, creator, handle)
 }
 
+/// Expect the server to check the compiler, and pretend it's GCC.
+fn expect_gcc(c: &mut MockCommandCreator) {
+    c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+}
+
+/// Expect a preprocessor invocation that succeeds.
+fn expect_preprocess(c: &mut MockCommandCreator) {
+    c.next_command_spawns(Ok(MockChild::new(exit_status(0), "preprocessor output", "")));
+}
+
+/// Expect a compiler invocation that writes `obj`, and prints `stdout` and
+/// `stderr`.
+fn expect_compile<T, U>(c: &mut MockCommandCreator, obj: PathBuf, stdout: T, stderr: U)
+    where T: AsRef<[u8]> + Send + 'static,
+          U: AsRef<[u8]> + Send + 'static,
+{
+    c.next_command_calls(move |_| {
+        File::create(&obj)
+            .and_then(|mut f| f.write_all(b"file contents"))
+            .map(|_| MockChild::new(exit_status(0), &stdout, &stderr))
+    });
+}
+
+/// Ask the server on `port` to compile `cmdline` with `exe` in `cwd`, and
+/// wait for the result.
+fn compile_on_server(port: u16,
+                     exe: &Path,
+                     cmdline: &Vec<OsString>,
+                     cwd: &Path,
+                     env_vars: Vec<(OsString, OsString)>) -> CompileFinished {
+    let mut conn = connect_to_server(port).unwrap();
+    match request_compile(&mut conn, exe, cmdline, cwd, env_vars).unwrap() {
+        CompileResponse::CompileStarted => {}
+        r @ _ => panic!("Unexpected compile response: {:?}", r),
+    }
+    match conn.read_one_response().unwrap() {
+        Response::CompileFinished(res) => res,
+        r @ _ => panic!("Unexpected response: {:?}", r),
+    }
+}
+
+/// Wait until the server on `port` has stored `count` cache entries. Cache
+/// writes finish after the compile's response is sent.
+fn wait_for_cache_writes(port: u16, count: u64) {
+    while request_stats(connect_to_server(port).unwrap()).unwrap().stats.cache_writes < count {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
 #[test]
 fn test_server_shutdown() {
     let f = TestFixture::new();
@@ -109,6 +204,44 @@ fn test_server_shutdown() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_stop_server_prints_stats() {
+    let f = TestFixture::new();
+    let (port, _sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler, so pretend to be an unsupported
+        // compiler.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "hello", "error")));
+    }
+    // Compile something, so there's a compile request to count.
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let client_creator = new_creator();
+    next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    let conn = connect_to_server(port).unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, f.tempdir.path(),
+                             Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap());
+    // Stopping the server reports its final stats.
+    let mut out = vec![];
+    stop_server(port, StatsFormat::json, &mut out).unwrap();
+    let info: ServerInfo = serde_json::from_slice(&out).unwrap();
+    assert_eq!(1, info.stats.compile_requests);
+    child.join().unwrap();
+    // There's nothing left to stop, which isn't an error.
+    let mut out = vec![];
+    stop_server(port, StatsFormat::text, &mut out).unwrap();
+    assert_eq!("no server running\n", String::from_utf8(out).unwrap());
+    // With JSON stats, that's still JSON.
+    let mut out = vec![];
+    stop_server(port, StatsFormat::json, &mut out).unwrap();
+    let info: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(Some(false), info.as_object().unwrap().get("running").and_then(|v| v.as_bool()));
+}
+
 #[test]
 fn test_server_idle_timeout() {
     let f = TestFixture::new();
@@ -121,6 +254,19 @@ fn test_server_idle_timeout() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_no_idle_timeout() {
+    let f = TestFixture::new();
+    // A zero idle timeout means the server never shuts down by itself.
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), ServerOptions { idle_timeout: Some(0), .. Default::default() });
+    thread::sleep(Duration::from_millis(200));
+    // It's still there to answer.
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(None, info.idle_timeout);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_stats() {
     let f = TestFixture::new();
@@ -130,12 +276,249 @@ fn test_server_stats() {
     // Ask it for stats.
     let info = request_stats(conn).unwrap();
     assert_eq!(0, info.stats.compile_requests);
+    assert_eq!(None, info.stats.hit_rate());
+    assert!(info.cache_location.starts_with("Local disk"));
+    assert_eq!(Some(0), info.cache_size);
+    assert_eq!(Some(usize::MAX), info.max_cache_size);
+    assert_eq!(Some(DEFAULT_IDLE_TIMEOUT), info.idle_timeout);
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_stats_workers() {
+    let f = TestFixture::new();
+    // Bind an arbitrary free port, then close it so nothing is listening.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let sccache = find_sccache_binary();
+    let run = |args: &[&str]| {
+        Command::new(&sccache)
+            .args(args)
+            .current_dir(f.tempdir.path())
+            .env("HOME", f.tempdir.path())
+            .env("XDG_CACHE_HOME", f.tempdir.path().join("cache"))
+            .env("SCCACHE_CONF", f.tempdir.path().join("missing-config"))
+            .env("SCCACHE_DIR", f.tempdir.path().join("cache"))
+            .env("SCCACHE_SERVER_PORT", port.to_string())
+            .env("SCCACHE_MAX_WORKERS", "3")
+            .output()
+            .unwrap()
+    };
+    // The server started with `SCCACHE_MAX_WORKERS` reports that many.
+    assert!(run(&["--start-server"]).status.success());
+    let output = run(&["--show-stats", "--stats-format", "json"]);
+    let stopped = run(&["--stop-server"]);
+    assert!(output.status.success());
+    assert!(stopped.status.success());
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let field = |name: &str| info.as_object().unwrap().get(name).and_then(|v| v.as_u64());
+    assert_eq!(Some(3), field("max_workers"));
+    assert_eq!(Some(0), field("active_workers"));
+}
+
+#[test]
+fn test_server_stats_csv() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let mut info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    info.stats.cache_hits = 3;
+    // Fields that need quoting.
+    info.compiler_versions.insert("/usr/bin/gcc,\"new\"".to_owned(), "gcc 1.0\nextra".to_owned());
+    let mut out = vec![];
+    write_stats_csv(&info, &mut out).unwrap();
+    let records = parse_csv(&String::from_utf8(out).unwrap());
+    assert_eq!(vec!["metric".to_owned(), "value".to_owned()], records[0]);
+    let rows = records[1..].iter().map(|r| {
+        assert_eq!(2, r.len(), "bad record: {:?}", r);
+        (r[0].clone(), r[1].clone())
+    }).collect::<HashMap<_, _>>();
+    assert_eq!(records.len() - 1, rows.len());
+    assert_eq!(Some(&"3".to_owned()), rows.get("stats.cache_hits"));
+    assert_eq!(Some(&info.max_workers.to_string()), rows.get("max_workers"));
+    assert_eq!(Some(&"gcc 1.0\nextra".to_owned()), rows.get("compiler_versions./usr/bin/gcc,\"new\""));
+    assert_eq!(Some(&"0".to_owned()), rows.get("timings.compile.total_us"));
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "hyper")]
+fn test_server_prometheus_metrics() {
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    let f = TestFixture::new();
+    let pool = CpuPool::new(1);
+    let storage = Arc::new(DiskCache::new_for_testing(&f.tempdir.path(), usize::MAX, &pool));
+    let (tx, rx) = mpsc::channel();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let child = thread::spawn(move || {
+        let core = Core::new().unwrap();
+        let srv = SccacheServer::new(0, pool, core, storage, &SocketOptions::default()).unwrap();
+        let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
+        let addr = "127.0.0.1:0".parse().unwrap();
+        tx.send(srv.listen_metrics(&addr).unwrap()).unwrap();
+        srv.run(shutdown_rx).unwrap();
+    });
+    let metrics_port = rx.recv().unwrap();
+    let mut conn = TcpStream::connect(("127.0.0.1", metrics_port)).unwrap();
+    conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "response: {}", response);
+    assert!(response.contains("sccache_cache_hits"), "response: {}", response);
+    assert!(response.to_lowercase().contains("content-type: application/openmetrics-text; version=1.0.0"),
+            "response: {}", response);
+    // Shut down the server.
+    shutdown_tx.send(()).unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_version_mismatch() {
+    use bincode;
+    use byteorder::{BigEndian, ByteOrder};
+    use errors::ErrorKind;
+    use protocol::{PROTOCOL_VERSION, Request};
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use unix_socket::{SocketListener, socket_path};
+
+    // Pretend to be a server from a different sccache, on the socket for
+    // an arbitrary free port.
+    let port = {
+        let core = Core::new().unwrap();
+        SocketListener::bind(0, 16, &core.handle()).unwrap().port()
+    };
+    let listener = UnixListener::bind(socket_path(port)).unwrap();
+    let child = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut request = vec![0; BigEndian::read_u32(&len) as usize];
+        stream.read_exact(&mut request).unwrap();
+        match bincode::deserialize(&request).unwrap() {
+            Request::Hello { version } => assert_eq!(PROTOCOL_VERSION, version),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+        let response = Response::Hello { version: PROTOCOL_VERSION + 1 };
+        let response = bincode::serialize(&response, bincode::Infinite).unwrap();
+        BigEndian::write_u32(&mut len, response.len() as u32);
+        stream.write_all(&len).unwrap();
+        stream.write_all(&response).unwrap();
+    });
+    let err = match connect_to_server(port) {
+        Ok(_) => panic!("Connected to a server with a different protocol version"),
+        Err(e) => e,
+    };
+    match *err.kind() {
+        ErrorKind::ServerVersionMismatch(server, client) => {
+            assert_eq!(PROTOCOL_VERSION + 1, server);
+            assert_eq!(PROTOCOL_VERSION, client);
+        }
+        ref k => panic!("Unexpected error: {:?}", k),
+    }
+    assert_eq!(format!("sccache server version {} differs from client {}; run --stop-server",
+                       PROTOCOL_VERSION + 1, PROTOCOL_VERSION),
+               err.to_string());
+    child.join().unwrap();
+    fs::remove_file(socket_path(port)).unwrap();
+}
+
+#[test]
+#[cfg(windows)]
+fn test_server_stats_named_pipe() {
+    use client::ServerConnection;
+    use named_pipe;
+
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    // Ask for stats over the server's pipe.
+    let pipe = named_pipe::connect(&named_pipe::pipe_name(port).unwrap()).unwrap();
+    let info = request_stats(ServerConnection::new(pipe).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    assert!(info.cache_location.starts_with("Local disk"));
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_many_connections() {
+    let f = TestFixture::new();
+    let backlog = 64;
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), ServerOptions {
+        socket_options: SocketOptions { backlog: backlog, .. Default::default() },
+        .. Default::default()
+    });
+    // Open a burst of connections before making any requests, as a
+    // parallel build would. None of them should be refused.
+    let conns = (0..backlog).map(|_| connect_to_server(port).unwrap()).collect::<Vec<_>>();
+    for conn in conns {
+        request_stats(conn).unwrap();
+    }
+    // Now signal it to shut down.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_evict_missing_key() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    // Connect to the server.
+    let conn = connect_to_server(port).unwrap();
+    // Evicting a key that isn't in the cache should succeed.
+    request_evict(conn, "0123456789abcdef".to_owned()).unwrap();
     // Now signal it to shut down.
     sender.send(ServerMessage::Shutdown).ok().unwrap();
     // Ensure that it shuts down.
     child.join().unwrap();
 }
 
+#[test]
+#[cfg(unix)]
+fn test_server_port_file_handoff() {
+    let f = TestFixture::new();
+    // Bind two arbitrary free ports, then close them so nothing is listening.
+    let (port1, port2) = {
+        let l1 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let l2 = TcpListener::bind("127.0.0.1:0").unwrap();
+        (l1.local_addr().unwrap().port(), l2.local_addr().unwrap().port())
+    };
+    let sccache = find_sccache_binary();
+    let run = |arg: &str, port: Option<u16>| {
+        let mut cmd = Command::new(&sccache);
+        cmd.arg(arg)
+            .current_dir(f.tempdir.path())
+            .env("HOME", f.tempdir.path())
+            .env("XDG_CACHE_HOME", f.tempdir.path().join("cache"))
+            .env("SCCACHE_CONF", f.tempdir.path().join("missing-config"))
+            .env("SCCACHE_DIR", f.tempdir.path().join("cache"));
+        match port {
+            Some(port) => cmd.env("SCCACHE_SERVER_PORT", port.to_string()),
+            None => cmd.env_remove("SCCACHE_SERVER_PORT"),
+        };
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "sccache {} failed: {}", arg, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    // The second server to start takes over from the first, so a client
+    // that doesn't name a port stops it rather than the first.
+    run("--start-server", Some(port1));
+    run("--start-server", Some(port2));
+    assert!(run("--stop-server", None).starts_with("Stopping sccache server"));
+    assert_eq!("no server running\n", run("--stop-server", Some(port2)));
+    assert!(run("--stop-server", Some(port1)).starts_with("Stopping sccache server"));
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
@@ -177,6 +560,62 @@ fn test_server_unsupported_compiler() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_cannot_cache_reasons() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    expect_gcc(&mut server_creator.lock().unwrap());
+    let client_creator = new_creator();
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let mut core = Core::new().unwrap();
+    let cmdlines: Vec<Vec<OsString>> = vec![
+        ovec!["-fsyntax-only", "-c", "file.c"],
+        ovec!["-c", "a.c", "b.c"],
+        ovec!["-fsyntax-only", "-c", "other.c"],
+    ];
+    for cmdline in cmdlines {
+        // The client runs the compiler itself.
+        next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+        let conn = connect_to_server(port).unwrap();
+        let mut stdout = Cursor::new(Vec::new());
+        let mut stderr = Cursor::new(Vec::new());
+        assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd,
+                                 Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap());
+    }
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(3, info.stats.requests_not_cacheable);
+    assert_eq!(Some(&2), info.stats.cannot_cache_reasons.get("-fsyntax-only"));
+    assert_eq!(Some(&1), info.stats.cannot_cache_reasons.get("multiple input files"));
+    assert_eq!(2, info.stats.cannot_cache_reasons.len());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_not_cacheable_is_not_a_miss() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    expect_gcc(&mut server_creator.lock().unwrap());
+    // The client runs the compiler itself.
+    let client_creator = new_creator();
+    next_command(&client_creator, Ok(MockChild::new(exit_status(0), "", "")));
+    let conn = connect_to_server(port).unwrap();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, &f.bins[0],
+                             ovec!["-fsyntax-only", "-c", "file.c"], f.tempdir.path(),
+                             Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.non_cacheable_compilations);
+    assert_eq!(0, info.stats.cache_misses);
+    assert_eq!(None, info.stats.hit_rate());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_compile() {
     match env_logger::init() {
@@ -193,21 +632,12 @@ fn test_server_compile() {
     let conn = connect_to_server(port).unwrap();
     {
         let mut c = server_creator.lock().unwrap();
-        // The server will check the compiler. Pretend it's GCC.
-        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        expect_gcc(&mut c);
         // Preprocessor invocation.
         c.next_command_spawns(Ok(MockChild::new(exit_status(0), PREPROCESSOR_STDOUT, PREPROCESSOR_STDERR)));
         // Compiler invocation.
         //TODO: wire up a way to get data written to stdin.
-        let obj = f.tempdir.path().join("file.o");
-        c.next_command_calls(move |_| {
-            // Pretend to compile something.
-            match File::create(&obj)
-                .and_then(|mut f| f.write_all(b"file contents")) {
-                    Ok(_) => Ok(MockChild::new(exit_status(0), STDOUT, STDERR)),
-                    Err(e) => Err(e),
-                }
-        });
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), STDOUT, STDERR);
     }
     // Ask the server to compile something.
     //TODO: MockCommand should validate these!
@@ -233,18 +663,1011 @@ fn test_server_compile() {
 }
 
 #[test]
-fn test_server_port_in_use() {
-    // Bind an arbitrary free port.
-    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-    let sccache = find_sccache_binary();
-    let output = Command::new(&sccache)
-        .arg("--start-server")
-        .env("SCCACHE_SERVER_PORT", listener.local_addr().unwrap().port().to_string())
-        .output()
+fn test_compile_direct() {
+    let f = TestFixture::new();
+    const STDOUT : &'static [u8] = b"some stdout";
+    let pool = CpuPool::new(1);
+    let storage = Arc::new(DiskCache::new(&f.tempdir.path().join("cache"), usize::MAX, &pool));
+    let creator = new_creator();
+    let obj = f.tempdir.path().join("file.o");
+    {
+        let mut c = creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, obj.clone(), STDOUT, "");
+    }
+    let exe = &f.bins[0];
+    let cmdline = ovec!["-c", "file.c", "-o", "file.o"];
+    let cwd = f.tempdir.path();
+    let mut core = Core::new().unwrap();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    assert_eq!(0, do_compile_direct(creator.clone(), &mut core, storage.clone(), &pool, exe, cmdline.clone(),
+                                    cwd, Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap());
+    assert_eq!(0, creator.lock().unwrap().children.len());
+    assert_eq!(STDOUT, stdout.into_inner().as_slice());
+    // The second compile is a cache hit, which doesn't run the compiler.
+    fs::remove_file(&obj).unwrap();
+    {
+        let mut c = creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+    }
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    assert_eq!(0, do_compile_direct(creator.clone(), &mut core, storage, &pool, exe, cmdline,
+                                    cwd, Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap());
+    assert_eq!(0, creator.lock().unwrap().children.len());
+    assert_eq!(STDOUT, stdout.into_inner().as_slice());
+    assert!(obj.exists());
+}
+
+#[test]
+fn test_server_compile_response_file() {
+    match env_logger::init() {
+        Ok(_) => {},
+        Err(_) => {},
+    }
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    const STDOUT : &'static [u8] = b"some stdout";
+    // The client expands this before sending the command line to the server.
+    File::create(f.tempdir.path().join("args.rsp"))
+        .and_then(|mut f| f.write_all(b"-c file.c -DFOO -o \"file.o\""))
         .unwrap();
-    assert!(!output.status.success());
-    let s = String::from_utf8_lossy(&output.stderr);
-    assert!(s.contains("Server startup failed:"),
-            "Output did not contain 'Failed to start server:':\n========\n{}\n========",
-            s);
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        // Preprocessor invocation, which should get the arguments from the file.
+        c.next_command_calls(|args: &[OsString]| {
+            assert!(args.iter().any(|a| a == "-DFOO"));
+            assert!(!args.iter().any(|a| a == "@args.rsp"));
+            Ok(MockChild::new(exit_status(0), "preprocessor output", ""))
+        });
+        // Compiler invocation.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |_| {
+            File::create(&obj)
+                .and_then(|mut f| f.write_all(b"file contents"))
+                .map(|_| MockChild::new(exit_status(0), STDOUT, ""))
+        });
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["@args.rsp".into()];
+    let cwd = f.tempdir.path();
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(STDOUT, stdout.into_inner().as_slice());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_compile_timeout() {
+    use futures::{future, Future};
+    use std::time::Instant;
+
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let (mut finish, running) = oneshot::channel();
+    let running = Arc::new(Mutex::new(Some(running)));
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        // The preprocessor hangs.
+        c.next_command_calls(move |_| {
+            let running = running.lock().unwrap().take().unwrap();
+            Ok(MockChild::new(exit_status(0), "", "").running_until(running))
+        });
+    }
+    const COMPILER_STDOUT: &'static [u8] = b"some stdout";
+    const COMPILER_STDERR: &'static [u8] = b"some stderr";
+    let client_creator = new_creator();
+    {
+        let mut c = client_creator.lock().unwrap();
+        // The client gives up on the server and runs the compiler itself.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), COMPILER_STDOUT, COMPILER_STDERR)));
+    }
+    let conn = connect_to_server(port).unwrap();
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let env_vars = vec![("SCCACHE_COMPILE_TIMEOUT".into(), "1".into())];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    let start = Instant::now();
+    assert_eq!(0, do_compile(client_creator.clone(), &mut core, conn, exe, cmdline, cwd, path, env_vars, &mut stdout, &mut stderr).unwrap());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    assert_eq!(COMPILER_STDOUT, &stdout.into_inner()[..]);
+    assert_eq!(COMPILER_STDERR, &stderr.into_inner()[..]);
+    // The server gives up on the hung preprocessor too.
+    future::poll_fn(|| finish.poll_cancel()).wait().unwrap();
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_compiler_check_gcc() {
+    let f = TestFixture::new();
+    let creator = new_creator();
+    // The detection probe's output.
+    next_command(&creator, Ok(MockChild::new(exit_status(0), "# 1 \"testfile.c\"\n\ngcc\n\"6.3.0\"\n", "")));
+    let mut stdout = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, compiler_check(creator.clone(), &mut core, &f.bins[0], f.tempdir.path(), Some(f.paths.clone()), &mut stdout).unwrap());
+    assert_eq!(0, creator.lock().unwrap().children.len());
+    let stdout = String::from_utf8(stdout.into_inner()).unwrap();
+    assert!(stdout.starts_with("Compiler: "), "{}", stdout);
+    assert!(stdout.ends_with("\nKind: gcc\nVersion: 6.3.0\n"), "{}", stdout);
+}
+
+#[test]
+fn test_compiler_check_unsupported() {
+    let f = TestFixture::new();
+    let creator = new_creator();
+    next_command(&creator, Ok(MockChild::new(exit_status(0), "hello", "")));
+    let mut stdout = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    assert_eq!(1, compiler_check(creator.clone(), &mut core, &f.bins[0], f.tempdir.path(), Some(f.paths.clone()), &mut stdout).unwrap());
+    let stdout = String::from_utf8(stdout.into_inner()).unwrap();
+    assert!(stdout.contains("Kind: unsupported\n"), "{}", stdout);
+}
+
+/// Run `verify_cache` against a server whose compiler writes `cached`, where
+/// compiling directly writes `direct`. Returns the status, stdout and stderr.
+fn run_verify_cache(direct: &'static [u8], cached: &'static [u8]) -> (i32, Vec<u8>, Vec<u8>) {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path().join("cache"), None);
+    let obj = f.tempdir.path().join("file.o");
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        // Preprocessor invocation for the forced cache miss.
+        expect_preprocess(&mut c);
+        // Compiler invocation.
+        let obj = obj.clone();
+        c.next_command_calls(move |_| {
+            File::create(&obj)
+                .and_then(|mut f| f.write_all(cached))
+                .map(|_| MockChild::new(exit_status(0), "", ""))
+        });
+        // Preprocessor invocation for the cache hit.
+        expect_preprocess(&mut c);
+    }
+    // The direct compile runs on the client.
+    let client_creator = new_creator();
+    next_command_calls(&client_creator, move |_| {
+        File::create(&obj)
+            .and_then(|mut f| f.write_all(direct))
+            .map(|_| MockChild::new(exit_status(0), "", ""))
+    });
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    let status = verify_cache(client_creator.clone(), &mut core, port, exe, cmdline, f.tempdir.path(),
+                              Some(f.paths.clone()), vec![], &mut stdout, &mut stderr).unwrap();
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    assert_eq!(0, client_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+    (status, stdout.into_inner(), stderr.into_inner())
+}
+
+#[test]
+fn test_verify_cache_match() {
+    let (status, stdout, stderr) = run_verify_cache(b"file contents", b"file contents");
+    assert_eq!(0, status, "stderr: {}", String::from_utf8_lossy(&stderr));
+    assert!(String::from_utf8_lossy(&stdout).contains("matches the direct compile (13 bytes)"));
+}
+
+#[test]
+fn test_verify_cache_mismatch() {
+    let (status, _stdout, stderr) = run_verify_cache(b"file contents", b"file kontents");
+    assert_eq!(1, status);
+    let stderr = String::from_utf8_lossy(&stderr);
+    assert!(stderr.contains("differs from the direct compile at byte 5"), "stderr: {}", stderr);
+}
+
+#[test]
+fn test_server_max_parallel() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), ServerOptions {
+        max_parallel: Some(1),
+        .. Default::default()
+    });
+    let (finish_first, first_running) = oneshot::channel();
+    let (finish_second, second_running) = oneshot::channel();
+    // Popped from the end as each compiler starts.
+    let running = Arc::new(Mutex::new(vec![second_running, first_running]));
+    // The outputs of the compilers that have started, in order.
+    let started = Arc::new(Mutex::new(vec![]));
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        // The preprocessor and compiler invocations for both compiles, in
+        // whatever order they happen.
+        for _ in 0..4 {
+            let dir = f.tempdir.path().to_path_buf();
+            let running = running.clone();
+            let started = started.clone();
+            c.next_command_calls(move |args| {
+                if args.iter().any(|a| a == "-E") {
+                    return Ok(MockChild::new(exit_status(0), format!("preprocessed {:?}", args), ""));
+                }
+                let obj = args.iter().skip_while(|a| *a != "-o").nth(1).unwrap().clone();
+                File::create(dir.join(&obj))?.write_all(b"file contents")?;
+                started.lock().unwrap().push(obj);
+                let finish = running.lock().unwrap().pop().unwrap();
+                Ok(MockChild::new(exit_status(0), "", "").running_until(finish))
+            });
+        }
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let mut conns = vec![];
+    for name in &["a", "b"] {
+        let mut conn = connect_to_server(port).unwrap();
+        let cmdline: Vec<OsString> = vec!["-c".into(), format!("{}.c", name).into(),
+                                          "-o".into(), format!("{}.o", name).into()];
+        // Both compiles start right away, even though only one can run.
+        match request_compile(&mut conn, exe, &cmdline, cwd, vec![]).unwrap() {
+            CompileResponse::CompileStarted => {}
+            r @ _ => panic!("Unexpected compile response: {:?}", r),
+        }
+        conns.push(conn);
+    }
+    let wait_until = |what: &str, done: &Fn(&ServerInfo) -> bool| {
+        for _ in 0..100 {
+            if done(&request_stats(connect_to_server(port).unwrap()).unwrap()) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Timed out waiting for {}", what);
+    };
+    // One compiler runs while the other compile waits in the queue.
+    wait_until("one running and one queued", &|info| {
+        info.compilations_running == 1 && info.compilations_queued == 1
+    });
+    assert_eq!(1, started.lock().unwrap().len());
+    // Finishing the first compiler lets the second one start.
+    finish_first.send(()).unwrap();
+    wait_until("the second compiler", &|_| started.lock().unwrap().len() == 2);
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.compilations_running);
+    assert_eq!(0, info.compilations_queued);
+    finish_second.send(()).unwrap();
+    for mut conn in conns {
+        match conn.read_one_response().unwrap() {
+            Response::CompileFinished(res) => assert_eq!(Some(0), res.retcode),
+            r @ _ => panic!("Unexpected response: {:?}", r),
+        }
+    }
+    wait_until("no running compilers", &|info| info.compilations_running == 0);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_stats_timings() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path().join("cache"), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+        // Preprocessor invocation for the cache hit.
+        expect_preprocess(&mut c);
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let compile = || {
+        compile_on_server(port, exe, &cmdline, cwd, vec![]).cache_status
+    };
+    assert_eq!(CacheStatus::Miss, compile());
+    wait_for_cache_writes(port, 1);
+    let first = request_stats(connect_to_server(port).unwrap()).unwrap().timings;
+    assert_eq!(1, first.preprocess.count);
+    assert_eq!(1, first.compile.count);
+    assert_eq!(1, first.cache_read.count);
+    assert_eq!(1, first.cache_write.count);
+    assert_eq!(CacheStatus::Hit, compile());
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    let second = info.timings.clone();
+    assert_eq!(2, second.preprocess.count);
+    assert_eq!(1, second.compile.count);
+    assert_eq!(2, second.cache_read.count);
+    assert_eq!(1, second.cache_write.count);
+    for &(before, after) in &[(first.preprocess, second.preprocess),
+                              (first.compile, second.compile),
+                              (first.cache_read, second.cache_read),
+                              (first.cache_write, second.cache_write)] {
+        assert!(after.total_us >= before.total_us);
+        assert!(after.total_us >= after.average_us);
+        assert_eq!(after.total_us / after.count, after.average_us);
+    }
+    // The JSON stats have the timings as integer microseconds.
+    let json = serde_json::to_value(&info).unwrap();
+    assert_eq!(Some(second.preprocess.total_us), json["timings"]["preprocess"]["total_us"].as_u64());
+    assert_eq!(Some(second.compile.average_us), json["timings"]["compile"]["average_us"].as_u64());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_ccache_wrapper() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+    }
+    mk_bin(&f.tempdir.path().join(SUBDIRS[0]), "ccache").unwrap();
+    // Run `ccache bin -c file.c -o file.o`. If the server were handed
+    // ccache as the compiler, it would see two inputs and refuse to cache.
+    let cmdline = vec![f.bins[0].clone().into(), "-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    // This creator shouldn't create any processes. It will assert if
+    // it tries to.
+    let client_creator = new_creator();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let path = Some(f.paths);
+    let mut core = Core::new().unwrap();
+    assert_eq!(0, do_compile(client_creator, &mut core, conn, "ccache".as_ref(), cmdline, cwd, path, vec![], &mut stdout, &mut stderr).unwrap());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_cache_status() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    const STDOUT : &'static [u8] = b"some stdout";
+    const STDERR : &'static [u8] = b"some stderr";
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), STDOUT, STDERR);
+        // Preprocessor invocation for the second compile, which should be
+        // a cache hit.
+        expect_preprocess(&mut c);
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    for expected in &[CacheStatus::Miss, CacheStatus::Hit] {
+        let res = compile_on_server(port, exe, &cmdline, cwd, vec![]);
+        assert_eq!(Some(0), res.retcode);
+        assert_eq!(*expected, res.cache_status);
+        assert_eq!(STDOUT, res.stdout.as_slice());
+        assert_eq!(STDERR, res.stderr.as_slice());
+    }
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(Some(50.0), info.stats.hit_rate());
+    assert!(info.cache_size.unwrap() > 0);
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_recache_path() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        // Preprocessor and compiler invocations for the first compile, which
+        // is a miss; the second, whose input doesn't match
+        // `SCCACHE_RECACHE_PATHS` and which is a hit; and the third, whose
+        // input does, and which skips the cache.
+        for i in 0..3 {
+            expect_preprocess(&mut c);
+            if i == 1 {
+                continue
+            }
+            expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+        }
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let rounds = [(None, CacheStatus::Miss),
+                  (Some("other*.c"), CacheStatus::Hit),
+                  (Some("fi?e.*"), CacheStatus::Miss)];
+    for &(recache, ref expected) in &rounds {
+        let env_vars: Vec<(OsString, OsString)> = recache.into_iter()
+            .map(|r| ("SCCACHE_RECACHE_PATHS".into(), r.into()))
+            .collect();
+        assert_eq!(*expected, compile_on_server(port, exe, &cmdline, cwd, env_vars).cache_status);
+    }
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_build_tag() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let env_vars = vec![("SCCACHE_BUILD_TAG".into(), "ci-1234".into())];
+    assert_eq!(CacheStatus::Miss, compile_on_server(port, exe, &cmdline, cwd, env_vars).cache_status);
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, info.stats.build_tags.len());
+    assert_eq!(BuildTagStats { compile_requests: 1, cache_hits: 0, cache_misses: 1 },
+               info.stats.build_tags["ci-1234"]);
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_preprocessed_input() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path().join("cache"), None);
+    File::create(f.tempdir.path().join("file.i"))
+        .and_then(|mut f| f.write_all(b"int main() { return 0; }"))
+        .unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        // No preprocessor invocation, only the compiler.
+        let obj = f.tempdir.path().join("file.o");
+        c.next_command_calls(move |args| {
+            assert_eq!(ovec!["-c", "-x", "cpp-output", "-", "-o", "file.o"], args);
+            match File::create(&obj)
+                .and_then(|mut f| f.write_all(b"file contents")) {
+                    Ok(_) => Ok(MockChild::new(exit_status(0), "", "")),
+                    Err(e) => Err(e),
+                }
+        });
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.i".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    for expected in &[CacheStatus::Miss, CacheStatus::Hit] {
+        let res = compile_on_server(port, exe, &cmdline, cwd, vec![]);
+        assert_eq!(Some(0), res.retcode);
+        assert_eq!(*expected, res.cache_status);
+        if *expected == CacheStatus::Miss {
+            // The cache write finishes after the compile response is sent.
+            wait_for_cache_writes(port, 1);
+        }
+    }
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_werror_not_reused() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    const WARNING : &'static [u8] = b"file.c:1: warning: something";
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        // Compiler invocation, which warns but succeeds.
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", WARNING);
+        // Preprocessor invocation with -Werror.
+        expect_preprocess(&mut c);
+        // Compiler invocation with -Werror, which turns the warning into an error.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(1), "", WARNING)));
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    let compile = |cmdline: Vec<OsString>| {
+        compile_on_server(port, exe, &cmdline, cwd, vec![])
+    };
+    let res = compile(vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()]);
+    assert_eq!(Some(0), res.retcode);
+    assert_eq!(CacheStatus::Miss, res.cache_status);
+    // Wait for the entry to be stored, so that a wrongly shared key would hit.
+    wait_for_cache_writes(port, 1);
+    let res = compile(vec!["-c".into(), "file.c".into(), "-Werror".into(), "-o".into(), "file.o".into()]);
+    assert_eq!(Some(1), res.retcode);
+    assert_neq!(CacheStatus::Hit, res.cache_status);
+    assert_eq!(WARNING, res.stderr.as_slice());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_output_replayed() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    // Output that isn't valid UTF-8 and has trailing whitespace, which must
+    // be replayed as is.
+    const STDOUT : &'static [u8] = b"note: \xe9t\xe9  \r\n";
+    const STDERR : &'static [u8] = b"file.c:1: warning: \xff\n\n";
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), STDOUT, STDERR);
+        // Preprocessor invocation for the second compile, which should be
+        // a cache hit.
+        expect_preprocess(&mut c);
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let res = compile_on_server(port, exe, &cmdline, cwd, vec![]);
+    assert_eq!(CacheStatus::Miss, res.cache_status);
+    wait_for_cache_writes(port, 1);
+    let res = compile_on_server(port, exe, &cmdline, cwd, vec![]);
+    assert_eq!(CacheStatus::Hit, res.cache_status);
+    assert_eq!(Some(0), res.retcode);
+    assert_eq!(STDOUT, res.stdout.as_slice());
+    assert_eq!(STDERR, res.stderr.as_slice());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_compile_killed_by_signal() {
+    use errors::ErrorKind;
+    use libc;
+
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        // Compiler invocation, which dies from SIGSEGV.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(libc::SIGSEGV), "", "")));
+    }
+    let exe = &f.bins[0];
+    let cmdline = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let mut stdout = Cursor::new(Vec::new());
+    let mut stderr = Cursor::new(Vec::new());
+    let mut core = Core::new().unwrap();
+    let conn = connect_to_server(port).unwrap();
+    let res = do_compile(new_creator(), &mut core, conn, exe, cmdline, cwd, Some(f.paths.clone()),
+                         vec![], &mut stdout, &mut stderr);
+    match res {
+        Err(ref e) => match *e.kind() {
+            ErrorKind::CompilerKilled(signal) => assert_eq!(libc::SIGSEGV, signal),
+            ref k => panic!("Unexpected error: {}", k),
+        },
+        Ok(r) => panic!("Unexpected exit status: {}", r),
+    }
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // The compile failed, and nothing was stored.
+    let stats = request_stats(connect_to_server(port).unwrap()).unwrap().stats;
+    assert_eq!(1, stats.compile_fails);
+    assert_eq!(0, stats.cache_writes);
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_clear_cache() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path().join("cache"), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        for _ in 0..2 {
+            expect_preprocess(&mut c);
+            expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+        }
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    let compile = || {
+        compile_on_server(port, exe, &cmdline, cwd, vec![]).cache_status
+    };
+    assert_eq!(CacheStatus::Miss, compile());
+    // The cache write finishes after the compile response is sent.
+    wait_for_cache_writes(port, 1);
+    let cleared = request_clear_cache(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(Some(1), cleared.entries);
+    assert!(cleared.bytes.unwrap() > 0);
+    // The entry is gone, so this compiles again.
+    assert_eq!(CacheStatus::Miss, compile());
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compile_too_fast() {
+    let f = TestFixture::new();
+    // Every compile is faster than an hour.
+    let options = ServerOptions {
+        min_compile_time: Some(Duration::from_secs(3600)),
+        .. Default::default()
+    };
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path().join("cache"), options);
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+    }
+    let exe = &f.bins[0];
+    let cwd = f.tempdir.path();
+    // The first compile goes through the cache, to measure the compiler.
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    assert_eq!(CacheStatus::Miss, compile_on_server(port, exe, &cmdline, cwd, vec![]).cache_status);
+    wait_for_cache_writes(port, 1);
+    // After that, the client is told to compile without the cache.
+    let cmdline: Vec<OsString> = vec!["-c".into(), "other.c".into(), "-o".into(), "other.o".into()];
+    let mut conn = connect_to_server(port).unwrap();
+    match request_compile(&mut conn, exe, &cmdline, cwd, vec![]).unwrap() {
+        CompileResponse::UnhandledCompile(_) => {}
+        r @ _ => panic!("Unexpected compile response: {:?}", r),
+    }
+    let stats = request_stats(connect_to_server(port).unwrap()).unwrap().stats;
+    assert_eq!(1, stats.requests_too_fast);
+    assert_eq!(1, stats.requests_executed);
+    assert_eq!(1, stats.cache_writes);
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compiler_versions() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC, and
+        // report its version.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc\n\"4.9.2\"", "")));
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    assert_eq!(Some(0), compile_on_server(port, exe, &cmdline, f.tempdir.path(), vec![]).retcode);
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    let exe = exe.to_string_lossy().into_owned();
+    assert_eq!(Some(&"4.9.2".to_owned()), info.compiler_versions.get(&exe));
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_reload_config() {
+    let f = TestFixture::new();
+    let old_cache_dir = f.tempdir.path().join("old-cache");
+    let new_cache_dir = f.tempdir.path().join("new-cache");
+    let config_path = f.tempdir.path().join("config");
+    let (port, sender, server_creator, child) = run_server_thread(&old_cache_dir, ServerOptions {
+        config_path: Some(config_path.clone()),
+        .. Default::default()
+    });
+    File::create(&config_path)
+        .and_then(|mut f| {
+            write!(f, "cache_type = \"disk\"\ncache_dir = {:?}\n", new_cache_dir.to_str().unwrap())
+        })
+        .unwrap();
+    let needs_restart = request_reload_config(connect_to_server(port).unwrap()).unwrap();
+    assert!(needs_restart.is_empty());
+    {
+        let mut c = server_creator.lock().unwrap();
+        expect_gcc(&mut c);
+        expect_preprocess(&mut c);
+        expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+        // Preprocessor invocation for the second compile, which should be
+        // a cache hit from the new cache dir.
+        expect_preprocess(&mut c);
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    let cwd = f.tempdir.path();
+    for expected in &[CacheStatus::Miss, CacheStatus::Hit] {
+        assert_eq!(*expected, compile_on_server(port, exe, &cmdline, cwd, vec![]).cache_status);
+    }
+    let count_entries = |dir: &Path| fs::read_dir(dir).map(|d| d.count()).unwrap_or(0);
+    assert_eq!(0, count_entries(&old_cache_dir));
+    assert!(count_entries(&new_cache_dir) > 0);
+    // A restart-only setting is reported when it changes, and not again by
+    // later reloads.
+    File::create(&config_path)
+        .and_then(|mut f| {
+            write!(f, "cache_type = \"disk\"\ncache_dir = {:?}\nlisten_backlog = 7\n", new_cache_dir.to_str().unwrap())
+        })
+        .unwrap();
+    let needs_restart = request_reload_config(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(vec!["listen_backlog".to_owned()], needs_restart);
+    let needs_restart = request_reload_config(connect_to_server(port).unwrap()).unwrap();
+    assert!(needs_restart.is_empty());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_compiler_changed() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler before each compile, since
+        // it changes in between.
+        for _ in 0..2 {
+            expect_gcc(&mut c);
+            expect_preprocess(&mut c);
+            expect_compile(&mut c, f.tempdir.path().join("file.o"), "", "");
+        }
+    }
+    let exe = &f.bins[0];
+    let cmdline: Vec<OsString> = vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()];
+    for contents in &["", "upgraded compiler"] {
+        // Upgrade the compiler in place.
+        File::create(exe).and_then(|mut f| f.write_all(contents.as_bytes())).unwrap();
+        // Nothing the current compiler built is in the cache yet.
+        assert_eq!(CacheStatus::Miss, compile_on_server(port, exe, &cmdline, f.tempdir.path(), vec![]).cache_status);
+    }
+    // Make sure we ran the mock processes.
+    assert_eq!(0, server_creator.lock().unwrap().children.len());
+    // Shut down the server.
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    // Ensure that it shuts down.
+    child.join().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_syslog_no_log_file() {
+    let f = TestFixture::new();
+    // Bind an arbitrary free port, then close it so nothing is listening.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let sccache = find_sccache_binary();
+    Command::new(&sccache)
+        .arg("--stop-server")
+        .current_dir(f.tempdir.path())
+        .env("SCCACHE_SERVER_PORT", port.to_string())
+        .env("SCCACHE_LOG_LEVEL", "debug")
+        .env("SCCACHE_SYSLOG", "1")
+        .output()
+        .unwrap();
+    assert!(!f.tempdir.path().join("sccache.log").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_port_in_use() {
+    // Bind an arbitrary free port.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let sccache = find_sccache_binary();
+    let output = Command::new(&sccache)
+        .arg("--start-server")
+        .env("SCCACHE_SERVER_PORT", listener.local_addr().unwrap().port().to_string())
+        .env("SCCACHE_SERVER_TCP", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let s = String::from_utf8_lossy(&output.stderr);
+    assert!(s.contains("Server startup failed:"),
+            "Output did not contain 'Failed to start server:':\n========\n{}\n========",
+            s);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_server_socket_in_use() {
+    use unix_socket::{SocketListener, socket_path};
+
+    // Take the socket for an arbitrary free port.
+    let core = Core::new().unwrap();
+    let listener = SocketListener::bind(0, 16, &core.handle()).unwrap();
+    let sccache = find_sccache_binary();
+    let output = Command::new(&sccache)
+        .arg("--start-server")
+        .env("SCCACHE_SERVER_PORT", listener.port().to_string())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let s = String::from_utf8_lossy(&output.stderr);
+    assert!(s.contains("Server startup failed:"),
+            "Output did not contain 'Failed to start server:':\n========\n{}\n========",
+            s);
+    // The running server's socket is left alone.
+    assert!(socket_path(listener.port()).exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_start_server_twice() {
+    use std::process::Stdio;
+
+    let f = TestFixture::new();
+    // Bind an arbitrary free port, then close it so nothing is listening.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let sccache = find_sccache_binary();
+    let run = |arg: &str| {
+        Command::new(&sccache)
+            .arg(arg)
+            .current_dir(f.tempdir.path())
+            .env("HOME", f.tempdir.path())
+            .env("XDG_CACHE_HOME", f.tempdir.path().join("cache"))
+            .env("SCCACHE_DIR", f.tempdir.path().join("cache"))
+            .env("SCCACHE_SERVER_PORT", port.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    };
+    // Start two servers at once, like two builds racing to start one.
+    let starts = vec![run("--start-server"), run("--start-server")];
+    let outputs = starts.into_iter().map(|c| c.wait_with_output().unwrap()).collect::<Vec<_>>();
+    assert_eq!(1, outputs.iter().filter(|o| o.status.success()).count());
+    // The other one reports which port it couldn't take.
+    for output in outputs.iter().filter(|o| !o.status.success()) {
+        let s = String::from_utf8_lossy(&output.stderr);
+        assert!(s.contains("Server startup failed:") && s.contains(&format!("port {}", port)),
+                "Output did not name port {}:\n========\n{}\n========", port, s);
+    }
+    // Stopping the server that started leaves no server to stop, which
+    // isn't an error.
+    assert!(run("--stop-server").wait_with_output().unwrap().status.success());
+    let output = run("--stop-server").wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!("no server running\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn test_show_config() {
+    let f = TestFixture::new();
+    let cache_dir = f.tempdir.path().join("cache");
+    let sccache = find_sccache_binary();
+    let show_config = |format: &str| {
+        let output = Command::new(&sccache)
+            .args(&["--show-config", "--stats-format", format])
+            .env("SCCACHE_CONF", f.tempdir.path().join("missing-config"))
+            .env("SCCACHE_DIR", &cache_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let text = show_config("text");
+    let line = text.lines().find(|l| l.starts_with("cache_dir ")).unwrap();
+    assert!(line.contains(&cache_dir.display().to_string()), "Unexpected line: {}", line);
+    assert!(line.ends_with("(env SCCACHE_DIR)"), "Unexpected line: {}", line);
+    let json: serde_json::Value = serde_json::from_str(&show_config("json")).unwrap();
+    let field = |setting: &serde_json::Value, name: &str| {
+        setting.as_object().unwrap().get(name).and_then(|v| v.as_str()).unwrap().to_owned()
+    };
+    let setting = json.as_array().unwrap().iter()
+        .find(|s| field(s, "name") == "cache_dir")
+        .unwrap();
+    assert_eq!(cache_dir.to_str().unwrap(), field(setting, "value"));
+    assert_eq!("env SCCACHE_DIR", field(setting, "source"));
+}
+
+#[test]
+#[cfg(windows)]
+fn test_server_pipe_in_use() {
+    use named_pipe::PipeListener;
+
+    // Take the pipe for an arbitrary free port.
+    let core = Core::new().unwrap();
+    let listener = PipeListener::bind(0, &core.handle()).unwrap();
+    let sccache = find_sccache_binary();
+    let output = Command::new(&sccache)
+        .arg("--start-server")
+        .env("SCCACHE_SERVER_PORT", listener.port().to_string())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let s = String::from_utf8_lossy(&output.stderr);
+    assert!(s.contains("Server startup failed:"),
+            "Output did not contain 'Failed to start server:':\n========\n{}\n========",
+            s);
+}
+
+#[test]
+fn test_duration_histogram_openmetrics() {
+    let mut h = DurationHistogram::default();
+    for &ms in &[1, 3, 7, 40, 40, 90, 200, 1500, 7000, 400000] {
+        h.record(Duration::from_millis(ms));
+    }
+    let mut out = String::new();
+    h.write_openmetrics("test_seconds", &mut out);
+    let buckets: Vec<(String, u64)> = out.lines()
+        .filter(|l| l.starts_with("test_seconds_bucket{le=\""))
+        .map(|l| {
+            let (le, value) = l.split_at(l.find("} ").unwrap());
+            (le["test_seconds_bucket{le=\"".len()..le.len() - 1].to_owned(),
+             value[2..].parse().unwrap())
+        })
+        .collect();
+    assert!(buckets.len() > 1);
+    for pair in buckets.windows(2) {
+        assert!(pair[0].1 <= pair[1].1, "Buckets not monotonic: {:?}", buckets);
+    }
+    // The smallest bucket holds durations up to 5ms.
+    assert_eq!(("0.005".to_owned(), 2), buckets[0]);
+    // The 400s duration is larger than every bucket but `+Inf`.
+    assert_eq!(("+Inf".to_owned(), 10), *buckets.last().unwrap());
+    assert_eq!(9, buckets[buckets.len() - 2].1);
+    assert!(out.contains("test_seconds_count 10\n"));
 }
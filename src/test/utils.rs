@@ -192,6 +192,40 @@ pub fn empty_output() -> process::Output {
     }
 }
 
+/// Parse `input` as CSV, returning the fields of each record. Quoted fields
+/// may contain separators, newlines and doubled quotes.
+pub fn parse_csv(input: &str) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(::std::mem::replace(&mut field, String::new())),
+            '\n' if !quoted => {
+                record.push(::std::mem::replace(&mut field, String::new()));
+                records.push(::std::mem::replace(&mut record, vec![]));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
 #[test]
 fn test_map_contains_ok() {
     let mut m = HashMap::new();
@@ -216,3 +250,10 @@ fn test_map_contains_wrong_value() {
     m.insert("b", 3);
     assert_map_contains!(m, ("a", 1), ("b", 2));
 }
+
+#[test]
+fn test_parse_csv() {
+    assert_eq!(parse_csv("a,b\n\"c,\"\"d\"\"\",\"e\nf\"\n"),
+               vec![vec!["a".to_owned(), "b".to_owned()],
+                    vec!["c,\"d\"".to_owned(), "e\nf".to_owned()]]);
+}
@@ -0,0 +1,241 @@
+// Copyright 2017 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The UNIX domain sockets that clients and the server talk over on Unix,
+//! which unlike a TCP port on localhost only their owner can connect to.
+//!
+//! Sockets are in a directory that only their user can get into, and clients
+//! only connect to sockets their user owns, so that another user of the
+//! machine can't pretend to be the server.
+
+use commands::DEFAULT_PORT;
+use futures::{Async, Poll, Stream};
+use libc;
+use std::env;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use tokio_core::reactor::Handle;
+use tokio_uds::{UnixListener, UnixStream};
+use uuid::Uuid;
+
+/// How many ports `SocketListener::bind` tries when asked for any free port.
+const BIND_ANY_ATTEMPTS: usize = 100;
+
+fn uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// Return the directory this user's sockets are in: `$XDG_RUNTIME_DIR` if
+/// it's set, and `sccache-<uid>` in the temp directory otherwise.
+fn socket_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::temp_dir().join(format!("sccache-{}", uid())),
+    }
+}
+
+/// Return the path of the socket the server for `port` listens on.
+pub fn socket_path(port: u16) -> PathBuf {
+    socket_path_in(&socket_dir(), uid(), port)
+}
+
+/// Check that `dir` is a directory that `uid` owns and that no other user
+/// can get into.
+fn check_private_dir(dir: &Path, uid: u32) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} must be a directory that only its owner can use",
+                                          dir.display())))
+    }
+    Ok(())
+}
+
+/// Create the directory for this user's sockets, if it doesn't exist, and
+/// check that only this user can get into it.
+fn create_socket_dir() -> io::Result<PathBuf> {
+    let dir = socket_dir();
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    check_private_dir(&dir, uid())?;
+    Ok(dir)
+}
+
+/// Return the path of the socket in `dir` that the server for `port` run by
+/// the user `uid` listens on. Sockets are per-user, so that users of a
+/// shared machine don't collide. Ports are only used to tell servers apart,
+/// like `SCCACHE_SERVER_PORT` does with TCP.
+fn socket_path_in(dir: &Path, uid: u32, port: u16) -> PathBuf {
+    if port == DEFAULT_PORT {
+        dir.join(format!("sccache-{}.sock", uid))
+    } else {
+        dir.join(format!("sccache-{}-{}.sock", uid, port))
+    }
+}
+
+/// Open the client end of the socket of the server for `port`, if it's in
+/// this user's socket directory and owned by this user.
+pub fn connect(port: u16) -> io::Result<net::UnixStream> {
+    check_private_dir(&socket_dir(), uid())?;
+    let path = socket_path(port);
+    if fs::symlink_metadata(&path)?.uid() != uid() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("{} is owned by another user", path.display())))
+    }
+    net::UnixStream::connect(&path)
+}
+
+/// Keep users other than the owner out of the socket `listener` at `path`,
+/// even when it's in a shared directory, and let up to `backlog` clients
+/// queue up.
+fn configure(listener: &net::UnixListener, path: &Path, backlog: i32) -> io::Result<()> {
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    if unsafe { libc::listen(listener.as_raw_fd(), backlog) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+/// A server's socket. This is a `Stream` of connections from clients. The
+/// socket is removed when this is dropped.
+pub struct SocketListener {
+    inner: UnixListener,
+    path: PathBuf,
+    port: u16,
+}
+
+impl SocketListener {
+    /// Listen on the socket for `port`, queueing up to `backlog` connections.
+    /// If `port` is zero, pick a port whose socket isn't in use.
+    pub fn bind(port: u16, backlog: i32, handle: &Handle) -> io::Result<SocketListener> {
+        if port != 0 {
+            return SocketListener::bind_port(port, backlog, handle)
+        }
+        let bytes = Uuid::new_v4();
+        let bytes = bytes.as_bytes();
+        let start = (bytes[0] as u16) << 8 | bytes[1] as u16;
+        let mut last_error = None;
+        for i in 0..BIND_ANY_ATTEMPTS {
+            let port = start.wrapping_add(i as u16);
+            if port == 0 || port == DEFAULT_PORT {
+                continue
+            }
+            match SocketListener::bind_port(port, backlog, handle) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrInUse, "no free socket path")
+        }))
+    }
+
+    fn bind_port(port: u16, backlog: i32, handle: &Handle) -> io::Result<SocketListener> {
+        let path = socket_path_in(&create_socket_dir()?, uid(), port);
+        if fs::symlink_metadata(&path).is_ok() {
+            // A socket that refuses connections was left behind by a server
+            // that didn't shut down cleanly, so it's safe to replace.
+            match net::UnixStream::connect(&path) {
+                Ok(_) => {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                              format!("{} is in use", path.display())))
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    debug!("removing stale socket {}", path.display());
+                    fs::remove_file(&path)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let listener = net::UnixListener::bind(&path)?;
+        let inner = configure(&listener, &path, backlog)
+            .and_then(|_| UnixListener::from_listener(listener, handle));
+        match inner {
+            Ok(inner) => Ok(SocketListener {
+                inner: inner,
+                path: path,
+                port: port,
+            }),
+            Err(e) => {
+                fs::remove_file(&path).ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// Return the port this socket is named after.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Stream for SocketListener {
+    type Item = UnixStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self.inner.accept() {
+            Ok((stream, _addr)) => Ok(Async::Ready(Some(stream))),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use commands::DEFAULT_PORT;
+    use std::path::Path;
+    use super::*;
+
+    #[test]
+    fn test_socket_path_per_user() {
+        let dir = Path::new("/run/user");
+        assert_eq!(Path::new("/run/user/sccache-1000.sock"),
+                   socket_path_in(dir, 1000, DEFAULT_PORT));
+        assert!(socket_path_in(dir, 1000, DEFAULT_PORT) != socket_path_in(dir, 1001, DEFAULT_PORT));
+        assert!(socket_path_in(dir, 1000, 1234) != socket_path_in(dir, 1001, 1234));
+        assert!(socket_path_in(dir, 1000, 1234) != socket_path_in(dir, 1000, DEFAULT_PORT));
+    }
+
+    #[test]
+    fn test_check_private_dir() {
+        use tempdir::TempDir;
+
+        let tempdir = TempDir::new("sccache_test_socket").unwrap();
+        let dir = tempdir.path().join("sockets");
+        DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert!(check_private_dir(&dir, uid()).is_ok());
+        // Directories of other users, or that other users can get into,
+        // aren't used.
+        assert!(check_private_dir(&dir, uid() + 1).is_err());
+        fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        assert!(check_private_dir(&dir, uid()).is_err());
+        assert_eq!(io::ErrorKind::NotFound,
+                   check_private_dir(&tempdir.path().join("missing"), uid()).unwrap_err().kind());
+    }
+}
@@ -13,18 +13,23 @@
 // limitations under the License.
 
 use futures::Future;
-use futures::future;
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
 use mock_command::{CommandChild, RunCommand};
 use ring::digest::{SHA512, Context};
+use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::hash::Hasher;
 use std::io::BufReader;
 use std::io::prelude::*;
-use std::path::PathBuf;
-use std::process::{self,Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{self,ExitStatus,Stdio};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tempdir::TempDir;
+use tokio_io::AsyncRead;
 
 use errors::*;
 
@@ -90,13 +95,17 @@ pub fn fmt_duration_as_secs(duration: &Duration) -> String
     format!("{}.{:03} s", duration.as_secs(), duration.subsec_nanos() / 1000_000)
 }
 
-/// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
+/// If `input`, write it to `child`'s stdin while also reading `child`'s stdout
+/// with `read_stdout` and its stderr, then wait on `child` and return its
+/// status and output.
 ///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
 /// to also write to stdin.
-fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
-                             -> SFuture<process::Output>
+fn wait_with_input<T, O, F>(mut child: T, input: Option<Vec<u8>>, read_stdout: F)
+                            -> SFuture<(ExitStatus, Option<O>, Vec<u8>)>
     where T: CommandChild + 'static,
+          O: 'static,
+          F: FnOnce(T::O) -> SFuture<O>,
 {
     use tokio_io::io::{write_all, read_to_end};
     let stdin = input.and_then(|i| {
@@ -104,8 +113,7 @@ fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
             write_all(stdin, i)
         })
     }).chain_err(|| "failed to write stdin");
-    let stdout = child.take_stdout().map(|io| read_to_end(io, Vec::new()));
-    let stdout = stdout.chain_err(|| "failed to read stdout");
+    let stdout = child.take_stdout().map(read_stdout);
     let stderr = child.take_stderr().map(|io| read_to_end(io, Vec::new()));
     let stderr = stderr.chain_err(|| "failed to read stderr");
 
@@ -116,12 +124,25 @@ fn wait_with_input_output<T>(mut child: T, input: Option<Vec<u8>>)
     });
 
     Box::new(status.join3(stdout, stderr).map(|(status, out, err)| {
-        let stdout = out.map(|p| p.1);
         let stderr = err.map(|p| p.1);
+        (status, out, stderr.unwrap_or_default())
+    }))
+}
+
+/// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
+fn wait_with_input_output<T>(child: T, input: Option<Vec<u8>>)
+                             -> SFuture<process::Output>
+    where T: CommandChild + 'static,
+{
+    use tokio_io::io::read_to_end;
+    let output = wait_with_input(child, input, |io| -> SFuture<Vec<u8>> {
+        Box::new(read_to_end(io, Vec::new()).map(|p| p.1).chain_err(|| "failed to read stdout"))
+    });
+    Box::new(output.map(|(status, stdout, stderr)| {
         process::Output {
             status: status,
             stdout: stdout.unwrap_or_default(),
-            stderr: stderr.unwrap_or_default(),
+            stderr: stderr,
         }
     }))
 }
@@ -154,6 +175,153 @@ pub fn run_input_output<C>(mut command: C, input: Option<Vec<u8>>)
              }))
 }
 
+/// A process's stdout, as read by `run_input_output_spilling`.
+pub enum Stdout {
+    /// All of the output, which was small enough to keep in memory.
+    Memory(Vec<u8>),
+    /// A file holding the output, in a temporary directory that's removed
+    /// when this is dropped.
+    File(TempDir, PathBuf),
+}
+
+/// The exit status and output of a process run by `run_input_output_spilling`.
+pub struct SpillOutput {
+    pub status: ExitStatus,
+    pub stdout: Stdout,
+    pub stderr: Vec<u8>,
+}
+
+impl SpillOutput {
+    /// Return how many bytes of output are held in memory.
+    pub fn buffered_size(&self) -> usize {
+        let stdout = match self.stdout {
+            Stdout::Memory(ref data) => data.len(),
+            Stdout::File(..) => 0,
+        };
+        stdout + self.stderr.len()
+    }
+
+    /// Return the output as a `process::Output`, reading stdout back into
+    /// memory if it was written to a file.
+    pub fn into_output(self) -> Result<process::Output> {
+        let stdout = match self.stdout {
+            Stdout::Memory(data) => data,
+            Stdout::File(_dir, path) => {
+                let mut data = vec![];
+                File::open(&path).and_then(|mut f| f.read_to_end(&mut data))?;
+                data
+            }
+        };
+        Ok(process::Output {
+            status: self.status,
+            stdout: stdout,
+            stderr: self.stderr,
+        })
+    }
+}
+
+impl From<process::Output> for SpillOutput {
+    fn from(output: process::Output) -> SpillOutput {
+        SpillOutput {
+            status: output.status,
+            stdout: Stdout::Memory(output.stdout),
+            stderr: output.stderr,
+        }
+    }
+}
+
+/// Read all of `io`, keeping it in memory until there's more than `limit`
+/// bytes of it, and from then on writing it to a file named `name` in a
+/// temporary directory instead. The file is written on `pool`, so that a
+/// large output doesn't hold up the thread reading it.
+fn read_spilling<R>(io: R, name: PathBuf, limit: usize, pool: CpuPool) -> SFuture<Stdout>
+    where R: AsyncRead + 'static,
+{
+    use tokio_io::io::read;
+    let start = (io, vec![0; 64 * 1024], Vec::new(), None);
+    Box::new(future::loop_fn(start, move |(io, buf, mut data, file): (R, Vec<u8>, Vec<u8>, Option<(TempDir, PathBuf, File)>)| {
+        let name = name.clone();
+        let pool = pool.clone();
+        read(io, buf).chain_err(|| "failed to read stdout").and_then(move |(io, buf, n)| -> SFuture<_> {
+            if n == 0 {
+                return f_ok(Loop::Break(match file {
+                    Some((dir, path, _)) => Stdout::File(dir, path),
+                    None => Stdout::Memory(data),
+                }))
+            }
+            let write = match file {
+                Some((dir, path, mut f)) => pool.spawn_fn(move || -> Result<_> {
+                    f.write_all(&buf[..n])?;
+                    Ok((buf, (dir, path, f)))
+                }),
+                None => {
+                    data.extend_from_slice(&buf[..n]);
+                    if data.len() <= limit {
+                        return f_ok(Loop::Continue((io, buf, data, None)))
+                    }
+                    pool.spawn_fn(move || -> Result<_> {
+                        let dir = TempDir::new("sccache")?;
+                        let path = dir.path().join(&name);
+                        let mut f = File::create(&path)?;
+                        f.write_all(&data)?;
+                        Ok((buf, (dir, path, f)))
+                    })
+                }
+            };
+            Box::new(write.map(move |(buf, file)| Loop::Continue((io, buf, Vec::new(), Some(file)))))
+        })
+    }))
+}
+
+/// Run `command` like `run_input_output`, except that once its stdout is
+/// over `limit` bytes, it's written to a file named `name` in a temporary
+/// directory as it's read rather than kept in memory, on `pool`.
+///
+/// If the command fails, the error only has its stdout if it was kept in
+/// memory.
+pub fn run_input_output_spilling<C>(mut command: C,
+                                    input: Option<Vec<u8>>,
+                                    name: &Path,
+                                    limit: usize,
+                                    pool: &CpuPool)
+                                    -> SFuture<SpillOutput>
+    where C: RunCommand
+{
+    let child = command
+        .no_console()
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::inherit() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .chain_err(|| "failed to spawn child");
+
+    let name = name.to_owned();
+    let pool = pool.clone();
+    Box::new(future::result(child)
+             .and_then(move |child| {
+                 let output = wait_with_input(child, input, move |io| read_spilling(io, name, limit, pool));
+                 output.and_then(|(status, stdout, stderr)| {
+                     let stdout = stdout.unwrap_or(Stdout::Memory(vec![]));
+                     if status.success() {
+                         return Ok(SpillOutput {
+                             status: status,
+                             stdout: stdout,
+                             stderr: stderr,
+                         })
+                     }
+                     let stdout = match stdout {
+                         Stdout::Memory(data) => data,
+                         Stdout::File(..) => vec![],
+                     };
+                     Err(ErrorKind::ProcessError(process::Output {
+                         status: status,
+                         stdout: stdout,
+                         stderr: stderr,
+                     }).into())
+                 })
+             }))
+}
+
 pub trait OsStrExt {
     fn starts_with(&self, s: &str) -> bool;
     fn split_prefix(&self, s: &str) -> Option<OsString>;
@@ -256,6 +424,198 @@ impl OsStrExt for OsStr {
     }
 }
 
+/// A soft limit on the memory held by in-flight compilations.
+///
+/// Large buffers, such as preprocessor output and cache entries waiting to be
+/// written, are accounted for with `reserve`. While the total is at or over
+/// the limit, `wait_for_room` holds off starting new work. Waiting work
+/// starts in the order it asked, as much at a time as is expected to fit.
+/// Only reserved buffers are counted, so this is an estimate rather than a
+/// hard cap.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Mutex<MemoryBudgetInner>>,
+}
+
+struct MemoryBudgetInner {
+    limit: Option<usize>,
+    in_use: usize,
+    /// A running average of the size of reservations, which is how much
+    /// each waiter is expected to reserve once it starts.
+    average: usize,
+    waiters: VecDeque<oneshot::Sender<()>>,
+}
+
+impl MemoryBudgetInner {
+    fn is_full(&self) -> bool {
+        self.limit.map(|limit| self.in_use >= limit).unwrap_or(false)
+    }
+
+    /// Start waiters, oldest first, for as long as the memory they're
+    /// expected to reserve fits in the budget.
+    fn wake_waiters(&mut self) {
+        let mut expected = self.in_use;
+        while self.limit.map(|limit| expected < limit).unwrap_or(true) {
+            match self.waiters.pop_front() {
+                // Waiters that have gone away don't take any room.
+                Some(waiter) => if waiter.send(()).is_ok() {
+                    expected += self.average;
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+/// Memory reserved from a `MemoryBudget`, which is given back when dropped.
+pub struct MemoryReservation {
+    budget: MemoryBudget,
+    size: usize,
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes, or an unlimited one if `None`.
+    pub fn new(limit: Option<usize>) -> MemoryBudget {
+        MemoryBudget {
+            inner: Arc::new(Mutex::new(MemoryBudgetInner {
+                limit: limit,
+                in_use: 0,
+                average: 0,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// The configured limit, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.lock().unwrap().limit
+    }
+
+    /// The number of bytes currently reserved.
+    pub fn in_use(&self) -> usize {
+        self.inner.lock().unwrap().in_use
+    }
+
+    /// Account for `size` bytes until the returned reservation is dropped.
+    ///
+    /// This never blocks, even if it takes the budget over its limit.
+    pub fn reserve(&self, size: usize) -> MemoryReservation {
+        let mut inner = self.inner.lock().unwrap();
+        let average = if inner.average == 0 { size } else { (inner.average * 7 + size) / 8 };
+        inner.in_use += size;
+        inner.average = average;
+        MemoryReservation {
+            budget: self.clone(),
+            size: size,
+        }
+    }
+
+    /// Return a future that resolves once the budget is under its limit,
+    /// and the work that was already waiting has started.
+    pub fn wait_for_room(&self) -> SFuture<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.is_full() && inner.waiters.is_empty() {
+            return f_ok(());
+        }
+        let (tx, rx) = oneshot::channel();
+        inner.waiters.push_back(tx);
+        // Nothing is reserved, so no reservation being dropped will start
+        // the waiters.
+        if inner.in_use == 0 {
+            inner.wake_waiters();
+        }
+        Box::new(rx.then(|_| Ok(())))
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        let mut inner = self.budget.inner.lock().unwrap();
+        inner.in_use -= self.size;
+        inner.wake_waiters();
+    }
+}
+
+/// A limit on the number of compilers running at once.
+///
+/// `acquire` resolves to a `CompileSlot` once fewer than the limit are held.
+/// Compiles beyond the limit wait in a queue, in the order they asked, rather
+/// than failing.
+#[derive(Clone)]
+pub struct CompileSlots {
+    inner: Arc<Mutex<CompileSlotsInner>>,
+}
+
+struct CompileSlotsInner {
+    limit: Option<usize>,
+    running: usize,
+    queue: VecDeque<oneshot::Sender<CompileSlot>>,
+}
+
+/// Permission to run a compiler, which is handed on to the next queued
+/// compile when dropped.
+pub struct CompileSlot {
+    slots: CompileSlots,
+}
+
+impl CompileSlots {
+    /// Allow `limit` compilers to run at once, or any number if `None`.
+    pub fn new(limit: Option<usize>) -> CompileSlots {
+        CompileSlots {
+            inner: Arc::new(Mutex::new(CompileSlotsInner {
+                limit: limit,
+                running: 0,
+                queue: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// The configured limit, if any.
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.lock().unwrap().limit
+    }
+
+    /// The number of slots currently held.
+    pub fn running(&self) -> usize {
+        self.inner.lock().unwrap().running
+    }
+
+    /// The number of compiles waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.inner.lock().unwrap().queue.len()
+    }
+
+    /// Return a future that resolves to a slot once one is free.
+    pub fn acquire(&self) -> SFuture<CompileSlot> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.limit.map(|limit| inner.running < limit).unwrap_or(true) {
+            inner.running += 1;
+            return f_ok(CompileSlot { slots: self.clone() });
+        }
+        let (tx, rx) = oneshot::channel();
+        inner.queue.push_back(tx);
+        Box::new(rx.map_err(|_| Error::from("compile slot was dropped")))
+    }
+}
+
+impl Drop for CompileSlot {
+    fn drop(&mut self) {
+        let next = {
+            let mut inner = self.slots.inner.lock().unwrap();
+            match inner.queue.pop_front() {
+                Some(next) => next,
+                None => {
+                    inner.running -= 1;
+                    return;
+                }
+            }
+        };
+        // If the next compile has gone away, the slot comes straight back
+        // and is dropped again, passing it further down the queue.
+        drop(next.send(CompileSlot { slots: self.slots.clone() }));
+    }
+}
+
 pub struct HashToDigest<'a> {
     pub digest: &'a mut Digest,
 }
@@ -272,8 +632,15 @@ impl<'a> Hasher for HashToDigest<'a> {
 
 #[cfg(test)]
 mod tests {
+    use futures::{Async, Future, future};
+    use futures_cpupool::CpuPool;
+    use mock_command::*;
     use std::ffi::{OsStr, OsString};
-    use super::OsStrExt;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+    use super::{CompileSlot, CompileSlots, MemoryBudget, OsStrExt, Stdout, run_input_output_spilling};
+    use test::utils::*;
 
     #[test]
     fn simple_starts_with() {
@@ -301,4 +668,134 @@ mod tests {
         assert_eq!(a.split_prefix("foo2"), None);
         assert_eq!(a.split_prefix("b"), None);
     }
+
+    #[test]
+    fn memory_budget_backpressure() {
+        let budget = MemoryBudget::new(Some(100));
+        assert!(budget.wait_for_room().wait().is_ok());
+
+        // Simulate large in-flight buffers that take the budget over its limit.
+        let preprocessed = budget.reserve(60);
+        let cache_write = budget.reserve(60);
+        assert_eq!(budget.in_use(), 120);
+        let mut wait = budget.wait_for_room();
+        fn ready(wait: &mut ::errors::SFuture<()>) -> Async<()> {
+            future::lazy(|| Ok::<_, ()>(wait.poll().unwrap())).wait().unwrap()
+        }
+        assert_eq!(ready(&mut wait), Async::NotReady);
+
+        // New work can start once enough memory has been released.
+        drop(preprocessed);
+        assert_eq!(budget.in_use(), 60);
+        assert_eq!(ready(&mut wait), Async::Ready(()));
+        drop(cache_write);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn memory_budget_fifo() {
+        fn ready(wait: &mut ::errors::SFuture<()>) -> Async<()> {
+            future::lazy(|| Ok::<_, ()>(wait.poll().unwrap())).wait().unwrap()
+        }
+        let budget = MemoryBudget::new(Some(100));
+        let first = budget.reserve(60);
+        let second = budget.reserve(60);
+        let mut waits = (0..3).map(|_| budget.wait_for_room()).collect::<Vec<_>>();
+        // Freeing 60 bytes only has room for the oldest waiter, which is
+        // expected to reserve about as much as the others.
+        drop(first);
+        assert_eq!(ready(&mut waits[0]), Async::Ready(()));
+        assert_eq!(ready(&mut waits[1]), Async::NotReady);
+        // New work doesn't jump ahead of work that's waiting.
+        let mut late = budget.wait_for_room();
+        assert_eq!(ready(&mut late), Async::NotReady);
+        // Freeing everything starts as many as fit, in order.
+        drop(second);
+        assert_eq!(ready(&mut waits[1]), Async::Ready(()));
+        assert_eq!(ready(&mut waits[2]), Async::Ready(()));
+        assert_eq!(ready(&mut late), Async::NotReady);
+        // With nothing reserved, nothing else would start the rest.
+        let mut last = budget.wait_for_room();
+        assert_eq!(ready(&mut late), Async::Ready(()));
+        assert_eq!(ready(&mut last), Async::Ready(()));
+    }
+
+    #[test]
+    fn compile_slots_queue() {
+        let slots = CompileSlots::new(Some(1));
+        let first = slots.acquire().wait().unwrap();
+        let mut second = slots.acquire();
+        let mut third = slots.acquire();
+        assert_eq!(slots.running(), 1);
+        assert_eq!(slots.queued(), 2);
+        fn poll_slot(wait: &mut ::errors::SFuture<CompileSlot>) -> Option<CompileSlot> {
+            future::lazy(|| {
+                Ok::<_, ()>(match wait.poll().unwrap() {
+                    Async::Ready(slot) => Some(slot),
+                    Async::NotReady => None,
+                })
+            }).wait().unwrap()
+        }
+        assert!(poll_slot(&mut second).is_none());
+        // Slots are handed out in the order they were asked for.
+        drop(first);
+        let second = poll_slot(&mut second);
+        assert!(second.is_some());
+        assert!(poll_slot(&mut third).is_none());
+        assert_eq!(slots.running(), 1);
+        assert_eq!(slots.queued(), 1);
+        // A compile that gave up waiting doesn't keep the slot from being released.
+        drop(third);
+        drop(second);
+        assert_eq!(slots.running(), 0);
+        assert_eq!(slots.queued(), 0);
+    }
+
+    #[test]
+    fn run_input_output_spills_large_output() {
+        let creator = new_creator();
+        let pool = CpuPool::new(1);
+        // 50 MB of output is written to a file, not kept in memory.
+        let big = (0..50 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        next_command(&creator, Ok(MockChild::new(exit_status(0), &big, "warning")));
+        let cmd = creator.clone().new_command_sync("cc");
+        let output = run_input_output_spilling(cmd, None, Path::new("foo.c"), 16 * 1024 * 1024, &pool).wait().unwrap();
+        assert_eq!(b"warning", output.stderr.as_slice());
+        assert_eq!(7, output.buffered_size());
+        match output.stdout {
+            Stdout::File(ref dir, ref path) => {
+                assert_eq!(dir.path().join("foo.c"), *path);
+                let mut contents = vec![];
+                File::open(path).and_then(|mut f| f.read_to_end(&mut contents)).unwrap();
+                assert!(contents == big, "spilled output differs");
+            }
+            Stdout::Memory(..) => panic!("Expected output in a file"),
+        }
+        // Small output stays in memory.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "int x;", "")));
+        let cmd = creator.clone().new_command_sync("cc");
+        let output = run_input_output_spilling(cmd, None, Path::new("foo.c"), 16 * 1024 * 1024, &pool).wait().unwrap();
+        match output.stdout {
+            Stdout::Memory(ref data) => assert_eq!(b"int x;", data.as_slice()),
+            Stdout::File(..) => panic!("Expected output in memory"),
+        }
+        // Failures don't hand back spilled output.
+        next_command(&creator, Ok(MockChild::new(exit_status(1), &big, "error")));
+        let cmd = creator.clone().new_command_sync("cc");
+        match run_input_output_spilling(cmd, None, Path::new("foo.c"), 16 * 1024 * 1024, &pool).wait() {
+            Err(::errors::Error(::errors::ErrorKind::ProcessError(output), _)) => {
+                assert!(output.stdout.is_empty());
+                assert_eq!(b"error", output.stderr.as_slice());
+            }
+            Ok(_) => panic!("Unexpected success"),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn memory_budget_unlimited() {
+        let budget = MemoryBudget::new(None);
+        let _big = budget.reserve(1 << 30);
+        assert!(budget.wait_for_room().wait().is_ok());
+    }
 }